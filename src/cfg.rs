@@ -39,6 +39,8 @@ cursor_padding = 1.5
 [visual]
 # Height in pixels of a line of text.
 font_height = 20
+# Distance between tab stops, in widths of a space.
+tab_stop = 4
 # Width of the line number bar.
 left_bar = 100
 # Padding between the line numbers and the text window.
@@ -55,6 +57,12 @@ selection_color = [255, 255, 255, 255]
 selection_bg_color = [10, 60, 180, 255]
 # Y offset applied to the selection highlight, proportional to the font height.
 selection_offset = 0.2
+# Background color of search matches.
+match_bg_color = [90, 70, 20, 255]
+# Background color of the selected search match.
+current_match_bg_color = [200, 120, 20, 255]
+# Color of the bytes in the hex view that were modified since the file was saved.
+edited_color = [255, 150, 60, 255]
 # Color of the scrollbar background.
 scrollbar_color = [10, 10, 10, 220]
 # Color of the corner square between the vertical and horizontal scrollbars
@@ -89,6 +97,56 @@ cursor_width = 2
 cursor_color = [255, 255, 255, 255]
 # Cursor blink half-period, in seconds.
 cursor_blink = 0.5
+# Height of the bars at the bottom of the file view, such as the find bar.
+bar_height = 24
+# Height of the bar text font.
+bar_font_height = 16
+# Horizontal padding of the bar text.
+bar_padding = 6
+# Background color of the bars.
+bar_bg_color = [20, 20, 20, 240]
+# Color of the text typed into bars.
+bar_text_color = [255, 255, 255, 255]
+# Color of informational text in bars.
+bar_info_color = [150, 150, 150, 255]
+# Color of the find bar option toggles (Alt+C, Alt+W and Alt+R), when disabled and
+# when enabled.
+bar_toggle_color = [[90, 90, 90, 255], [255, 200, 80, 255]]
+# Height of the status bar at the bottom of the window.
+status_height = 22
+# Background color of the status bar.
+status_bg_color = [10, 10, 10, 255]
+# Text color of the status bar.
+status_text_color = [180, 180, 180, 255]
+# Size of dialog boxes.
+dialog_size = [420, 110]
+# Size of dialog buttons.
+dialog_button_size = [90, 26]
+# Padding around the contents of dialog boxes.
+dialog_padding = 14
+# Height of the dialog text font.
+dialog_font_height = 16
+# Color that shades the file view behind dialogs.
+dialog_shade_color = [0, 0, 0, 160]
+# Background color of dialog boxes.
+dialog_bg_color = [30, 30, 30, 255]
+# Background color of focused/unfocused dialog buttons.
+dialog_button_color = [[10, 60, 180, 255], [50, 50, 50, 255]]
+# Text color of dialogs.
+dialog_text_color = [255, 255, 255, 255]
+# Size of notification toasts.
+toast_size = [380, 28]
+# Gap between notification toasts and the edges of the file view.
+toast_margin = 8
+# Height of the notification toast font.
+toast_font_height = 15
+# For how many seconds informational toasts are shown. Error toasts stay until they
+# are clicked.
+toast_duration = 4.0
+# Background color of informational/error toasts.
+toast_bg_color = [[40, 40, 40, 240], [130, 30, 30, 240]]
+# Text color of notification toasts.
+toast_text_color = [255, 255, 255, 255]
 
 [log]
 # Log the time that each rendering stage takes
@@ -126,10 +184,52 @@ realloc_threshold = 100000
 read_size = 1000000
 # How far away from the screen to preload file data.
 load_radius = 1000000
+# How often to check whether a followed file grew, in seconds.
+follow_interval = 0.5
+# How often to check whether the file was changed by another process, in seconds.
+watch_interval = 1.0
+# When viewing a compressed file, how many megabytes of decompressed data to leave
+# between snapshots of the decoder.
+# Reading from the middle of the file decompresses up to this much data, and each
+# gzip snapshot takes about 40KB of memory.
+checkpoint_spacing_mb = 1.0
+# Upper limit on the memory used by the snapshots of the decoder, in megabytes.
+# Once it is reached, every other snapshot is dropped and the spacing doubles.
+max_checkpoint_mb = 64
 # The maximum amount of data that can be copied out of the file.
 # When selecting a range of this size, the data for this range will be loaded
 # into RAM!
 max_selection_copy = 500000000
+
+[search]
+# Maximum amount of search matches to keep track of.
+# Matches beyond this amount are counted, but cannot be jumped to.
+max_matches = 1000000
+# Maximum length of a regular expression match, in bytes.
+# The file is searched in chunks, and matches longer than this that cross a chunk
+# boundary may be cut short or missed.
+max_match_len = 4096
+# Upper limit on the memory used to keep track of the matches of a replace-all, in
+# megabytes.
+# Each match takes a few dozen bytes, and replacing more matches than fit fails.
+max_replace_mb = 256
+
+[edit]
+# Upper limit on the memory used by the undo history.
+# The oldest edits are forgotten when this limit is reached.
+max_history_mb = 64
+# Directory where the state of open buffers is persisted, including any unsaved
+# edits, so that they can be recovered after a restart.
+# If empty, a `gaze/journal` directory in the per-user data directory is used, that
+# is `$XDG_DATA_HOME`, `~/.local/share`, `~/Library/Application Support` or
+# `%LOCALAPPDATA%`.
+journal_dir = ""
+# How often to persist the state of open buffers, in seconds.
+persist_interval = 10
+# Convert all line breaks to "LF", "CRLF" or "CR" when saving a buffer.
+# Converting writes out a full copy of the file and forgets the undo history.
+# If empty, line breaks are saved as they are.
+save_line_ending = ""
 "#;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -146,6 +246,7 @@ pub struct SlideIcon {
 pub struct Visual {
     /// In pixels.
     pub font_height: f32,
+    pub tab_stop: u32,
     pub left_bar: f32,
     pub linenum_pad: f32,
     pub linenum_color: [u8; 4],
@@ -154,6 +255,9 @@ pub struct Visual {
     pub selection_color: [u8; 4],
     pub selection_bg_color: [u8; 4],
     pub selection_offset: f32,
+    pub match_bg_color: [u8; 4],
+    pub current_match_bg_color: [u8; 4],
+    pub edited_color: [u8; 4],
     pub scrollbar_color: [u8; 4],
     pub scrollhandle_color: [u8; 4],
     pub scrollcorner_color: [u8; 4],
@@ -170,6 +274,30 @@ pub struct Visual {
     pub cursor_width: f32,
     pub cursor_color: [u8; 4],
     pub cursor_blink: f64,
+    pub bar_height: f32,
+    pub bar_font_height: f32,
+    pub bar_padding: f32,
+    pub bar_bg_color: [u8; 4],
+    pub bar_text_color: [u8; 4],
+    pub bar_info_color: [u8; 4],
+    pub bar_toggle_color: [[u8; 4]; 2],
+    pub status_height: f32,
+    pub status_bg_color: [u8; 4],
+    pub status_text_color: [u8; 4],
+    pub dialog_size: [f32; 2],
+    pub dialog_button_size: [f32; 2],
+    pub dialog_padding: f32,
+    pub dialog_font_height: f32,
+    pub dialog_shade_color: [u8; 4],
+    pub dialog_bg_color: [u8; 4],
+    pub dialog_button_color: [[u8; 4]; 2],
+    pub dialog_text_color: [u8; 4],
+    pub toast_size: [f32; 2],
+    pub toast_margin: f32,
+    pub toast_font_height: f32,
+    pub toast_duration: f64,
+    pub toast_bg_color: [[u8; 4]; 2],
+    pub toast_text_color: [u8; 4],
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -198,9 +326,28 @@ pub struct FileLoading {
     pub realloc_threshold: usize,
     pub read_size: usize,
     pub load_radius: usize,
+    pub follow_interval: f64,
+    pub watch_interval: f64,
+    pub checkpoint_spacing_mb: f64,
+    pub max_checkpoint_mb: f64,
     pub max_selection_copy: usize,
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Search {
+    pub max_matches: usize,
+    pub max_match_len: usize,
+    pub max_replace_mb: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Edit {
+    pub max_history_mb: f64,
+    pub journal_dir: String,
+    pub persist_interval: f64,
+    pub save_line_ending: String,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DragButton {
     pub button: u16,
@@ -232,6 +379,8 @@ pub struct Cfg {
     #[serde(rename = "file")]
     pub f: FileLoading,
     pub ui: Ui,
+    pub search: Search,
+    pub edit: Edit,
     pub log: Log,
 }
 impl Default for Cfg {
@@ -247,6 +396,34 @@ impl Cfg {
         Some(near_exe)
     }
 
+    /// The directory where buffer journals are stored.
+    pub fn journal_dir(&self) -> Option<PathBuf> {
+        if self.edit.journal_dir.is_empty() {
+            let mut dir = Self::user_data_dir()?;
+            dir.push("gaze");
+            dir.push("journal");
+            Some(dir)
+        } else {
+            Some(self.edit.journal_dir.clone().into())
+        }
+    }
+
+    /// The per-user directory where applications keep their data, if there is one.
+    fn user_data_dir() -> Option<PathBuf> {
+        let var = |name: &str| {
+            std::env::var_os(name)
+                .map(PathBuf::from)
+                .filter(|p| p.is_absolute())
+        };
+        if cfg!(windows) {
+            var("LOCALAPPDATA")
+        } else if cfg!(target_os = "macos") {
+            var("HOME").map(|home| home.join("Library/Application Support"))
+        } else {
+            var("XDG_DATA_HOME").or_else(|| var("HOME").map(|home| home.join(".local/share")))
+        }
+    }
+
     pub fn load_path() -> Option<PathBuf> {
         let mut cur = PathBuf::from(".");
         cur.push(CFG_PATH);
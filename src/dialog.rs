@@ -0,0 +1,144 @@
+//! Modal dialogs, drawn by gaze itself.
+//!
+//! The dialog state and layout do not depend on the window, so they can be driven
+//! without a display.
+
+use crate::{cfg::Cfg, prelude::*, ScreenRect};
+use gl::winit::event::VirtualKeyCode;
+
+#[cfg(test)]
+mod test;
+
+/// An answer to a dialog.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Choice {
+    Save,
+    Discard,
+    Cancel,
+    Reload,
+    Keep,
+}
+impl Choice {
+    pub fn label(self) -> &'static str {
+        match self {
+            Choice::Save => "Save",
+            Choice::Discard => "Discard",
+            Choice::Cancel => "Cancel",
+            Choice::Reload => "Reload",
+            Choice::Keep => "Keep",
+        }
+    }
+
+    /// The key that picks this choice right away.
+    fn shortcut(self) -> VirtualKeyCode {
+        match self {
+            Choice::Save => VirtualKeyCode::S,
+            Choice::Discard => VirtualKeyCode::D,
+            Choice::Cancel => VirtualKeyCode::C,
+            Choice::Reload => VirtualKeyCode::R,
+            Choice::Keep => VirtualKeyCode::K,
+        }
+    }
+}
+
+/// Where each part of a dialog goes on the screen.
+pub struct DialogLayout {
+    /// The dialog box itself.
+    pub frame: ScreenRect,
+    /// The baseline origin of the message text.
+    pub message: Vec2,
+    /// The bounds of each button, in the same order as the choices.
+    pub buttons: Vec<ScreenRect>,
+}
+
+pub struct Dialog {
+    pub message: String,
+    pub choices: Vec<Choice>,
+    /// The index of the choice that is picked by pressing Enter.
+    pub focus: usize,
+}
+impl Dialog {
+    /// Ask whether to save the changes to a file before closing it.
+    pub fn save_changes(name: &str) -> Dialog {
+        Dialog {
+            message: format!("Save changes to \"{}\"?", name),
+            choices: vec![Choice::Save, Choice::Discard, Choice::Cancel],
+            focus: 0,
+        }
+    }
+
+    /// Ask whether to reload a file that was changed by another process, describing
+    /// how it changed.
+    pub fn reload(name: &str, what: &str, dirty: bool) -> Dialog {
+        let question = match dirty {
+            true => "Reload and lose your changes?",
+            false => "Reload it?",
+        };
+        Dialog {
+            message: format!("\"{}\" {}. {}", name, what, question),
+            choices: vec![Choice::Reload, Choice::Keep],
+            focus: 0,
+        }
+    }
+
+    /// Center the dialog box on the screen, with the buttons aligned to the bottom
+    /// right corner.
+    pub fn layout(&self, k: &Cfg, screen: ScreenRect) -> DialogLayout {
+        let size = Vec2::from(k.g.dialog_size);
+        let button = Vec2::from(k.g.dialog_button_size);
+        let pad = k.g.dialog_padding;
+        let min = ((screen.min + screen.max - size) / 2.).round();
+        let frame = ScreenRect {
+            min,
+            max: min + size,
+        };
+        let n = self.choices.len();
+        let buttons = (0..n)
+            .map(|i| {
+                let max = vec2(
+                    frame.max.x - pad - (n - 1 - i) as f32 * (button.x + pad),
+                    frame.max.y - pad,
+                );
+                ScreenRect {
+                    min: max - button,
+                    max,
+                }
+            })
+            .collect();
+        DialogLayout {
+            frame,
+            message: frame.min + vec2(pad, pad + k.g.dialog_font_height),
+            buttons,
+        }
+    }
+
+    /// Handle a key press.
+    /// Returns the picked choice, if any.
+    pub fn handle_key(&mut self, key: VirtualKeyCode, shift: bool) -> Option<Choice> {
+        use VirtualKeyCode::*;
+        let n = self.choices.len();
+        match key {
+            Left => self.focus = (self.focus + n - 1) % n,
+            Right => self.focus = (self.focus + 1) % n,
+            Tab if shift => self.focus = (self.focus + n - 1) % n,
+            Tab => self.focus = (self.focus + 1) % n,
+            Return | NumpadEnter | Space => return self.choices.get(self.focus).copied(),
+            Escape if self.choices.contains(&Choice::Cancel) => return Some(Choice::Cancel),
+            _ => {
+                return self.choices.iter().copied().find(|c| c.shortcut() == key);
+            }
+        }
+        None
+    }
+
+    /// Handle a click at the given screen position.
+    /// Returns the picked choice, if any.
+    pub fn handle_click(&self, k: &Cfg, screen: ScreenRect, pos: Vec2) -> Option<Choice> {
+        let layout = self.layout(k, screen);
+        layout
+            .buttons
+            .iter()
+            .position(|b| b.is_inside(pos))
+            .map(|i| self.choices[i])
+    }
+}
@@ -0,0 +1,53 @@
+use crate::{
+    cfg::Cfg,
+    dialog::{Choice, Dialog},
+    prelude::*,
+    ScreenRect,
+};
+use gl::winit::event::VirtualKeyCode::*;
+
+fn screen() -> ScreenRect {
+    ScreenRect {
+        min: vec2(0., 0.),
+        max: vec2(800., 600.),
+    }
+}
+
+#[test]
+fn dialog_keys() {
+    let mut d = Dialog::save_changes("file.txt");
+    assert_eq!(d.handle_key(Return, false), Some(Choice::Save));
+    assert_eq!(d.handle_key(Right, false), None);
+    assert_eq!(d.handle_key(Return, false), Some(Choice::Discard));
+    assert_eq!(d.handle_key(Tab, true), None);
+    assert_eq!(d.handle_key(Left, false), None);
+    assert_eq!(d.handle_key(Space, false), Some(Choice::Cancel));
+    assert_eq!(d.handle_key(D, false), Some(Choice::Discard));
+    assert_eq!(d.handle_key(Escape, false), Some(Choice::Cancel));
+    assert_eq!(d.handle_key(X, false), None);
+}
+
+#[test]
+fn dialog_clicks() {
+    let k = Cfg::default();
+    let d = Dialog::save_changes("file.txt");
+    let layout = d.layout(&k, screen());
+    assert!(layout.frame.min.x >= 0. && layout.frame.max.x <= 800.);
+    assert!(layout.frame.min.y >= 0. && layout.frame.max.y <= 600.);
+    for (i, b) in layout.buttons.iter().enumerate() {
+        let center = (b.min + b.max) / 2.;
+        assert!(layout.frame.is_inside(b.min) && layout.frame.is_inside(b.max - 1.));
+        assert_eq!(d.handle_click(&k, screen(), center), Some(d.choices[i]));
+    }
+    assert_eq!(d.handle_click(&k, screen(), layout.message), None);
+    assert_eq!(d.handle_click(&k, screen(), vec2(1., 1.)), None);
+}
+
+#[test]
+fn dialog_reload() {
+    let mut d = Dialog::reload("file.txt", "was truncated", true);
+    assert!(d.message.contains("lose your changes"));
+    assert_eq!(d.handle_key(K, false), Some(Choice::Keep));
+    assert_eq!(d.handle_key(Escape, false), None);
+    assert_eq!(d.handle_key(Return, false), Some(Choice::Reload));
+}
@@ -1,7 +1,7 @@
 use std::mem::ManuallyDrop;
 
 use crate::{cfg::Cfg, prelude::*, ScreenRect, WindowState};
-use ab_glyph::{Font, Glyph};
+use ab_glyph::{Font, Glyph, ScaleFont};
 use gl::glium::{
     index::{IndicesSource, PrimitiveType},
     uniforms::{MagnifySamplerFilter, MinifySamplerFilter, Uniforms},
@@ -268,6 +268,50 @@ impl DrawState {
             timing: TimingLog::new(),
         })
     }
+
+    /// Queue a single line of overlay text, with its baseline starting at `pos`.
+    /// Characters that would go past `max_x` are left out.
+    /// Returns the horizontal position at which the text ends.
+    pub fn push_aux_line(
+        &mut self,
+        text: &str,
+        mut pos: Vec2,
+        height: f32,
+        color: [u8; 4],
+        max_x: f32,
+    ) -> f32 {
+        let font = self.font.as_scaled(height);
+        for c in text.chars() {
+            let id = font.glyph_id(c);
+            let adv = font.h_advance(id);
+            if pos.x + adv > max_x {
+                break;
+            }
+            self.aux_text.push(
+                &mut self.glyphs,
+                color,
+                Glyph {
+                    id,
+                    scale: height.into(),
+                    position: pos.to_array().into(),
+                },
+            );
+            pos.x += adv;
+        }
+        pos.x
+    }
+
+    /// The width of a line of text, at the given font height.
+    pub fn text_width(&self, text: &str, height: f32) -> f32 {
+        let font = self.font.as_scaled(height);
+        text.chars().map(|c| font.h_advance(font.glyph_id(c))).sum()
+    }
+
+    /// The vertical offset from the center of a line of text to its baseline.
+    pub fn center_to_baseline(&self, height: f32) -> f32 {
+        let font = self.font.as_scaled(height);
+        (font.ascent() + font.descent()) / 2.
+    }
 }
 
 pub struct FrameCtx {
@@ -337,6 +381,15 @@ pub fn draw(state: &mut WindowState) -> Result<Option<Instant>> {
 
     state.draw.timing.mark("frame-init");
 
+    // Draw the status bar background, its contents are filled in along with the file
+    {
+        let bounds = WindowState::status_bar_bounds(&state.k, state.screen);
+        state
+            .draw
+            .aux_vbo
+            .push_quad(bounds, state.k.g.status_bg_color);
+    }
+
     // Draw file text, and anything else that requires locking the shared file block
     if let Some(mut ftab) = state.take_ftab(state.cur_tab) {
         crate::fileview::drawing::draw_withtext(state, &mut ftab, &mut ctx)?;
@@ -363,13 +416,23 @@ pub fn draw(state: &mut WindowState) -> Result<Option<Instant>> {
             // Draw tab title
             let [top, rt, bot, lt] = state.k.g.tab_padding;
             let fonth = state.k.g.tab_height - top - bot;
+            let mut title = tab.file.friendly_name().to_string();
+            if tab.file.is_dirty() {
+                title.insert_str(0, "\u{2022} ");
+            }
+            if let Some(p) = tab.file.save_progress() {
+                title = format!("{} ({:.0}%)", title, p * 100.);
+                // Poll the progress instead of redrawing continuously, the save
+                // might take a long time
+                ctx.schedule_redraw(Instant::now() + Duration::from_millis(100));
+            }
             let mut replace_with_dots_from = 0;
             let mut truncate = None;
             {
                 let mut x = 0.;
                 let extra = tab.file.layout().advance_for('.' as u32) as f32 * fonth * 3.;
                 let limit = tab_view.size().x - lt - rt;
-                for (i, c) in tab.file.friendly_name().chars().enumerate() {
+                for (i, c) in title.chars().enumerate() {
                     if x + extra <= limit {
                         replace_with_dots_from = i;
                     }
@@ -381,7 +444,7 @@ pub fn draw(state: &mut WindowState) -> Result<Option<Instant>> {
                 }
             }
             let mut pos = vec2(tab_view.min.x + lt, tab_view.max.y - bot);
-            for (i, mut c) in tab.file.friendly_name().chars().enumerate() {
+            for (i, mut c) in title.chars().enumerate() {
                 if let Some(truncate) = truncate {
                     if i >= truncate {
                         break;
@@ -408,6 +471,66 @@ pub fn draw(state: &mut WindowState) -> Result<Option<Instant>> {
 
     state.draw.timing.mark("draw-tabs");
 
+    // Draw the toasts over the file view
+    {
+        state.poll_notices();
+        let now = Instant::now();
+        if let Some(expire) = state.toasts.expire(&state.k, now) {
+            ctx.schedule_redraw(expire);
+        }
+        let k = &state.k.g;
+        let view = WindowState::fileview_bounds(&state.k, state.screen);
+        let layout = state.toasts.layout(&state.k, view);
+        let draw = &mut state.draw;
+        let fonth = k.toast_font_height;
+        for (toast, &bounds) in state.toasts.list.iter().zip(&layout) {
+            draw.aux_vbo
+                .push_quad(bounds, k.toast_bg_color[toast.error as usize]);
+            let pad = ((bounds.size().y - fonth) / 2.).max(0.);
+            let pos = vec2(
+                bounds.min.x + pad,
+                (bounds.min.y + bounds.max.y) / 2. + draw.center_to_baseline(fonth),
+            );
+            draw.push_aux_line(
+                &toast.text,
+                pos.round(),
+                fonth,
+                k.toast_text_color,
+                bounds.max.x - pad,
+            );
+        }
+    }
+
+    state.draw.timing.mark("draw-toasts");
+
+    // Draw the dialog above everything else
+    if let Some(dialog) = &state.dialog {
+        let k = &state.k.g;
+        let layout = dialog.layout(&state.k, state.screen);
+        let draw = &mut state.draw;
+        draw.aux_vbo.push_quad(
+            WindowState::fileview_bounds(&state.k, state.screen),
+            k.dialog_shade_color,
+        );
+        draw.aux_vbo.push_quad(layout.frame, k.dialog_bg_color);
+        let (fonth, color) = (k.dialog_font_height, k.dialog_text_color);
+        let max_x = layout.frame.max.x - k.dialog_padding;
+        draw.push_aux_line(&dialog.message, layout.message, fonth, color, max_x);
+        for (i, (&choice, &bounds)) in dialog.choices.iter().zip(&layout.buttons).enumerate() {
+            draw.aux_vbo
+                .push_quad(bounds, k.dialog_button_color[(i != dialog.focus) as usize]);
+            // Center the label within the button
+            let label = choice.label();
+            let pos = vec2(
+                (bounds.min.x + bounds.max.x - draw.text_width(label, fonth)) / 2.,
+                (bounds.min.y + bounds.max.y) / 2. + draw.center_to_baseline(fonth),
+            );
+            draw.push_aux_line(label, pos.round(), fonth, color, max_x);
+        }
+    }
+
+    state.draw.timing.mark("draw-dialog");
+
     // Process the queued glyphs, uploading their rasterized images to the GPU
     let res = state
         .draw
@@ -1,16 +1,42 @@
+use std::collections::VecDeque;
+
 use ab_glyph::{Font, FontArc};
 
-use crate::{
-    cfg::Cfg,
-    filebuf::linemap::LineMap,
-    filebuf::{linemap::decode_utf8, sparse::SparseData},
-    prelude::*,
+use crate::{cfg::Cfg, filebuf::linemap::LineMap, filebuf::sparse::SparseData, prelude::*};
+
+pub use self::{
+    compress::Codec,
+    encoding::{Encoding, LineEnding},
+    history::{EditInfo, Restored},
+    persist::ViewState,
+    replace::ReplaceProgress,
+    search::{SearchQuery, SearchResults},
 };
 
-use self::linemap::{decode_utf8_rev, LineMapper};
+use self::{
+    compress::Decompressed,
+    history::History,
+    linemap::LineMapper,
+    persist::{Journal, JournalKey, JournalWriter},
+    piece::{Piece, PieceTable, Source},
+    replace::Replacer,
+    save::{modified_time, SaveJob},
+    search::{Dropped, Matcher},
+    watch::FileStamp,
+};
 
+mod compress;
+mod encoding;
+mod history;
 mod linemap;
+mod persist;
+mod piece;
+mod replace;
+mod save;
+mod search;
 mod sparse;
+mod spool;
+mod watch;
 
 #[cfg(test)]
 mod test;
@@ -20,9 +46,59 @@ pub struct LoadedData {
     /// seek large files quickly but also find precise characters quickly.
     pub linemap: LineMap,
     pub data: SparseData,
+    /// Maps the edited buffer to the file and the edited data.
+    /// `None` until the manager thread opens the file.
+    pub pieces: Option<PieceTable>,
+    pub history: History,
     pub hot: FileRect,
+    /// A range of bytes to load instead of the hot area, for views that show
+    /// bytes at fixed positions and need no linemap to find them.
+    hot_bytes: Option<ops::Range<i64>>,
     pub sel: Option<ops::Range<i64>>,
+    /// The state of the file view, to persist it.
+    pub view: ViewState,
+    /// A view state restored from a journal, to be picked up by the file view.
+    restored_view: Option<ViewState>,
     pub pending_sel_copy: bool,
+    pub pending_save: bool,
+    /// Pastes requested by the file view, waiting for the clipboard contents.
+    pending_pastes: VecDeque<PasteRequest>,
+    /// The ranges replaced by the last pastes and the lengths of the pasted text,
+    /// to be picked up by the file view.
+    pasted: Vec<(ops::Range<i64>, i64)>,
+    /// The search that is being carried out in the background, if any.
+    search: Option<SearchResults>,
+    /// The matcher for the current search, used by the main thread to highlight the
+    /// visible matches.
+    /// It is compiled by the manager thread, since compiling a regex might take a
+    /// while.
+    highlighter: Option<(SearchQuery, Arc<Matcher>)>,
+    /// The replace-all that is being carried out in the background, if any.
+    replace: Option<ReplaceProgress>,
+    /// The range replaced by the last replace-all and the length of the replaced
+    /// text, to be picked up by the file view.
+    replaced: Option<(ops::Range<i64>, i64)>,
+    /// The line that the file view wants to go to, if its position is not known yet.
+    /// The manager thread linemaps from the start of the buffer towards it.
+    goto_line: Option<i64>,
+    /// How the file was changed by another process, if it was.
+    /// The file pieces cannot be trusted anymore, so nothing else is read from the
+    /// file until the buffer is reloaded.
+    external_change: Option<String>,
+    /// Throw away the buffer and open the file again, as requested by the frontend.
+    pending_reload: bool,
+    /// A handle to the freshly saved file, which the piece table now refers to.
+    /// Picked up by the manager thread.
+    reopened: Option<File>,
+    /// Whether the manager thread should guess the encoding from the start of the
+    /// buffer.
+    /// Cleared once it does, or once the encoding is chosen by hand.
+    detect_encoding: bool,
+    /// The most common line ending at the start of the buffer, if it has any line
+    /// breaks at all.
+    line_ending: Option<LineEnding>,
+    /// Whether the manager thread should find out the line ending again.
+    detect_line_ending: bool,
     pub warn_time: Option<Duration>,
 }
 impl LoadedData {
@@ -30,26 +106,260 @@ impl LoadedData {
         max_loaded: usize,
         merge_batch_size: usize,
         realloc_threshold: usize,
+        max_history: usize,
         warn_time: Option<Duration>,
     ) -> Self {
         Self {
             linemap: LineMap::new(),
             data: SparseData::new(max_loaded, merge_batch_size, realloc_threshold),
+            pieces: None,
+            history: History::new(max_history),
             hot: default(),
+            hot_bytes: None,
             sel: None,
+            view: default(),
+            restored_view: None,
             pending_sel_copy: false,
+            pending_save: false,
+            pending_pastes: default(),
+            pasted: vec![],
+            search: None,
+            highlighter: None,
+            replace: None,
+            replaced: None,
+            goto_line: None,
+            external_change: None,
+            pending_reload: false,
+            reopened: None,
+            detect_encoding: true,
+            line_ending: None,
+            detect_line_ending: true,
             warn_time,
         }
     }
 
-    fn try_get_hot_range(&self) -> Option<(i64, i64, i64)> {
+    /// Replace the contents of the buffer by the first `size` bytes of the file,
+    /// forgetting everything about the old contents, including the undo history.
+    fn reset(&mut self, size: i64) {
+        let pieces = PieceTable::new(size);
+        let encoding = self.linemap.encoding;
+        self.linemap = LineMap::new();
+        self.linemap.file_size = size;
+        self.linemap.encoding = encoding;
+        self.linemap.edited = true;
+        self.data.segments.clear();
+        self.data.file_size = size;
+        self.data.edited = true;
+        self.history.clear();
+        if let Some(search) = &mut self.search {
+            search.restart(pieces.version(), size);
+        }
+        self.replace = None;
+        self.replaced = None;
+        self.pending_pastes.clear();
+        // Let the file view clamp its state to the new buffer
+        self.restored_view = Some(self.view);
+        self.pieces = Some(pieces);
+    }
+
+    /// Decode the buffer with the given encoding from now on.
+    /// The linemap is thrown away and built again, since characters may now be
+    /// anywhere else.
+    fn set_encoding(&mut self, encoding: Encoding) {
+        self.detect_encoding = false;
+        if self.linemap.encoding == encoding {
+            return;
+        }
+        let mut linemap = LineMap::new();
+        linemap.file_size = self.linemap.file_size;
+        linemap.encoding = encoding;
+        linemap.edited = true;
+        self.linemap = linemap;
+        // Line breaks look different in other encodings
+        self.detect_line_ending = true;
+        // And so does the query
+        if let (Some(search), Some(pieces)) = (&mut self.search, &self.pieces) {
+            search.restart(pieces.version(), pieces.len());
+            search.error = None;
+        }
+    }
+
+    /// Replace the given range of the buffer by the given data.
+    /// The range is clamped to the buffer.
+    ///
+    /// The linemap and the loaded data are shifted around to reflect the edit, so
+    /// the buffer does not need to be scanned again.
+    /// Returns the start of the clamped range and the removed pieces, or `None` if the
+    /// file is not open yet.
+    fn splice(
+        &mut self,
+        layout: &CharLayout,
+        range: ops::Range<i64>,
+        data: &[u8],
+    ) -> Option<(i64, Vec<Piece>)> {
+        let pieces = self.pieces.as_mut()?;
+        let len = pieces.len();
+        let l = range.start.clamp(0, len);
+        let r = range.end.clamp(l, len);
+        let removed = pieces.splice(l..r, data);
+        self.linemap.splice(&self.data, layout, l..r, data);
+        self.data.splice(l..r, data);
+        self.shift_pending(l..r, data.len() as i64);
+        Some((l, removed))
+    }
+
+    /// Replace the given range of the buffer by the given pieces, whose data might
+    /// not be loaded.
+    /// Returns the removed pieces, or `None` if the file is not open yet.
+    fn splice_pieces(
+        &mut self,
+        layout: &CharLayout,
+        range: ops::Range<i64>,
+        ins: Vec<Piece>,
+    ) -> Option<Vec<Piece>> {
+        let pieces = self.pieces.as_mut()?;
+        let len: i64 = ins.iter().map(|p| p.len).sum();
+        // Small RAM pieces are shown right away, larger ones are loaded in the background
+        let mut data = None;
+        if len <= self.data.merge_batch_size as i64 {
+            data = ins.iter().map(|p| p.ram()).collect::<Option<Vec<_>>>();
+        }
+        let data = data.map(|d| d.concat());
+        let removed = pieces.splice_pieces(range.clone(), ins);
+        self.shift_pending(range.clone(), len);
+        match data {
+            Some(data) => {
+                self.linemap
+                    .splice(&self.data, layout, range.clone(), &data);
+                self.data.splice(range, &data);
+            }
+            None => {
+                self.linemap.splice_unloaded(layout, range.clone(), len);
+                self.data.splice_unloaded(range, len);
+            }
+        }
+        Some(removed)
+    }
+
+    /// Move the pending pastes and the search matches along with an edit that
+    /// replaced the given range by `len` bytes, so that pastes still land where they
+    /// were requested and the search does not start over.
+    /// A replace-all in progress is told where the buffer was edited too, for the
+    /// same reason.
+    fn shift_pending(&mut self, range: ops::Range<i64>, len: i64) {
+        if let (Some(search), Some(p)) = (&mut self.search, &self.pieces) {
+            search.edited(range.clone(), len, p.version());
+        }
+        if let (Some(replace), Some(p)) = (&mut self.replace, &self.pieces) {
+            replace.edited(range.start, p.version());
+        }
+        let shift = |off: &mut i64| {
+            if *off >= range.end {
+                *off += len - (range.end - range.start);
+            } else if *off > range.start {
+                *off = range.start + len;
+            }
+        };
+        for p in self.pending_pastes.iter_mut() {
+            shift(&mut p.range.start);
+            shift(&mut p.range.end);
+            for off in p.sel_before.iter_mut() {
+                shift(off);
+            }
+        }
+    }
+
+    /// Apply an edit and record it in the undo history.
+    /// Returns `false` if the file is not open yet.
+    fn edit(
+        &mut self,
+        layout: &CharLayout,
+        range: ops::Range<i64>,
+        data: &[u8],
+        info: EditInfo,
+    ) -> bool {
+        match self.splice(layout, range, data) {
+            Some((at, old)) => {
+                let table = self.pieces.as_ref().unwrap();
+                self.history.push(table, at, old, data.len() as i64, info);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Apply an edit that inserts the given data, and record it in the undo history.
+    /// Unlike `edit`, a large insertion is not mapped right away: it is left as a
+    /// hole in the linemap and loaded back in the background, piece by piece.
+    /// Returns `false` if the file is not open yet.
+    fn edit_owned(
+        &mut self,
+        layout: &CharLayout,
+        range: ops::Range<i64>,
+        data: Vec<u8>,
+        info: EditInfo,
+    ) -> bool {
+        let len = match &self.pieces {
+            Some(p) => p.len(),
+            None => return false,
+        };
+        let l = range.start.clamp(0, len);
+        let r = range.end.clamp(l, len);
+        let new_len = data.len() as i64;
+        let ins = if data.is_empty() {
+            vec![]
+        } else {
+            vec![Piece {
+                len: new_len,
+                src: Source::Ram(data.into(), 0),
+            }]
+        };
+        let old = self.splice_pieces(layout, l..r, ins).unwrap();
+        let table = self.pieces.as_ref().unwrap();
+        self.history.push(table, l, old, new_len, info);
+        true
+    }
+
+    /// Undo the last edit.
+    /// The selection is restored to the one from before the edit.
+    fn undo(&mut self, layout: &CharLayout) -> Option<Restored> {
+        let step = self.history.take_undo()?;
+        let range = step.at..step.at + step.new_len();
+        self.splice_pieces(layout, range.clone(), step.old.clone());
+        let restored = Restored {
+            range,
+            len: step.old_len(),
+            sel: step.info.sel_before,
+        };
+        self.history.undone(step);
+        Some(restored)
+    }
+
+    /// Redo the last undone edit.
+    /// The selection is restored to the one from after the edit.
+    fn redo(&mut self, layout: &CharLayout) -> Option<Restored> {
+        let step = self.history.take_redo()?;
+        let range = step.at..step.at + step.old_len();
+        self.splice_pieces(layout, range.clone(), step.new.clone());
+        let restored = Restored {
+            range,
+            len: step.new_len(),
+            sel: step.info.sel_after,
+        };
+        self.history.redone(step);
+        Some(restored)
+    }
+
+    /// Get the offsets at the start, middle and end of the given rectangle, as far
+    /// as the linemap knows.
+    fn try_get_range(&self, rect: FileRect) -> Option<(i64, i64, i64)> {
         // Get bounds
-        let base = self.hot.corner.base_offset;
-        let y0 = self.hot.corner.delta_y.floor() as i64;
-        let ym = (self.hot.corner.delta_y + self.hot.size.y / 2.).floor() as i64;
-        let y1 = (self.hot.corner.delta_y + self.hot.size.y).ceil() as i64;
-        let x0 = self.hot.corner.delta_x;
-        let x1 = self.hot.corner.delta_x + self.hot.size.x;
+        let base = rect.corner.base_offset;
+        let y0 = rect.corner.delta_y.floor() as i64;
+        let ym = (rect.corner.delta_y + rect.size.y / 2.).floor() as i64;
+        let y1 = (rect.corner.delta_y + rect.size.y).ceil() as i64;
+        let x0 = rect.corner.delta_x;
+        let x1 = rect.corner.delta_x + rect.size.x;
         let xm = (x0 + x1) / 2.;
         // Get offsets
         let l = self.linemap.pos_to_anchor(base, y0, x0)?.1;
@@ -59,8 +369,11 @@ impl LoadedData {
     }
 
     fn get_hot_range(&self) -> (i64, i64, i64) {
+        if let Some(r) = &self.hot_bytes {
+            return (r.start, r.start + (r.end - r.start) / 2, r.end);
+        }
         let m = self.hot.corner.base_offset;
-        self.try_get_hot_range().unwrap_or((m, m, m))
+        self.try_get_range(self.hot).unwrap_or((m, m, m))
     }
 
     fn surroundings_to_range(
@@ -118,6 +431,18 @@ impl LoadedData {
         if lscreen - r < load_radius && l - rscreen <= load_radius {
             return ((l, r), true);
         }
+        // Linemap from the start of the buffer until the requested line is reached
+        if let Some(line) = self.goto_line {
+            let len = self.linemap.file_size;
+            let l = match self.linemap.start_extent() {
+                Some((end, lines)) if lines < line => end,
+                Some(_) => len,
+                None => 0,
+            };
+            if l < len {
+                return ((l, len.min(l + max_len)), false);
+            }
+        }
         // Finished loading the local range, now attempt to load the selected range
         if let Some(sel) = self.sel.as_ref() {
             if sel.end - sel.start <= max_sel {
@@ -153,70 +478,694 @@ impl Surroundings {
     }
 }
 
+/// Something that happened in the background that the user should know about.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Notice {
+    /// Whether something went wrong, as opposed to being just informative.
+    pub error: bool,
+    pub text: String,
+}
+
 struct Shared {
     path: PathBuf,
     friendly_name: String,
     stop: AtomicCell<bool>,
     sleeping: AtomicCell<bool>,
     last_file_size: AtomicCell<i64>,
+    /// The modification time of the file when it was last opened or saved.
+    /// Used to detect whether the file was modified externally.
+    last_file_mtime: AtomicCell<Option<std::time::SystemTime>>,
+    /// The length of the buffer, including any edits.
+    buffer_len: AtomicCell<i64>,
+    /// How much of the buffer has been written to disk, if a save is in progress.
+    save_progress: AtomicCell<Option<f32>>,
+    /// Whether the buffer differs from the file on disk.
+    dirty: AtomicCell<bool>,
+    /// Whether the unsaved edits should be thrown away instead of persisted.
+    discard: AtomicCell<bool>,
+    /// Whether to watch the file for appended data, like `tail -f`.
+    follow: AtomicCell<bool>,
+    /// Whether the buffer is backed by a spool file holding a stream, instead of the
+    /// file at `path`, which does not exist.
+    spooled: bool,
+    /// Whether the stream is still being copied into the spool file.
+    spooling: AtomicCell<bool>,
+    /// How the file is compressed, if it is.
+    codec: AtomicCell<Option<Codec>>,
+    /// Whether the compressed file is still being decompressed for the first time.
+    decoding: AtomicCell<bool>,
+    /// Whether the manager thread stopped because of an error, which was already
+    /// reported.
+    dead: AtomicCell<bool>,
+    /// Notices for the frontend to show.
+    notices: Sender<Notice>,
     loaded: Mutex<LoadedData>,
     k: Cfg,
     layout: CharLayout,
 }
+impl Shared {
+    /// Update the buffer length and dirty flag after the pieces changed.
+    fn edited(&self, pieces: &PieceTable) {
+        self.buffer_len.store(pieces.len());
+        self.dirty
+            .store(!pieces.is_pristine(self.last_file_size.load()));
+    }
+
+    /// Tell the user about something that happened in the background.
+    fn notify(&self, error: bool, text: String) {
+        println!("{}", text);
+        let _ = self.notices.send(Notice { error, text });
+    }
+}
+
+/// A request to replace a range of the buffer by the contents of the clipboard.
+/// If the buffer is edited before the clipboard contents arrive, the range is moved
+/// along with the edit.
+struct PasteRequest {
+    range: ops::Range<i64>,
+    sel_before: [i64; 2],
+}
 
 struct FileManager {
     shared: Arc<Shared>,
     file: File,
     read_buf: Vec<u8>,
     linemapper: LineMapper,
+    /// The canonical path of the file, used to identify its journal.
+    canonical_path: PathBuf,
+    /// Where to persist the state of the buffer, if anywhere.
+    journal_path: Option<PathBuf>,
+    /// The journal being appended to, if it was written during this session.
+    journal: Option<JournalWriter>,
+    /// The state of the buffer when it was last persisted.
+    persisted: Option<(u64, ViewState, JournalKey)>,
+    last_persist: Instant,
+    /// When the file was last checked for appended data.
+    last_follow: Instant,
+    /// The state of the file at `path` the last time it was checked.
+    stamp: Option<FileStamp>,
+    /// When the file was last checked for external changes.
+    last_watch: Instant,
+    /// Whether the buffer was being saved when the file was last checked.
+    was_saving: bool,
+    /// The error that checking the file failed with last, if it did.
+    /// Only reported once, until checking succeeds again.
+    watch_error: Option<String>,
+    /// Whether the stream was still being spooled when the file was last followed.
+    spooling: bool,
+    /// If the file is compressed, the decompressed data is read through this instead.
+    decompressed: Option<Decompressed>,
+    /// The matcher for the last query that was searched or replaced.
+    matcher: Option<(SearchQuery, Arc<Matcher>)>,
+    /// The replace-all in progress, if any.
+    replacer: Option<Replacer>,
 }
 impl FileManager {
-    fn new(shared: Arc<Shared>) -> Result<Self> {
-        let mut file = File::open(&shared.path)?;
-        let file_size: i64 = file
-            .seek(io::SeekFrom::End(0))
-            .context("failed to determine length of file")?
-            .try_into()
-            .context("file way too large")?; // can only fail for files larger than 2^63-1
+    /// Open the file at the shared path, or take over the given spool file.
+    fn new(shared: Arc<Shared>, spool: Option<File>) -> Result<Self> {
+        let (mut file, decompressed) = match spool {
+            Some(file) => (file, None),
+            None => {
+                let file = File::open(&shared.path)?;
+                let decompressed = Self::decompress(&shared, &file)?;
+                (file, decompressed)
+            }
+        };
+        let file_size: i64 = match &decompressed {
+            // The length is unknown until the whole file is decompressed
+            Some(d) => d.len(),
+            None => file
+                .seek(io::SeekFrom::End(0))
+                .context("failed to determine length of file")?
+                .try_into()
+                .context("file way too large")?, // can only fail for files larger than 2^63-1
+        };
         shared.last_file_size.store(file_size);
-        let memk = &shared.k.f.linemap_mem;
-        let max_linemap_memory = ((file_size as f64 * memk.fract)
-            .clamp(memk.min_mb * 1024. * 1024., memk.max_mb * 1024. * 1024.)
-            as i64)
-            .clamp(0, isize::MAX as i64) as usize;
+        shared.last_file_mtime.store(modified_time(&file));
+        let canonical_path = fs::canonicalize(&shared.path).unwrap_or_else(|_| shared.path.clone());
+        let key = JournalKey {
+            path: canonical_path.clone(),
+            size: file_size,
+            mtime: modified_time(&file),
+        };
+        // Streams are gone once read, so there is no point in persisting their edits
+        // Compressed files cannot be saved, so they are not persisted either
+        let journal_path = match shared.spooled || decompressed.is_some() {
+            true => None,
+            false => shared.k.journal_dir().map(|dir| key.journal_path(&dir)),
+        };
+        // Restore unsaved edits from a previous session
+        let mut pieces = PieceTable::new(file_size);
+        let mut restored_view = None;
+        if let Some(journal) = journal_path
+            .as_deref()
+            .and_then(|path| Self::restore_journal(&shared, path, &key))
+        {
+            pieces = PieceTable::from_pieces(journal.pieces);
+            restored_view = Some(journal.view);
+        }
+        let buffer_len = pieces.len();
+        shared.edited(&pieces);
         {
             let mut loaded = shared.loaded.lock();
-            loaded.linemap.file_size = file_size;
-            loaded.data.file_size = file_size;
+            loaded.linemap.file_size = buffer_len;
+            loaded.data.file_size = buffer_len;
+            loaded.pieces = Some(pieces);
+            loaded.restored_view = restored_view;
         }
         Ok(Self {
-            linemapper: LineMapper::new(
-                shared.layout.clone(),
-                file_size,
-                max_linemap_memory,
-                shared.k.f.migrate_batch_size,
-            ),
+            linemapper: Self::linemapper(&shared, buffer_len),
+            stamp: FileStamp::read(&shared.path).ok().map(|s| s.sampled(&file)),
+            last_watch: Instant::now(),
+            was_saving: false,
+            watch_error: None,
+            spooling: shared.spooled,
+            decompressed,
             read_buf: default(),
             file,
+            canonical_path,
+            journal_path,
+            journal: None,
+            persisted: None,
+            last_persist: Instant::now(),
+            last_follow: Instant::now(),
+            matcher: None,
+            replacer: None,
             shared,
         })
     }
 
+    /// Create a linemapper for a buffer of the given length, spreading the anchors to
+    /// fit within the configured memory.
+    fn linemapper(shared: &Shared, buffer_len: i64) -> LineMapper {
+        let memk = &shared.k.f.linemap_mem;
+        let max_linemap_memory = ((buffer_len as f64 * memk.fract)
+            .clamp(memk.min_mb * 1024. * 1024., memk.max_mb * 1024. * 1024.)
+            as i64)
+            .clamp(0, isize::MAX as i64) as usize;
+        LineMapper::new(
+            shared.layout.clone(),
+            buffer_len,
+            max_linemap_memory,
+            shared.k.f.migrate_batch_size,
+        )
+    }
+
+    /// Check whether the file is compressed, and if so start decompressing it.
+    fn decompress(shared: &Shared, file: &File) -> Result<Option<Decompressed>> {
+        let codec = Codec::detect(file);
+        shared.codec.store(codec);
+        shared.decoding.store(codec.is_some());
+        let codec = match codec {
+            Some(codec) => codec,
+            None => return Ok(None),
+        };
+        let spacing = (shared.k.f.checkpoint_spacing_mb * 1024. * 1024.) as i64;
+        let max_mem = (shared.k.f.max_checkpoint_mb * 1024. * 1024.) as usize;
+        let d = Decompressed::new(file.try_clone()?, codec, spacing, max_mem)
+            .with_context(|| format!("failed to decompress {} file", codec.name()))?;
+        Ok(Some(d))
+    }
+
+    /// Throw away the buffer, including any unsaved edits, and open the file again.
+    fn reload(&mut self) -> Result<()> {
+        let file = File::open(&self.shared.path)?;
+        let meta = file.metadata()?;
+        self.decompressed = Self::decompress(&self.shared, &file)?;
+        let size = match &self.decompressed {
+            Some(d) => d.len(),
+            None => meta.len() as i64,
+        };
+        self.stamp = Some(FileStamp::of(&meta).sampled(&file));
+        self.file = file;
+        self.linemapper = Self::linemapper(&self.shared, size);
+        self.replacer = None;
+        self.shared.last_file_size.store(size);
+        self.shared.last_file_mtime.store(meta.modified().ok());
+        let mut loaded = self.shared.loaded.lock();
+        loaded.reset(size);
+        loaded.external_change = None;
+        loaded.detect_line_ending = true;
+        if let Some(p) = &loaded.pieces {
+            self.shared.edited(p);
+        }
+        println!("reloaded {} bytes", size);
+        Ok(())
+    }
+
+    /// Stop trusting the file, because it changed or could not be read.
+    fn file_changed(&mut self, what: String) {
+        println!("\"{}\" {}", self.shared.path.display(), what);
+        let mut loaded = self.shared.loaded.lock();
+        if let Some(search) = &mut loaded.search {
+            search.error = Some("The file changed on disk".to_string());
+        }
+        if let Some(replace) = &mut loaded.replace {
+            replace.error = Some("The file changed on disk".to_string());
+        }
+        self.replacer = None;
+        loaded.external_change = Some(what);
+    }
+
+    /// Check whether the file was changed by another process.
+    fn watch(&mut self) {
+        self.last_watch = Instant::now();
+        if self.shared.spooled {
+            // Only this process writes to the spool file
+            return;
+        }
+        let busy = {
+            let loaded = self.shared.loaded.lock();
+            if loaded.external_change.is_some() {
+                return;
+            }
+            loaded.pending_save || loaded.reopened.is_some()
+        };
+        // Saving changes the file too, so take note of the saved file instead
+        if busy || self.shared.save_progress.load().is_some() {
+            self.was_saving = true;
+            return;
+        }
+        let now = match FileStamp::read(&self.shared.path) {
+            Ok(now) => now,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.file_changed("was deleted".to_string());
+                return;
+            }
+            Err(err) => {
+                let err = format!("failed to check file: {:#}", err);
+                if self.watch_error.as_ref() != Some(&err) {
+                    self.shared.notify(true, err.clone());
+                    self.watch_error = Some(err);
+                }
+                return;
+            }
+        };
+        self.watch_error = None;
+        if mem::take(&mut self.was_saving) {
+            self.stamp = Some(now.sampled(&self.file));
+            return;
+        }
+        let stamp = match &self.stamp {
+            Some(stamp) => stamp,
+            None => return,
+        };
+        if let Some(what) = stamp.change(&now, &self.file) {
+            self.file_changed(what.to_string());
+        } else if !stamp.same_as(&now) {
+            // Compare against the file as it is now from now on, so that rewrites
+            // after an append are noticed too
+            self.stamp = Some(now.sampled(&self.file));
+        }
+    }
+
+    /// Read the journal of a previous session, if it refers to the current version of
+    /// the file.
+    fn restore_journal(shared: &Shared, path: &Path, key: &JournalKey) -> Option<Journal> {
+        if !path.exists() {
+            return None;
+        }
+        let journal = match Journal::read(path) {
+            Ok(journal) => journal,
+            Err(err) => {
+                shared.notify(
+                    true,
+                    format!("failed to read journal \"{}\": {:#}", path.display(), err),
+                );
+                return None;
+            }
+        };
+        if journal.key.path != key.path {
+            // Not actually the journal for this file
+            return None;
+        }
+        if journal.key != *key {
+            if journal.has_edits() {
+                // Keep the journal around, in case the edits are valuable
+                let stale = path.with_extension("journal.stale");
+                let _ = fs::rename(path, &stale);
+                shared.notify(
+                    true,
+                    format!(
+                        "\"{}\" was modified since its unsaved edits were persisted, \
+                        discarding them (kept at \"{}\")",
+                        key.path.display(),
+                        stale.display(),
+                    ),
+                );
+            }
+            return None;
+        }
+        if journal.has_edits() {
+            shared.notify(
+                false,
+                format!("restored unsaved edits to \"{}\"", key.path.display()),
+            );
+        }
+        Some(journal)
+    }
+
+    /// Write the state of the buffer to its journal, if it changed since the last time
+    /// it was persisted.
+    /// Buffers without unsaved edits have no journal at all.
+    fn persist(&mut self) {
+        self.last_persist = Instant::now();
+        if self.shared.discard.load() {
+            return;
+        }
+        let path = match &self.journal_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let journal = {
+            let loaded = self.shared.loaded.lock();
+            let pieces = match &loaded.pieces {
+                Some(p) => p,
+                None => return,
+            };
+            let key = JournalKey {
+                path: self.canonical_path.clone(),
+                size: self.shared.last_file_size.load(),
+                mtime: self.shared.last_file_mtime.load(),
+            };
+            let state = (pieces.version(), loaded.view, key.clone());
+            if self.persisted.as_ref() == Some(&state) {
+                return;
+            }
+            self.persisted = Some(state);
+            Journal {
+                key,
+                view: loaded.view,
+                pieces: pieces.pieces_in(0..pieces.len()),
+            }
+        };
+        if !journal.has_edits() {
+            // Nothing worth recovering, so don't leave older edits behind either
+            self.journal = None;
+            let _ = fs::remove_file(&path);
+            return;
+        }
+        // Only append what changed, unless the journal is for an older version of the
+        // file or is mostly stale data
+        let result = match &mut self.journal {
+            Some(w) if w.key == journal.key && !w.is_bloated() => w.append(&journal),
+            _ => {
+                if let Some(dir) = path.parent() {
+                    let _ = fs::create_dir_all(dir);
+                }
+                JournalWriter::create(&path, &journal).map(|w| self.journal = Some(w))
+            }
+        };
+        if let Err(err) = result {
+            // Start over with a fresh journal next time
+            self.journal = None;
+            self.shared.notify(
+                true,
+                format!(
+                    "failed to persist buffer state to \"{}\": {:#}",
+                    path.display(),
+                    err
+                ),
+            );
+        }
+    }
+
+    /// Fetch the clipboard contents and insert them into the buffer, at the range of
+    /// the oldest pending paste.
+    fn paste(&mut self) {
+        // Do not hold the lock, since fetching the clipboard may take a while
+        let text = gl::clipboard::get();
+        // The request stays in the queue until now, so that any edits in the meantime
+        // move it around
+        let mut loaded = self.shared.loaded.lock();
+        let req = match loaded.pending_pastes.pop_front() {
+            Some(req) => req,
+            None => return,
+        };
+        let text = match text {
+            Ok(Some(text)) => text,
+            Ok(None) => {
+                self.shared
+                    .notify(false, "clipboard has no text".to_string());
+                return;
+            }
+            Err(err) => {
+                self.shared
+                    .notify(true, format!("error getting clipboard: {}", err));
+                return;
+            }
+        };
+        let text = loaded.linemap.encoding.encode(&text);
+        let len = text.len() as i64;
+        let info = EditInfo {
+            sel_before: req.sel_before,
+            sel_after: [req.range.start + len; 2],
+            typed: false,
+        };
+        if loaded.edit_owned(&self.shared.layout, req.range.clone(), text, info) {
+            if let Some(p) = &loaded.pieces {
+                self.shared.edited(p);
+            }
+            loaded.pasted.push((req.range, len));
+        }
+    }
+
+    fn persist_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.shared.k.edit.persist_interval.max(0.1))
+    }
+
+    fn watch_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.shared.k.f.watch_interval.max(0.01))
+    }
+
+    fn follow_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.shared.k.f.follow_interval.max(0.01))
+    }
+
+    /// Pick up any data appended to the file since it was last checked.
+    /// The appended data is added to the end of the buffer, unloaded, and is loaded
+    /// and mapped like any other part of the buffer.
+    fn follow(&mut self) {
+        self.last_follow = Instant::now();
+        if self.shared.save_progress.load().is_some() || self.decompressed.is_some() {
+            // The file is about to be replaced, or its length is not the buffer length
+            return;
+        }
+        if self.shared.loaded.lock().external_change.is_some() {
+            // Nothing else is read from the file until it is reloaded
+            return;
+        }
+        let meta = match self.file.metadata() {
+            Ok(meta) => meta,
+            Err(err) => {
+                self.shared
+                    .notify(true, format!("failed to check file size: {:#}", err));
+                return;
+            }
+        };
+        // Growing is expected, so it does not count as an external change, unless the
+        // data that the buffer already has was rewritten too
+        // Note that the stamp of the open file is used, so that a replaced file is still
+        // noticed
+        let now = FileStamp::of(&meta);
+        if let Some(what) = self.stamp.as_ref().and_then(|s| s.change(&now, &self.file)) {
+            self.file_changed(what.to_string());
+            return;
+        }
+        if !self.grow(meta.len() as i64) {
+            return;
+        }
+        self.shared.last_file_mtime.store(meta.modified().ok());
+        self.stamp = Some(now.sampled(&self.file));
+    }
+
+    /// Add the file data past the last known file size to the end of the buffer.
+    /// Returns whether the buffer grew.
+    fn grow(&self, size: i64) -> bool {
+        let old_size = self.shared.last_file_size.load();
+        if size <= old_size {
+            return false;
+        }
+        let mut guard = self.shared.loaded.lock();
+        let loaded = &mut *guard;
+        if loaded.pending_save || loaded.reopened.is_some() || loaded.external_change.is_some() {
+            return false;
+        }
+        let len = match &loaded.pieces {
+            Some(p) => p.len(),
+            None => return false,
+        };
+        let tail = Piece {
+            len: size - old_size,
+            src: Source::File(old_size),
+        };
+        loaded.splice_pieces(&self.shared.layout, len..len, vec![tail]);
+        self.shared.last_file_size.store(size);
+        if let Some(p) = &loaded.pieces {
+            self.shared.edited(p);
+        }
+        true
+    }
+
+    /// Decompress the next chunk of a compressed file, if it was not fully
+    /// decompressed yet.
+    /// The buffer grows as the file is decompressed, at most as often as a followed
+    /// file would.
+    /// Returns whether there is more left to decompress.
+    fn index_step(&mut self) -> bool {
+        let d = match &mut self.decompressed {
+            Some(d) if self.shared.decoding.load() => d,
+            _ => return false,
+        };
+        if let Err(err) = d.index_step(self.shared.k.f.read_size) {
+            // Keep whatever could be decompressed
+            self.shared.notify(
+                true,
+                format!("failed to decompress the whole file: {:#}", err),
+            );
+            d.done = true;
+        }
+        if let Some(notice) = d.notice.take() {
+            self.shared.notify(notice.error, notice.text);
+        }
+        let (len, done) = (d.len(), d.done);
+        if done || self.last_follow.elapsed() >= self.follow_interval() {
+            self.last_follow = Instant::now();
+            self.grow(len);
+        }
+        if done {
+            self.shared.decoding.store(false);
+        }
+        !done
+    }
+
     fn run(mut self) -> Result<()> {
         while !self.shared.stop.load() {
+            if self.last_persist.elapsed() >= self.persist_interval() {
+                self.persist();
+            }
+            // Spooled streams grow like followed files, and once they are over the last
+            // of the data is picked up right away
+            let spooling = mem::replace(&mut self.spooling, self.shared.spooling.load());
+            let due = self.last_follow.elapsed() >= self.follow_interval();
+            if (spooling && !self.spooling) || (due && (self.spooling || self.shared.follow.load()))
+            {
+                self.follow();
+            }
+            if self.last_watch.elapsed() >= self.watch_interval() {
+                self.watch();
+            }
+            let (paste, reload, stale) = {
+                let mut loaded = self.shared.loaded.lock();
+                let reload = mem::take(&mut loaded.pending_reload);
+                (
+                    !loaded.pending_pastes.is_empty(),
+                    reload,
+                    loaded.external_change.is_some(),
+                )
+            };
+            if reload {
+                if let Err(err) = self.reload() {
+                    self.file_changed(format!("could not be reloaded: {:#}", err));
+                }
+                continue;
+            }
+            if paste {
+                self.paste();
+            }
+            if !stale {
+                if let Err(err) = self.detect_format() {
+                    self.file_changed(format!("could not be read: {:#}", err));
+                }
+            }
+            // Searching reads through the file, which cannot be trusted if it changed
+            let (mut searching, mut replacing) = (false, false);
+            if !stale {
+                match self
+                    .search_step()
+                    .and_then(|s| Ok((s, self.replace_step()?)))
+                {
+                    Ok(steps) => (searching, replacing) = steps,
+                    Err(err) => self.file_changed(format!("could not be read: {:#}", err)),
+                }
+            }
+
+            // Merge any segments that were left touching by edits
+            self.linemapper.merge_touching(&self.shared.loaded);
+            SparseData::merge_touching(&self.shared.loaded);
+
             // Find something to do
             let keep;
+            let pieces;
+            let mut before = 0;
             let ((l, r), store_data) = {
                 let mut loaded = self.shared.loaded.lock();
+                // Any edits from now on invalidate the range we are about to load
+                loaded.linemap.edited = false;
+                loaded.data.edited = false;
+                self.linemapper.encoding = loaded.linemap.encoding;
+
+                // Switch over to the saved file, which is what the pieces now refer to
+                if let Some(file) = loaded.reopened.take() {
+                    self.file = file;
+                }
+
+                // Start saving the buffer in the background
+                if loaded.pending_save && self.shared.save_progress.load().is_none() {
+                    loaded.pending_save = false;
+                    if self.shared.spooled {
+                        self.shared.notify(
+                            true,
+                            "the buffer was read from a stream and cannot be saved".to_string(),
+                        );
+                    } else if self.decompressed.is_some() {
+                        self.shared
+                            .notify(true, "compressed files cannot be saved".to_string());
+                    } else if let Some(what) = &loaded.external_change {
+                        // The file pieces might no longer hold the data they refer to
+                        self.shared
+                            .notify(true, format!("the file {}, reload it before saving", what));
+                    } else if let Some(p) = &loaded.pieces {
+                        let convert = match self.shared.k.edit.save_line_ending.as_str() {
+                            "" => None,
+                            name => {
+                                let le = LineEnding::from_name(name);
+                                if le.is_none() {
+                                    self.shared.notify(
+                                        true,
+                                        format!("unknown line ending \"{}\", not converting", name),
+                                    );
+                                }
+                                le.map(|le| (loaded.linemap.encoding, le))
+                            }
+                        };
+                        match self.file.try_clone() {
+                            Ok(file) => SaveJob {
+                                file,
+                                pieces: p.pieces_in(0..p.len()),
+                                version: p.version(),
+                                convert,
+                                view: loaded.view,
+                                manager: thread::current(),
+                            }
+                            .spawn(self.shared.clone()),
+                            Err(err) => self
+                                .shared
+                                .notify(true, format!("failed to save file: {:#}", err)),
+                        }
+                    }
+                }
 
                 // Process clipboard copy operations
                 if let (true, Some(sel)) = (loaded.pending_sel_copy, loaded.sel.as_ref()) {
                     let data = loaded.data.longest_prefix(sel.start);
                     if data.len() as i64 >= sel.end - sel.start {
                         let data = &data[..(sel.end - sel.start) as usize];
-                        match set_clipboard(data) {
+                        match set_clipboard(data, loaded.linemap.encoding) {
                             Ok(()) => println!("put {} bytes into clipboard", data.len()),
-                            Err(err) => println!("error setting clipboard: {:#}", err),
+                            Err(err) => self
+                                .shared
+                                .notify(true, format!("error setting clipboard: {:#}", err)),
                         }
                         loaded.pending_sel_copy = false;
                         MutexGuard::bump(&mut loaded);
@@ -228,12 +1177,27 @@ impl FileManager {
                 let load_radius = self.shared.k.f.load_radius as i64;
                 let (keepl, _keepm, keepr) = loaded.get_hot_range();
                 keep = keepl - load_radius..keepr + load_radius;
-                let out = loaded.get_range_to_load(
-                    self.shared.k.f.read_size as i64,
-                    load_radius,
-                    self.shared.k.f.max_selection_copy as i64,
-                );
+                // Keep the loaded data visible, but do not load anything else if the
+                // file changed
+                let out = match loaded.external_change {
+                    Some(_) => ((0, 0), false),
+                    None => loaded.get_range_to_load(
+                        self.shared.k.f.read_size as i64,
+                        load_radius,
+                        self.shared.k.f.max_selection_copy as i64,
+                    ),
+                };
                 let segn = loaded.data.segments.len();
+                let ((l, r), _) = out;
+                // Read a little around the segment, to find out whether line breaks
+                // at its edges are cut in half
+                pieces = match &loaded.pieces {
+                    Some(p) if l < r => {
+                        before = l.min(2) as usize;
+                        p.pieces_in(l - before as i64..(r + 2).min(p.len()))
+                    }
+                    _ => vec![],
+                };
                 drop(loaded);
 
                 let lockt = start.elapsed();
@@ -251,40 +1215,387 @@ impl FileManager {
                 if l % (16 * 1024 * 1024) > r % (16 * 1024 * 1024) {
                     eprintln!("loaded {:.2}MB", l as f64 / 1024. / 1024.);
                 }
-                self.load_segment(l, (r - l) as usize, keep, store_data)?;
+                if let Err(err) =
+                    self.load_segment(l, (r - l) as usize, before, &pieces, keep, store_data)
+                {
+                    self.file_changed(format!("could not be read: {:#}", err));
+                }
+                continue;
+            }
+            if !stale && self.index_step() {
+                continue;
+            }
+            if searching || replacing {
                 continue;
             }
             // Nothing to load, make sure to idle respectfully
             // The frontend will notify us if there is any relevant change
+            // Wake up periodically to persist the buffer state and check whether the
+            // file changed or grew
+            let mut timeout = self.persist_interval().min(self.watch_interval());
+            if self.shared.follow.load() || self.spooling {
+                timeout = timeout.min(self.follow_interval());
+            }
             self.shared.sleeping.store(true);
-            thread::park();
+            thread::park_timeout(timeout);
             self.shared.sleeping.store(false);
         }
+        if self.shared.discard.load() {
+            self.journal = None;
+            if let Some(path) = &self.journal_path {
+                let _ = fs::remove_file(path);
+            }
+        } else {
+            self.persist();
+        }
+        Ok(())
+    }
+
+    /// Scan the next chunk of the buffer for search matches.
+    /// Returns whether there is anything left to scan.
+    ///
+    /// The buffer is scanned one `read_size` chunk at a time, without holding the
+    /// lock, so a new query takes over as soon as the current chunk is done.
+    fn search_step(&mut self) -> Result<bool> {
+        let k = &self.shared.k;
+        let chunk = k.f.read_size.max(1) as i64;
+        let (query, version, rescanning, start, end, ctx, room, pieces) = {
+            let mut guard = self.shared.loaded.lock();
+            let loaded = &mut *guard;
+            let (search, table) = match (&mut loaded.search, &loaded.pieces) {
+                (Some(search), Some(table)) => (search, table),
+                _ => return Ok(false),
+            };
+            // Edits move the matches along, so this only happens if the search missed
+            // an edit somehow
+            if search.version != table.version() {
+                search.restart(table.version(), table.len());
+            }
+            if search.is_done() {
+                return Ok(false);
+            }
+            let encoding = loaded.linemap.encoding;
+            let matcher = match &self.matcher {
+                Some((q, m)) if *q == search.query && m.encoding() == encoding => m,
+                _ => {
+                    // Compiling a regex might take a while, so do it outside the lock
+                    let query = search.query.clone();
+                    drop(guard);
+                    if let Err(err) = self.matcher(&query, encoding) {
+                        if let Some(search) = &mut self.shared.loaded.lock().search {
+                            if search.query == query {
+                                search.error = Some(format!("{:#}", err));
+                            }
+                        }
+                    }
+                    return Ok(true);
+                }
+            };
+            // Let the main thread highlight the visible matches with the same matcher
+            if !matches!(&loaded.highlighter, Some((q, m)) if *q == search.query && Arc::ptr_eq(m, matcher))
+            {
+                loaded.highlighter = Some((search.query.clone(), matcher.clone()));
+            }
+            // Overlap with the next chunk, to catch matches that cross the boundary
+            // Also include some context before the chunk, for lookbehind
+            let overlap = matcher.max_len() as i64 - 1;
+            search.margin = overlap;
+            // Edited ranges that were already scanned go first, since they are small
+            let (start, limit) = match search.rescan.first() {
+                Some(w) => (w.start, w.end),
+                None => (search.scanned, table.len()),
+            };
+            let ctx = start.min(Matcher::LOOKBEHIND as i64);
+            let end = (start + chunk).min(limit);
+            let read_end = (end + overlap).min(table.len());
+            (
+                search.query.clone(),
+                search.version,
+                !search.rescan.is_empty(),
+                start,
+                end,
+                ctx,
+                k.search.max_matches.saturating_sub(search.matches.len()),
+                table.pieces_in(start - ctx..read_end),
+            )
+        };
+        let len = pieces.iter().map(|p| p.len).sum::<i64>() as usize;
+        self.read_pieces(&pieces, len)?;
+        // Only keep as many matches as can be stored, so that publishing them
+        // is quick
+        let mut found = vec![];
+        let mut dropped: Option<Dropped> = None;
+        let mut next = end;
+        let base = start - ctx;
+        let matcher = &self.matcher.as_ref().unwrap().1;
+        matcher.find_all(&self.read_buf[..len], ctx as usize, |m| {
+            let m = base + m.start as i64..base + m.end as i64;
+            if m.start < end {
+                // The next chunk picks up after this match, so that matches
+                // never overlap
+                next = next.max(m.end);
+                if found.len() < room {
+                    found.push(m);
+                } else {
+                    match &mut dropped {
+                        Some(d) => {
+                            d.range.end = m.end;
+                            d.count += 1;
+                        }
+                        None => dropped = Some(Dropped { range: m, count: 1 }),
+                    }
+                }
+            }
+        });
+        // Publish the matches, unless the search or the buffer changed meanwhile
+        let mut loaded = self.shared.loaded.lock();
+        let loaded = &mut *loaded;
+        let current = loaded.pieces.as_ref().map(|p| p.version()) == Some(version);
+        if let (true, Some(search)) = (current, &mut loaded.search) {
+            let max_matches = self.shared.k.search.max_matches;
+            if search.query != query || search.version != version {
+                // Stale
+            } else if rescanning {
+                if search.rescan.first().map(|w| w.start) == Some(start) {
+                    search.add_rescanned(start, next, found, dropped, max_matches);
+                }
+            } else if search.scanned == start {
+                search.add(found, dropped, max_matches);
+                search.scanned = next;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Get the matcher for the given query and encoding, compiling it unless it is
+    /// the one used last.
+    /// Compiling a regex might take a while, so the lock should not be held.
+    fn matcher(&mut self, query: &SearchQuery, encoding: Encoding) -> Result<Arc<Matcher>> {
+        if let Some((q, m)) = &self.matcher {
+            if q == query && m.encoding() == encoding {
+                return Ok(m.clone());
+            }
+        }
+        let m = Arc::new(Matcher::new(
+            query,
+            encoding,
+            self.shared.k.search.max_match_len,
+        )?);
+        self.matcher = Some((query.clone(), m.clone()));
+        Ok(m)
+    }
+
+    /// Scan the next chunk of the buffer for matches to replace, and apply the
+    /// replacement as a single edit once the entire buffer is scanned.
+    /// Returns whether there is anything left to do.
+    fn replace_step(&mut self) -> Result<bool> {
+        let k = &self.shared.k;
+        let chunk = k.f.read_size.max(1) as i64;
+        {
+            let mut guard = self.shared.loaded.lock();
+            let loaded = &mut *guard;
+            let (progress, table) = match (&mut loaded.replace, &loaded.pieces) {
+                (Some(progress), Some(table)) if !progress.is_done() => (progress, table),
+                _ => {
+                    self.replacer = None;
+                    return Ok(false);
+                }
+            };
+            let encoding = loaded.linemap.encoding;
+            let edited = progress.edited.take();
+            let current = match &mut self.replacer {
+                Some(r) if r.is_for(progress) && r.matcher.encoding() == encoding => {
+                    match edited {
+                        _ if r.version == table.version() => true,
+                        Some((from, version)) if version == table.version() => {
+                            // Keep what was found before the edits
+                            let snapshot = PieceTable::from_pieces(table.pieces_in(0..table.len()));
+                            r.edited(from, snapshot, version);
+                            progress.scanned = r.scanned;
+                            progress.total = table.len();
+                            progress.count = r.count;
+                            true
+                        }
+                        // The pieces changed some other way, such as by saving
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            if !current {
+                // Start over, scanning a snapshot of the current buffer
+                let query = progress.query.clone();
+                let replacement = progress.replacement.clone();
+                let version = table.version();
+                let snapshot = PieceTable::from_pieces(table.pieces_in(0..table.len()));
+                progress.scanned = 0;
+                progress.total = table.len();
+                progress.count = 0;
+                drop(guard);
+                self.replacer = None;
+                match self.matcher(&query, encoding) {
+                    Ok(m) => {
+                        self.replacer =
+                            Some(Replacer::new(query, replacement, m, version, snapshot));
+                    }
+                    Err(err) => {
+                        if let Some(progress) = &mut self.shared.loaded.lock().replace {
+                            if progress.query == query {
+                                progress.error = Some(format!("{:#}", err));
+                            }
+                        }
+                    }
+                }
+                return Ok(true);
+            }
+        }
+        let mut r = self.replacer.take().unwrap();
+        let total = r.snapshot.len();
+        if r.scanned < total {
+            // Scan the next chunk, with the same overlap and context as searches
+            let overlap = r.matcher.max_len() as i64 - 1;
+            let start = r.scanned;
+            let ctx = start.min(Matcher::LOOKBEHIND as i64);
+            let end = (start + chunk).min(total);
+            let read_end = (end + overlap).min(total);
+            let pieces = r.snapshot.pieces_in(start - ctx..read_end);
+            let len = pieces.iter().map(|p| p.len).sum::<i64>() as usize;
+            self.read_pieces(&pieces, len)?;
+            let base = start - ctx;
+            let mut found = vec![];
+            let mut next = end;
+            r.matcher
+                .find_all(&self.read_buf[..len], ctx as usize, |m| {
+                    if base + (m.start as i64) < end {
+                        next = next.max(base + m.end as i64);
+                        found.push(m);
+                    }
+                });
+            r.add(&self.read_buf[..len], base, &found);
+            r.scanned = next;
+            let max_mem = (self.shared.k.search.max_replace_mb * 1024. * 1024.) as usize;
+            if let Some(progress) = &mut self.shared.loaded.lock().replace {
+                if r.is_for(progress) {
+                    progress.scanned = r.scanned;
+                    progress.count = r.count;
+                    if r.mem() > max_mem {
+                        progress.error = Some(format!(
+                            "Too many matches to replace at once, over {} so far",
+                            r.count
+                        ));
+                        return Ok(false);
+                    }
+                }
+            }
+            self.replacer = Some(r);
+            return Ok(true);
+        }
+        // Apply the replacement, unless the buffer changed meanwhile
+        let mut guard = self.shared.loaded.lock();
+        let loaded = &mut *guard;
+        let current = loaded.pieces.as_ref().map(|p| p.version()) == Some(r.version);
+        let progress = match &mut loaded.replace {
+            Some(progress) if current && r.is_for(progress) => progress,
+            _ => return Ok(true),
+        };
+        progress.scanned = total;
+        progress.count = r.count;
+        progress.done = true;
+        if let Some((range, ins)) = r.finish() {
+            let info = EditInfo {
+                sel_before: progress.sel_before,
+                sel_after: [range.start; 2],
+                typed: false,
+            };
+            let new_len = ins.iter().map(|p| p.len).sum();
+            let old = loaded
+                .splice_pieces(&self.shared.layout, range.clone(), ins)
+                .unwrap();
+            let table = loaded.pieces.as_ref().unwrap();
+            loaded.history.push(table, range.start, old, new_len, info);
+            self.shared.edited(table);
+            loaded.replaced = Some((range, new_len));
+        }
+        Ok(false)
+    }
+
+    /// Guess the encoding and the line ending of the buffer from its first few
+    /// kilobytes, if they were not found out or chosen yet.
+    /// A stream is only sampled once enough of it arrives.
+    fn detect_format(&mut self) -> Result<()> {
+        const SAMPLE_LEN: i64 = 4 * 1024;
+        let pieces = {
+            let loaded = self.shared.loaded.lock();
+            match &loaded.pieces {
+                Some(p) if loaded.detect_encoding || loaded.detect_line_ending => {
+                    if self.spooling && p.len() < SAMPLE_LEN {
+                        return Ok(());
+                    }
+                    p.pieces_in(0..p.len().min(SAMPLE_LEN))
+                }
+                _ => return Ok(()),
+            }
+        };
+        let len = pieces.iter().map(|p| p.len as usize).sum();
+        self.read_pieces(&pieces, len)?;
+        let sample = &self.read_buf[..len];
+        let mut loaded = self.shared.loaded.lock();
+        // The buffer might have been edited or the encoding chosen by hand meanwhile,
+        // but the start of the buffer is still a fine sample
+        if loaded.detect_encoding {
+            let encoding = Encoding::detect(sample);
+            println!("detected {} encoding", encoding.name());
+            loaded.set_encoding(encoding);
+        }
+        if loaded.detect_line_ending {
+            loaded.line_ending = LineEnding::detect(loaded.linemap.encoding, sample);
+            loaded.detect_line_ending = false;
+        }
         Ok(())
     }
 
+    /// Read the data behind the given pieces into the start of `read_buf`.
+    fn read_pieces(&mut self, pieces: &[Piece], len: usize) -> Result<()> {
+        if self.read_buf.len() < len {
+            self.read_buf.resize(len, 0);
+        }
+        let (file, decompressed) = (&self.file, &mut self.decompressed);
+        piece::read_pieces(pieces, &mut self.read_buf[..len], &mut |off, buf| {
+            match decompressed {
+                Some(d) => d.read_exact_at(buf, off)?,
+                None => read_exact_at(file, buf, off as u64)?,
+            }
+            Ok(())
+        })
+    }
+
     fn load_segment(
         &mut self,
         offset: i64,
         len: usize,
+        before: usize,
+        pieces: &[Piece],
         keep: ops::Range<i64>,
         store_data: bool,
     ) -> Result<()> {
         let read_start = Instant::now();
 
-        if self.read_buf.len() < len {
-            self.read_buf.resize(len, 0);
-        }
-        (&self.file).seek(io::SeekFrom::Start(offset as u64))?;
-        (&self.file).read_exact(&mut self.read_buf[..len])?;
+        // Gather the data from the file and from the edits
+        let total = pieces.iter().map(|p| p.len as usize).sum();
+        self.read_pieces(pieces, total)?;
 
         let lmap_start = Instant::now();
-        self.linemapper
-            .process_data(&self.shared.loaded, offset, &self.read_buf[..len]);
+        self.linemapper.process_data(
+            &self.shared.loaded,
+            offset - before as i64,
+            &self.read_buf[..total],
+            before..before + len,
+        );
 
         let data_start = Instant::now();
         if store_data {
             let mut read_buf = mem::take(&mut self.read_buf);
+            read_buf.drain(..before);
             read_buf.truncate(len);
             SparseData::insert_data(&self.shared.loaded, offset, read_buf);
             SparseData::cleanup(&self.shared.k, &self.shared.loaded, keep);
@@ -329,7 +1640,7 @@ impl FileManager {
                         end.x(s),
                     );
                     for a in s.anchors.iter() {
-                        println!("      {} at {}:{}", a.offset, a.y(s), a.x(s));
+                        println!("      {} at {}:{}", a.off(s), a.y(s), a.x(s));
                     }
                 }
             }
@@ -338,13 +1649,43 @@ impl FileManager {
     }
 }
 
+/// Read exactly enough bytes to fill the buffer, starting at the given file offset.
+/// Does not use the file cursor, so the same file can be read from several threads.
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(file, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct CharLayout {
     char_adv: FxHashMap<u32, f32>,
     default_adv: f32,
+    /// The distance between tab stops.
+    tab_width: f64,
 }
 impl CharLayout {
-    pub fn new(font: &FontArc) -> Self {
+    pub const TAB: u32 = '\t' as u32;
+
+    /// `tab_stop` is the distance between tab stops, in widths of a space.
+    pub fn new(font: &FontArc, tab_stop: u32) -> Self {
         let font_h = font.height_unscaled();
         let mut char_adv: FxHashMap<u32, f32> = default();
         char_adv.reserve(font.glyph_count());
@@ -352,21 +1693,42 @@ impl CharLayout {
             char_adv.insert(c as u32, font.h_advance_unscaled(glyph) / font_h);
         }
         println!("got {} char -> hadvance mappings", char_adv.len());
+        let default_adv = font.h_advance_unscaled(font.glyph_id('\0')) / font_h;
+        let space_adv = *char_adv.get(&(' ' as u32)).unwrap_or(&default_adv) as f64;
         Self {
-            default_adv: font.h_advance_unscaled(font.glyph_id('\0')) / font_h,
+            default_adv,
             char_adv,
+            tab_width: space_adv * tab_stop.max(1) as f64,
         }
     }
 
     /// Get the horizontal advance distance for the given unicode codepoint.
+    /// Tabs get a fixed advance, see `advance_at` to lay them out in a line.
     pub fn advance_for(&self, codepoint: u32) -> f64 {
         *self.char_adv.get(&codepoint).unwrap_or(&self.default_adv) as f64
     }
+
+    /// Get the horizontal advance distance for the given unicode codepoint, placed
+    /// at the given X position from the start of its line.
+    /// Tabs advance up to the next tab stop.
+    pub fn advance_at(&self, codepoint: u32, x: f64) -> f64 {
+        match codepoint {
+            Self::TAB => self.tab_stop(x) - x,
+            c => self.advance_for(c),
+        }
+    }
+
+    /// The X position that a tab at the given X position advances to.
+    pub fn tab_stop(&self, x: f64) -> f64 {
+        // Positions are sums of floats, so a tab stop may be reached slightly short
+        ((x / self.tab_width + 1e-6).floor() + 1.) * self.tab_width
+    }
 }
 
 pub struct FileBuffer {
     manager: JoinHandle<Result<()>>,
     shared: Arc<Shared>,
+    notices: Receiver<Notice>,
 }
 impl Drop for FileBuffer {
     fn drop(&mut self) {
@@ -379,6 +1741,30 @@ impl Drop for FileBuffer {
 }
 impl FileBuffer {
     pub fn new(path: PathBuf, layout: CharLayout, k: Cfg) -> Result<FileBuffer> {
+        Self::open(path, None, layout, k)
+    }
+
+    /// Read a stream of unknown length, such as standard input, into a new buffer.
+    /// The stream is copied into a temporary file in the background, and the buffer
+    /// grows as data arrives.
+    pub fn spool(
+        name: &str,
+        input: Box<dyn Read + Send>,
+        layout: CharLayout,
+        k: Cfg,
+    ) -> Result<FileBuffer> {
+        let file = spool::anonymous_file()?;
+        let out = file.try_clone()?;
+        Self::open(name.into(), Some((file, out, input)), layout, k)
+    }
+
+    fn open(
+        path: PathBuf,
+        spool: Option<(File, File, Box<dyn Read + Send>)>,
+        layout: CharLayout,
+        k: Cfg,
+    ) -> Result<FileBuffer> {
+        let (notice_tx, notices) = channel::unbounded();
         let shared = Arc::new(Shared {
             friendly_name: path
                 .file_name()
@@ -389,11 +1775,24 @@ impl FileBuffer {
             stop: false.into(),
             sleeping: false.into(),
             last_file_size: 0.into(),
+            last_file_mtime: None.into(),
+            buffer_len: 0.into(),
+            save_progress: None.into(),
+            follow: false.into(),
+            spooled: spool.is_some(),
+            spooling: spool.is_some().into(),
+            codec: None.into(),
+            decoding: false.into(),
+            dead: false.into(),
+            notices: notice_tx,
+            dirty: false.into(),
+            discard: false.into(),
             layout,
             loaded: Mutex::new(LoadedData::new(
                 (k.f.max_loaded_mb * 1024. * 1024.).ceil() as usize,
                 k.f.merge_batch_size,
                 k.f.realloc_threshold,
+                (k.edit.max_history_mb * 1024. * 1024.).ceil() as usize,
                 if k.log.lock_warn_ms < 0. {
                     None
                 } else {
@@ -402,16 +1801,35 @@ impl FileBuffer {
             )),
             k,
         });
-        // TOOD: Check and display errors on the frontend
+        let (file, spool) = match spool {
+            Some((file, out, input)) => (Some(file), Some((out, input))),
+            None => (None, None),
+        };
         let manager = {
             let shared = shared.clone();
             thread::spawn(move || {
-                FileManager::new(shared)?.run()?;
+                let res = FileManager::new(shared.clone(), file).and_then(|m| m.run());
+                if let Err(err) = &res {
+                    shared.dead.store(true);
+                    shared.notify(
+                        true,
+                        format!("stopped loading \"{}\": {:#}", shared.path.display(), err),
+                    );
+                }
                 println!("manager thread finishing");
-                Ok(())
+                res
             })
         };
-        Ok(Self { manager, shared })
+        if let Some((out, input)) = spool {
+            let shared = shared.clone();
+            let manager = manager.thread().clone();
+            thread::spawn(move || spool::spool(&shared, input, out, manager));
+        }
+        Ok(Self {
+            manager,
+            shared,
+            notices,
+        })
     }
 
     pub fn lock(&self) -> FileLock {
@@ -426,8 +1844,94 @@ impl FileBuffer {
         &self.shared.friendly_name
     }
 
-    pub fn file_size(&self) -> i64 {
-        self.shared.last_file_size.load()
+    /// Stop the backend and wait for it to persist the state of the buffer.
+    /// Gives up after the given timeout.
+    pub fn close(&self, timeout: Duration) {
+        self.shared.stop.store(true);
+        self.manager.thread().unpark();
+        let start = Instant::now();
+        while !self.manager.is_finished() && start.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Whether the buffer has edits that were not saved to disk.
+    pub fn is_dirty(&self) -> bool {
+        self.shared.dirty.load()
+    }
+
+    /// Throw away the unsaved edits once the buffer is closed, instead of
+    /// persisting them for the next session.
+    pub fn discard(&self) {
+        self.shared.discard.store(true);
+    }
+
+    /// Whether the backend finished running, after the buffer was closed.
+    pub fn is_closed(&self) -> bool {
+        self.manager.is_finished()
+    }
+
+    /// Take the notices that the backend sent since the last call.
+    /// If the backend died without being closed, this is reported once as well.
+    pub fn take_notices(&self) -> Vec<Notice> {
+        if self.manager.is_finished() && !self.shared.stop.load() && !self.shared.dead.load() {
+            // Errors are reported by the manager thread itself, so it panicked
+            self.shared.dead.store(true);
+            self.shared.notify(
+                true,
+                format!(
+                    "stopped loading \"{}\" unexpectedly",
+                    self.shared.path.display()
+                ),
+            );
+        }
+        self.notices.try_iter().collect()
+    }
+
+    /// How much of the buffer has been written to disk, if it is being saved.
+    pub fn save_progress(&self) -> Option<f32> {
+        self.shared.save_progress.load()
+    }
+
+    /// How the file was changed by another process, if it was.
+    pub fn external_change(&self) -> Option<String> {
+        self.shared.loaded.lock().external_change.clone()
+    }
+
+    /// Throw away the buffer, including any unsaved edits, and open the file again.
+    pub fn reload(&self) {
+        self.shared.loaded.lock().pending_reload = true;
+        self.manager.thread().unpark();
+    }
+
+    /// Start or stop watching the file for appended data.
+    pub fn set_follow(&self, follow: bool) {
+        self.shared.follow.store(follow);
+        self.manager.thread().unpark();
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.shared.follow.load()
+    }
+
+    /// How the file is compressed, if it is.
+    pub fn codec(&self) -> Option<Codec> {
+        self.shared.codec.load()
+    }
+
+    /// Whether the buffer is still growing as the compressed file is decompressed.
+    pub fn is_decoding(&self) -> bool {
+        self.shared.decoding.load()
+    }
+
+    /// Whether the buffer is still growing as a stream is read into it.
+    pub fn is_spooling(&self) -> bool {
+        self.shared.spooling.load()
+    }
+
+    /// The length of the buffer, including any unsaved edits.
+    pub fn len(&self) -> i64 {
+        self.shared.buffer_len.load()
     }
 }
 
@@ -436,6 +1940,9 @@ pub struct DataAt<'a> {
     pub dy: i64,
     /// X position relative to the reference anchor.
     pub dx: f64,
+    /// X position of the reference anchor from the start of its line, which tab stops
+    /// depend on.
+    pub base_x: f64,
     /// Absolute position of the data.
     pub offset: i64,
     /// As much data as it could be collected starting at `offset`.
@@ -478,7 +1985,7 @@ impl FileLock<'_> {
         let mut dy = lo.y_offset - base.y_offset;
         // Remove excess data before the target position
         while !data.is_empty() && (dy < y || dy == y && dx < x) {
-            let (c, adv) = decode_utf8(data);
+            let (c, adv) = self.encoding().decode_text(data);
             match c.unwrap_or(LineMapper::REPLACEMENT_CHAR) {
                 LineMapper::NEWLINE => {
                     if dy == y {
@@ -491,7 +1998,7 @@ impl FileLock<'_> {
                     dx = -base.x_offset;
                 }
                 c => {
-                    let hadv = self.filebuf.layout().advance_for(c);
+                    let hadv = self.filebuf.layout().advance_at(c, base.x_offset + dx);
                     if dy == y && dx + hadv * hdiv > x {
                         break;
                     }
@@ -504,6 +2011,7 @@ impl FileLock<'_> {
         Some(DataAt {
             dy,
             dx,
+            base_x: base.x_offset,
             offset,
             data,
         })
@@ -524,7 +2032,7 @@ impl FileLock<'_> {
         let mut dx = anchor.x_offset - base.x_offset;
         let mut dy = anchor.y_offset - base.y_offset;
         while !data.is_empty() && offset < precise_offset {
-            let (c, adv) = decode_utf8(data);
+            let (c, adv) = self.encoding().decode_text(data);
             match c.unwrap_or(LineMapper::REPLACEMENT_CHAR) {
                 LineMapper::NEWLINE => {
                     dy += 1;
@@ -534,7 +2042,7 @@ impl FileLock<'_> {
                     dx = -base.x_offset;
                 }
                 c => {
-                    let hadv = self.filebuf.layout().advance_for(c);
+                    let hadv = self.filebuf.layout().advance_at(c, base.x_offset + dx);
                     dx += hadv;
                 }
             }
@@ -544,6 +2052,7 @@ impl FileLock<'_> {
         Some(DataAt {
             dy,
             dx,
+            base_x: base.x_offset,
             offset,
             data,
         })
@@ -568,13 +2077,13 @@ impl FileLock<'_> {
             // Process readable text
             on_char_or_line(data.offset, data.dx, data.dy, None);
             while !data.data.is_empty() && (data.dy < y || data.dx < x1) {
-                let (c, adv) = decode_utf8(data.data);
+                let (c, adv) = self.encoding().decode_text(data.data);
                 match c.unwrap_or(LineMapper::REPLACEMENT_CHAR) {
                     LineMapper::NEWLINE => {
                         break;
                     }
                     c => {
-                        let hadv = self.filebuf.layout().advance_for(c);
+                        let hadv = self.filebuf.layout().advance_at(c, data.base_x + data.dx);
                         on_char_or_line(data.offset, data.dx, data.dy, Some((c, hadv)));
                         data.dx += hadv;
                     }
@@ -588,22 +2097,94 @@ impl FileLock<'_> {
     pub fn set_hot_area(&mut self, area: FileRect, selection: Option<ops::Range<i64>>) {
         // Set hot area
         let loaded = &mut *self.loaded;
-        let prev = (loaded.hot, loaded.sel.clone());
+        let prev = (loaded.hot, loaded.hot_bytes.take(), loaded.sel.clone());
         loaded.hot = area;
         loaded.sel = selection.clone();
-        if prev != (area, selection) {
+        if prev != (area, None, selection) {
             self.filebuf.manager.thread().unpark();
         }
     }
 
+    /// Like `set_hot_area`, but for a plain range of bytes instead of an area of
+    /// laid out text.
+    pub fn set_hot_bytes(&mut self, range: ops::Range<i64>, selection: Option<ops::Range<i64>>) {
+        let loaded = &mut *self.loaded;
+        let hot = FileRect {
+            corner: FilePos {
+                base_offset: range.start,
+                ..default()
+            },
+            size: default(),
+        };
+        let prev = (loaded.hot_bytes.clone(), loaded.sel.clone());
+        loaded.hot = hot;
+        loaded.hot_bytes = Some(range.clone());
+        loaded.sel = selection.clone();
+        if prev != (Some(range), selection) {
+            self.filebuf.manager.thread().unpark();
+        }
+    }
+
+    /// Let the backend know about the state of the view, so that it can be persisted.
+    pub fn set_view(&mut self, view: ViewState) {
+        self.loaded.view = view;
+    }
+
+    /// Take the view state that was restored from a previous session, if any.
+    pub fn take_restored_view(&mut self) -> Option<ViewState> {
+        self.loaded.restored_view.take()
+    }
+
     pub fn is_backend_idle(&self) -> bool {
         // Let the frontend know whether the entire text is loaded or not
+        // A paste should also be picked up as soon as it lands
         self.filebuf.shared.sleeping.load()
+            && self.loaded.pending_pastes.is_empty()
+            && self.loaded.pasted.is_empty()
+            && self.loaded.replaced.is_none()
+    }
+
+    /// The parts of the given range that were modified since the file was opened or
+    /// last saved.
+    pub fn edited_ranges(&self, range: ops::Range<i64>) -> Vec<ops::Range<i64>> {
+        match &self.loaded.pieces {
+            Some(table) => table.ram_ranges(range),
+            None => vec![],
+        }
+    }
+
+    /// The loaded bytes starting at the given offset.
+    /// May be shorter than needed, or empty if the offset is not loaded.
+    pub fn loaded_bytes(&self, offset: i64) -> &[u8] {
+        self.loaded.data.longest_prefix(offset)
+    }
+
+    /// The encoding that the buffer is decoded with.
+    pub fn encoding(&self) -> Encoding {
+        self.loaded.linemap.encoding
+    }
+
+    /// Decode the buffer with another encoding, overriding the detected one.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.loaded.set_encoding(encoding);
+        self.filebuf.manager.thread().unpark();
+    }
+
+    /// Encode typed or pasted text with the encoding of the buffer.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        self.encoding().encode(text)
+    }
+
+    /// The most common line ending in the buffer, if it is known and there are any
+    /// line breaks.
+    pub fn line_ending(&self) -> Option<LineEnding> {
+        self.loaded.line_ending
     }
 
     /// Moves the given offset by a certain amount of characters.
     ///
-    /// O(n) in the amount of characters due to UTF-8.
+    /// O(n) in the amount of characters due to variable-length encodings.
+    /// A CRLF line break counts as a single character.
     /// May not have enough data to complete the offset.
     /// In this case, it fails but returns the farthest it could get.
     pub fn char_delta(&self, mut offset: i64, delta: i16) -> StdResult<i64, i64> {
@@ -614,7 +2195,7 @@ impl FileLock<'_> {
                 if data.is_empty() {
                     return Err(offset);
                 }
-                let (_c, rev) = decode_utf8_rev(data);
+                let (_c, rev) = self.encoding().decode_text_rev(data);
                 data = &data[..data.len() - rev];
                 offset -= rev as i64;
             }
@@ -625,7 +2206,7 @@ impl FileLock<'_> {
                 if data.is_empty() {
                     return Err(offset);
                 }
-                let (_c, adv) = decode_utf8(data);
+                let (_c, adv) = self.encoding().decode_text(data);
                 data = &data[adv..];
                 offset += adv as i64;
             }
@@ -633,6 +2214,310 @@ impl FileLock<'_> {
         Ok(offset)
     }
 
+    /// Round the given offset down to the start of the character that contains it.
+    /// Returns `None` if the data around the offset is not loaded yet.
+    pub fn char_boundary(&self, offset: i64) -> Option<i64> {
+        let offset = offset.clamp(0, self.loaded.linemap.file_size);
+        if offset == self.loaded.linemap.file_size {
+            return Some(offset);
+        }
+        // A character is at most 4 bytes long, so its start is close by
+        for start in (offset - 3).max(0)..=offset {
+            let data = self.loaded.data.longest_prefix(start);
+            if data.is_empty() {
+                continue;
+            }
+            let enc = self.encoding();
+            let aligned = enc.skip_partial(start, data) == 0;
+            let (c, adv) = enc.decode_text(data);
+            if start == offset || aligned && c.is_ok() && start + adv as i64 > offset {
+                return Some(start);
+            }
+        }
+        None
+    }
+
+    /// Replace the given range of the buffer by the given data.
+    /// Edits are only kept in memory, the file itself is never modified.
+    ///
+    /// The edit is recorded in the undo history.
+    /// Does nothing and returns `false` if the file is not open yet.
+    pub fn splice(&mut self, range: ops::Range<i64>, data: &[u8], info: EditInfo) -> bool {
+        let layout = &self.filebuf.shared.layout;
+        let ok = self.loaded.edit(layout, range, data, info);
+        if ok {
+            self.edited();
+        }
+        ok
+    }
+
+    /// Undo the last edit.
+    /// Returns what changed, along with the selection from before the edit.
+    pub fn undo(&mut self) -> Option<Restored> {
+        let restored = self.loaded.undo(&self.filebuf.shared.layout)?;
+        self.edited();
+        Some(restored)
+    }
+
+    /// Redo the last undone edit.
+    /// Returns what changed, along with the selection from after the edit.
+    pub fn redo(&mut self) -> Option<Restored> {
+        let restored = self.loaded.redo(&self.filebuf.shared.layout)?;
+        self.edited();
+        Some(restored)
+    }
+
+    /// Let the backend know that the buffer was edited.
+    fn edited(&mut self) {
+        if let Some(p) = &self.loaded.pieces {
+            self.filebuf.shared.edited(p);
+        }
+        self.filebuf.manager.thread().unpark();
+    }
+
+    /// Request the backend to write the buffer to disk.
+    /// The save happens in the background, and the file can still be viewed and
+    /// edited in the meantime.
+    pub fn save(&mut self) {
+        self.loaded.pending_save = true;
+        self.filebuf.manager.thread().unpark();
+    }
+
+    /// Request the backend to replace the given range by the clipboard contents.
+    /// The clipboard is fetched in the background, and once the text is inserted
+    /// the edit can be picked up through `take_pasted`.
+    /// Pastes are applied in the order they are requested.
+    pub fn paste(&mut self, range: ops::Range<i64>, sel_before: [i64; 2]) {
+        if self.loaded.pieces.is_some() {
+            self.loaded
+                .pending_pastes
+                .push_back(PasteRequest { range, sel_before });
+            self.filebuf.manager.thread().unpark();
+        }
+    }
+
+    /// Whether any paste is still waiting for the clipboard contents.
+    pub fn is_pasting(&self) -> bool {
+        !self.loaded.pending_pastes.is_empty()
+    }
+
+    /// Take the ranges replaced by the pastes since the last call, along with the
+    /// length of the pasted text, in the order they were applied.
+    pub fn take_pasted(&mut self) -> Vec<(ops::Range<i64>, i64)> {
+        mem::take(&mut self.loaded.pasted)
+    }
+
+    /// Start searching the buffer for the given query in the background,
+    /// replacing any previous search.
+    /// If `None`, stops searching.
+    pub fn search(&mut self, query: Option<SearchQuery>) {
+        let loaded = &mut *self.loaded;
+        loaded.search = match (query, &loaded.pieces) {
+            (Some(query), Some(p)) => Some(SearchResults::new(query, p.version(), p.len())),
+            _ => None,
+        };
+        self.filebuf.manager.thread().unpark();
+    }
+
+    /// The state of the current search, if any.
+    pub fn search_results(&self) -> Option<&SearchResults> {
+        self.loaded.search.as_ref()
+    }
+
+    /// Find the matches of the current search within the given rectangle, looking
+    /// at the loaded data only.
+    /// This does not depend on how far the background search got, so the visible
+    /// matches can be highlighted as soon as the manager thread compiles the query.
+    pub fn visible_matches(&mut self, view: FileRect) -> Vec<ops::Range<i64>> {
+        let loaded = &mut *self.loaded;
+        // Nothing is highlighted until the manager thread compiles the query
+        let matcher = match (&loaded.search, &loaded.highlighter) {
+            (Some(s), Some((q, m))) if s.query == *q && m.encoding() == loaded.linemap.encoding => {
+                m
+            }
+            _ => return vec![],
+        };
+        let (l, _, r) = match loaded.try_get_range(view) {
+            Some(range) => range,
+            None => return vec![],
+        };
+        // Matches might start before the view and end inside it, and vice versa
+        // Include some context too, for lookbehind
+        let extra = matcher.max_len() as i64;
+        let mut start = (l - extra).max(0);
+        if loaded.data.longest_prefix(start).is_empty() {
+            start = l;
+        }
+        let mut base = (start - Matcher::LOOKBEHIND as i64).max(0);
+        let mut data = loaded.data.longest_prefix(base);
+        if data.len() as i64 <= start - base {
+            // The context is not loaded, so go without it
+            base = start;
+            data = loaded.data.longest_prefix(base);
+        }
+        let end = (r + extra - base).clamp(0, data.len() as i64);
+        if end <= start - base {
+            return vec![];
+        }
+        let mut out = vec![];
+        matcher.find_all(&data[..end as usize], (start - base) as usize, |m| {
+            let m = base + m.start as i64..base + m.end as i64;
+            if m.end > l && m.start < r {
+                out.push(m);
+            }
+        });
+        out
+    }
+
+    /// Replace the given range, which should be a match of the given query, with
+    /// the replacement text.
+    /// Returns the length of the replacement, or `None` if the range is not a
+    /// match, its data is not loaded or the query is not compiled yet.
+    pub fn replace_match(
+        &mut self,
+        range: ops::Range<i64>,
+        query: &SearchQuery,
+        replacement: &str,
+        sel_before: [i64; 2],
+    ) -> Option<i64> {
+        let matcher = match &self.loaded.highlighter {
+            Some((q, m)) if q == query && m.encoding() == self.encoding() => m.clone(),
+            _ => return None,
+        };
+        // Include some context before the match, for lookbehind
+        let ctx = range.start.min(Matcher::LOOKBEHIND as i64);
+        let data = self.loaded.data.longest_prefix(range.start - ctx);
+        let m = ctx as usize..(ctx + range.end - range.start) as usize;
+        if !matcher.is_match(data, m.clone()) {
+            return None;
+        }
+        let mut out = vec![];
+        matcher.replace(data, m, replacement, &mut out);
+        let len = out.len() as i64;
+        let info = EditInfo {
+            sel_before,
+            sel_after: [range.start + len; 2],
+            typed: false,
+        };
+        self.splice(range, &out, info).then_some(len)
+    }
+
+    /// Replace all matches of the given query in the background.
+    /// The replacement is applied as a single edit, which can be picked up through
+    /// `take_replaced`.
+    pub fn replace_all(&mut self, query: SearchQuery, replacement: String, sel_before: [i64; 2]) {
+        self.loaded.replace = Some(ReplaceProgress::new(query, replacement, sel_before));
+        self.filebuf.manager.thread().unpark();
+    }
+
+    /// Forget about the current replace-all, stopping it if it is still running.
+    pub fn stop_replace(&mut self) {
+        if self.loaded.replace.take().is_some() {
+            self.filebuf.manager.thread().unpark();
+        }
+    }
+
+    /// The state of the current replace-all, if any.
+    pub fn replace_progress(&self) -> Option<&ReplaceProgress> {
+        self.loaded.replace.as_ref()
+    }
+
+    /// Take the range replaced by the last replace-all, along with the length of
+    /// the replaced text.
+    pub fn take_replaced(&mut self) -> Option<(ops::Range<i64>, i64)> {
+        self.loaded.replaced.take()
+    }
+
+    /// Find the start of the given line, counting from zero.
+    /// Returns the offset of the line and whether it is exact, which is only the case
+    /// if the buffer is mapped from its start up to the line.
+    /// Otherwise, the offset is extrapolated from the average line length seen so far.
+    pub fn locate_line(&self, line: i64) -> (i64, bool) {
+        let len = self.loaded.linemap.file_size;
+        let avg = self.loaded.linemap.average_line_len().unwrap_or(80.);
+        match self.lookup_pos(0, line, 0., 0.5) {
+            Some(at) if at.dy >= line || at.offset >= len => (at.offset, true),
+            Some(at) => {
+                let off = at.offset + ((line - at.dy) as f64 * avg) as i64;
+                (off.min(len), false)
+            }
+            None => (((line as f64 * avg) as i64).min(len), false),
+        }
+    }
+
+    /// How far the buffer is mapped from its start, without gaps.
+    pub fn mapped_from_start(&self) -> i64 {
+        self.loaded.linemap.start_extent().map_or(0, |(end, _)| end)
+    }
+
+    /// The amount of lines in the buffer, once it is mapped from start to end.
+    pub fn line_count(&self) -> Option<i64> {
+        let (end, lines) = self.loaded.linemap.start_extent()?;
+        (end >= self.loaded.linemap.file_size).then_some(lines + 1)
+    }
+
+    /// Find the line and column of the given offset, both counting from one.
+    /// The line is only known if the buffer is mapped from its start up to the
+    /// offset, and the column if the start of its line is loaded and close enough.
+    pub fn line_col(&self, offset: i64) -> (Option<i64>, Option<i64>) {
+        const MAX_COLUMN_SCAN: i64 = 64 * 1024;
+        let line = self
+            .lookup_offset(0, offset)
+            .filter(|at| at.offset == offset)
+            .map(|at| at.dy + 1);
+        // Positions are relative to the anchor before the base offset, so first find
+        // the line of the offset itself
+        let col = self
+            .lookup_offset(offset, offset)
+            .filter(|at| at.offset == offset)
+            .and_then(|at| self.lookup_pos(offset, at.dy, f64::NEG_INFINITY, 0.5))
+            .filter(|at| {
+                let before = self.loaded.data.longest_suffix(at.offset);
+                at.offset == 0
+                    || !before.is_empty()
+                        && self.encoding().decode_text_rev(before).0 == Ok(LineMapper::NEWLINE)
+            })
+            .filter(|at| offset - at.offset <= MAX_COLUMN_SCAN)
+            .and_then(|at| {
+                let data = at.data.get(..(offset - at.offset) as usize)?;
+                let mut col = 1;
+                let mut data = data;
+                while !data.is_empty() {
+                    data = &data[self.encoding().decode_text(data).1..];
+                    col += 1;
+                }
+                Some(col)
+            });
+        (line, col)
+    }
+
+    /// How much of the buffer is linemapped and how much is loaded, in bytes.
+    pub fn coverage(&self) -> (i64, i64) {
+        (
+            self.loaded.linemap.mapped_len(),
+            self.loaded.data.loaded_len(),
+        )
+    }
+
+    /// How the file was changed by another process, if it was.
+    pub fn external_change(&self) -> Option<&str> {
+        self.loaded.external_change.as_deref()
+    }
+
+    /// Make the backend linemap from the start of the buffer towards the given line,
+    /// so that its exact position is found as soon as possible.
+    pub fn goto_line(&mut self, line: Option<i64>) {
+        if self.loaded.goto_line != line {
+            self.loaded.goto_line = line;
+            self.filebuf.manager.thread().unpark();
+        }
+    }
+
+    /// Whether a save was requested and has not finished yet.
+    pub fn is_saving(&self) -> bool {
+        self.loaded.pending_save || self.filebuf.save_progress().is_some()
+    }
+
     /// Request the backend to copy the selected text.
     pub fn copy_selection(&mut self) {
         self.loaded.pending_sel_copy = true;
@@ -759,8 +2644,13 @@ impl<'a> LoadedDataGuard<'a> {
     }
 }
 
-fn set_clipboard(data: &[u8]) -> Result<()> {
-    let text = std::str::from_utf8(data).context("invalid utf-8 data")?;
-    gl::clipboard::set(text).map_err(|e| anyhow!("{}", e))?;
+fn set_clipboard(data: &[u8], encoding: Encoding) -> Result<()> {
+    let text = match encoding {
+        Encoding::Utf8 => std::str::from_utf8(data)
+            .context("invalid utf-8 data")?
+            .to_string(),
+        enc => enc.decode_all(data),
+    };
+    gl::clipboard::set(&text).map_err(|e| anyhow!("{}", e))?;
     Ok(())
 }
@@ -0,0 +1,561 @@
+//! Reads gzip and zstd compressed files as if they were decompressed.
+//!
+//! Compressed data can only be decoded sequentially, so reading from the middle of a
+//! huge file would mean decompressing everything before it.
+//! Instead, the file is decompressed once in the background, taking a snapshot of the
+//! decoder every so often, like zlib's `zran` example does.
+//! Random reads then resume decoding from the closest snapshot before them.
+//! Snapshots take memory, so once they take too much every other one is dropped
+//! and they are taken half as often from then on.
+//!
+//! The whole state of a deflate decoder is the inflater state plus the last 32KB of
+//! output, so gzip snapshots can be taken anywhere.
+//! The state of a zstd decoder cannot be copied, so zstd snapshots are only taken
+//! between frames. Multi-frame files (like the ones written by `pzstd` or `zstd
+//! --adapt`) are quick to seek, but files compressed into a single frame would have
+//! to be decoded from the start.
+//! Instead, once the decoder goes too long without a snapshot, the rest of the
+//! decompressed data is written to a temporary file as it is indexed, and read
+//! from there.
+
+use miniz_oxide::inflate::{
+    core::{decompress, inflate_flags, DecompressorOxide},
+    TINFLStatus,
+};
+use zstd::stream::raw::Operation;
+
+use crate::prelude::*;
+
+use super::{read_exact_at, spool::anonymous_file, Notice};
+
+/// The size of the deflate window, which is all the output a decoder needs to
+/// remember.
+const WINDOW: usize = 32 * 1024;
+
+/// How much compressed data to read from the file at once.
+const READ_SIZE: usize = 64 * 1024;
+
+/// How many times the checkpoint spacing the decoder may go without a checkpoint,
+/// before the decompressed data is spooled to a temporary file instead.
+const SPOOL_AFTER: i64 = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+impl Codec {
+    /// Find out how a file is compressed from its first bytes, if it is.
+    pub(super) fn detect(file: &File) -> Option<Codec> {
+        let mut magic = [0; 4];
+        let n = read_at(file, &mut magic, 0).ok()?;
+        match &magic[..n] {
+            [0x1f, 0x8b, 0x08, ..] => Some(Codec::Gzip),
+            [0x28, 0xb5, 0x2f, 0xfd] => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compressed data read from the file, waiting to be decoded.
+struct Input {
+    buf: Vec<u8>,
+    pos: usize,
+    /// The file offset of the next byte to decode.
+    off: u64,
+    /// Whether the end of the file was reached.
+    eof: bool,
+}
+impl Input {
+    fn at(off: u64) -> Self {
+        Self {
+            buf: vec![],
+            pos: 0,
+            off,
+            eof: false,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.pos += n;
+        self.off += n as u64;
+    }
+
+    /// Read more data from the file if all of the buffered data was decoded.
+    fn fill(&mut self, file: &File) -> Result<()> {
+        if self.pos < self.buf.len() || self.eof {
+            return Ok(());
+        }
+        self.buf.resize(READ_SIZE, 0);
+        let n = read_at(file, &mut self.buf, self.off)?;
+        self.buf.truncate(n);
+        self.pos = 0;
+        self.eof = n == 0;
+        Ok(())
+    }
+
+    fn next_byte(&mut self, file: &File) -> Result<Option<u8>> {
+        self.fill(file)?;
+        let b = self.data().first().copied();
+        if b.is_some() {
+            self.consume(1);
+        }
+        Ok(b)
+    }
+
+    fn skip(&mut self, file: &File, mut n: usize) -> Result<()> {
+        while n > 0 {
+            self.fill(file)?;
+            ensure!(!self.eof, "file is truncated");
+            let k = n.min(self.data().len());
+            self.consume(k);
+            n -= k;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum GzipPhase {
+    /// Expecting the header of a gzip member.
+    Header,
+    /// Inflating the body of a member.
+    Deflate,
+    /// Expecting the trailer of a member, after which another member may follow.
+    Trailer,
+    End,
+}
+
+/// Everything a gzip decoder needs to resume, other than its position in the file.
+#[derive(Clone)]
+struct GzipState {
+    phase: GzipPhase,
+    inflater: DecompressorOxide,
+    /// The last output of the inflater, wrapping around.
+    window: Box<[u8]>,
+    /// Where the next output goes in the window.
+    wpos: usize,
+}
+
+struct GzipDecoder {
+    input: Input,
+    state: Box<GzipState>,
+    /// Output in the window that was not handed out yet.
+    pending: ops::Range<usize>,
+}
+impl GzipDecoder {
+    /// Parse a member header.
+    /// Returns false if there is no member, because the file ended or is followed by
+    /// garbage.
+    fn read_header(&mut self, file: &File) -> Result<bool> {
+        const FHCRC: u8 = 1 << 1;
+        const FEXTRA: u8 = 1 << 2;
+        const FNAME: u8 = 1 << 3;
+        const FCOMMENT: u8 = 1 << 4;
+        let mut head = [0; 10];
+        for b in head.iter_mut() {
+            match self.input.next_byte(file)? {
+                Some(x) => *b = x,
+                None => return Ok(false),
+            }
+        }
+        if head[..3] != [0x1f, 0x8b, 0x08] {
+            return Ok(false);
+        }
+        let flags = head[3];
+        if flags & FEXTRA != 0 {
+            let lo = self.input.next_byte(file)?.unwrap_or(0);
+            let hi = self.input.next_byte(file)?.unwrap_or(0);
+            self.input
+                .skip(file, u16::from_le_bytes([lo, hi]) as usize)?;
+        }
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                while self.input.next_byte(file)?.context("file is truncated")? != 0 {}
+            }
+        }
+        if flags & FHCRC != 0 {
+            self.input.skip(file, 2)?;
+        }
+        Ok(true)
+    }
+
+    fn read(&mut self, file: &File, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            if !self.pending.is_empty() {
+                let n = buf.len().min(self.pending.len());
+                let src = self.pending.start..self.pending.start + n;
+                buf[..n].copy_from_slice(&self.state.window[src]);
+                self.pending.start += n;
+                return Ok(n);
+            }
+            match self.state.phase {
+                GzipPhase::Header => {
+                    if self.read_header(file)? {
+                        self.state.inflater = DecompressorOxide::new();
+                        self.state.phase = GzipPhase::Deflate;
+                    } else {
+                        self.state.phase = GzipPhase::End;
+                    }
+                }
+                GzipPhase::Deflate => {
+                    let state = &mut *self.state;
+                    self.input.fill(file)?;
+                    let flags = match self.input.eof {
+                        true => 0,
+                        false => inflate_flags::TINFL_FLAG_HAS_MORE_INPUT,
+                    };
+                    let (status, nin, nout) = decompress(
+                        &mut state.inflater,
+                        self.input.data(),
+                        &mut state.window,
+                        state.wpos,
+                        flags,
+                    );
+                    self.input.consume(nin);
+                    self.pending = state.wpos..state.wpos + nout;
+                    state.wpos = (state.wpos + nout) % WINDOW;
+                    match status {
+                        TINFLStatus::Done => state.phase = GzipPhase::Trailer,
+                        TINFLStatus::NeedsMoreInput | TINFLStatus::HasMoreOutput => {}
+                        TINFLStatus::FailedCannotMakeProgress => bail!("file is truncated"),
+                        _ => bail!("corrupt gzip data"),
+                    }
+                }
+                GzipPhase::Trailer => {
+                    // The checksum and length of the member
+                    self.input.skip(file, 8)?;
+                    self.state.phase = GzipPhase::Header;
+                }
+                GzipPhase::End => return Ok(0),
+            }
+        }
+    }
+}
+
+struct ZstdDecoder {
+    input: Input,
+    raw: zstd::stream::raw::Decoder<'static>,
+    buf: Vec<u8>,
+    pending: ops::Range<usize>,
+    /// Whether the last frame was fully decoded, so a new decoder can take over.
+    between_frames: bool,
+}
+impl ZstdDecoder {
+    fn new(input: u64) -> Result<Self> {
+        Ok(Self {
+            input: Input::at(input),
+            raw: zstd::stream::raw::Decoder::new()?,
+            buf: vec![0; READ_SIZE],
+            pending: 0..0,
+            between_frames: true,
+        })
+    }
+
+    fn read(&mut self, file: &File, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            if !self.pending.is_empty() {
+                let n = buf.len().min(self.pending.len());
+                let src = self.pending.start..self.pending.start + n;
+                buf[..n].copy_from_slice(&self.buf[src]);
+                self.pending.start += n;
+                return Ok(n);
+            }
+            self.input.fill(file)?;
+            if self.input.data().is_empty() {
+                ensure!(self.between_frames, "file is truncated");
+                return Ok(0);
+            }
+            let status = self
+                .raw
+                .run_on_buffers(self.input.data(), &mut self.buf)
+                .context("corrupt zstd data")?;
+            self.input.consume(status.bytes_read);
+            self.pending = 0..status.bytes_written;
+            self.between_frames = status.remaining == 0;
+        }
+    }
+}
+
+enum Decoder {
+    Gzip(GzipDecoder),
+    Zstd(ZstdDecoder),
+}
+
+/// A point where decoding can be resumed from, without decoding anything before it.
+enum Checkpoint {
+    Gzip { input: u64, state: Box<GzipState> },
+    Zstd { input: u64 },
+}
+impl Checkpoint {
+    /// An estimate of the memory used by the checkpoint.
+    fn mem(&self) -> usize {
+        let state = match self {
+            Checkpoint::Gzip { state, .. } => mem::size_of::<GzipState>() + state.window.len(),
+            Checkpoint::Zstd { .. } => 0,
+        };
+        mem::size_of::<(i64, Checkpoint)>() + state
+    }
+}
+
+/// A decoder along with how much output it handed out.
+struct Cursor {
+    decoder: Decoder,
+    out: i64,
+}
+impl Cursor {
+    fn resume(cp: &Checkpoint, out: i64) -> Result<Self> {
+        let decoder = match cp {
+            Checkpoint::Gzip { input, state } => Decoder::Gzip(GzipDecoder {
+                input: Input::at(*input),
+                state: state.clone(),
+                pending: 0..0,
+            }),
+            Checkpoint::Zstd { input } => Decoder::Zstd(ZstdDecoder::new(*input)?),
+        };
+        Ok(Self { decoder, out })
+    }
+
+    /// Decode some data into the start of the buffer.
+    /// Returns how many bytes were decoded, or zero at the end of the data.
+    fn read(&mut self, file: &File, buf: &mut [u8]) -> Result<usize> {
+        let n = match &mut self.decoder {
+            Decoder::Gzip(d) => d.read(file, buf)?,
+            Decoder::Zstd(d) => d.read(file, buf)?,
+        };
+        self.out += n as i64;
+        Ok(n)
+    }
+
+    /// Take a snapshot of the decoder, if it is at a point where it can.
+    fn checkpoint(&self) -> Option<Checkpoint> {
+        match &self.decoder {
+            Decoder::Gzip(d) if d.pending.is_empty() => Some(Checkpoint::Gzip {
+                input: d.input.off,
+                state: d.state.clone(),
+            }),
+            Decoder::Zstd(d) if d.pending.is_empty() && d.between_frames => {
+                Some(Checkpoint::Zstd { input: d.input.off })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A compressed file, which can be read at any decompressed offset that was already
+/// indexed.
+pub(super) struct Decompressed {
+    file: File,
+    codec: Codec,
+    /// Snapshots of the decoder along with their decompressed offset, in order.
+    checkpoints: Vec<(i64, Checkpoint)>,
+    /// The minimum distance between checkpoints, in decompressed bytes.
+    /// Doubles whenever the checkpoints are thinned out.
+    pub(super) spacing: i64,
+    /// The memory used by the checkpoints.
+    pub(super) checkpoint_mem: usize,
+    /// How much memory the checkpoints may use before they are thinned out.
+    max_checkpoint_mem: usize,
+    /// The decoder that builds the index, which is at the end of the indexed data.
+    indexer: Cursor,
+    /// Whether the whole file was indexed.
+    pub done: bool,
+    /// The decoder that was used for the last read, which is reused by reads right
+    /// after it.
+    reader: Option<Cursor>,
+    scratch: Vec<u8>,
+    /// The decompressed data from the given offset onwards, if the decoder could not
+    /// be snapshotted for too long.
+    spool: Option<(i64, File)>,
+    /// Whether spooling was given up on, because the temporary file failed.
+    spool_failed: bool,
+    /// Something that the user should know about, if anything.
+    pub notice: Option<Notice>,
+}
+impl Decompressed {
+    pub fn new(file: File, codec: Codec, spacing: i64, max_checkpoint_mem: usize) -> Result<Self> {
+        let start = match codec {
+            Codec::Gzip => Checkpoint::Gzip {
+                input: 0,
+                state: Box::new(GzipState {
+                    phase: GzipPhase::Header,
+                    inflater: DecompressorOxide::new(),
+                    window: vec![0; WINDOW].into_boxed_slice(),
+                    wpos: 0,
+                }),
+            },
+            Codec::Zstd => Checkpoint::Zstd { input: 0 },
+        };
+        let mut d = Self {
+            file,
+            codec,
+            indexer: Cursor::resume(&start, 0)?,
+            checkpoint_mem: start.mem(),
+            max_checkpoint_mem,
+            checkpoints: vec![(0, start)],
+            spacing: spacing.max(WINDOW as i64),
+            done: false,
+            reader: None,
+            scratch: vec![0; READ_SIZE],
+            spool: None,
+            spool_failed: false,
+            notice: None,
+        };
+        // Make sure that the file decodes at all
+        d.index_step(1)?;
+        Ok(d)
+    }
+
+    /// How much decompressed data was indexed so far.
+    pub fn len(&self) -> i64 {
+        self.indexer.out
+    }
+
+    /// Decompress at least `budget` more bytes of the file, or until the end of the
+    /// file, taking snapshots along the way.
+    pub fn index_step(&mut self, budget: usize) -> Result<()> {
+        let end = self.indexer.out + budget as i64;
+        while !self.done && self.indexer.out < end {
+            let n = self.indexer.read(&self.file, &mut self.scratch)?;
+            if n == 0 {
+                self.done = true;
+            }
+            if let Some((_, spool)) = &mut self.spool {
+                if let Err(err) = spool.write_all(&self.scratch[..n]) {
+                    // Reads go back to decoding from the last checkpoint
+                    self.spool = None;
+                    self.spool_failed(err.into());
+                }
+                continue;
+            }
+            let last = self.checkpoints.last().map(|(out, _)| *out).unwrap_or(0);
+            if self.indexer.out - last >= self.spacing {
+                if let Some(cp) = self.indexer.checkpoint() {
+                    self.checkpoint_mem += cp.mem();
+                    self.checkpoints.push((self.indexer.out, cp));
+                    if self.checkpoint_mem > self.max_checkpoint_mem {
+                        self.thin_checkpoints();
+                    }
+                } else if self.indexer.out - last >= SPOOL_AFTER * self.spacing {
+                    self.start_spool();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every other checkpoint, and take them half as often from now on.
+    /// Random reads get slower, since they decode from further away, but the
+    /// memory used by the checkpoints stays bounded regardless of the file size.
+    fn thin_checkpoints(&mut self) {
+        let mut i = 0;
+        self.checkpoints.retain(|_| {
+            i += 1;
+            i % 2 == 1
+        });
+        self.checkpoint_mem = self.checkpoints.iter().map(|(_, cp)| cp.mem()).sum();
+        self.spacing *= 2;
+    }
+
+    /// Start writing the decompressed data to a temporary file, since there is no
+    /// telling when the decoder can be snapshotted next.
+    fn start_spool(&mut self) {
+        if self.spool_failed {
+            return;
+        }
+        match anonymous_file() {
+            Ok(file) => {
+                self.spool = Some((self.indexer.out, file));
+                self.notice = Some(Notice {
+                    error: false,
+                    text: format!(
+                        "this {} file cannot be seeked, so it is decompressed into a \
+                        temporary file",
+                        self.codec.name()
+                    ),
+                });
+            }
+            Err(err) => self.spool_failed(err),
+        }
+    }
+
+    fn spool_failed(&mut self, err: Error) {
+        self.spool_failed = true;
+        self.notice = Some(Notice {
+            error: true,
+            text: format!(
+                "this {} file cannot be seeked, and decompressing it into a temporary \
+                file failed, so jumping around will be slow: {:#}",
+                self.codec.name(),
+                err
+            ),
+        });
+    }
+
+    /// Fill the buffer with the decompressed data at the given offset, which must
+    /// have been indexed already.
+    pub fn read_exact_at(&mut self, mut buf: &mut [u8], offset: i64) -> Result<()> {
+        ensure!(
+            offset + buf.len() as i64 <= self.len(),
+            "read past the decompressed data"
+        );
+        // Anything that was spooled is read straight from the temporary file
+        if let Some((start, spool)) = &self.spool {
+            let split = (start - offset).clamp(0, buf.len() as i64) as usize;
+            let (before, spooled) = buf.split_at_mut(split);
+            read_exact_at(spool, spooled, (offset + split as i64 - start) as u64)
+                .context("failed to read temporary file")?;
+            buf = before;
+            if buf.is_empty() {
+                return Ok(());
+            }
+        }
+        // Resume from the closest checkpoint, unless the last read is closer
+        let i = self.checkpoints.partition_point(|(out, _)| *out <= offset) - 1;
+        let (cp_out, cp) = &self.checkpoints[i];
+        let mut cur = match self.reader.take() {
+            Some(r) if r.out <= offset && r.out >= *cp_out => r,
+            _ => Cursor::resume(cp, *cp_out)?,
+        };
+        while cur.out < offset {
+            let n = (offset - cur.out).min(self.scratch.len() as i64) as usize;
+            ensure!(
+                cur.read(&self.file, &mut self.scratch[..n])? > 0,
+                "file ended early"
+            );
+        }
+        while !buf.is_empty() {
+            let n = cur.read(&self.file, buf)?;
+            ensure!(n > 0, "file ended early");
+            buf = &mut buf[n..];
+        }
+        self.reader = Some(cur);
+        Ok(())
+    }
+}
+
+/// Read as many bytes as are available at the given file offset, up to the length of
+/// the buffer.
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    loop {
+        #[cfg(unix)]
+        let res = std::os::unix::fs::FileExt::read_at(file, buf, offset);
+        #[cfg(windows)]
+        let res = std::os::windows::fs::FileExt::seek_read(file, buf, offset);
+        match res {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            res => return res,
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! Text encodings that a buffer can be decoded with, and the line endings that
+//! split it into lines.
+//!
+//! The rest of the buffer deals in bytes, so an encoding only has to split bytes
+//! into characters in both directions, and find where characters start when
+//! decoding from an arbitrary offset.
+
+use crate::prelude::*;
+
+use super::linemap::{decode_utf8, decode_utf8_rev, is_utf8_cont, utf8_seq_len};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+}
+impl Encoding {
+    /// All encodings, in the order that the file view cycles through them.
+    pub const ALL: [Encoding; 4] = [
+        Encoding::Utf8,
+        Encoding::Utf16Le,
+        Encoding::Utf16Be,
+        Encoding::Windows1252,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16 LE",
+            Encoding::Utf16Be => "UTF-16 BE",
+            Encoding::Windows1252 => "Windows-1252",
+        }
+    }
+
+    /// The encoding that comes after this one in `ALL`.
+    pub fn next(self) -> Encoding {
+        let i = Self::ALL.iter().position(|&e| e == self).unwrap_or(0);
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+
+    /// Guess the encoding of a buffer from the data at its start.
+    /// A byte order mark settles it.
+    /// Otherwise, UTF-16 is recognized by the zero bytes between ASCII characters,
+    /// and anything that is not valid UTF-8 is taken to be Windows-1252.
+    pub fn detect(sample: &[u8]) -> Encoding {
+        if sample.starts_with(&[0xef, 0xbb, 0xbf]) {
+            return Encoding::Utf8;
+        }
+        if sample.starts_with(&[0xff, 0xfe]) {
+            return Encoding::Utf16Le;
+        }
+        if sample.starts_with(&[0xfe, 0xff]) {
+            return Encoding::Utf16Be;
+        }
+        let units = sample.len() / 2;
+        if units >= 8 {
+            let zeros = |parity: usize| {
+                sample[..units * 2]
+                    .iter()
+                    .skip(parity)
+                    .step_by(2)
+                    .filter(|&&b| b == 0)
+                    .count()
+            };
+            let (even, odd) = (zeros(0), zeros(1));
+            if odd * 4 >= units * 3 && even * 8 <= units {
+                return Encoding::Utf16Le;
+            }
+            if even * 4 >= units * 3 && odd * 8 <= units {
+                return Encoding::Utf16Be;
+            }
+        }
+        match std::str::from_utf8(sample) {
+            Ok(_) => Encoding::Utf8,
+            // The sample may cut a character short
+            Err(err) if err.error_len().is_none() => Encoding::Utf8,
+            Err(_) => Encoding::Windows1252,
+        }
+    }
+
+    /// Decode a single character from the given non-empty byte slice.
+    /// Returns the character, or the offending byte if it is not valid, along with
+    /// the amount of bytes it takes up.
+    pub fn decode(self, b: &[u8]) -> (Result<u32, u8>, usize) {
+        match self {
+            Encoding::Utf8 => decode_utf8(b),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if b.len() < 2 {
+                    return (Err(b[0]), 1);
+                }
+                let u = self.unit([b[0], b[1]]);
+                match u {
+                    0xd800..=0xdbff if b.len() >= 4 => match self.unit([b[2], b[3]]) {
+                        lo @ 0xdc00..=0xdfff => (Ok(surrogate_pair(u, lo)), 4),
+                        _ => (Err(b[0]), 2),
+                    },
+                    0xd800..=0xdfff => (Err(b[0]), 2),
+                    u => (Ok(u), 2),
+                }
+            }
+            Encoding::Windows1252 => (Ok(windows1252(b[0])), 1),
+        }
+    }
+
+    /// Similar to `decode` but in reverse, decoding the character at the end of the
+    /// slice.
+    pub fn decode_rev(self, b: &[u8]) -> (Result<u32, u8>, usize) {
+        let n = b.len();
+        match self {
+            Encoding::Utf8 => decode_utf8_rev(b),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                if n < 2 {
+                    return (Err(b[n - 1]), 1);
+                }
+                let u = self.unit([b[n - 2], b[n - 1]]);
+                match u {
+                    0xdc00..=0xdfff if n >= 4 => match self.unit([b[n - 4], b[n - 3]]) {
+                        hi @ 0xd800..=0xdbff => (Ok(surrogate_pair(hi, u)), 4),
+                        _ => (Err(b[n - 2]), 2),
+                    },
+                    0xd800..=0xdfff => (Err(b[n - 2]), 2),
+                    u => (Ok(u), 2),
+                }
+            }
+            Encoding::Windows1252 => (Ok(windows1252(b[n - 1])), 1),
+        }
+    }
+
+    /// How many bytes to skip from the start of data at the given buffer offset, to
+    /// reach the start of a character.
+    /// At most 3 bytes are skipped, which always works for valid text.
+    pub fn skip_partial(self, offset: i64, data: &[u8]) -> usize {
+        match self {
+            Encoding::Utf8 => data
+                .iter()
+                .take(3)
+                .take_while(|&&b| is_utf8_cont(b))
+                .count(),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                // Code units are aligned to the start of the buffer
+                let skip = offset.rem_euclid(2) as usize;
+                match data.get(skip..skip + 2) {
+                    Some(&[b0, b1]) if (0xdc00..=0xdfff).contains(&self.unit([b0, b1])) => skip + 2,
+                    _ => skip,
+                }
+            }
+            Encoding::Windows1252 => 0,
+        }
+    }
+
+    /// How many bytes at the end of data that ends at the given buffer offset belong
+    /// to a character that is cut short.
+    /// At most 3 bytes are cut, which always works for valid text.
+    pub fn cut_partial(self, end: i64, data: &[u8]) -> usize {
+        let n = data.len();
+        match self {
+            Encoding::Utf8 => (0..3.min(n))
+                .find(|&i| utf8_seq_len(data[n - i - 1]) > i + 1)
+                .map_or(0, |i| i + 1),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let cut = (end.rem_euclid(2) as usize).min(n);
+                match data[..n - cut] {
+                    [.., b0, b1] if (0xd800..=0xdbff).contains(&self.unit([b0, b1])) => cut + 2,
+                    _ => cut,
+                }
+            }
+            Encoding::Windows1252 => 0,
+        }
+    }
+
+    /// Encode the given text.
+    /// Characters that cannot be represented are replaced by question marks.
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => text.as_bytes().to_vec(),
+            Encoding::Utf16Le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Encoding::Utf16Be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            Encoding::Windows1252 => text
+                .chars()
+                .map(|c| match c as u32 {
+                    c @ (0..=0x7f | 0xa0..=0xff) => c as u8,
+                    c => WINDOWS_1252
+                        .iter()
+                        .position(|&w| w as u32 == c)
+                        .map_or(b'?', |i| 0x80 + i as u8),
+                })
+                .collect(),
+        }
+    }
+
+    /// Like `decode`, but decodes any line ending as a single newline character.
+    /// A carriage return at the end of the slice is taken to be on its own.
+    pub fn decode_text(self, b: &[u8]) -> (Result<u32, u8>, usize) {
+        match self.decode(b) {
+            (Ok(CR), adv) => match b.get(adv..) {
+                Some(rest) if !rest.is_empty() && self.decode(rest).0 == Ok(LF) => {
+                    (Ok(LF), adv + self.decode(rest).1)
+                }
+                _ => (Ok(LF), adv),
+            },
+            c => c,
+        }
+    }
+
+    /// Similar to `decode_text` but in reverse.
+    /// A line feed at the start of the slice is taken to be on its own.
+    pub fn decode_text_rev(self, b: &[u8]) -> (Result<u32, u8>, usize) {
+        match self.decode_rev(b) {
+            (Ok(LF), adv) if adv < b.len() => match self.decode_rev(&b[..b.len() - adv]) {
+                (Ok(CR), adv2) => (Ok(LF), adv + adv2),
+                _ => (Ok(LF), adv),
+            },
+            (Ok(CR), adv) => (Ok(LF), adv),
+            c => c,
+        }
+    }
+
+    /// Decode a whole slice into text, replacing anything that is not valid.
+    pub fn decode_all(self, mut data: &[u8]) -> String {
+        let mut out = String::with_capacity(data.len());
+        while !data.is_empty() {
+            let (c, adv) = self.decode(data);
+            out.push(
+                c.ok()
+                    .and_then(char::from_u32)
+                    .unwrap_or(char::REPLACEMENT_CHARACTER),
+            );
+            data = &data[adv..];
+        }
+        out
+    }
+
+    /// Read a UTF-16 code unit with the byte order of this encoding.
+    fn unit(self, b: [u8; 2]) -> u32 {
+        match self {
+            Encoding::Utf16Be => u16::from_be_bytes(b) as u32,
+            _ => u16::from_le_bytes(b) as u32,
+        }
+    }
+}
+
+/// How lines are separated in a buffer.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+impl LineEnding {
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR",
+        }
+    }
+
+    /// Parse a line ending from its case-insensitive name.
+    pub fn from_name(name: &str) -> Option<LineEnding> {
+        [LineEnding::Lf, LineEnding::CrLf, LineEnding::Cr]
+            .into_iter()
+            .find(|le| le.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    /// Find the most common line ending in the given data.
+    /// Returns `None` if there are no line breaks at all.
+    pub fn detect(enc: Encoding, mut sample: &[u8]) -> Option<LineEnding> {
+        let mut counts = [0; 3];
+        while !sample.is_empty() {
+            let (c, adv) = enc.decode_text(sample);
+            if c == Ok(LF) {
+                let le = match enc.decode(sample).0 {
+                    Ok(LF) => LineEnding::Lf,
+                    _ if adv > enc.decode(sample).1 => LineEnding::CrLf,
+                    _ => LineEnding::Cr,
+                };
+                counts[le as usize] += 1;
+            }
+            sample = &sample[adv..];
+        }
+        let (i, &n) = counts.iter().enumerate().rev().max_by_key(|&(_, n)| n)?;
+        (n > 0).then_some([LineEnding::Lf, LineEnding::CrLf, LineEnding::Cr][i])
+    }
+}
+
+const CR: u32 = '\r' as u32;
+const LF: u32 = '\n' as u32;
+
+fn surrogate_pair(hi: u32, lo: u32) -> u32 {
+    0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00)
+}
+
+/// The characters that Windows-1252 places at `0x80..0xa0`, where Latin-1 has
+/// control characters.
+/// The five unassigned bytes are kept as the control characters.
+const WINDOWS_1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+fn windows1252(b: u8) -> u32 {
+    match b {
+        0x80..=0x9f => WINDOWS_1252[(b - 0x80) as usize] as u32,
+        b => b as u32,
+    }
+}
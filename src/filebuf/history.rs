@@ -0,0 +1,245 @@
+//! Undo/redo history of buffer edits.
+
+use std::collections::VecDeque;
+
+use crate::prelude::*;
+
+use super::piece::{Overwritten, Piece, PieceTable, Source};
+
+/// Describes an edit, for the purposes of the undo history.
+#[derive(Clone, Copy, Debug)]
+pub struct EditInfo {
+    /// The selection before the edit, as `[first, second]`.
+    pub sel_before: [i64; 2],
+    /// The selection after the edit, as `[first, second]`.
+    pub sel_after: [i64; 2],
+    /// Whether the edit was typed in.
+    /// Consecutive typed edits are undone as a single step.
+    pub typed: bool,
+}
+
+/// What an undo or redo changed in the buffer, so that the view can follow along.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Restored {
+    /// The range of the buffer that was replaced.
+    pub range: ops::Range<i64>,
+    /// The length of the data that replaced it.
+    pub len: i64,
+    /// The selection from the time of the edit, as `[first, second]`.
+    pub sel: [i64; 2],
+}
+
+/// A single undoable edit.
+/// The data is stored as references to pieces, so undoing even a huge edit does not
+/// require copying any data around.
+pub(super) struct Step {
+    /// Where the edit starts.
+    pub at: i64,
+    /// The pieces that were removed by the edit.
+    pub old: Vec<Piece>,
+    /// The pieces that were inserted by the edit.
+    pub new: Vec<Piece>,
+    pub info: EditInfo,
+}
+impl Step {
+    pub fn old_len(&self) -> i64 {
+        self.old.iter().map(|p| p.len).sum()
+    }
+
+    pub fn new_len(&self) -> i64 {
+        self.new.iter().map(|p| p.len).sum()
+    }
+
+    /// An estimate of the memory kept alive by this step.
+    fn mem(&self) -> usize {
+        let ram = |ps: &[Piece]| -> usize { ps.iter().map(|p| p.mem()).sum() };
+        mem::size_of::<Self>() + ram(&self.old) + ram(&self.new)
+    }
+}
+
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+    /// Memory used by all of the steps.
+    mem: usize,
+    /// Forget the oldest steps to keep the memory usage under this amount.
+    max_mem: usize,
+}
+impl History {
+    pub fn new(max_mem: usize) -> Self {
+        Self {
+            undo: default(),
+            redo: default(),
+            mem: 0,
+            max_mem,
+        }
+    }
+
+    /// Record an edit that was just applied to the given piece table.
+    /// `old` are the pieces that the edit removed, and `new_len` is the length of
+    /// the inserted data.
+    pub(super) fn push(
+        &mut self,
+        table: &PieceTable,
+        at: i64,
+        old: Vec<Piece>,
+        new_len: i64,
+        info: EditInfo,
+    ) {
+        let could_redo = !self.redo.is_empty();
+        for s in self.redo.drain(..) {
+            self.mem -= s.mem();
+        }
+        // Extend the last step if we are typing right after it, or overwriting
+        // what was just typed
+        if let Some(last) = self.undo.back_mut() {
+            let end = last.at + last.new_len();
+            if info.typed
+                && last.info.typed
+                && !could_redo
+                && (last.at..=end).contains(&at)
+                && info.sel_before == last.info.sel_after
+            {
+                // Anything overwritten past the end of the last step comes from
+                // before it, so it is also replaced by the extended step
+                let old_len: i64 = old.iter().map(|p| p.len).sum();
+                let touched = (end - at).min(old_len);
+                let untouched = PieceTable::from_pieces(old).pieces_in(touched..old_len);
+                self.mem -= last.mem();
+                last.old.extend(untouched);
+                last.new = table.pieces_in(last.at..(at + old_len).max(end) - old_len + new_len);
+                last.info.sel_after = info.sel_after;
+                self.mem += last.mem();
+                return;
+            }
+        }
+        let step = Step {
+            at,
+            new: table.pieces_in(at..at + new_len),
+            old,
+            info,
+        };
+        self.mem += step.mem();
+        self.undo.push_back(step);
+        self.trim();
+    }
+
+    /// Forget the oldest steps until the memory usage is under the limit.
+    fn trim(&mut self) {
+        while self.mem > self.max_mem && self.undo.len() > 1 {
+            let s = self.undo.pop_front().unwrap();
+            self.mem -= s.mem();
+        }
+    }
+
+    /// Take the last edit out of the undo history.
+    /// Once undone, it should be handed back through `undone`.
+    pub(super) fn take_undo(&mut self) -> Option<Step> {
+        let s = self.undo.pop_back()?;
+        self.mem -= s.mem();
+        Some(s)
+    }
+
+    pub(super) fn undone(&mut self, step: Step) {
+        self.mem += step.mem();
+        self.redo.push(step);
+    }
+
+    /// Take the last undone edit out of the redo history.
+    /// Once redone, it should be handed back through `redone`.
+    pub(super) fn take_redo(&mut self) -> Option<Step> {
+        let s = self.redo.pop()?;
+        self.mem -= s.mem();
+        Some(s)
+    }
+
+    pub(super) fn redone(&mut self, step: Step) {
+        self.mem += step.mem();
+        self.undo.push_back(step);
+    }
+
+    /// Move any data backed by the given overwritten ranges of the file into RAM, so
+    /// that the steps keep their original contents.
+    pub(super) fn detach(&mut self, overwritten: &[Overwritten]) {
+        self.rebase(|p, out| {
+            p.detach(overwritten, out);
+            true
+        });
+    }
+
+    /// Forget every step, because the buffer was replaced entirely.
+    pub(super) fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.mem = 0;
+    }
+
+    /// Make the steps refer to another file, once the buffer is saved to a new file.
+    /// `map` pushes the pieces that hold the same data as the given piece, or
+    /// returns `false` if the data is gone.
+    /// Steps that cannot be moved over are forgotten, along with the steps that can
+    /// only be reached through them.
+    pub(super) fn rebase(&mut self, mut map: impl FnMut(&Piece, &mut Vec<Piece>) -> bool) {
+        let mut rebase_step = |s: &mut Step| {
+            for ps in [&mut s.old, &mut s.new] {
+                let mut out = Vec::with_capacity(ps.len());
+                for p in ps.iter() {
+                    if !map(p, &mut out) {
+                        return false;
+                    }
+                }
+                *ps = out;
+            }
+            true
+        };
+        // Steps are taken from the back, so the failed step cuts off everything
+        // before it
+        let keep_undo = match self.undo.iter_mut().rposition(|s| !rebase_step(s)) {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        self.undo.drain(..keep_undo);
+        let keep_redo = match self.redo.iter_mut().rposition(|s| !rebase_step(s)) {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        self.redo.drain(..keep_redo);
+        self.mem = self
+            .undo
+            .iter()
+            .chain(self.redo.iter())
+            .map(|s| s.mem())
+            .sum();
+        self.trim();
+    }
+
+    /// The ranges of the file that the steps refer to, sorted and merged.
+    pub(super) fn file_ranges(&self) -> Vec<ops::Range<i64>> {
+        fn collect(p: &Piece, ranges: &mut Vec<ops::Range<i64>>) {
+            match &p.src {
+                Source::File(off) => ranges.push(*off..off + p.len),
+                Source::Ram(..) => {}
+                Source::Replace(r, _) => {
+                    for p in r.input.pieces() {
+                        collect(p, ranges);
+                    }
+                }
+            }
+        }
+        let mut ranges = vec![];
+        for s in self.undo.iter().chain(self.redo.iter()) {
+            for p in s.old.iter().chain(s.new.iter()) {
+                collect(p, &mut ranges);
+            }
+        }
+        ranges.sort_unstable_by_key(|r| r.start);
+        let mut merged: Vec<ops::Range<i64>> = Vec::with_capacity(ranges.len());
+        for r in ranges {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        merged
+    }
+}
@@ -2,7 +2,10 @@ use std::collections::VecDeque;
 
 use crate::prelude::*;
 
-use super::{CharLayout, FilePos, FileRect, LoadedData, LoadedDataGuard, Surroundings};
+use super::{
+    encoding::Encoding, sparse::SparseData, CharLayout, FilePos, FileRect, LoadedData,
+    LoadedDataGuard, Surroundings,
+};
 
 /// There are two diferent "coordinate systems" in a text file:
 /// - Raw byte offset
@@ -28,14 +31,24 @@ pub struct LineMap {
     /// TODO: Set an upper limit on the amount of linemap segments before
     /// dropping small/old segments.
     pub(super) segments: Vec<MappedSegment>,
-    /// If set to another value, it should only increase!
+    /// The size of the buffer.
+    /// Only changes when the buffer is edited.
     pub(super) file_size: i64,
+    /// Set whenever the linemap is edited.
+    /// Any offsets or segment indices held by the manager thread across locks may be
+    /// stale if this flag is set, so any multi-lock operation must abort.
+    pub(super) edited: bool,
+    /// The encoding that the segments were mapped with.
+    /// Changing it requires mapping the whole buffer again.
+    pub(super) encoding: Encoding,
 }
 impl LineMap {
     pub fn new() -> Self {
         Self {
             segments: default(),
             file_size: 0,
+            edited: false,
+            encoding: default(),
         }
     }
 
@@ -92,6 +105,36 @@ impl LineMap {
         Surroundings::Out(prev, self.file_size)
     }
 
+    /// If the start of the buffer is mapped, get the end of the segment that maps it
+    /// and the amount of line breaks before that end.
+    /// Y coordinates within this segment are absolute line numbers.
+    pub fn start_extent(&self) -> Option<(i64, i64)> {
+        let s = self.segments.first().filter(|s| s.start == 0)?;
+        let last = s.anchors.back()?;
+        Some((s.end, last.y(s)))
+    }
+
+    /// The amount of bytes covered by the mapped segments.
+    pub fn mapped_len(&self) -> i64 {
+        self.segments.iter().map(|s| s.end - s.start).sum()
+    }
+
+    /// The average length of a line in bytes, over all of the mapped segments.
+    /// `None` if nothing is mapped yet.
+    pub fn average_line_len(&self) -> Option<f64> {
+        let (mut bytes, mut lines) = (0, 0);
+        for s in self.segments.iter() {
+            if let (Some(first), Some(last)) = (s.anchors.front(), s.anchors.back()) {
+                bytes += s.end - s.start;
+                lines += last.y(s) - first.y(s);
+            }
+        }
+        if bytes == 0 {
+            return None;
+        }
+        Some(bytes as f64 / lines.max(1) as f64)
+    }
+
     /// Maps the given screen file position to an absolute offset that is at or before
     /// the given position.
     /// Returns a base anchor and the nearest anchor before the position.
@@ -109,7 +152,7 @@ impl LineMap {
         }
         let y = base.y(s) + dy;
         let x = base.x_with(s.base_x_relative, is_x_abs) + dx;
-        Some((base, s.locate_lower(y, x)))
+        Some((s.resolve(base), s.resolve(s.locate_lower(y, x))))
     }
 
     /// Maps the given screen file position to an absolute offset that is at or after
@@ -134,7 +177,7 @@ impl LineMap {
         }
         let y = base.y(s) + dy;
         let x = base.x_with(s.base_x_relative, is_x_abs) + dx;
-        Some((base, s.locate_upper(y, x)))
+        Some((s.resolve(base), s.resolve(s.locate_upper(y, x))))
     }
 
     /// If the two offsets are comparable (that is, they reside in the same segment
@@ -150,7 +193,7 @@ impl LineMap {
             // The offsets are in different absoluteness contexts
             return None;
         }
-        Some((base, lo))
+        Some((s1.resolve(base), s1.resolve(lo)))
     }
 
     fn offset_to_base(&self, base_offset: i64) -> Option<(&MappedSegment, Anchor)> {
@@ -207,13 +250,134 @@ impl LineMap {
         }
     }
 
+    /// Update the linemap to reflect that the bytes in `range` were replaced by `ins`,
+    /// shifting everything after the range.
+    ///
+    /// `data` must hold the buffer data from *before* the edit.
+    /// If the data around the edit is loaded, the segment containing the edit is
+    /// updated in place.
+    /// Otherwise, the mapping around the edited range is dropped and left for the
+    /// manager thread to map again.
+    pub fn splice(
+        &mut self,
+        data: &SparseData,
+        layout: &CharLayout,
+        range: ops::Range<i64>,
+        ins: &[u8],
+    ) {
+        let delta = ins.len() as i64 - (range.end - range.start);
+        let mut i = self.find_after(range.start);
+        let crlf = self.splits_crlf(data, range.clone(), ins);
+        let in_place = match self.segments.get_mut(i) {
+            Some(s) if !crlf && s.start <= range.start && range.end <= s.end => {
+                s.splice(data, layout, self.encoding, range.clone(), ins)
+            }
+            _ => false,
+        };
+        if in_place {
+            i += 1;
+        } else {
+            i = self.cut(Self::around(range));
+        }
+        for s in self.segments[i..].iter_mut() {
+            s.shift(layout, delta);
+        }
+        self.file_size += delta;
+        self.edited = true;
+    }
+
+    /// Replace the given range by `len` bytes of data that is not loaded.
+    /// The mappings around the edit are dropped, and the background thread maps them
+    /// again later.
+    pub fn splice_unloaded(&mut self, layout: &CharLayout, range: ops::Range<i64>, len: i64) {
+        let delta = len - (range.end - range.start);
+        let i = self.cut(Self::around(range));
+        for s in self.segments[i..].iter_mut() {
+            s.shift(layout, delta);
+        }
+        self.file_size += delta;
+        self.edited = true;
+    }
+
+    /// Whether an edit might join or split a CRLF line break at either of its ends,
+    /// which changes the layout of the characters around the edit.
+    /// If the data around the edit is not loaded, assumes that it might.
+    fn splits_crlf(&self, data: &SparseData, range: ops::Range<i64>, ins: &[u8]) -> bool {
+        let enc = self.encoding;
+        let first = |b: &[u8]| (!b.is_empty()).then(|| enc.decode(b).0);
+        let last = |b: &[u8]| (!b.is_empty()).then(|| enc.decode_rev(b).0);
+        let is = |c: Option<StdResult<u32, u8>>, want: u32| c.is_none_or(|c| c == Ok(want));
+        let (cr, lf) = (LineMapper::CARRIAGE_RETURN, LineMapper::NEWLINE);
+        let (l, r) = (range.start, range.end);
+        let before = last(data.longest_suffix(l));
+        let after = first(data.longest_prefix(r));
+        let (old_first, old_last) = match l < r {
+            true => (first(data.longest_prefix(l)), last(data.longest_suffix(r))),
+            false => (after, before),
+        };
+        let (new_first, new_last) = match ins.is_empty() {
+            false => (first(ins), last(ins)),
+            true => (after, before),
+        };
+        is(before, cr) && (is(old_first, lf) || is(new_first, lf))
+            || is(after, lf) && (is(old_last, cr) || is(new_last, cr))
+    }
+
+    /// The range to drop the mapping of when editing the given range.
+    /// Includes the characters on either side, since their layout might change if
+    /// they are line breaks.
+    fn around(range: ops::Range<i64>) -> ops::Range<i64> {
+        range.start - 2..range.end + 2
+    }
+
+    /// Drop the mapping of the given range, splitting the segments that overlap it.
+    /// Returns the index of the first segment after the range.
+    fn cut(&mut self, range: ops::Range<i64>) -> usize {
+        let mut i = self.find_after(range.start);
+        while let Some(s) = self.segments.get_mut(i) {
+            if s.start > range.end {
+                break;
+            }
+            let base = s.base_offset;
+            // Anchors at or before the start of the range are kept in a prefix segment
+            let k0 = s
+                .anchors
+                .partition_point(|a| a.offset + base <= range.start);
+            // Anchors at or after the end of the range are kept in a suffix segment
+            let k1 = s.anchors.partition_point(|a| a.offset + base < range.end);
+            let n = s.anchors.len();
+            match (k0 >= 2, n - k1 >= 2) {
+                (true, true) => {
+                    let suffix = s.split_off(k0, k1);
+                    self.segments.insert(i + 1, suffix);
+                    return i + 1;
+                }
+                (true, false) => {
+                    s.truncate_back(k0);
+                    i += 1;
+                }
+                (false, true) => {
+                    s.truncate_front(k1);
+                    return i;
+                }
+                (false, false) => {
+                    self.segments.remove(i);
+                }
+            }
+        }
+        i
+    }
+
     /// Dump the linemap data for debugging.
     pub(super) fn dump_anchors(&self) {
         eprintln!("dumping anchors...");
         println!("{} segments", self.segments.len());
         for seg in self.segments.iter() {
             println!("segment [{}, {})", seg.start, seg.end);
-            println!("  base_x: {}, base_y: {}", seg.base_x_relative, seg.base_y);
+            println!(
+                "  base_offset: {}, base_x: {}, base_y: {}",
+                seg.base_offset, seg.base_x_relative, seg.base_y
+            );
             println!(
                 "  rel_width: {}, widest_line: {}",
                 seg.rel_width, seg.widest_line
@@ -226,7 +390,8 @@ impl LineMap {
             println!("  {} anchors:", seg.anchors.len());
             for anchor in seg.anchors.iter() {
                 println!(
-                    "    off: {}, dy: {} ({}), dx: {} ({})",
+                    "    off: {} ({}), dy: {} ({}), dx: {} ({})",
+                    anchor.off(seg),
                     anchor.offset,
                     anchor.y(seg),
                     anchor.y_offset,
@@ -274,10 +439,13 @@ pub struct LineMapper {
     pub(super) bytes_per_anchor: usize,
     pub(super) migrate_batch_size: usize,
     pub(super) layout: CharLayout,
+    /// A copy of the encoding of the linemap, updated by the manager thread.
+    pub(super) encoding: Encoding,
 }
 impl LineMapper {
     pub const REPLACEMENT_CHAR: u32 = char::REPLACEMENT_CHARACTER as u32;
     pub const NEWLINE: u32 = '\n' as u32;
+    pub const CARRIAGE_RETURN: u32 = '\r' as u32;
 
     pub fn new(
         layout: CharLayout,
@@ -295,39 +463,44 @@ impl LineMapper {
             layout,
             bytes_per_anchor,
             migrate_batch_size,
+            encoding: default(),
         }
     }
 
-    /// Note: A prefix and/or suffix of at most length 3 may be discarded from the given
-    /// segment to align with UTF-8 character boundaries.
+    /// Note: A prefix and/or suffix of a few bytes may be discarded from the given
+    /// segment to align with character boundaries.
     /// They will not be discarded on the edges if the `rigid` flags are set.
+    ///
+    /// `buf` holds the segment at `range`, along with whatever is known of the data
+    /// around it, and starts at buffer offset `base`.
     fn create_segment(
         &self,
-        mut offset: i64,
-        mut data: &[u8],
+        base: i64,
+        buf: &[u8],
+        range: ops::Range<usize>,
         rigid_left: bool,
         rigid_right: bool,
     ) -> MappedSegment {
-        // Try our best to align the beginning and end of the segment to UTF-8 boundaries
-        // Always works for valid UTF-8
+        // Try our best to align the beginning and end of the segment to character
+        // boundaries
+        // Always works for valid text
+        // CRLF line breaks are single characters, so they are never split either,
+        // assuming the worst if the data around the segment is unknown
+        let enc = self.encoding;
+        let (mut l, mut r) = (range.start, range.end);
         if !rigid_left {
-            for _ in 0..3.min(data.len()) {
-                if is_utf8_cont(data[0]) {
-                    offset += 1;
-                    data = &data[1..];
-                } else {
-                    break;
-                }
+            l += enc.skip_partial(base + l as i64, &buf[l..r]).min(r - l);
+            if starts_with_lf(enc, &buf[l..r]) && (l == 0 || ends_with_cr(enc, &buf[..l])) {
+                l += enc.decode(&buf[l..r]).1;
             }
         }
         if !rigid_right {
-            for i in 0..3.min(data.len()) {
-                if utf8_seq_len(data[data.len() - i - 1]) > i + 1 {
-                    data = &data[..data.len() - i - 1];
-                    break;
-                }
+            r -= enc.cut_partial(base + r as i64, &buf[l..r]);
+            if ends_with_cr(enc, &buf[l..r]) && (r == buf.len() || starts_with_lf(enc, &buf[r..])) {
+                r -= enc.decode_rev(&buf[l..r]).1;
             }
         }
+        let (offset, data) = (base + l as i64, &buf[l..r]);
 
         let end = offset + data.len() as i64;
         let rand = ((offset + 143) as u32).wrapping_mul(0x9e3779b9);
@@ -335,11 +508,13 @@ impl LineMapper {
             MappedSegment {
                 start: offset,
                 end,
+                base_offset: offset,
                 base_y: (rand & 0xFFFF) as i64,
                 base_x_relative: (rand >> 16) as f64,
                 first_absolute: 0,
                 widest_line: 0.,
                 rel_width: 0.,
+                tab_phase: 0.,
                 anchors: VecDeque::with_capacity(data.len() / self.bytes_per_anchor + 2),
             }
         };
@@ -348,8 +523,15 @@ impl LineMapper {
         let mut cur_y = -seg.base_y;
         let mut abs_x = offset == 0;
         let mut cur_x = if abs_x { 0. } else { -seg.base_x_relative };
+        // The relative line is laid out as if it started at a tab stop
+        let base_x = seg.base_x_relative;
+        let col = |cur_x: f64, abs_x: bool| if abs_x { cur_x } else { cur_x + base_x };
+        // The position of the last anchor, if it is in the current line, and of the
+        // first tab after it
+        let mut anchor_col = None;
+        let mut tab = f64::NAN;
         while i < data.len() {
-            let (c, adv) = decode_utf8(&data[i..]);
+            let (c, adv) = self.encoding.decode_text(&data[i..]);
             let place_anchor = anchor_acc >= self.bytes_per_anchor;
             let c_i = i;
             let c = c.unwrap_or(Self::REPLACEMENT_CHAR);
@@ -359,13 +541,16 @@ impl LineMapper {
             if place_anchor {
                 anchor_acc -= self.bytes_per_anchor;
                 seg.anchors.push_back(Anchor {
-                    offset: offset + c_i as i64,
+                    offset: c_i as i64,
                     y_offset: cur_y,
                     x_offset: cur_x,
+                    tab,
                 });
                 if !abs_x {
                     seg.first_absolute += 1;
                 }
+                anchor_col = Some(col(cur_x, abs_x));
+                tab = f64::NAN;
             }
             match c {
                 Self::NEWLINE => {
@@ -378,9 +563,14 @@ impl LineMapper {
                     cur_x = 0.;
                     cur_y += 1;
                     abs_x = true;
+                    anchor_col = None;
                 }
                 c => {
-                    cur_x += self.layout.advance_for(c);
+                    let x = col(cur_x, abs_x);
+                    if let (CharLayout::TAB, Some(a), true) = (c, anchor_col, tab.is_nan()) {
+                        tab = x - a;
+                    }
+                    cur_x += self.layout.advance_at(c, x);
                 }
             }
         }
@@ -391,9 +581,10 @@ impl LineMapper {
         }
         if anchor_acc != 0 {
             seg.anchors.push_back(Anchor {
-                offset: end,
+                offset: data.len() as i64,
                 y_offset: cur_y,
                 x_offset: cur_x,
+                tab,
             });
             if !abs_x {
                 seg.first_absolute += 1;
@@ -405,12 +596,43 @@ impl LineMapper {
     /// Merge two exactly adjacent segments.
     fn merge_segments(&self, linemap: LineMapHandle, l_idx: usize) {
         lock_linemap!(linemap, lmap_store, lmap);
+        if lmap.edited {
+            return;
+        }
         let into_left =
             lmap.segments[l_idx].anchors.len() >= lmap.segments[l_idx + 1].anchors.len();
         fn get_two(lmap: &mut LineMap, l: usize) -> (&mut MappedSegment, &mut MappedSegment) {
             let (a, b) = lmap.segments.split_at_mut(l + 1);
             (&mut a[l], &mut b[0])
         }
+        // The relative-X prefix of the right segment, if any, continues the last line
+        // of the left segment, so the text after its first tab moves to other tab
+        // stops
+        // Slowly look for that tab first
+        let mut tab = None;
+        let mut i = 1;
+        loop {
+            let (_, r) = get_two(lmap, l_idx);
+            let j = (i + self.migrate_batch_size).min(r.first_absolute + 1);
+            if i >= j {
+                break;
+            }
+            tab = r.find_rel_tab(i..j);
+            if tab.is_some() {
+                break;
+            }
+            i = j;
+            lock_linemap!(linemap, lmap_store, lmap => bump);
+            if lmap.edited {
+                return;
+            }
+        }
+        let tab = {
+            let (l, r) = get_two(lmap, l_idx);
+            let end_col = l.col(*l.anchors.back().unwrap());
+            tab.map(|(k, t)| (k, tab_shift(&self.layout, r.tab_phase, end_col, t)))
+        };
+        let rel_shift = tab.map_or(0., |(_, shift)| shift);
         {
             // NOTE: The maximum width of the segments will temporarily be wrong, but
             // doing this correctly is way too expensive with the current implementation
@@ -420,10 +642,10 @@ impl LineMapper {
             if l.first_absolute < l.anchors.len() {
                 // Factor the absolute line that is created by tacking the relative
                 // line onto an absolute line
-                let w = l.anchors.back().unwrap().x_abs() + r.rel_width;
+                let w = l.anchors.back().unwrap().x_abs() + r.rel_width + rel_shift;
                 wide = wide.max(w);
             } else {
-                l.rel_width += r.rel_width;
+                l.rel_width += r.rel_width + rel_shift;
             }
             if into_left {
                 l.widest_line = wide;
@@ -433,29 +655,55 @@ impl LineMapper {
             r.rel_width = l.rel_width;
         }
         if !into_left {
-            // There is a very special case when merging a segment into the right
-            // If the left segment ends with absolute X coordinates but the right
-            // segment has a relative-X prefix, we *must* update the entire prefix
-            // to be absolute before merging
-            let (mut lsrc, mut rdst) = get_two(lmap, l_idx);
+            let (lsrc, rdst) = get_two(lmap, l_idx);
+            let lsrc_end_anchor = *lsrc.anchors.back().unwrap();
+            let end_col = lsrc.col(lsrc_end_anchor);
+            let tab_shift_at = |i: usize| match tab {
+                Some((k, shift)) if i >= k => shift,
+                _ => 0.,
+            };
+            rdst.tab_phase = end_col;
             if lsrc.first_absolute < lsrc.anchors.len() {
-                let lsrc_end_anchor = *lsrc.anchors.back().unwrap();
+                // There is a very special case when merging a segment into the right
+                // If the left segment ends with absolute X coordinates but the right
+                // segment has a relative-X prefix, we *must* update the entire prefix
+                // to be absolute before merging
                 let end_x = lsrc_end_anchor.x_abs();
                 // Slowly bring the `first_absolute` line to the left
-                while rdst.first_absolute > 0 {
+                loop {
+                    let (_, rdst) = get_two(lmap, l_idx);
+                    if rdst.first_absolute == 0 {
+                        break;
+                    }
                     let l = rdst.first_absolute.saturating_sub(self.migrate_batch_size);
                     for i in l..rdst.first_absolute {
                         let a = &mut rdst.anchors[i];
-                        a.x_offset = a.x_offset + (rdst.base_x_relative + end_x);
+                        a.x_offset = a.x_offset + (rdst.base_x_relative + end_x) + tab_shift_at(i);
                     }
                     rdst.first_absolute = l;
                     // Keep bumping the linemap to not block the main thread
-                    drop(lsrc);
-                    drop(rdst);
                     lock_linemap!(linemap, lmap_store, lmap => bump);
-                    let (l, r) = get_two(lmap, l_idx);
-                    lsrc = l;
-                    rdst = r;
+                    if lmap.edited {
+                        return;
+                    }
+                }
+            } else if let Some((k, shift)) = tab {
+                // The prefix stays relative, but the anchors after the tab still move
+                let mut i = k;
+                loop {
+                    let (_, rdst) = get_two(lmap, l_idx);
+                    let j = (i + self.migrate_batch_size).min(rdst.first_absolute);
+                    if i >= j {
+                        break;
+                    }
+                    for a in rdst.anchors.range_mut(i..j) {
+                        a.x_offset += shift;
+                    }
+                    i = j;
+                    lock_linemap!(linemap, lmap_store, lmap => bump);
+                    if lmap.edited {
+                        return;
+                    }
                 }
             }
         }
@@ -476,6 +724,12 @@ impl LineMapper {
                 let dst_end_anchor = *ldst.anchors.back().unwrap();
                 let end_y = dst_end_anchor.y(ldst);
                 let end_x = dst_end_anchor.x(ldst);
+                // The relative-X anchors after the first tab in this batch move to
+                // other tab stops
+                let end_col = ldst.col(dst_end_anchor);
+                let tab = rsrc
+                    .find_rel_tab(0..batch_size + 1)
+                    .map(|(k, t)| (k, tab_shift(&self.layout, rsrc.tab_phase, end_col, t)));
                 ldst.anchors.pop_back();
                 // Map the absolute index from the right segment to the left segment
                 let og_rsrc_first_absolute = rsrc.first_absolute;
@@ -509,7 +763,16 @@ impl LineMapper {
                         // be duplicated
                         rsrc.anchors.pop_front();
                     };
+                    if i == 0 {
+                        // The tab before the end anchor of the left segment
+                        a.tab = dst_end_anchor.tab;
+                    }
+                    let shift = match tab {
+                        Some((k, shift)) if i >= k => shift,
+                        _ => 0.,
+                    };
                     // Convert between coordinate bases
+                    a.offset += rsrc.base_offset - ldst.base_offset;
                     a.y_offset = a.y_offset + (rsrc.base_y - ldst.base_y + end_y);
                     // Whether this anchor will be absolute in the destination segment
                     let dst_abs = og_ldst_len - 1 + i >= ldst.first_absolute;
@@ -519,12 +782,13 @@ impl LineMapper {
                         (true, true) => {} // No conversion
                         (true, false) => {
                             // Remove the base offset, then nudge by the end x
-                            a.x_offset = a.x_offset + (rsrc.base_x_relative + end_x);
+                            a.x_offset = a.x_offset + (rsrc.base_x_relative + end_x) + shift;
                         }
                         (false, false) => {
                             // Convert between bases and nudge by the end x
-                            a.x_offset =
-                                a.x_offset + (rsrc.base_x_relative - ldst.base_x_relative + end_x);
+                            a.x_offset = a.x_offset
+                                + (rsrc.base_x_relative - ldst.base_x_relative + end_x)
+                                + shift;
                         }
                         (false, true) => {
                             // Should never happen, because an absolute anchor
@@ -537,10 +801,11 @@ impl LineMapper {
                 // Shift the right segment down by whatever was removed
                 rsrc.base_y += shift_y;
                 rsrc.base_x_relative += shift_x;
+                rsrc.tab_phase -= shift_x;
                 // Keep the end and start offsets in sync with the endpoint anchors
-                let src_start_anchor = rsrc.anchors.front().unwrap();
-                ldst.end = src_start_anchor.offset;
-                rsrc.start = src_start_anchor.offset;
+                let src_start_anchor = rsrc.anchors.front().unwrap().off(rsrc);
+                ldst.end = src_start_anchor;
+                rsrc.start = src_start_anchor;
             } else {
                 // Move anchors FROM THE LEFT segment TO THE RIGHT segment
                 let (lsrc, rdst) = get_two(lmap, l_idx);
@@ -552,6 +817,7 @@ impl LineMapper {
                 // the start anchor of the right segment
                 let og_lsrc_len = lsrc.anchors.len();
                 let lsrc_cap_anchor = lsrc.anchors.pop_back().unwrap();
+                rdst.anchors.front_mut().unwrap().tab = lsrc_cap_anchor.tab;
                 // Get the anchor that will be the end of the left segment/start of the right segment
                 let src_end_idx = og_lsrc_len - 1 - batch_size;
                 let src_end_anchor = lsrc.anchors[src_end_idx];
@@ -570,11 +836,18 @@ impl LineMapper {
                         .first_absolute
                         .min(lsrc.first_absolute.max(src_end_idx) - src_end_idx);
                 }
-                lsrc.first_absolute = lsrc.first_absolute.min(src_end_idx);
+                // `src_end_idx` is the last anchor left in the left segment, so if it was
+                // relative the whole segment stays relative, which is marked by
+                // `first_absolute` being the anchor count
+                lsrc.first_absolute = lsrc.first_absolute.min(src_end_idx + 1);
                 // Shift all Y coordinates in the right segment by the end Y of the left segment
                 rdst.base_y += shift_y;
                 // Shift all relative X coordinates in the right segment by the end of the left segment
                 rdst.base_x_relative += shift_x;
+                if src_end_idx < og_lsrc_first_absolute {
+                    // The relative line now starts where `src_end_anchor` is
+                    rdst.tab_phase = lsrc.col_with(src_end_anchor, false);
+                }
                 for i in (0..batch_size).rev() {
                     let mut a = *lsrc.anchors.back().unwrap();
                     if i != 0 {
@@ -595,18 +868,23 @@ impl LineMapper {
                         }
                         true => {} // No conversion
                     }
+                    a.offset += lsrc.base_offset - rdst.base_offset;
                     a.y_offset = a.y_offset + (-src_end_anchor.y_offset - rdst.base_y);
                     rdst.anchors.push_front(a);
                 }
                 // Keep the end and start offsets in sync with the endpoint anchors
-                let rdst_start_anchor = rdst.anchors.front().unwrap();
-                lsrc.end = rdst_start_anchor.offset;
-                rdst.start = rdst_start_anchor.offset;
+                let rdst_start_anchor = rdst.anchors.front().unwrap().off(rdst);
+                lsrc.end = rdst_start_anchor;
+                rdst.start = rdst_start_anchor;
             }
             // Bump the linemap mutex to keep latency low
             // Safe to do because at this point the segments are in
             // a valid state
             lock_linemap!(linemap, lmap_store, lmap => bump);
+            if lmap.edited {
+                // The segments were left touching, they will be merged later
+                return;
+            }
         }
         // Finally, remove the empty source segment
         let empty = lmap.segments.remove(l_idx + if into_left { 1 } else { 0 });
@@ -628,8 +906,8 @@ impl LineMapper {
                 if s.start >= s.end {
                     touching = true;
                 }
-                assert_eq!(s.start, s.anchors.front().unwrap().offset);
-                assert_eq!(s.end, s.anchors.back().unwrap().offset);
+                assert_eq!(s.start, s.anchors.front().unwrap().off(s));
+                assert_eq!(s.end, s.anchors.back().unwrap().off(s));
                 assert!(s.first_absolute <= s.anchors.len());
                 assert_eq!(s.anchors.front().unwrap().y_offset + s.base_y, 0);
                 for i in 1..s.anchors.len() {
@@ -665,6 +943,9 @@ impl LineMapper {
         }
         // check if this segment merges into a segment to the left
         lock_linemap!(linemap, lmap_store, lmap);
+        if lmap.edited {
+            return;
+        }
         let mut i = lmap.find_before(seg.start);
         let mut merge_left = false;
         if i == lmap.segments.len() {
@@ -706,50 +987,90 @@ impl LineMapper {
         }
     }
 
+    /// Merge all touching segments.
+    /// Edits can leave segments touching, so this should be called periodically.
+    pub fn merge_touching(&self, linemap: LineMapHandle) {
+        let mut i = 0;
+        loop {
+            {
+                lock_linemap!(linemap, lmap);
+                // Segment indices are looked up from scratch on every lock
+                lmap.edited = false;
+                loop {
+                    if i + 1 >= lmap.segments.len() {
+                        return;
+                    }
+                    if lmap.segments[i].end >= lmap.segments[i + 1].start {
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            self.merge_segments(linemap, i);
+        }
+    }
+
     /// Process a piece of data, adding any missing line mappings from it.
     ///
-    /// Note: A prefix and/or suffix of at most length 3 may be discarded from the given
-    /// segment to align with UTF-8 character boundaries.
-    pub fn process_data<'a>(&self, linemap: LineMapHandle, offset: i64, mut data: &[u8]) {
+    /// Note: A prefix and/or suffix of a few bytes may be discarded from the given
+    /// segment to align with character boundaries.
+    ///
+    /// `buf` holds the piece at `range`, along with a couple of bytes of the data
+    /// around it if there is any, and starts at buffer offset `base`.
+    /// The surrounding data tells whether the piece splits a CRLF at its edges.
+    pub fn process_data<'a>(
+        &self,
+        linemap: LineMapHandle,
+        base: i64,
+        buf: &[u8],
+        range: ops::Range<usize>,
+    ) {
         // iterate over the "holes" that are contained in the received range
-        let end = offset + data.len() as i64;
-        let mut i;
+        let offset = base + range.start as i64;
+        let end = base + range.end as i64;
         let mut l;
         let mut rigid_left = offset == 0;
         {
             lock_linemap!(linemap, lmap);
-            i = lmap.find_after(offset);
+            if lmap.edited {
+                return;
+            }
             l = offset;
-            if let Some(s) = lmap.segments.get(i) {
+            if let Some(s) = lmap.segments.get(lmap.find_after(offset)) {
                 if s.start <= offset {
                     l = s.end.min(end);
-                    data = &data[(l - offset) as usize..];
-                    i += 1;
                     rigid_left = true;
                 }
             }
         }
+        if l >= end {
+            return;
+        }
         loop {
             // we have a hole from `l` to `r`
             let (r, next_l, rigid_right) = {
                 lock_linemap!(linemap, lmap);
+                if lmap.edited {
+                    return;
+                }
+                // look up the next segment every time, because inserting a segment
+                // may merge it with its neighbours and shift the indices around
                 lmap.segments
-                    .get(i)
+                    .get(lmap.find_after(l + 1))
                     .map(|s| (s.start.min(end), s.end.min(end), s.start <= end))
                     .unwrap_or((end, end, end == lmap.file_size))
             };
             // process data first without locking the linemap
-            let seg = self.create_segment(l, &data[..(r - l) as usize], rigid_left, rigid_right);
+            let hole = (l - base) as usize..(r - base) as usize;
+            let seg = self.create_segment(base, buf, hole, rigid_left, rigid_right);
             rigid_left = true;
             // insert the data into the linemap
             self.insert_segment(linemap, seg);
             // advance to the next hole
-            if r >= end {
+            if next_l >= end {
                 break;
             } else {
-                data = &data[(next_l - l) as usize..];
                 l = next_l;
-                i += 1;
             }
         }
     }
@@ -765,6 +1086,10 @@ pub struct MappedSegment {
     /// Exclusive end of this segment in absolute bytes.
     /// This information is redundant with the offset of the last anchor.
     pub(super) end: i64,
+    /// Base byte offset.
+    /// The offsets in anchors must be added with this value to have any meaning.
+    /// This allows to shift the entire segment quickly when the buffer is edited.
+    pub(super) base_offset: i64,
     /// The index of the first anchor that has an absolute X coordinate.
     /// If there are no absolute anchors, it is the amount of anchors.
     pub(super) first_absolute: usize,
@@ -787,11 +1112,321 @@ pub struct MappedSegment {
     /// If there is no relative line, this is zero.
     /// This value may be completely wrong if segments are currently being merged!
     pub(super) rel_width: f64,
+    /// The X position from the start of its line that the relative line was laid out
+    /// from, since tab stops depend on it.
+    /// Once the start of the line is known, the anchors after the first tab in the
+    /// relative line move by `tab_shift` more than the anchors before it.
+    pub(super) tab_phase: f64,
     /// A set of anchor points, representing known reference points with X and Y coordinates.
     /// There is always an anchor at the start of the segment and at the end of the segment.
     pub(super) anchors: VecDeque<Anchor>,
 }
 impl MappedSegment {
+    /// Move the entire segment by the given amount of bytes.
+    fn shift(&mut self, layout: &CharLayout, delta: i64) {
+        self.start += delta;
+        self.end += delta;
+        self.base_offset += delta;
+        if self.start == 0 && self.first_absolute > 0 {
+            // The segment now starts at the start of the file, so its first line is
+            // now absolute
+            let (k, shift) = match self.find_rel_tab(0..self.first_absolute) {
+                Some((k, t)) => (k, tab_shift(layout, self.tab_phase, 0., t)),
+                None => (self.first_absolute, 0.),
+            };
+            for (i, a) in self.anchors.range_mut(..self.first_absolute).enumerate() {
+                a.x_offset += self.base_x_relative + if i >= k { shift } else { 0. };
+            }
+            self.widest_line = self.widest_line.max(self.rel_width + shift);
+            self.first_absolute = 0;
+            self.base_x_relative = 0.;
+            self.rel_width = 0.;
+            self.tab_phase = 0.;
+        }
+    }
+
+    /// Keep only the first `k` anchors.
+    fn truncate_back(&mut self, k: usize) {
+        self.anchors.truncate(k);
+        self.first_absolute = self.first_absolute.min(k);
+        self.end = self.anchors.back().unwrap().off(self);
+    }
+
+    /// Remove the first `k` anchors.
+    fn truncate_front(&mut self, k: usize) {
+        self.anchors.drain(..k);
+        self.first_absolute = self.first_absolute.saturating_sub(k);
+        self.rebase_front();
+    }
+
+    /// Split this segment in two, keeping `anchors[..k0]` in this segment and
+    /// returning a new segment with `anchors[k1..]`.
+    /// Only moves the smaller half around.
+    fn split_off(&mut self, k0: usize, k1: usize) -> MappedSegment {
+        let n = self.anchors.len();
+        let tail = if n - k1 <= k0 {
+            let tail = self.anchors.range(k1..).copied().collect();
+            self.anchors.truncate(k0);
+            tail
+        } else {
+            let head = self.anchors.range(..k0).copied().collect();
+            self.anchors.drain(..k1);
+            mem::replace(&mut self.anchors, head)
+        };
+        let mut tail = MappedSegment {
+            start: 0,
+            end: 0,
+            base_offset: self.base_offset,
+            first_absolute: self.first_absolute.saturating_sub(k1),
+            base_y: self.base_y,
+            base_x_relative: self.base_x_relative,
+            widest_line: self.widest_line,
+            rel_width: self.rel_width,
+            tab_phase: self.tab_phase,
+            anchors: tail,
+        };
+        tail.rebase_front();
+        self.truncate_back(k0);
+        tail
+    }
+
+    /// Make the first anchor the start of the segment, after a prefix of anchors has
+    /// been removed.
+    /// Because the start of its line is no longer mapped, the X coordinates of the
+    /// first line become relative to the new start.
+    fn rebase_front(&mut self) {
+        let front = *self.anchors.front().unwrap();
+        let front_x = front.x_with(self.base_x_relative, self.first_absolute == 0);
+        self.tab_phase = self.col_with(front, self.first_absolute == 0);
+        self.anchors[0].tab = f64::NAN;
+        let mut n = 0;
+        for (i, a) in self.anchors.iter_mut().enumerate() {
+            if a.y_offset != front.y_offset {
+                break;
+            }
+            a.x_offset = a.x_with(self.base_x_relative, i >= self.first_absolute) - front_x;
+            n += 1;
+        }
+        // The first line is at most as wide as the widest line in the segment
+        let line_w = self.widest_line.max(self.rel_width) - front_x;
+        self.first_absolute = n;
+        self.base_x_relative = 0.;
+        self.rel_width = line_w.max(self.anchors[n - 1].x_offset);
+        self.base_y = -front.y_offset;
+        self.start = front.off(self);
+        self.end = self.anchors.back().unwrap().off(self);
+    }
+
+    /// Update this segment in place to reflect that the bytes in `range` were replaced
+    /// by `ins`, shifting the anchors after the range.
+    ///
+    /// Requires the data between the edit and the surrounding anchors to be loaded.
+    /// Returns `false` and leaves the segment untouched if it cannot be updated.
+    fn splice(
+        &mut self,
+        data: &SparseData,
+        layout: &CharLayout,
+        enc: Encoding,
+        range: ops::Range<i64>,
+        ins: &[u8],
+    ) -> bool {
+        let (l, r) = (range.start, range.end);
+        if l == r && ins.is_empty() {
+            return true;
+        }
+        if l == self.start && r == self.end && ins.is_empty() {
+            // The entire segment is deleted
+            return false;
+        }
+        let base = self.base_offset;
+        // Anchors at or before the start of the edit stay where they are
+        let a0 = self.anchors.partition_point(|a| a.offset + base <= l) - 1;
+        // Anchors after the edit are shifted
+        let mut a1 = self
+            .anchors
+            .partition_point(|a| a.offset + base < r.max(l + 1));
+        let n = self.anchors.len();
+
+        // Measure the position of the start of the edit from the previous anchor
+        // Also find the first tab after the anchor in its line, relative to the anchor
+        let (yl0, col_a0) = (self.anchors[a0].y(self), self.col(self.anchors[a0]));
+        let mut tab = f64::NAN;
+        let (yl, xl, rel_l) = {
+            let a = self.anchors[a0];
+            let (mut y, mut x, mut rel) = (a.y(self), col_a0, !self.is_x_absolute(a));
+            let mut off = a.off(self);
+            let mut buf = data.longest_prefix(off);
+            while off < l {
+                if buf.is_empty() {
+                    return false;
+                }
+                let (c, adv) = enc.decode_text(buf);
+                match c.unwrap_or(LineMapper::REPLACEMENT_CHAR) {
+                    LineMapper::NEWLINE => {
+                        y += 1;
+                        x = 0.;
+                        rel = false;
+                    }
+                    c => {
+                        if c == CharLayout::TAB && y == yl0 && tab.is_nan() {
+                            tab = x - col_a0;
+                        }
+                        x += layout.advance_at(c, x);
+                    }
+                }
+                buf = &buf[adv..];
+                off += adv as i64;
+            }
+            if off != l {
+                return false;
+            }
+            (y, x, rel)
+        };
+        // The X positions of relative lines are kept without the phase
+        let phase = |rel: bool| if rel { self.tab_phase } else { 0. };
+
+        // Figure out the position of the end of the edit after the edit
+        let ins_m = measure(layout, enc, ins, xl);
+        let y_new = yl + ins_m.nl;
+        let x_new = ins_m.last;
+        let rel_new = rel_l && ins_m.nl == 0;
+        if tab.is_nan() && yl == yl0 {
+            tab = ins_m.tab - col_a0;
+        }
+
+        // Lay out the text between the end of the edit and the next anchor
+        // The X position is only known if the end of the edit is in the same line as
+        // the next anchor, in which case the anchors after it in the line move along
+        let (yr, right, mut line_w) = match self.anchors.get(a1) {
+            Some(&a) => {
+                let len = (a.off(self) - r) as usize;
+                let buf = data.longest_prefix(r);
+                if buf.len() < len {
+                    return false;
+                }
+                let m = measure(layout, enc, &buf[..len], x_new);
+                if tab.is_nan() && y_new == yl0 {
+                    tab = m.tab - col_a0;
+                }
+                if m.nl == 0 {
+                    // Also keep an upper bound of the width of the line after the
+                    // anchor
+                    let tail = (self.widest_line.max(self.rel_width) - a.x(self)).max(0.);
+                    (
+                        a.y(self),
+                        Some((self.col(a), m.last - self.col(a))),
+                        m.last + tail,
+                    )
+                } else {
+                    (a.y(self) - m.nl, None, m.first)
+                }
+            }
+            None => (yl, None, x_new),
+        };
+
+        // Remove the anchors inside the edited range
+        // If an anchor marks the end of a deletion and another one marks the start,
+        // they now mark the same offset, so remove one of them
+        let dup = ins.is_empty() && a1 < n && self.anchors[a0].off(self) == l && {
+            self.anchors[a1].off(self) == r
+        };
+        let drop_to = if dup { a1 + 1 } else { a1 };
+        let mut first_absolute = self.first_absolute;
+        if first_absolute > a0 {
+            first_absolute = (first_absolute.saturating_sub(drop_to - a0 - 1)).max(a0 + 1);
+        }
+        self.anchors.drain(a0 + 1..drop_to);
+        a1 = a0 + 1;
+        let n = self.anchors.len();
+
+        if a1 < n {
+            if !dup {
+                self.anchors[a1].tab = tab;
+            }
+            // Fix up the X coordinates of the anchors in the same line as the end of
+            // the edit
+            // They move along with the end of the edit up to the first tab, and from
+            // then on they move from tab stop to tab stop
+            let mut same_line = 0;
+            if let Some((mut prev_col, mut shift)) = right {
+                let d = shift;
+                for i in a1..=n {
+                    let a = self.anchors.get(i).copied();
+                    let col = a.map(|a| self.col_with(a, i >= first_absolute));
+                    if let Some(a) = a.filter(|a| !a.tab.is_nan() && (dup || i > a1)) {
+                        let t = prev_col + a.tab;
+                        shift = layout.tab_stop(t + shift) - layout.tab_stop(t);
+                    }
+                    match (a, col) {
+                        (Some(a), Some(col)) if a.y(self) == yr => {
+                            let x = col + shift - phase(rel_new);
+                            self.anchors[i].x_offset =
+                                if rel_new { x - self.base_x_relative } else { x };
+                            prev_col = col;
+                            same_line += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                // The rest of the line moves like the tab after the last anchor in it
+                line_w += shift - d;
+            }
+            if first_absolute > a0 {
+                first_absolute = a1 + if rel_new { same_line } else { 0 };
+            }
+            // Shift the offsets and Y coordinates of the anchors after the edit
+            // Equivalently, shift the entire segment and move the anchors before the
+            // edit back, whichever is cheaper
+            let (dy, delta) = (y_new - yr, ins.len() as i64 - (r - l));
+            if n - a1 <= a1 {
+                for a in self.anchors.range_mut(a1..) {
+                    a.offset += delta;
+                    a.y_offset += dy;
+                }
+            } else {
+                for a in self.anchors.range_mut(..a1) {
+                    a.offset -= delta;
+                    a.y_offset -= dy;
+                }
+                self.base_offset += delta;
+                self.base_y += dy;
+            }
+        } else if !ins.is_empty() {
+            // The edit is at the end of the segment, so extend it
+            let x = x_new - phase(rel_new);
+            self.anchors.push_back(Anchor {
+                offset: l + ins.len() as i64 - self.base_offset,
+                y_offset: y_new - self.base_y,
+                x_offset: if rel_new { x - self.base_x_relative } else { x },
+                tab,
+            });
+            if first_absolute > a0 && !rel_new {
+                first_absolute = a1;
+            } else if first_absolute > a0 {
+                first_absolute = a1 + 1;
+            }
+        }
+        self.first_absolute = first_absolute;
+        self.end = self.anchors.back().unwrap().off(self);
+
+        // Update the line widths, erring on the side of overestimating them
+        if rel_new {
+            self.rel_width = self.rel_width.max(line_w - self.tab_phase);
+        } else {
+            self.widest_line = self.widest_line.max(line_w);
+            if ins_m.nl > 0 {
+                // The line at the start of the edit now ends within the inserted data
+                self.widest_line = self.widest_line.max(ins_m.widest);
+                if rel_l {
+                    self.rel_width = ins_m.first - self.tab_phase;
+                } else {
+                    self.widest_line = self.widest_line.max(ins_m.first);
+                }
+            }
+        }
+        true
+    }
+
     /// Check if the given anchor has an absolute X coordinate.
     fn is_x_absolute(&self, anchor: Anchor) -> bool {
         match self.anchors.get(self.first_absolute) {
@@ -800,8 +1435,48 @@ impl MappedSegment {
         }
     }
 
+    /// Get the X position of the given anchor from the start of its line.
+    /// Relative anchors assume that the line starts at `tab_phase`.
+    fn col(&self, anchor: Anchor) -> f64 {
+        self.col_with(anchor, self.is_x_absolute(anchor))
+    }
+
+    fn col_with(&self, anchor: Anchor, is_abs: bool) -> f64 {
+        match is_abs {
+            true => anchor.x_offset,
+            false => anchor.x_offset + self.base_x_relative + self.tab_phase,
+        }
+    }
+
+    /// Find the first tab of the relative line that lies between the anchors in
+    /// `range`.
+    /// The tab may also be after the last relative anchor, in which case it is found
+    /// through the anchor after it.
+    /// Returns the index of the first anchor after it, and its relative X position.
+    fn find_rel_tab(&self, range: ops::Range<usize>) -> Option<(usize, f64)> {
+        let end = (self.first_absolute + 1).min(self.anchors.len());
+        (range.start.max(1)..range.end.min(end))
+            .find(|&i| !self.anchors[i].tab.is_nan())
+            .map(|i| {
+                let prev = self.anchors[i - 1];
+                (
+                    i,
+                    prev.x_offset + self.base_x_relative + self.anchors[i].tab,
+                )
+            })
+    }
+
+    /// Convert a raw anchor into an anchor with an absolute byte offset and X
+    /// position, suitable to be handed out of the linemap.
+    fn resolve(&self, mut anchor: Anchor) -> Anchor {
+        anchor.x_offset = self.col(anchor);
+        anchor.offset += self.base_offset;
+        anchor
+    }
+
     /// Find the last anchor before or at the given offset.
     fn find_lower(&self, offset: i64) -> Option<Anchor> {
+        let offset = offset - self.base_offset;
         match self.anchors.partition_point(|a| a.offset <= offset) {
             0 => None,
             i => Some(self.anchors[i - 1]),
@@ -810,6 +1485,7 @@ impl MappedSegment {
 
     /// Find the first anchor at or after the given offset.
     fn _find_upper(&self, offset: i64) -> Option<Anchor> {
+        let offset = offset - self.base_offset;
         self.anchors
             .get(self.anchors.partition_point(|a| a.offset < offset))
             .copied()
@@ -824,7 +1500,7 @@ impl MappedSegment {
             .anchors
             .get(self.first_absolute)
             .map(|a| a.offset)
-            .unwrap_or(self.end + 1);
+            .unwrap_or(self.end - self.base_offset + 1);
         match self.anchors.partition_point(|a| {
             a.y(self) < y
                 || a.y(self) == y && a.x_with(self.base_x_relative, a.offset >= rel_offset) <= x
@@ -843,7 +1519,7 @@ impl MappedSegment {
             .anchors
             .get(self.first_absolute)
             .map(|a| a.offset)
-            .unwrap_or(self.end + 1);
+            .unwrap_or(self.end - self.base_offset + 1);
         *self
             .anchors
             .get(self.anchors.partition_point(|a| {
@@ -854,8 +1530,77 @@ impl MappedSegment {
     }
 }
 
+/// How much further the text after the first tab of a relative line moves than the
+/// text before it, when the line was laid out from X position `from` and turns out
+/// to start at `to`.
+/// `tab` is the position of the tab relative to the start of the line.
+fn tab_shift(layout: &CharLayout, from: f64, to: f64, tab: f64) -> f64 {
+    layout.tab_stop(to + tab) - layout.tab_stop(from + tab) - (to - from)
+}
+
+/// The layout of a piece of text, laid out starting at some X position.
+struct Measure {
+    /// The amount of newlines in the text.
+    nl: i64,
+    /// The X position at the end of the first line.
+    first: f64,
+    /// The X position at the end of the widest line.
+    widest: f64,
+    /// The X position at the end of the last line.
+    last: f64,
+    /// The X position of the first tab in the first line, or `NAN` if there is none.
+    tab: f64,
+}
+
+/// Lay out the given data starting at X position `x`.
+fn measure(layout: &CharLayout, enc: Encoding, data: &[u8], x: f64) -> Measure {
+    let mut m = Measure {
+        nl: 0,
+        first: x,
+        widest: 0.,
+        last: x,
+        tab: f64::NAN,
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let (c, adv) = enc.decode_text(&data[i..]);
+        match c.unwrap_or(LineMapper::REPLACEMENT_CHAR) {
+            LineMapper::NEWLINE => {
+                if m.nl == 0 {
+                    m.first = m.last;
+                }
+                m.widest = m.widest.max(m.last);
+                m.nl += 1;
+                m.last = 0.;
+            }
+            c => {
+                if c == CharLayout::TAB && m.nl == 0 && m.tab.is_nan() {
+                    m.tab = m.last;
+                }
+                m.last += layout.advance_at(c, m.last);
+            }
+        }
+        i += adv;
+    }
+    if m.nl == 0 {
+        m.first = m.last;
+    }
+    m.widest = m.widest.max(m.last);
+    m
+}
+
+/// Check if the first character of the given data is a line feed.
+fn starts_with_lf(enc: Encoding, data: &[u8]) -> bool {
+    !data.is_empty() && enc.decode(data).0 == Ok(LineMapper::NEWLINE)
+}
+
+/// Check if the last character of the given data is a carriage return.
+fn ends_with_cr(enc: Encoding, data: &[u8]) -> bool {
+    !data.is_empty() && enc.decode_rev(data).0 == Ok(LineMapper::CARRIAGE_RETURN)
+}
+
 /// Check if the given byte is a UTF-8 continuation byte.
-fn is_utf8_cont(b: u8) -> bool {
+pub(super) fn is_utf8_cont(b: u8) -> bool {
     b & 0b1100_0000 == 0b1000_0000
 }
 
@@ -864,7 +1609,7 @@ fn is_utf8_cont(b: u8) -> bool {
 /// byte), returns 0.
 /// Does not handle invalid UTF-8, this must be handled while
 /// parsing the sequence.
-fn utf8_seq_len(b: u8) -> usize {
+pub(super) fn utf8_seq_len(b: u8) -> usize {
     if b & 0b1000_0000 == 0 {
         1
     } else if b & 0b0100_0000 == 0 {
@@ -970,12 +1715,21 @@ pub fn decode_utf8_rev(b: &[u8]) -> (Result<u32, u8>, usize) {
 
 #[derive(Clone, Copy, Debug)]
 pub struct Anchor {
-    /// The absolute byte offset that this anchor marks.
+    /// The byte offset that this anchor marks, relative to the `base_offset` value of
+    /// the containing segment.
+    /// Anchors handed out of the linemap have absolute offsets instead.
     pub offset: i64,
     /// The line number relative to the `base_y` value of the containing segment.
     pub y_offset: i64,
     /// The `f64` X position of this anchor, may be relative or absolute.
+    /// Anchors handed out of the linemap have the X position from the start of the
+    /// line instead, which relative lines only know up to `tab_phase`.
     pub x_offset: f64,
+    /// The X distance from the previous anchor to the first tab after it in its line,
+    /// or `NAN` if there is no such tab before this anchor.
+    /// Tabs advance up to the next tab stop, so when the text before a tab moves the
+    /// text after it moves by a multiple of the tab width instead.
+    pub tab: f64,
 }
 impl Anchor {
     /// Only correct for relative-X anchors.
@@ -1000,6 +1754,10 @@ impl Anchor {
         }
     }
 
+    pub fn off(&self, s: &MappedSegment) -> i64 {
+        self.offset + s.base_offset
+    }
+
     pub fn y(&self, s: &MappedSegment) -> i64 {
         self.y_with(s.base_y)
     }
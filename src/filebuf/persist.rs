@@ -0,0 +1,456 @@
+//! Persists the state of a buffer, so that unsaved edits survive a restart.
+//!
+//! The journal stores the pieces of the buffer, along with the cursor and scroll
+//! position.
+//! It is a log of records: the data of the edited pieces is written once, when it
+//! first shows up, and whenever the buffer changes only the new list of pieces is
+//! appended, referring to the data written before.
+//! The last complete list is the one that counts, so a journal that was cut short
+//! by a crash still holds the state before it.
+//! Once most of the journal is data that the buffer no longer refers to, it is
+//! written again from scratch.
+//! Replace-alls are stored as their matches, just like they are kept in memory.
+//! It is only valid as long as the backing file is not modified, so it is keyed
+//! by the path, size and modification time of the file.
+
+use std::{
+    hash::{Hash, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::prelude::*;
+
+use super::{
+    piece::{Piece, PieceTable, Source},
+    replace::{Replaced, ReplacedMatch},
+    FilePos,
+};
+
+const MAGIC: &[u8; 8] = b"GAZEJRNL";
+const VERSION: u32 = 2;
+
+/// A record with the data of RAM pieces.
+const DATA: u8 = 0;
+/// A record with a replace-all.
+const REPLACED: u8 = 1;
+/// A record with the view and the list of pieces.
+const STATE: u8 = 2;
+
+/// How much data that the buffer no longer refers to a journal may hold before it
+/// is written again, on top of as much as the data that it does refer to.
+const SLACK: u64 = 1024 * 1024;
+
+/// The state of a file view, as seen by the backend.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct ViewState {
+    /// The selection, as `[first, second]`.
+    pub sel: [i64; 2],
+    pub scroll: FilePos,
+}
+
+/// Identifies the exact version of the file that a journal refers to.
+#[derive(Clone, PartialEq, Debug)]
+pub(super) struct JournalKey {
+    pub path: PathBuf,
+    pub size: i64,
+    pub mtime: Option<SystemTime>,
+}
+impl JournalKey {
+    /// Where the journal for this file is stored.
+    pub fn journal_path(&self, dir: &Path) -> PathBuf {
+        let mut h = rustc_hash::FxHasher::default();
+        self.path.hash(&mut h);
+        dir.join(format!("{:016x}.journal", h.finish()))
+    }
+}
+
+pub(super) struct Journal {
+    pub key: JournalKey,
+    pub view: ViewState,
+    pub pieces: Vec<Piece>,
+}
+impl Journal {
+    /// Whether the journal holds any unsaved edits.
+    pub fn has_edits(&self) -> bool {
+        match &self.pieces[..] {
+            [] => self.key.size != 0,
+            [p] => !matches!(p.src, Source::File(0)) || p.len != self.key.size,
+            _ => true,
+        }
+    }
+
+    /// Read a journal from the given path.
+    pub fn read(path: &Path) -> Result<Journal> {
+        let data = fs::read(path)?;
+        let mut r = &data[..];
+        ensure!(take(&mut r, 8)? == MAGIC, "not a journal file");
+        ensure!(
+            take(&mut r, 4)? == VERSION.to_le_bytes(),
+            "unsupported journal version"
+        );
+        let path_len = get_u64(&mut r)? as usize;
+        let key_path = String::from_utf8_lossy(take(&mut r, path_len)?).into_owned();
+        let size = get_u64(&mut r)? as i64;
+        let mtime = match get_u64(&mut r)? {
+            u64::MAX => None,
+            nanos => Some(UNIX_EPOCH + Duration::from_nanos(nanos)),
+        };
+        let mut records = Records { size, ..default() };
+        let mut state = None;
+        // A record that was cut short or garbled is where the journal ends
+        while let Some((kind, mut body)) = get_record(&mut r) {
+            match kind {
+                DATA => {
+                    records.data.insert(records.next_id, body.into());
+                    records.next_id += 1;
+                }
+                REPLACED => {
+                    let replaced = records.get_replaced(&mut body)?;
+                    records.replaced.insert(records.next_id, Arc::new(replaced));
+                    records.next_id += 1;
+                }
+                STATE => state = Some(body),
+                _ => bail!("invalid record kind"),
+            }
+        }
+        let mut r = state.context("journal holds no state")?;
+        let sel = [get_u64(&mut r)? as i64, get_u64(&mut r)? as i64];
+        let scroll = FilePos {
+            base_offset: get_u64(&mut r)? as i64,
+            delta_x: f64::from_bits(get_u64(&mut r)?),
+            delta_y: f64::from_bits(get_u64(&mut r)?),
+        };
+        let pieces = records.get_pieces(&mut r)?;
+        Ok(Journal {
+            key: JournalKey {
+                path: key_path.into(),
+                size,
+                mtime,
+            },
+            view: ViewState { sel, scroll },
+            pieces,
+        })
+    }
+}
+
+/// Data that was written to a journal, along with the id of its record.
+struct Written<T: ?Sized> {
+    /// Kept alive, so that its address is not reused for other data.
+    _data: Arc<T>,
+    id: u64,
+    /// The size of the record.
+    size: u64,
+}
+
+/// A journal that is appended to as the buffer changes.
+pub(super) struct JournalWriter {
+    file: File,
+    pub key: JournalKey,
+    /// The RAM data that is in the journal, by its address.
+    data: FxHashMap<usize, Written<[u8]>>,
+    /// The replace-alls that are in the journal, by their address.
+    replaced: FxHashMap<usize, Written<Replaced>>,
+    next_id: u64,
+    /// The length of the journal.
+    len: u64,
+    /// The size of the last state record.
+    state_len: u64,
+}
+impl JournalWriter {
+    /// Write a new journal to the given path, atomically replacing any old one.
+    pub fn create(path: &Path, journal: &Journal) -> Result<Self> {
+        let tmp = path.with_extension("tmp");
+        let mut w = Self {
+            file: File::create(&tmp)?,
+            key: journal.key.clone(),
+            data: default(),
+            replaced: default(),
+            next_id: 0,
+            len: 0,
+            state_len: 0,
+        };
+        let mut out = vec![];
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        let key_path = journal.key.path.to_string_lossy();
+        put_u64(&mut out, key_path.len() as u64);
+        out.extend_from_slice(key_path.as_bytes());
+        put_u64(&mut out, journal.key.size as u64);
+        let mtime = journal
+            .key
+            .mtime
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(u64::MAX);
+        put_u64(&mut out, mtime);
+        w.put_state(&mut out, journal);
+        w.file.write_all(&out)?;
+        w.file.sync_all()?;
+        w.len = out.len() as u64;
+        fs::rename(&tmp, path)?;
+        Ok(w)
+    }
+
+    /// Append the new state of the buffer, along with any data that is not in the
+    /// journal yet.
+    /// If this fails, the journal should be written again from scratch.
+    pub fn append(&mut self, journal: &Journal) -> Result<()> {
+        ensure!(
+            journal.key == self.key,
+            "journal is for another version of the file"
+        );
+        let mut out = vec![];
+        self.put_state(&mut out, journal);
+        self.file.write_all(&out)?;
+        self.file.sync_data()?;
+        self.len += out.len() as u64;
+        Ok(())
+    }
+
+    /// Whether the journal is mostly data that the buffer no longer refers to, and
+    /// should be written again from scratch.
+    pub fn is_bloated(&self) -> bool {
+        let data = self.data.values().map(|w| w.size);
+        let replaced = self.replaced.values().map(|w| w.size);
+        let live = self.state_len + data.chain(replaced).sum::<u64>();
+        self.len > 2 * live + SLACK
+    }
+
+    fn put_state(&mut self, out: &mut Vec<u8>, journal: &Journal) {
+        let mut body = vec![];
+        put_u64(&mut body, journal.view.sel[0] as u64);
+        put_u64(&mut body, journal.view.sel[1] as u64);
+        put_u64(&mut body, journal.view.scroll.base_offset as u64);
+        put_u64(&mut body, journal.view.scroll.delta_x.to_bits());
+        put_u64(&mut body, journal.view.scroll.delta_y.to_bits());
+        let mut live = default();
+        self.put_pieces(out, &mut body, &journal.pieces, &mut live);
+        self.state_len = put_record(out, STATE, &body);
+        // Let go of the data that the buffer no longer refers to, so that it can be
+        // freed
+        self.data.retain(|addr, _| live.contains(addr));
+        self.replaced.retain(|addr, _| live.contains(addr));
+    }
+
+    /// Put references to the given pieces into `refs`, and records for any data
+    /// that is not in the journal yet into `out`.
+    /// The addresses of all data referred to are added to `live`.
+    fn put_pieces(
+        &mut self,
+        out: &mut Vec<u8>,
+        refs: &mut Vec<u8>,
+        pieces: &[Piece],
+        live: &mut FxHashSet<usize>,
+    ) {
+        put_u64(refs, pieces.len() as u64);
+        for p in pieces {
+            put_u64(refs, p.len as u64);
+            match &p.src {
+                Source::File(off) => {
+                    refs.push(0);
+                    put_u64(refs, *off as u64);
+                }
+                Source::Ram(data, off) => {
+                    let addr = Arc::as_ptr(data) as *const u8 as usize;
+                    live.insert(addr);
+                    let id = match self.data.get(&addr) {
+                        Some(w) => w.id,
+                        None => {
+                            let id = self.next_id;
+                            self.next_id += 1;
+                            let size = put_record(out, DATA, data);
+                            let data = data.clone();
+                            self.data.insert(
+                                addr,
+                                Written {
+                                    _data: data,
+                                    id,
+                                    size,
+                                },
+                            );
+                            id
+                        }
+                    };
+                    refs.push(1);
+                    put_u64(refs, id);
+                    put_u64(refs, *off as u64);
+                }
+                Source::Replace(r, off) => {
+                    // The original pieces go first, and are still referred to
+                    // even if the replace-all is in the journal already
+                    let mut body = vec![];
+                    put_u64(&mut body, r.len as u64);
+                    self.put_pieces(out, &mut body, r.input.pieces(), live);
+                    let addr = Arc::as_ptr(r) as usize;
+                    live.insert(addr);
+                    let id = match self.replaced.get(&addr) {
+                        Some(w) => w.id,
+                        None => {
+                            put_u64(&mut body, r.matches.len() as u64);
+                            for m in r.matches.iter() {
+                                for x in [m.input.start, m.input.end, m.output.start, m.output.end]
+                                {
+                                    put_u64(&mut body, x as u64);
+                                }
+                                put_u64(&mut body, m.text as u64);
+                            }
+                            put_u64(&mut body, r.texts.len() as u64);
+                            body.extend_from_slice(&r.texts);
+                            let id = self.next_id;
+                            self.next_id += 1;
+                            let size = put_record(out, REPLACED, &body);
+                            let data = r.clone();
+                            self.replaced.insert(
+                                addr,
+                                Written {
+                                    _data: data,
+                                    id,
+                                    size,
+                                },
+                            );
+                            id
+                        }
+                    };
+                    refs.push(2);
+                    put_u64(refs, id);
+                    put_u64(refs, *off as u64);
+                }
+            }
+        }
+    }
+}
+
+/// The records read from a journal so far, by id.
+/// Ids are counted across all records with data, in the order they were written.
+#[derive(Default)]
+struct Records {
+    /// The size of the file that the journal refers to.
+    size: i64,
+    data: FxHashMap<u64, Arc<[u8]>>,
+    replaced: FxHashMap<u64, Arc<Replaced>>,
+    next_id: u64,
+}
+impl Records {
+    /// Read a list of pieces, checking that they are within the bounds of the file
+    /// and of the records they refer to.
+    fn get_pieces(&self, r: &mut &[u8]) -> Result<Vec<Piece>> {
+        let n = get_u64(r)?;
+        let mut pieces = vec![];
+        for _ in 0..n {
+            let len = get_u64(r)? as i64;
+            ensure!(len > 0, "invalid piece length");
+            let src = match take(r, 1)?[0] {
+                0 => {
+                    let off = get_u64(r)? as i64;
+                    ensure!(
+                        off >= 0 && off + len <= self.size,
+                        "piece out of file bounds"
+                    );
+                    Source::File(off)
+                }
+                1 => {
+                    let data = get_record_data(&self.data, get_u64(r)?)?;
+                    let off = get_u64(r)? as usize;
+                    ensure!(off + len as usize <= data.len(), "piece out of data bounds");
+                    Source::Ram(data, off)
+                }
+                2 => {
+                    let replaced = get_record_data(&self.replaced, get_u64(r)?)?;
+                    let off = get_u64(r)? as i64;
+                    ensure!(
+                        off >= 0 && off + len <= replaced.len,
+                        "piece out of replace bounds"
+                    );
+                    Source::Replace(replaced, off)
+                }
+                _ => bail!("invalid piece kind"),
+            };
+            pieces.push(Piece { len, src });
+        }
+        Ok(pieces)
+    }
+
+    fn get_replaced(&self, r: &mut &[u8]) -> Result<Replaced> {
+        let total = get_u64(r)? as i64;
+        let input = PieceTable::from_pieces(self.get_pieces(r)?);
+        let mut matches = vec![];
+        for _ in 0..get_u64(r)? {
+            let input = get_u64(r)? as i64..get_u64(r)? as i64;
+            let output = get_u64(r)? as i64..get_u64(r)? as i64;
+            let text = get_u64(r)? as usize;
+            matches.push(ReplacedMatch {
+                input,
+                output,
+                text,
+            });
+        }
+        let texts_len = get_u64(r)? as usize;
+        let texts = take(r, texts_len)?;
+        ensure!(
+            matches.iter().all(|m| m.input.end <= input.len()
+                && m.output.end <= total
+                && m.text + (m.output.end - m.output.start) as usize <= texts.len()),
+            "replaced match out of bounds"
+        );
+        Ok(Replaced {
+            input,
+            matches: matches.into(),
+            texts: texts.into(),
+            len: total,
+        })
+    }
+}
+
+fn get_record_data<T: ?Sized>(records: &FxHashMap<u64, Arc<T>>, id: u64) -> Result<Arc<T>> {
+    records
+        .get(&id)
+        .cloned()
+        .context("piece refers to a missing record")
+}
+
+/// Put a record with the given kind and body, followed by its length and a checksum
+/// so that a record cut short can be told apart.
+/// Returns the size of the record.
+fn put_record(out: &mut Vec<u8>, kind: u8, body: &[u8]) -> u64 {
+    let start = out.len();
+    out.push(kind);
+    put_u64(out, body.len() as u64);
+    put_u64(out, checksum(body));
+    out.extend_from_slice(body);
+    (out.len() - start) as u64
+}
+
+/// Get the next record, if it is complete and intact.
+fn get_record<'a>(r: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let mut rest = *r;
+    let kind = take(&mut rest, 1).ok()?[0];
+    let len = get_u64(&mut rest).ok()?;
+    let sum = get_u64(&mut rest).ok()?;
+    let body = take(&mut rest, usize::try_from(len).ok()?).ok()?;
+    if checksum(body) != sum {
+        return None;
+    }
+    *r = rest;
+    Some((kind, body))
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut h = rustc_hash::FxHasher::default();
+    h.write(data);
+    h.finish()
+}
+
+fn put_u64(out: &mut Vec<u8>, x: u64) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn take<'a>(r: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    ensure!(r.len() >= n, "journal is truncated");
+    let (head, tail) = r.split_at(n);
+    *r = tail;
+    Ok(head)
+}
+
+fn get_u64(r: &mut &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(take(r, 8)?.try_into().unwrap()))
+}
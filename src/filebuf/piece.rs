@@ -0,0 +1,348 @@
+use crate::prelude::*;
+
+use super::replace::Replaced;
+
+/// Where the data of a piece comes from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// Backed by the original file, starting at the given file offset.
+    File(i64),
+    /// Backed by RAM, starting at the given index into the shared buffer.
+    Ram(Arc<[u8]>, usize),
+    /// Backed by the result of a replace-all, starting at the given offset into the
+    /// replaced text.
+    Replace(Arc<Replaced>, i64),
+}
+
+/// The original contents of a range of the file, kept in RAM after the range is
+/// overwritten by a save, or the file is replaced by a saved copy.
+pub struct Overwritten {
+    pub offset: i64,
+    pub data: Arc<[u8]>,
+}
+
+/// A contiguous range of the buffer, backed by a single source.
+#[derive(Clone, Debug)]
+pub struct Piece {
+    pub len: i64,
+    pub src: Source,
+}
+impl Piece {
+    /// Get the subrange `[start, start + len)` of this piece.
+    fn slice(&self, start: i64, len: i64) -> Piece {
+        Piece {
+            len,
+            src: match &self.src {
+                Source::File(off) => Source::File(off + start),
+                Source::Ram(data, off) => Source::Ram(data.clone(), off + start as usize),
+                Source::Replace(r, off) => Source::Replace(r.clone(), off + start),
+            },
+        }
+    }
+
+    /// Get the RAM data of this piece, if it is backed by RAM.
+    pub fn ram(&self) -> Option<&[u8]> {
+        match &self.src {
+            Source::Ram(data, off) => Some(&data[*off..*off + self.len as usize]),
+            Source::File(_) | Source::Replace(..) => None,
+        }
+    }
+
+    /// An estimate of the memory kept alive by this piece, not counting the file
+    /// data that it refers to.
+    pub fn mem(&self) -> usize {
+        mem::size_of::<Piece>()
+            + match &self.src {
+                Source::File(_) => 0,
+                Source::Ram(..) => self.len as usize,
+                Source::Replace(r, _) => r.mem(),
+            }
+    }
+
+    /// Push the same data as this piece into `out`, with any part of it that is
+    /// backed by an overwritten range of the file moved into RAM.
+    /// `overwritten` must be sorted by offset and not overlap.
+    /// Returns whether any data was moved.
+    pub fn detach(&self, overwritten: &[Overwritten], out: &mut Vec<Piece>) -> bool {
+        let off = match &self.src {
+            Source::File(off) => *off,
+            Source::Ram(..) => {
+                out.push(self.clone());
+                return false;
+            }
+            Source::Replace(r, off) => {
+                // The replaced text reads from the original pieces
+                let mut moved = false;
+                let r = r
+                    .map_input(|p, out| {
+                        moved |= p.detach(overwritten, out);
+                        true
+                    })
+                    .unwrap();
+                out.push(match moved {
+                    true => Piece {
+                        len: self.len,
+                        src: Source::Replace(Arc::new(r), *off),
+                    },
+                    false => self.clone(),
+                });
+                return moved;
+            }
+        };
+        let mut moved = false;
+        let end = off + self.len;
+        let mut at = off;
+        let mut i = overwritten.partition_point(|o| o.offset + o.data.len() as i64 <= off);
+        while at < end {
+            match overwritten.get(i) {
+                Some(o) if o.offset < end => {
+                    let (l, r) = (o.offset.max(at), (o.offset + o.data.len() as i64).min(end));
+                    if at < l {
+                        out.push(self.slice(at - off, l - at));
+                    }
+                    out.push(Piece {
+                        len: r - l,
+                        src: Source::Ram(o.data.clone(), (l - o.offset) as usize),
+                    });
+                    at = r;
+                    i += 1;
+                    moved = true;
+                }
+                _ => {
+                    out.push(self.slice(at - off, end - at));
+                    at = end;
+                }
+            }
+        }
+        moved
+    }
+}
+
+/// Maps virtual offsets of a buffer to the data that backs them.
+///
+/// An unmodified buffer is a single piece that covers the entire file.
+/// Every edit splits the pieces around the edited range, removes the deleted
+/// pieces and inserts a RAM-backed piece with the inserted data.
+/// The file itself is never touched until the buffer is saved.
+///
+/// Pieces are kept in a flat list, so edits are linear in the amount of pieces,
+/// but independent of the size of the buffer.
+pub struct PieceTable {
+    pieces: Vec<Piece>,
+    /// The virtual offset at which each piece starts.
+    starts: Vec<i64>,
+    /// The total length of the buffer.
+    len: i64,
+    /// Incremented on every edit.
+    version: u64,
+}
+impl PieceTable {
+    /// Inserted RAM pieces below this size are merged with adjacent RAM pieces,
+    /// to avoid creating one piece per typed character.
+    const MERGE_THRESHOLD: i64 = 4 * 1024;
+
+    /// Create a piece table for an unmodified file of the given size.
+    pub fn new(file_size: i64) -> Self {
+        let mut pieces = vec![];
+        if file_size > 0 {
+            pieces.push(Piece {
+                len: file_size,
+                src: Source::File(0),
+            });
+        }
+        Self::from_pieces(pieces)
+    }
+
+    /// Create a piece table out of a list of pieces.
+    pub fn from_pieces(pieces: Vec<Piece>) -> Self {
+        let mut table = Self {
+            pieces,
+            starts: vec![],
+            len: 0,
+            version: 0,
+        };
+        table.recompute_starts();
+        table
+    }
+
+    /// The virtual length of the buffer.
+    pub fn len(&self) -> i64 {
+        self.len
+    }
+
+    /// A number that changes whenever the buffer is edited.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// All of the pieces, in order.
+    pub fn pieces(&self) -> &[Piece] {
+        &self.pieces
+    }
+
+    /// Whether the table maps to the entire file as-is, without any edits.
+    pub fn is_pristine(&self, file_size: i64) -> bool {
+        let mut at = 0;
+        for p in self.pieces.iter() {
+            match p.src {
+                Source::File(off) if off == at => at += p.len,
+                _ => return false,
+            }
+        }
+        at == file_size
+    }
+
+    fn recompute_starts(&mut self) {
+        self.starts.clear();
+        let mut acc = 0;
+        for p in self.pieces.iter() {
+            self.starts.push(acc);
+            acc += p.len;
+        }
+        self.len = acc;
+    }
+
+    /// Make sure that there is a piece boundary at the given offset, and return the
+    /// index of the piece that starts at it.
+    fn split_at(&mut self, offset: i64) -> usize {
+        let i = self.starts.partition_point(|&s| s <= offset);
+        if i == 0 {
+            return 0;
+        }
+        let p = &self.pieces[i - 1];
+        let rel = offset - self.starts[i - 1];
+        if rel >= p.len {
+            return i;
+        } else if rel == 0 {
+            return i - 1;
+        }
+        let (l, r) = (p.slice(0, rel), p.slice(rel, p.len - rel));
+        self.pieces[i - 1] = l;
+        self.pieces.insert(i, r);
+        self.starts.insert(i, offset);
+        i
+    }
+
+    /// Replace the given range of the buffer by the given pieces.
+    /// Returns the removed pieces.
+    pub fn splice_pieces(&mut self, range: ops::Range<i64>, ins: Vec<Piece>) -> Vec<Piece> {
+        let l = self.split_at(range.start);
+        let r = self.split_at(range.end);
+        let removed = self.pieces.splice(l..r, ins).collect();
+        self.recompute_starts();
+        self.version += 1;
+        removed
+    }
+
+    /// Replace the given range of the buffer by the given data.
+    /// Returns the removed pieces.
+    pub fn splice(&mut self, range: ops::Range<i64>, data: &[u8]) -> Vec<Piece> {
+        let l = self.split_at(range.start);
+        let r = self.split_at(range.end);
+        let removed = self.pieces.drain(l..r).collect();
+        if !data.is_empty() {
+            // Merge small insertions into an adjacent RAM piece
+            let prev = l.checked_sub(1).map(|i| &self.pieces[i]);
+            match prev.and_then(|p| p.ram()) {
+                Some(prev_data)
+                    if prev_data.len() + data.len() <= Self::MERGE_THRESHOLD as usize =>
+                {
+                    let mut merged = Vec::with_capacity(prev_data.len() + data.len());
+                    merged.extend_from_slice(prev_data);
+                    merged.extend_from_slice(data);
+                    self.pieces[l - 1] = Piece {
+                        len: merged.len() as i64,
+                        src: Source::Ram(merged.into(), 0),
+                    };
+                }
+                _ => self.pieces.insert(
+                    l,
+                    Piece {
+                        len: data.len() as i64,
+                        src: Source::Ram(data.into(), 0),
+                    },
+                ),
+            }
+        }
+        self.recompute_starts();
+        self.version += 1;
+        removed
+    }
+
+    /// Move any data backed by the given overwritten ranges of the file into RAM.
+    /// The contents of the buffer stay the same, but the version still changes if
+    /// any data was moved, since older copies of the pieces are no longer valid.
+    pub fn detach(&mut self, overwritten: &[Overwritten]) {
+        let mut pieces = Vec::with_capacity(self.pieces.len());
+        let mut moved = false;
+        for p in self.pieces.iter() {
+            moved |= p.detach(overwritten, &mut pieces);
+        }
+        if moved {
+            self.pieces = pieces;
+            self.recompute_starts();
+            self.version += 1;
+        }
+    }
+
+    /// Get the pieces that back the given range, clipped to the range.
+    pub fn pieces_in(&self, range: ops::Range<i64>) -> Vec<Piece> {
+        let mut out = vec![];
+        let mut i = self.starts.partition_point(|&s| s <= range.start).max(1) - 1;
+        while i < self.pieces.len() && self.starts[i] < range.end {
+            let p = &self.pieces[i];
+            let l = (range.start - self.starts[i]).max(0);
+            let r = (range.end - self.starts[i]).min(p.len);
+            if l < r {
+                out.push(p.slice(l, r - l));
+            }
+            i += 1;
+        }
+        out
+    }
+
+    /// Get the parts of the given range that are backed by RAM, that is, the parts
+    /// that were edited instead of read from the file.
+    pub fn ram_ranges(&self, range: ops::Range<i64>) -> Vec<ops::Range<i64>> {
+        let mut out: Vec<ops::Range<i64>> = vec![];
+        let mut push = |r: ops::Range<i64>| match out.last_mut() {
+            Some(last) if last.end == r.start => last.end = r.end,
+            _ => out.push(r),
+        };
+        let mut at = range.start;
+        for p in self.pieces_in(range) {
+            match &p.src {
+                Source::File(_) => {}
+                Source::Ram(..) => push(at..at + p.len),
+                Source::Replace(r, off) => {
+                    for r in r.ram_ranges(*off..off + p.len) {
+                        push(r.start - off + at..r.end - off + at);
+                    }
+                }
+            }
+            at += p.len;
+        }
+        out
+    }
+}
+
+/// Read the data behind the given pieces into `buf`, which must be as long as the
+/// pieces.
+/// File pieces are read through `read_file`.
+pub fn read_pieces(
+    pieces: &[Piece],
+    buf: &mut [u8],
+    read_file: &mut dyn FnMut(i64, &mut [u8]) -> Result<()>,
+) -> Result<()> {
+    let mut at = 0;
+    for p in pieces {
+        let buf = &mut buf[at..at + p.len as usize];
+        match &p.src {
+            Source::File(off) => read_file(*off, buf)?,
+            Source::Ram(..) => buf.copy_from_slice(p.ram().unwrap()),
+            Source::Replace(r, off) => r.read(*off, buf, read_file)?,
+        }
+        at += p.len as usize;
+    }
+    Ok(())
+}
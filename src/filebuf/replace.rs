@@ -0,0 +1,368 @@
+//! Replaces all matches of a query in the background.
+//!
+//! The buffer is scanned chunk by chunk, just like when searching, but instead of
+//! publishing the matches the manager thread records where they are and what they
+//! are replaced by.
+//! Once the scan is done, the replaced range becomes a single piece that refers to
+//! the original pieces and the list of matches, and the replaced text is only
+//! produced as the piece is read.
+//! So even a replace-all over a huge file is cheap to apply, save and undo.
+//! The matches themselves are kept in memory though, so replacing too many of them
+//! fails instead.
+
+use crate::prelude::*;
+
+use super::{
+    piece::{self, Piece, PieceTable, Source},
+    search::{Matcher, SearchQuery},
+};
+
+/// A match that was replaced by a replace-all.
+#[derive(Clone, Debug)]
+pub struct ReplacedMatch {
+    /// Where the match is in the original text.
+    pub input: ops::Range<i64>,
+    /// Where its replacement is in the replaced text.
+    pub output: ops::Range<i64>,
+    /// Where its replacement starts in `Replaced::texts`.
+    pub text: usize,
+}
+
+/// The text of a range of the buffer with all matches of a query replaced.
+///
+/// Only the matches are stored, along with the original pieces.
+/// The replaced text is put together whenever it is read.
+pub struct Replaced {
+    /// The pieces of the original text.
+    pub input: PieceTable,
+    /// Sorted and not overlapping.
+    pub matches: Arc<[ReplacedMatch]>,
+    /// The replacement texts.
+    /// Starts with the replacement as given, which is shared by every match that
+    /// does not refer to capture groups.
+    pub texts: Arc<[u8]>,
+    /// The length of the replaced text.
+    pub len: i64,
+}
+impl Replaced {
+    /// The offset to add to the original text to get the replaced text, between
+    /// the previous match and the `i`-th match.
+    fn delta(&self, i: usize) -> i64 {
+        match self.matches.get(i) {
+            Some(m) => m.output.start - m.input.start,
+            None => self.len - self.input.len(),
+        }
+    }
+
+    /// Fill `buf` with the replaced text starting at `offset`, reading the file
+    /// pieces of the original text through `read_file`.
+    pub fn read(
+        &self,
+        offset: i64,
+        buf: &mut [u8],
+        read_file: &mut dyn FnMut(i64, &mut [u8]) -> Result<()>,
+    ) -> Result<()> {
+        let end = offset + buf.len() as i64;
+        let mut at = offset;
+        let mut i = self.matches.partition_point(|m| m.output.end <= at);
+        while at < end {
+            // Copy the original text up to the next match
+            let stop = match self.matches.get(i) {
+                Some(m) => m.output.start.min(end),
+                None => end,
+            };
+            if at < stop {
+                let delta = self.delta(i);
+                let pieces = self.input.pieces_in(at - delta..stop - delta);
+                let out = &mut buf[(at - offset) as usize..(stop - offset) as usize];
+                piece::read_pieces(&pieces, out, read_file)?;
+                at = stop;
+            }
+            // Then the replacement
+            if let Some(m) = self.matches.get(i).filter(|_| at < end) {
+                let stop = m.output.end.min(end);
+                let text = m.text + (at - m.output.start) as usize;
+                buf[(at - offset) as usize..(stop - offset) as usize]
+                    .copy_from_slice(&self.texts[text..text + (stop - at) as usize]);
+                at = stop;
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the parts of the given range of the replaced text that do not come from
+    /// the file, that is, the replacements and the edits made to the original text.
+    pub fn ram_ranges(&self, range: ops::Range<i64>) -> Vec<ops::Range<i64>> {
+        let mut out = vec![];
+        let mut at = range.start;
+        let mut i = self.matches.partition_point(|m| m.output.end <= at);
+        while at < range.end {
+            let stop = match self.matches.get(i) {
+                Some(m) => m.output.start.min(range.end),
+                None => range.end,
+            };
+            if at < stop {
+                let delta = self.delta(i);
+                for r in self.input.ram_ranges(at - delta..stop - delta) {
+                    out.push(r.start + delta..r.end + delta);
+                }
+                at = stop;
+            }
+            if let Some(m) = self.matches.get(i).filter(|_| at < range.end) {
+                let stop = m.output.end.min(range.end);
+                if at < stop {
+                    out.push(at..stop);
+                }
+                at = stop;
+                i += 1;
+            }
+        }
+        out
+    }
+
+    /// Build the same replaced text, but over the original pieces mapped through `f`.
+    /// Returns `None` if `f` fails for any of them.
+    pub fn map_input(&self, mut f: impl FnMut(&Piece, &mut Vec<Piece>) -> bool) -> Option<Self> {
+        let mut input = Vec::with_capacity(self.input.pieces().len());
+        for p in self.input.pieces() {
+            if !f(p, &mut input) {
+                return None;
+            }
+        }
+        Some(Self {
+            input: PieceTable::from_pieces(input),
+            matches: self.matches.clone(),
+            texts: self.texts.clone(),
+            len: self.len,
+        })
+    }
+
+    /// An estimate of the memory kept alive by the replaced text, not counting the
+    /// file data that it refers to.
+    pub fn mem(&self) -> usize {
+        let input: usize = self.input.pieces().iter().map(|p| p.mem()).sum();
+        input + self.matches.len() * mem::size_of::<ReplacedMatch>() + self.texts.len()
+    }
+}
+impl fmt::Debug for Replaced {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Replaced")
+            .field("len", &self.len)
+            .field("input_len", &self.input.len())
+            .field("matches", &self.matches.len())
+            .finish()
+    }
+}
+
+/// The state of a replace-all, shared between the manager thread and the frontend.
+pub struct ReplaceProgress {
+    pub query: SearchQuery,
+    pub replacement: String,
+    /// The selection at the time the replace was requested, to restore on undo.
+    pub(super) sel_before: [i64; 2],
+    /// How much of the buffer has been scanned so far.
+    pub scanned: i64,
+    /// The length of the buffer being scanned.
+    pub total: i64,
+    /// The amount of matches replaced so far.
+    pub count: u64,
+    /// Whether the replacement was applied to the buffer.
+    pub done: bool,
+    /// Why the replace failed, if it did.
+    pub error: Option<String>,
+    /// The lowest offset that was edited since the manager thread last took a
+    /// snapshot of the buffer, along with the version of the buffer after the edit.
+    pub(super) edited: Option<(i64, u64)>,
+}
+impl ReplaceProgress {
+    pub(super) fn new(query: SearchQuery, replacement: String, sel_before: [i64; 2]) -> Self {
+        Self {
+            query,
+            replacement,
+            sel_before,
+            scanned: 0,
+            total: 0,
+            count: 0,
+            done: false,
+            error: None,
+            edited: None,
+        }
+    }
+
+    /// Take note of an edit at the given offset, which brought the buffer to the
+    /// given version.
+    pub(super) fn edited(&mut self, at: i64, version: u64) {
+        let from = self.edited.map_or(at, |(from, _)| from.min(at));
+        self.edited = Some((from, version));
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done || self.error.is_some()
+    }
+
+    /// How much of the buffer has been scanned, between 0 and 1.
+    pub fn progress(&self) -> f32 {
+        if self.total <= 0 {
+            1.
+        } else {
+            (self.scanned as f64 / self.total as f64) as f32
+        }
+    }
+}
+
+/// A replace-all in progress, owned by the manager thread.
+pub(super) struct Replacer {
+    pub query: SearchQuery,
+    pub replacement: String,
+    pub matcher: Arc<Matcher>,
+    /// The version of the piece table that is being scanned.
+    pub version: u64,
+    /// A copy of the piece table at the time the replace started, so that it can
+    /// be scanned without holding the lock.
+    pub snapshot: PieceTable,
+    /// The matches found so far, relative to the start of the first match.
+    pub matches: Vec<ReplacedMatch>,
+    /// The replacement texts of the matches.
+    pub texts: Vec<u8>,
+    /// The length of the plain replacement at the start of `texts`.
+    pub plain: usize,
+    /// The start of the first match, if any match was found yet.
+    pub first: Option<i64>,
+    /// The end of the last match.
+    pub cursor: i64,
+    /// How much longer the replaced text is than the original text so far.
+    pub delta: i64,
+    pub scanned: i64,
+    pub count: u64,
+}
+impl Replacer {
+    pub fn new(
+        query: SearchQuery,
+        replacement: String,
+        matcher: Arc<Matcher>,
+        version: u64,
+        snapshot: PieceTable,
+    ) -> Self {
+        let texts = matcher.encoding().encode(&replacement);
+        Self {
+            plain: texts.len(),
+            query,
+            replacement,
+            matcher,
+            version,
+            snapshot,
+            matches: vec![],
+            texts,
+            first: None,
+            cursor: 0,
+            delta: 0,
+            scanned: 0,
+            count: 0,
+        }
+    }
+
+    pub fn is_for(&self, progress: &ReplaceProgress) -> bool {
+        self.query == progress.query && self.replacement == progress.replacement
+    }
+
+    /// Carry on scanning `snapshot`, a newer version of the buffer that was edited
+    /// from `from` onwards.
+    /// Whatever was found before the edits is kept, and the rest is scanned again.
+    pub fn edited(&mut self, from: i64, snapshot: PieceTable, version: u64) {
+        self.snapshot = snapshot;
+        self.version = version;
+        // Matches might change anywhere around the edit, including the context used
+        // to check word boundaries
+        let margin = Matcher::LOOKBEHIND as i64 + self.matcher.max_len() as i64 - 1;
+        let mut ws = (from - margin).max(0);
+        if ws >= self.scanned {
+            // The scan will get there anyway
+            return;
+        }
+        let first = self.first.unwrap_or(0);
+        let keep = self.matches.partition_point(|m| first + m.input.end <= ws);
+        if let Some(m) = self.matches.get(keep) {
+            ws = ws.min(first + m.input.start);
+        }
+        self.matches.truncate(keep);
+        self.count = keep as u64;
+        self.scanned = ws;
+        match self.matches.last() {
+            Some(last) => {
+                self.cursor = first + last.input.end;
+                self.delta = last.output.end - last.input.end;
+                // The texts of the matches are stored in order, after the plain one
+                let plain = self.plain;
+                let texts = self
+                    .matches
+                    .iter()
+                    .rev()
+                    .map(|m| m.text + (m.output.end - m.output.start) as usize)
+                    .find(|&end| end > plain)
+                    .unwrap_or(plain);
+                self.texts.truncate(texts);
+            }
+            None => {
+                self.first = None;
+                self.cursor = 0;
+                self.delta = 0;
+                self.texts.truncate(self.plain);
+            }
+        }
+    }
+
+    /// Add the matches found in a chunk.
+    /// `data` holds the chunk, which starts at buffer offset `base`, and `found`
+    /// are the ranges of the matches within `data`.
+    pub fn add(&mut self, data: &[u8], base: i64, found: &[ops::Range<usize>]) {
+        let mut text = vec![];
+        for m in found {
+            let (start, end) = (base + m.start as i64, base + m.end as i64);
+            let first = *self.first.get_or_insert(start);
+            text.clear();
+            self.matcher
+                .replace(data, m.clone(), &self.replacement, &mut text);
+            // Only keep the replacements that differ from the plain replacement
+            let at = if text[..] == self.texts[..self.plain] {
+                0
+            } else {
+                self.texts.extend_from_slice(&text);
+                self.texts.len() - text.len()
+            };
+            let output = start - first + self.delta;
+            self.matches.push(ReplacedMatch {
+                input: start - first..end - first,
+                output: output..output + text.len() as i64,
+                text: at,
+            });
+            self.delta += text.len() as i64 - (end - start);
+            self.cursor = end;
+            self.count += 1;
+        }
+    }
+
+    /// The memory used to keep track of the matches found so far.
+    pub fn mem(&self) -> usize {
+        self.matches.len() * mem::size_of::<ReplacedMatch>() + self.texts.len()
+    }
+
+    /// Build the pieces that replace the range from the first match to the last.
+    pub fn finish(self) -> Option<(ops::Range<i64>, Vec<Piece>)> {
+        let first = self.first?;
+        let replaced = Replaced {
+            input: PieceTable::from_pieces(self.snapshot.pieces_in(first..self.cursor)),
+            matches: self.matches.into(),
+            texts: self.texts.into(),
+            len: self.cursor - first + self.delta,
+        };
+        let mut pieces = vec![];
+        if replaced.len > 0 {
+            pieces.push(Piece {
+                len: replaced.len,
+                src: Source::Replace(Arc::new(replaced), 0),
+            });
+        }
+        Some((first..self.cursor, pieces))
+    }
+}
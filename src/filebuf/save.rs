@@ -0,0 +1,610 @@
+//! Writes the edited buffer back to disk.
+
+use std::{ffi::OsString, time::SystemTime};
+
+use crate::prelude::*;
+
+use super::{
+    encoding::{Encoding, LineEnding},
+    piece::{Overwritten, Piece, PieceTable, Source},
+    read_exact_at, Shared, ViewState,
+};
+
+/// A snapshot of the buffer, to be written to disk in the background.
+pub(super) struct SaveJob {
+    /// A handle to the file that backs the file pieces.
+    /// Note that this may not be the file currently at `path`, if it was replaced
+    /// by a previous save.
+    pub file: File,
+    /// The pieces of the buffer at the time of the snapshot.
+    pub pieces: Vec<Piece>,
+    /// The version of the piece table at the time of the snapshot.
+    pub version: u64,
+    /// Convert every line break to the given line ending while writing, decoding
+    /// the buffer with the given encoding.
+    pub convert: Option<(Encoding, LineEnding)>,
+    /// The view state at the time of the snapshot, to move it along with the text
+    /// if line breaks are converted.
+    pub view: ViewState,
+    /// The manager thread, to wake it up once the save finishes.
+    pub manager: thread::Thread,
+}
+impl SaveJob {
+    pub fn spawn(self, shared: Arc<Shared>) {
+        shared.save_progress.store(Some(0.));
+        thread::spawn(move || {
+            let manager = self.manager.clone();
+            if let Err(err) = self.run(&shared) {
+                shared.notify(true, format!("failed to save file: {:#}", err));
+            }
+            shared.save_progress.store(None);
+            manager.unpark();
+        });
+    }
+
+    fn run(self, shared: &Shared) -> Result<()> {
+        let start = Instant::now();
+        let mut len = self.pieces.iter().map(|p| p.len).sum();
+        let mut conv = self.convert.map(|(enc, le)| {
+            let v = self.view;
+            Converter::new(enc, le, [v.sel[0], v.sel[1], v.scroll.base_offset])
+        });
+        // If possible, only write the edited ranges into the original file
+        // Otherwise, write out a full copy of the buffer
+        let in_place = match conv {
+            Some(_) => None,
+            None => self.open_in_place(shared),
+        };
+        let (saved, reopened) = match in_place {
+            Some(out) => {
+                self.write_in_place(shared, &out)?;
+                (out, None)
+            }
+            None => {
+                let out = self.write_copy(shared, &mut conv)?;
+                (out.try_clone()?, Some(out))
+            }
+        };
+        // Line breaks were converted, so the file no longer matches the buffer
+        let conv = conv.filter(|c| c.changed);
+        if let Some(c) = &conv {
+            len = c.out_len;
+        }
+        // The undo history still refers to the old file
+        let relocation = match (&reopened, &conv) {
+            (Some(_), None) => self.relocation(shared),
+            _ => None,
+        };
+        // The file now holds the snapshot, so the buffer can refer to it directly
+        {
+            let mut loaded = shared.loaded.lock();
+            let unchanged = matches!(&loaded.pieces, Some(p) if p.version() == self.version);
+            // Keep track of the file that the pieces refer to
+            if unchanged || reopened.is_none() {
+                shared.last_file_size.store(len);
+                shared.last_file_mtime.store(modified_time(&saved));
+            }
+            if unchanged {
+                match &conv {
+                    Some(c) => {
+                        // The old offsets are meaningless now
+                        if loaded.view == self.view {
+                            let [a, b, scroll] = c.mapped.map(|m| m.unwrap_or(len));
+                            loaded.view.sel = [a, b];
+                            loaded.view.scroll.base_offset = scroll;
+                        }
+                        loaded.reset(len);
+                        loaded.line_ending = Some(c.to);
+                    }
+                    None => {
+                        loaded.pieces = Some(PieceTable::new(len));
+                        if let Some(r) = &relocation {
+                            loaded.history.rebase(|p, out| r.relocate(p, out));
+                        }
+                    }
+                }
+                loaded.reopened = reopened;
+            }
+            if let Some(p) = &loaded.pieces {
+                shared.edited(p);
+            }
+            // Otherwise, the buffer was edited while saving
+            // The file pieces only refer to ranges that were not written in place,
+            // or to the old file, which stays alive as long as the manager holds a
+            // handle to it
+        }
+        println!(
+            "saved {} bytes in {:.2}s",
+            len,
+            start.elapsed().as_secs_f64()
+        );
+        Ok(())
+    }
+
+    /// Find out where the data that the undo history refers to ended up in the saved
+    /// copy, so that the history can be moved over to it.
+    /// Data that did not make it into the copy is read into RAM, as long as it fits
+    /// in the history memory limit.
+    /// Returns `None` if the buffer was edited in the meantime, since then the
+    /// buffer keeps referring to the old file anyway.
+    fn relocation(&self, shared: &Shared) -> Option<Relocation> {
+        let ranges = {
+            let loaded = shared.loaded.lock();
+            if !matches!(&loaded.pieces, Some(p) if p.version() == self.version) {
+                return None;
+            }
+            loaded.history.file_ranges()
+        };
+        let reloc = Relocation::new(&self.pieces);
+        let mut budget = (shared.k.edit.max_history_mb * 1024. * 1024.) as i64;
+        let mut missing = vec![];
+        for r in ranges {
+            for gap in reloc.gaps(r) {
+                let len = gap.end - gap.start;
+                if len > budget {
+                    continue;
+                }
+                let mut data = vec![0; len as usize];
+                if read_exact_at(&self.file, &mut data, gap.start as u64).is_ok() {
+                    budget -= len;
+                    missing.push(Overwritten {
+                        offset: gap.start,
+                        data: data.into(),
+                    });
+                }
+            }
+        }
+        Some(Relocation { missing, ..reloc })
+    }
+
+    /// Open the original file for writing, if the buffer can be saved by
+    /// overwriting only the edited ranges.
+    ///
+    /// This is only possible if every edit replaced some bytes by the same amount
+    /// of bytes, and the file was not modified externally since it was opened.
+    /// Replace-alls are always written out as a copy, since their text is read from
+    /// the file while it is written.
+    fn open_in_place(&self, shared: &Shared) -> Option<File> {
+        // Make sure that every file piece is still at its original offset
+        let mut at = 0;
+        for p in self.pieces.iter() {
+            match p.src {
+                Source::File(off) if off != at => return None,
+                Source::Replace(..) => return None,
+                _ => {}
+            }
+            at += p.len;
+        }
+        if at != shared.last_file_size.load() {
+            return None;
+        }
+        // Make sure the file at `path` is the one we loaded, and that it did not change
+        let out = fs::OpenOptions::new().write(true).open(&shared.path).ok()?;
+        let (ours, theirs) = (self.file.metadata().ok()?, out.metadata().ok()?);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if (ours.dev(), ours.ino()) != (theirs.dev(), theirs.ino()) {
+                return None;
+            }
+        }
+        let mtime = theirs.modified().ok();
+        if ours.len() != theirs.len()
+            || theirs.len() as i64 != at
+            || mtime.is_none()
+            || mtime != shared.last_file_mtime.load()
+        {
+            return None;
+        }
+        Some(out)
+    }
+
+    /// Overwrite the edited ranges of the original file.
+    fn write_in_place(&self, shared: &Shared, out: &File) -> Result<()> {
+        // Pieces created by later edits or kept in the undo history might still refer
+        // to the ranges about to be overwritten, so move their old contents into RAM
+        // before anything is written
+        let mut overwritten = vec![];
+        let mut at = 0;
+        for p in self.pieces.iter() {
+            if p.ram().is_some() {
+                let mut old = vec![0; p.len as usize];
+                read_exact_at(&self.file, &mut old, at as u64)
+                    .context("failed to read original file")?;
+                overwritten.push(Overwritten {
+                    offset: at,
+                    data: old.into(),
+                });
+            }
+            at += p.len;
+        }
+        {
+            let mut loaded = shared.loaded.lock();
+            let loaded = &mut *loaded;
+            if let Some(p) = &mut loaded.pieces {
+                p.detach(&overwritten);
+            }
+            loaded.history.detach(&overwritten);
+            // Anything being loaded right now might read the new data
+            loaded.linemap.edited = true;
+            loaded.data.edited = true;
+        }
+
+        let total: i64 = overwritten.iter().map(|o| o.data.len() as i64).sum();
+        let mut at = 0;
+        let mut done = 0;
+        for p in self.pieces.iter() {
+            if let Some(data) = p.ram() {
+                write_all_at(out, data, at as u64).context("failed to write to file")?;
+                done += p.len;
+                shared.save_progress.store(Some(done as f32 / total as f32));
+            }
+            at += p.len;
+        }
+        out.sync_data().context("failed to flush file to disk")?;
+        Ok(())
+    }
+
+    /// Write a full copy of the buffer to a temporary file, and atomically rename
+    /// it over the original file.
+    /// Returns the new file.
+    fn write_copy(&self, shared: &Shared, conv: &mut Option<Converter>) -> Result<File> {
+        let path = &shared.path;
+        // Write to a temporary file in the same directory, so that it can be
+        // atomically renamed over the original file
+        let name = path.file_name().context("path has no file name")?;
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(".gaze-save");
+        let tmp_path = path.with_file_name(tmp_name);
+        let out = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        if let Ok(meta) = self.file.metadata() {
+            let _ = out.set_permissions(meta.permissions());
+        }
+        let out = match self.write(shared, out, conv) {
+            Ok(out) => out,
+            Err(err) => {
+                let _ = fs::remove_file(&tmp_path);
+                return Err(err);
+            }
+        };
+        if let Err(err) = fs::rename(&tmp_path, path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err).context("failed to replace original file");
+        }
+        #[cfg(unix)]
+        if let Some(dir) = path.parent() {
+            // Make sure the rename itself is durable
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            if let Ok(dir) = File::open(dir) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(out)
+    }
+
+    /// Write the buffer into the given file, and make sure it reaches the disk.
+    fn write(&self, shared: &Shared, out: File, conv: &mut Option<Converter>) -> Result<File> {
+        let total: i64 = self.pieces.iter().map(|p| p.len).sum();
+        let chunk = shared.k.f.read_size.max(1);
+        let mut buf = vec![0; chunk];
+        let mut out = io::BufWriter::with_capacity(chunk, out);
+        let mut done = 0;
+        for p in self.pieces.iter() {
+            match &p.src {
+                Source::File(off) => {
+                    // Copy unchanged data straight from the original file
+                    let mut at = 0;
+                    while at < p.len {
+                        let n = (p.len - at).min(chunk as i64) as usize;
+                        read_exact_at(&self.file, &mut buf[..n], (off + at) as u64)
+                            .context("failed to read original file")?;
+                        match conv {
+                            Some(c) => c.write(&mut out, &buf[..n])?,
+                            None => out.write_all(&buf[..n])?,
+                        }
+                        at += n as i64;
+                        done += n as i64;
+                        shared.save_progress.store(Some(done as f32 / total as f32));
+                    }
+                }
+                Source::Ram(..) => {
+                    match conv {
+                        Some(c) => c.write(&mut out, p.ram().unwrap())?,
+                        None => out.write_all(p.ram().unwrap())?,
+                    }
+                    done += p.len;
+                }
+                Source::Replace(r, off) => {
+                    // Put the replaced text together chunk by chunk
+                    let mut at = 0;
+                    while at < p.len {
+                        let n = (p.len - at).min(chunk as i64) as usize;
+                        r.read(off + at, &mut buf[..n], &mut |off, buf| {
+                            read_exact_at(&self.file, buf, off as u64)
+                                .context("failed to read original file")
+                        })?;
+                        match conv {
+                            Some(c) => c.write(&mut out, &buf[..n])?,
+                            None => out.write_all(&buf[..n])?,
+                        }
+                        at += n as i64;
+                        done += n as i64;
+                        shared.save_progress.store(Some(done as f32 / total as f32));
+                    }
+                }
+            }
+        }
+        if let Some(c) = conv {
+            c.finish(&mut out)?;
+        }
+        let out = out.into_inner().map_err(|e| e.into_error())?;
+        out.sync_all().context("failed to flush file to disk")?;
+        Ok(out)
+    }
+}
+
+/// Maps ranges of the old file to the same data in a full copy of the buffer.
+struct Relocation {
+    /// The file pieces of the copy, as `(old offset, new offset, length)`.
+    /// Sorted by old offset, without overlaps.
+    moved: Vec<(i64, i64, i64)>,
+    /// Data of the old file that is not in the copy, kept in RAM instead.
+    missing: Vec<Overwritten>,
+}
+impl Relocation {
+    /// Build the mapping out of the pieces that were written to the copy.
+    fn new(pieces: &[Piece]) -> Self {
+        let mut moved = vec![];
+        let mut at = 0;
+        for p in pieces {
+            if let Source::File(off) = p.src {
+                moved.push((off, at, p.len));
+            }
+            at += p.len;
+        }
+        moved.sort_unstable_by_key(|m| m.0);
+        // The same data may have been written more than once, any copy will do
+        let mut covered = 0;
+        moved.retain_mut(|(old, new, len)| {
+            if *old < covered {
+                let skip = (covered - *old).min(*len);
+                *old += skip;
+                *new += skip;
+                *len -= skip;
+            }
+            covered = covered.max(*old + *len);
+            *len > 0
+        });
+        Self {
+            moved,
+            missing: vec![],
+        }
+    }
+
+    /// The parts of the given range of the old file that are not in the copy.
+    fn gaps(&self, range: ops::Range<i64>) -> Vec<ops::Range<i64>> {
+        let mut out = vec![];
+        let mut at = range.start;
+        let mut i = self.moved.partition_point(|m| m.0 + m.2 <= at);
+        while at < range.end {
+            match self.moved.get(i) {
+                Some(&(old, _, len)) if old < range.end => {
+                    if at < old {
+                        out.push(at..old);
+                    }
+                    at = at.max(old + len);
+                    i += 1;
+                }
+                _ => {
+                    out.push(at..range.end);
+                    at = range.end;
+                }
+            }
+        }
+        out
+    }
+
+    /// Push the pieces that hold the same data as the given piece, but in the copy
+    /// or in RAM.
+    /// Returns `false` if some of the data is not available anymore.
+    fn relocate(&self, piece: &Piece, out: &mut Vec<Piece>) -> bool {
+        let mut detached = vec![];
+        piece.detach(&self.missing, &mut detached);
+        for p in detached {
+            let off = match &p.src {
+                Source::File(off) => *off,
+                Source::Ram(..) => {
+                    out.push(p);
+                    continue;
+                }
+                Source::Replace(r, off) => {
+                    match r.map_input(|p, out| self.relocate(p, out)) {
+                        Some(r) => out.push(Piece {
+                            len: p.len,
+                            src: Source::Replace(Arc::new(r), *off),
+                        }),
+                        None => return false,
+                    }
+                    continue;
+                }
+            };
+            let (mut at, end) = (off, off + p.len);
+            let mut i = self.moved.partition_point(|m| m.0 + m.2 <= at);
+            while at < end {
+                match self.moved.get(i) {
+                    Some(&(old, new, len)) if old <= at => {
+                        let n = (old + len).min(end) - at;
+                        out.push(Piece {
+                            len: n,
+                            src: Source::File(new + at - old),
+                        });
+                        at += n;
+                        i += 1;
+                    }
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Converts the line breaks of the buffer as it is written out.
+struct Converter {
+    /// The code unit size of the encoding.
+    /// Line breaks are always made up of whole units.
+    unit: usize,
+    enc: Encoding,
+    to: LineEnding,
+    /// The encoded line ending to write for every line break.
+    line_break: Vec<u8>,
+    /// The start of a code unit that was cut short by the end of a chunk.
+    carry: Vec<u8>,
+    /// Whether the last unit was a carriage return, which may be followed by a
+    /// line feed.
+    pending_cr: bool,
+    /// The amount of bytes read and written.
+    in_len: i64,
+    out_len: i64,
+    /// Offsets of the buffer to move along with the text, and where they end up.
+    offsets: [i64; 3],
+    mapped: [Option<i64>; 3],
+    /// Whether the output differs from the buffer at all.
+    changed: bool,
+    out: Vec<u8>,
+}
+impl Converter {
+    fn new(enc: Encoding, to: LineEnding, offsets: [i64; 3]) -> Self {
+        Self {
+            unit: enc.encode("\n").len(),
+            enc,
+            to,
+            line_break: enc.encode(to.as_str()),
+            carry: vec![],
+            pending_cr: false,
+            in_len: 0,
+            out_len: 0,
+            offsets,
+            mapped: [None; 3],
+            changed: false,
+            out: vec![],
+        }
+    }
+
+    fn write(&mut self, out: &mut impl Write, mut data: &[u8]) -> io::Result<()> {
+        self.out.clear();
+        // Complete the unit left over from the last chunk
+        if !self.carry.is_empty() {
+            let n = (self.unit - self.carry.len()).min(data.len());
+            self.carry.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.carry.len() == self.unit {
+                let u = mem::take(&mut self.carry);
+                self.push_unit(&u);
+            }
+        }
+        let whole = data.len() / self.unit * self.unit;
+        for u in data[..whole].chunks(self.unit) {
+            self.push_unit(u);
+        }
+        self.carry.extend_from_slice(&data[whole..]);
+        out.write_all(&self.out)
+    }
+
+    fn finish(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.out.clear();
+        if mem::take(&mut self.pending_cr) {
+            self.push_break(LineEnding::Cr);
+        }
+        let carry = mem::take(&mut self.carry);
+        self.map_offsets(self.in_len);
+        self.out.extend_from_slice(&carry);
+        self.in_len += carry.len() as i64;
+        self.out_len += carry.len() as i64;
+        self.map_offsets(i64::MAX);
+        out.write_all(&self.out)
+    }
+
+    fn push_unit(&mut self, u: &[u8]) {
+        let c = self.enc.decode(u).0;
+        let at = self.in_len;
+        self.in_len += u.len() as i64;
+        if mem::take(&mut self.pending_cr) {
+            if c == Ok(LF) {
+                // Offsets in the middle of a CRLF go to the start of the line break
+                self.map_offsets(at);
+                self.push_break(LineEnding::CrLf);
+                return;
+            }
+            self.push_break(LineEnding::Cr);
+        }
+        self.map_offsets(at);
+        match c {
+            Ok(CR) => self.pending_cr = true,
+            Ok(LF) => self.push_break(LineEnding::Lf),
+            _ => {
+                self.out.extend_from_slice(u);
+                self.out_len += u.len() as i64;
+            }
+        }
+    }
+
+    /// Write out the target line ending in place of the given one.
+    fn push_break(&mut self, from: LineEnding) {
+        self.changed |= from != self.to;
+        self.out.extend_from_slice(&self.line_break);
+        self.out_len += self.line_break.len() as i64;
+    }
+
+    /// Move the offsets up to the given input offset to the current output offset.
+    fn map_offsets(&mut self, upto: i64) {
+        for (&o, m) in self.offsets.iter().zip(self.mapped.iter_mut()) {
+            if o <= upto && m.is_none() {
+                *m = Some(self.out_len);
+            }
+        }
+    }
+}
+
+const CR: u32 = '\r' as u32;
+const LF: u32 = '\n' as u32;
+
+/// Get the last modification time of a file, if the platform supports it.
+pub(super) fn modified_time(file: &File) -> Option<SystemTime> {
+    file.metadata().and_then(|m| m.modified()).ok()
+}
+
+/// Write the entire buffer at the given file offset.
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            match std::os::windows::fs::FileExt::seek_write(file, buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
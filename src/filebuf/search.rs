@@ -0,0 +1,456 @@
+//! Searches through the entire buffer in the background.
+//!
+//! The manager thread scans the buffer chunk by chunk, regardless of what is
+//! loaded or linemapped, and publishes the matches as byte offsets.
+
+use memchr::memmem;
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::prelude::*;
+
+use super::encoding::Encoding;
+
+/// What to search for.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    /// Interpret the text as a regular expression, instead of a literal string.
+    pub regex: bool,
+    pub case_insensitive: bool,
+    /// Only match whole words, ie. matches must start and end at word boundaries.
+    pub whole_word: bool,
+}
+
+/// Finds the matches of a query within a chunk of data.
+pub(super) enum Matcher {
+    /// Plain literal search, which is much faster than going through the regex
+    /// engine.
+    /// The needle and the replacements are encoded with the given encoding.
+    Literal(memmem::Finder<'static>, Encoding),
+    Regex {
+        re: Regex,
+        max_len: usize,
+        /// Whether to expand `$name` capture group references in replacements.
+        expand: bool,
+        encoding: Encoding,
+    },
+}
+impl Matcher {
+    /// How many bytes of context before a chunk are needed to evaluate word
+    /// boundaries and line anchors at the start of the chunk.
+    /// One UTF-8 character is enough.
+    pub const LOOKBEHIND: usize = 4;
+
+    /// Build a matcher for the given query, to search text in the given encoding.
+    /// Regex matches are assumed to be at most `max_match_len` bytes long.
+    ///
+    /// The regex engine only understands UTF-8, so anything but plain literal
+    /// queries is refused in other encodings, except for ASCII queries in
+    /// Windows-1252, where ASCII looks the same.
+    pub fn new(query: &SearchQuery, encoding: Encoding, max_match_len: usize) -> Result<Matcher> {
+        ensure!(!query.text.is_empty(), "empty query");
+        if !query.regex && !query.case_insensitive && !query.whole_word {
+            return Ok(Matcher::Literal(
+                memmem::Finder::new(&encoding.encode(&query.text)).into_owned(),
+                encoding,
+            ));
+        }
+        match encoding {
+            Encoding::Utf8 => {}
+            Encoding::Windows1252 => ensure!(
+                query.text.is_ascii(),
+                "regex, case-insensitive and whole-word searches only work with ASCII \
+                queries in {} text",
+                encoding.name()
+            ),
+            Encoding::Utf16Le | Encoding::Utf16Be => bail!(
+                "regex, case-insensitive and whole-word searches do not work in {} text",
+                encoding.name()
+            ),
+        }
+        let mut pattern = if query.regex {
+            query.text.clone()
+        } else {
+            regex::escape(&query.text)
+        };
+        if query.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        let re = RegexBuilder::new(&pattern)
+            .case_insensitive(query.case_insensitive)
+            .multi_line(true)
+            .build()?;
+        Ok(Matcher::Regex {
+            re,
+            max_len: max_match_len.max(query.text.len()).max(1),
+            expand: query.regex,
+            encoding,
+        })
+    }
+
+    /// The maximum length of a match.
+    /// Consecutive chunks must overlap by one byte less than this, so that
+    /// matches that cross chunk boundaries are not missed.
+    pub fn max_len(&self) -> usize {
+        match self {
+            Matcher::Literal(finder, _) => finder.needle().len(),
+            Matcher::Regex { max_len, .. } => *max_len,
+        }
+    }
+
+    /// Find all non-overlapping, non-empty matches within `data[from..]`.
+    /// The data before `from` is only used as context for lookbehind.
+    pub fn find_all(&self, data: &[u8], from: usize, mut f: impl FnMut(ops::Range<usize>)) {
+        match self {
+            Matcher::Literal(finder, _) => {
+                let n = finder.needle().len();
+                for i in finder.find_iter(&data[from..]) {
+                    f(from + i..from + i + n);
+                }
+            }
+            Matcher::Regex { re, .. } => {
+                let mut at = from;
+                while at <= data.len() {
+                    let m = match re.find_at(data, at) {
+                        Some(m) => m,
+                        None => break,
+                    };
+                    if m.is_empty() {
+                        // Empty matches are useless to jump to
+                        at = m.end() + 1;
+                    } else {
+                        f(m.range());
+                        at = m.end();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Check whether `data[m]` is a match on its own, which is the case for all
+    /// matches reported by `find_all`.
+    pub fn is_match(&self, data: &[u8], m: ops::Range<usize>) -> bool {
+        match self {
+            Matcher::Literal(finder, _) => data.get(m) == Some(finder.needle()),
+            Matcher::Regex { re, .. } => {
+                m.start < m.end && re.find_at(data, m.start).map(|f| f.range()) == Some(m)
+            }
+        }
+    }
+
+    /// The encoding of the text that this matcher searches.
+    pub fn encoding(&self) -> Encoding {
+        match self {
+            Matcher::Literal(_, encoding) => *encoding,
+            Matcher::Regex { encoding, .. } => *encoding,
+        }
+    }
+
+    /// Append the replacement for the match `data[m]` to `out`.
+    /// For regex queries, `$1` and `${name}` in the replacement refer to capture
+    /// groups.
+    pub fn replace(&self, data: &[u8], m: ops::Range<usize>, replacement: &str, out: &mut Vec<u8>) {
+        match self {
+            Matcher::Regex {
+                re, expand: true, ..
+            } if replacement.contains('$') => {
+                if let Some(caps) = re.captures_at(data, m.start) {
+                    caps.expand(&self.encoding().encode(replacement), out);
+                }
+            }
+            _ => out.extend_from_slice(&self.encoding().encode(replacement)),
+        }
+    }
+}
+
+/// How far apart matches that were counted but not kept can be and still be
+/// recorded together.
+/// Edits anywhere within a record count all of its matches again, but records
+/// that are too fine take up too much memory.
+const DROPPED_SPAN: i64 = 1024 * 1024;
+
+/// Matches that were counted but not kept, all of them within `range`.
+#[derive(Clone, PartialEq, Debug)]
+pub(super) struct Dropped {
+    pub range: ops::Range<i64>,
+    pub count: u64,
+}
+impl Dropped {
+    fn merge(&mut self, other: Dropped) {
+        self.range = self.range.start.min(other.range.start)..self.range.end.max(other.range.end);
+        self.count += other.count;
+    }
+}
+
+/// The state of a search, shared between the manager thread and the frontend.
+pub struct SearchResults {
+    pub query: SearchQuery,
+    /// The matches found so far, sorted by offset.
+    /// Only up to a limited amount of matches are kept.
+    pub matches: Vec<ops::Range<i64>>,
+    /// The total amount of matches found so far, including any that were not kept.
+    pub count: u64,
+    /// How much of the buffer has been scanned so far.
+    pub scanned: i64,
+    /// The length of the buffer being scanned.
+    pub total: i64,
+    /// Why the search failed, if it did.
+    pub error: Option<String>,
+    /// The version of the piece table that the matches refer to.
+    /// If the buffer is edited, the matches are moved along with the edit.
+    pub(super) version: u64,
+    /// Ranges behind `scanned` that have to be scanned again, because they were
+    /// edited.
+    /// Sorted and not overlapping.
+    pub(super) rescan: Vec<ops::Range<i64>>,
+    /// How far before an edit a match might start and still overlap the edit,
+    /// that is, one byte less than the maximum length of a match.
+    pub(super) margin: i64,
+    /// Roughly where the matches that were only counted are, so that edits only
+    /// have to count the ones around them again.
+    /// Sorted and not overlapping.
+    pub(super) dropped: Vec<Dropped>,
+}
+impl SearchResults {
+    pub(super) fn new(query: SearchQuery, version: u64, total: i64) -> Self {
+        Self {
+            query,
+            matches: vec![],
+            count: 0,
+            scanned: 0,
+            total,
+            error: None,
+            version,
+            rescan: vec![],
+            margin: 0,
+            dropped: vec![],
+        }
+    }
+
+    /// Throw away the matches and scan the buffer again from the start.
+    pub(super) fn restart(&mut self, version: u64, total: i64) {
+        self.matches.clear();
+        self.count = 0;
+        self.scanned = 0;
+        self.total = total;
+        self.version = version;
+        self.rescan.clear();
+        self.dropped.clear();
+    }
+
+    /// Move the matches along with an edit that replaced the given range by `len`
+    /// bytes, and scan the text around the edit again.
+    pub(super) fn edited(&mut self, range: ops::Range<i64>, len: i64, version: u64) {
+        let (l, r) = (range.start, range.end);
+        let delta = len - (r - l);
+        let total = self.total + delta;
+        self.version = version;
+        self.total = total;
+        // Offsets within the replaced range end up at either side of the new text
+        let start = |off: i64| if off >= r { off + delta } else { off.min(l) };
+        let end = |off: i64| {
+            if off >= r {
+                off + delta
+            } else if off > l {
+                l + len
+            } else {
+                off
+            }
+        };
+        for m in self.matches.iter_mut() {
+            *m = start(m.start)..end(m.end);
+        }
+        for w in self.rescan.iter_mut() {
+            *w = start(w.start)..end(w.end);
+        }
+        for d in self.dropped.iter_mut() {
+            d.range = start(d.range.start)..end(d.range.end);
+        }
+        // Whatever was not scanned yet is still not scanned, even if it moved
+        self.scanned = if self.scanned > r {
+            self.scanned + delta
+        } else {
+            self.scanned.min(l)
+        };
+        // Matches might appear or disappear anywhere around the new text, including
+        // the context used to check word boundaries
+        let ctx = Matcher::LOOKBEHIND as i64;
+        let mut ws = (l - ctx - self.margin).max(0);
+        let mut we = (l + len + ctx + self.margin).min(total);
+        // Matches that overlap the window are counted again, so the window has to
+        // cover them entirely, including the ones that were only counted
+        let (a, b, i, j) = loop {
+            let a = self.matches.partition_point(|m| m.end <= ws);
+            let b = self.matches.partition_point(|m| m.start < we);
+            let i = self.dropped.partition_point(|d| d.range.end <= ws);
+            let j = self.dropped.partition_point(|d| d.range.start < we);
+            let old = (ws, we);
+            if a < b {
+                ws = ws.min(self.matches[a].start);
+                we = we.max(self.matches[b - 1].end);
+            }
+            if i < j {
+                ws = ws.min(self.dropped[i].range.start);
+                we = we.max(self.dropped[j - 1].range.end);
+            }
+            if (ws, we) == old {
+                break (a, b, i, j);
+            }
+        };
+        self.count -= (b - a) as u64;
+        self.matches.drain(a..b);
+        self.count -= self.dropped.drain(i..j).map(|d| d.count).sum::<u64>();
+        if ws >= self.scanned {
+            // The scan will get there anyway
+        } else if we >= self.scanned {
+            // The main scan covers any windows past this point again
+            self.scanned = ws;
+            for w in self.rescan.iter_mut() {
+                w.end = w.end.min(ws);
+            }
+        } else {
+            let i = self.rescan.partition_point(|w| w.end < ws);
+            let j = self.rescan.partition_point(|w| w.start <= we);
+            if i < j {
+                ws = ws.min(self.rescan[i].start);
+                we = we.max(self.rescan[j - 1].end);
+            }
+            self.rescan.splice(i..j, std::iter::once(ws..we));
+        }
+        self.rescan.retain(|w| w.start < w.end);
+    }
+
+    /// Add the matches found while scanning the first range in `rescan` again,
+    /// from `start` up to `next`.
+    pub(super) fn add_rescanned(
+        &mut self,
+        start: i64,
+        next: i64,
+        found: Vec<ops::Range<i64>>,
+        dropped: Option<Dropped>,
+        max_matches: usize,
+    ) {
+        let w = &mut self.rescan[0];
+        w.start = next;
+        if w.start >= w.end {
+            self.rescan.remove(0);
+        }
+        // The last match might run into the next one, which then no longer counts
+        let i = self.matches.partition_point(|m| m.start < start);
+        let j = self.matches.partition_point(|m| m.start < next);
+        self.count -= (j - i) as u64;
+        self.count += found.len() as u64;
+        self.matches.splice(i..j, found);
+        // It might also run into matches that were only counted, which then have to
+        // be counted again
+        let i = self.dropped.partition_point(|d| d.range.end <= start);
+        let j = self.dropped.partition_point(|d| d.range.start < next);
+        if i < j {
+            let end = self.dropped[j - 1].range.end;
+            self.count -= self.dropped.drain(i..j).map(|d| d.count).sum::<u64>();
+            if end > next {
+                match self.rescan.first_mut() {
+                    Some(w) if w.start == next => w.end = w.end.max(end),
+                    _ => self.rescan.insert(0, next..end),
+                }
+                while self.rescan.len() > 1 && self.rescan[1].start <= self.rescan[0].end {
+                    let w = self.rescan.remove(1);
+                    self.rescan[0].end = self.rescan[0].end.max(w.end);
+                }
+            }
+        }
+        if let Some(d) = dropped {
+            self.count += d.count;
+            self.add_dropped(d);
+        }
+        if self.matches.len() > max_matches {
+            let extra = self.matches.split_off(max_matches);
+            for m in extra {
+                self.add_dropped(Dropped { range: m, count: 1 });
+            }
+        }
+        // Keep the main scan from finding the last match again
+        self.scanned = self.scanned.max(next);
+    }
+
+    /// Record matches that were counted but not kept.
+    /// Records that overlap are merged, and so are records that are close enough,
+    /// to keep their amount down.
+    fn add_dropped(&mut self, mut d: Dropped) {
+        let close = |a: &ops::Range<i64>, b: &ops::Range<i64>| {
+            a.end > b.start || a.end.max(b.end) - a.start <= DROPPED_SPAN
+        };
+        let mut i = self
+            .dropped
+            .partition_point(|o| o.range.start < d.range.start);
+        if i > 0 && close(&self.dropped[i - 1].range, &d.range) {
+            i -= 1;
+            d.merge(self.dropped.remove(i));
+        }
+        while i < self.dropped.len() && close(&d.range, &self.dropped[i].range) {
+            d.merge(self.dropped.remove(i));
+        }
+        self.dropped.insert(i, d);
+    }
+
+    pub fn is_done(&self) -> bool {
+        (self.scanned >= self.total && self.rescan.is_empty()) || self.error.is_some()
+    }
+
+    /// How much of the buffer has been scanned, between 0 and 1.
+    pub fn progress(&self) -> f32 {
+        if self.total <= 0 {
+            1.
+        } else {
+            (self.scanned as f64 / self.total as f64) as f32
+        }
+    }
+
+    /// Add the matches found while scanning a chunk, along with the matches that
+    /// were found but not kept.
+    pub(super) fn add(
+        &mut self,
+        found: Vec<ops::Range<i64>>,
+        dropped: Option<Dropped>,
+        max_matches: usize,
+    ) {
+        self.count += found.len() as u64;
+        let room = max_matches.saturating_sub(self.matches.len());
+        let mut found = found.into_iter();
+        self.matches.extend(found.by_ref().take(room));
+        for m in found {
+            self.add_dropped(Dropped { range: m, count: 1 });
+        }
+        if let Some(d) = dropped {
+            self.count += d.count;
+            self.add_dropped(d);
+        }
+    }
+
+    /// Find the first match after the given selection, wrapping around to the
+    /// start of the buffer.
+    pub fn next_match(&self, sel: ops::Range<i64>) -> Option<ops::Range<i64>> {
+        let i = self.matches.partition_point(|m| {
+            if sel.start < sel.end {
+                m.start <= sel.start
+            } else {
+                m.start < sel.start
+            }
+        });
+        self.matches
+            .get(i)
+            .or_else(|| self.matches.first())
+            .cloned()
+    }
+
+    /// Find the last match before the given selection, wrapping around to the end
+    /// of the buffer.
+    pub fn prev_match(&self, sel: ops::Range<i64>) -> Option<ops::Range<i64>> {
+        let i = self.matches.partition_point(|m| m.start < sel.start);
+        match i {
+            0 => self.matches.last(),
+            i => self.matches.get(i - 1),
+        }
+        .cloned()
+    }
+}
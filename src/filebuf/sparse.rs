@@ -37,7 +37,8 @@ macro_rules! lock_sparse {
 /// Holds sparse segments of data loaded from a potentially huge file.
 pub struct SparseData {
     pub(super) segments: Vec<SparseSegment>,
-    /// If set to another value, it should only increase!
+    /// The size of the buffer.
+    /// Only changes when the buffer is edited.
     pub(super) file_size: i64,
     /// Start dropping far away data to keep memory usage under this amount.
     pub(super) max_loaded: usize,
@@ -47,6 +48,10 @@ pub struct SparseData {
     /// of this length could take way too long, and instead allocate a new copy
     /// while off the lock.
     pub(super) realloc_threshold: usize,
+    /// Set whenever the data is edited.
+    /// Any offsets or segment indices held by the manager thread across locks may be
+    /// stale if this flag is set, so any multi-lock operation must abort.
+    pub(super) edited: bool,
 }
 impl SparseData {
     pub fn new(max_loaded: usize, merge_batch_size: usize, realloc_threshold: usize) -> Self {
@@ -56,6 +61,7 @@ impl SparseData {
             max_loaded,
             merge_batch_size,
             realloc_threshold,
+            edited: false,
         }
     }
 
@@ -172,6 +178,9 @@ impl SparseData {
     /// Avoids locking the loaded data for long periods, even with huge segments.
     fn merge_segments(handle: SparseHandle, l_idx: usize, force_into_left: Option<bool>) {
         lock_sparse!(handle, store, sparse);
+        if sparse.edited {
+            return;
+        }
         fn get_two(sparse: &mut SparseData, i: usize) -> (&mut SparseSegment, &mut SparseSegment) {
             let (l, r) = sparse.segments.split_at_mut(i + 1);
             (l.last_mut().unwrap(), r.first_mut().unwrap())
@@ -205,10 +214,16 @@ impl SparseData {
                     data: Demem::with_capacity(0, realloc_size),
                 };
             });
+            if sparse.edited {
+                return;
+            }
             sparse.segments.insert(l_idx, seg);
             lock_sparse!(handle, store, sparse => unlocked {
                 Self::merge_segments(handle, l_idx, Some(true));
             });
+            if sparse.edited {
+                return;
+            }
         } else if !into_left && r_realloc {
             // Create a segment with enough capacity for both, and merge right segment into it
             let off =
@@ -220,10 +235,16 @@ impl SparseData {
                     data: Demem::with_capacity(realloc_size, 0),
                 };
             });
+            if sparse.edited {
+                return;
+            }
             sparse.segments.insert(l_idx + 2, seg);
             lock_sparse!(handle, store, sparse => unlocked {
                 Self::merge_segments(handle, l_idx+1, Some(false));
             });
+            if sparse.edited {
+                return;
+            }
         }
         // Copy data from one segment to the other
         // Make sure to bump the mutex regularly
@@ -251,6 +272,10 @@ impl SparseData {
             }
             // Bump the mutex to allow the main thread to access data with low latency
             lock_sparse!(handle, store, sparse => bump);
+            if sparse.edited {
+                // The segments were left touching, they will be merged later
+                return;
+            }
         }
         // Remove the empty segment
         let empty = sparse
@@ -269,6 +294,9 @@ impl SparseData {
         }
         // First, insert the data
         lock_sparse!(handle, store, sparse);
+        if sparse.edited {
+            return;
+        }
         let mut i = sparse.insert_segment(offset, data);
         if i > 0
             && sparse.segments[i - 1].offset + sparse.segments[i - 1].data.len() as i64
@@ -277,6 +305,9 @@ impl SparseData {
             lock_sparse!(handle, store, sparse => unlocked {
                 Self::merge_segments(handle, i-1, None);
             });
+            if sparse.edited {
+                return;
+            }
             i -= 1;
         }
         if i + 1 < sparse.segments.len()
@@ -340,6 +371,10 @@ impl SparseData {
                     });
                     // Then, copy live data to this new location
                     loop {
+                        if sparse.edited {
+                            // The segment might have moved, give up on shrinking
+                            break;
+                        }
                         let s = &sparse.segments[i];
                         let batch_size = sparse.merge_batch_size.min(size - new.len());
                         new.extend_right(&s.data[new.len()..new.len() + batch_size]);
@@ -349,6 +384,10 @@ impl SparseData {
                         // Be mindful to keep bumping the mutex
                         lock_sparse!(handle, store, sparse => bump);
                     }
+                    if sparse.edited {
+                        free_later.push(new);
+                        break;
+                    }
                     // Finally, replace the old container with the new tight container
                     // This will drop the old container and free its memory!
                     // (So do it off the lock)
@@ -399,6 +438,189 @@ impl SparseData {
         }
     }
 
+    /// Merge all touching segments.
+    /// Edits can leave segments touching, so this should be called periodically.
+    pub fn merge_touching(handle: SparseHandle) {
+        let mut i = 0;
+        loop {
+            {
+                lock_sparse!(handle, sparse);
+                // Segment indices are looked up from scratch on every lock
+                sparse.edited = false;
+                loop {
+                    if i + 1 >= sparse.segments.len() {
+                        return;
+                    }
+                    let s = &sparse.segments[i];
+                    if s.offset + s.data.len() as i64 == sparse.segments[i + 1].offset {
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            Self::merge_segments(handle, i, None);
+        }
+    }
+
+    /// Update the loaded data to reflect that the bytes in `range` were replaced by
+    /// `ins`, shifting everything after the range.
+    ///
+    /// Never copies more than `merge_batch_size` bytes of existing data, so it is fast
+    /// even with huge segments.
+    /// If that is not enough to keep a segment contiguous, the data far away from the
+    /// edit is dropped, and left for the manager thread to load again.
+    pub fn splice(&mut self, range: ops::Range<i64>, ins: &[u8]) {
+        let (l, r) = (range.start, range.end);
+        let delta = ins.len() as i64 - (r - l);
+        // Merges that were interrupted by an edit might leave empty segments around
+        self.segments.retain(|s| !s.data.is_empty());
+        let mut i = self.find_after(l);
+        self.file_size += delta;
+        self.edited = true;
+
+        if let Some(s) = self.segments.get(i) {
+            if s.offset < l && s.offset + s.data.len() as i64 > r {
+                // The edit is strictly inside a segment
+                for s in self.segments[i + 1..].iter_mut() {
+                    s.offset += delta;
+                }
+                self.splice_within(i, range, ins);
+                return;
+            }
+        }
+
+        // Trim the segments that overlap the edited range
+        let mut prefix = false;
+        while let Some(s) = self.segments.get_mut(i) {
+            let end = s.offset + s.data.len() as i64;
+            if s.offset > r {
+                break;
+            }
+            if s.offset < l {
+                // Keep the data before the edit
+                s.data.consume_right((end - l) as usize);
+                prefix = true;
+                i += 1;
+            } else if end > r {
+                // Keep the data after the edit
+                s.data.consume_left((r - s.offset) as usize);
+                s.offset = r;
+                break;
+            } else {
+                // The segment is entirely within the edit
+                self.segments.remove(i);
+            }
+        }
+
+        // Shift everything after the edit
+        for s in self.segments[i..].iter_mut() {
+            s.offset += delta;
+        }
+
+        // Add the inserted data, preferrably by extending an adjacent segment
+        if ins.is_empty() {
+            return;
+        }
+        let thres = self.realloc_threshold;
+        let cheap = |d: &Demem, spare: usize| d.capacity() < thres || spare >= ins.len();
+        if prefix
+            && cheap(
+                &self.segments[i - 1].data,
+                self.segments[i - 1].data.spare_right(),
+            )
+        {
+            self.segments[i - 1].data.extend_right(ins);
+        } else if let Some(s) = self
+            .segments
+            .get_mut(i)
+            .filter(|s| s.offset == l + ins.len() as i64 && cheap(&s.data, s.data.spare_left()))
+        {
+            s.data.extend_left(ins);
+            s.offset = l;
+        } else {
+            self.segments.insert(
+                i,
+                SparseSegment {
+                    offset: l,
+                    data: ins.to_vec().into(),
+                },
+            );
+        }
+    }
+
+    /// Replace the given range by `len` bytes of data that is not loaded.
+    /// Any data around the edit that does not fit is dropped, and loaded again later.
+    pub fn splice_unloaded(&mut self, range: ops::Range<i64>, len: i64) {
+        let l = range.start;
+        self.splice(range, &[]);
+        // Open up a gap for the unloaded data
+        for s in self.segments.iter_mut() {
+            let end = s.offset + s.data.len() as i64;
+            if s.offset >= l {
+                s.offset += len;
+            } else if end > l {
+                s.data.consume_right((end - l) as usize);
+            }
+        }
+        self.file_size += len;
+    }
+
+    /// Replace a range that is strictly inside segment `i`, with data on both sides.
+    /// Only moves the data on the smaller side of the edit.
+    fn splice_within(&mut self, i: usize, range: ops::Range<i64>, ins: &[u8]) {
+        let (batch, thres) = (self.merge_batch_size, self.realloc_threshold);
+        let s = &mut self.segments[i];
+        let h = (range.start - s.offset) as usize;
+        let d = (range.end - range.start) as usize;
+        let t = s.data.len() - h - d;
+        if h <= t {
+            // Move the head over to the tail
+            let keep = h.min(batch);
+            let head = s.data[h - keep..h].to_vec();
+            s.data.consume_left(h + d);
+            if s.data.capacity() < thres || s.data.spare_left() >= keep + ins.len() {
+                s.data.extend_left(ins);
+                s.data.extend_left(&head);
+                s.offset = range.start - keep as i64;
+            } else {
+                s.offset = range.start + ins.len() as i64;
+                let mut data = head;
+                data.extend_from_slice(ins);
+                self.segments.insert(
+                    i,
+                    SparseSegment {
+                        offset: range.start - keep as i64,
+                        data: data.into(),
+                    },
+                );
+            }
+        } else {
+            // Move the tail over to the head
+            let keep = t.min(batch);
+            let tail = s.data[h + d..h + d + keep].to_vec();
+            s.data.consume_right(d + t);
+            if s.data.capacity() < thres || s.data.spare_right() >= keep + ins.len() {
+                s.data.extend_right(ins);
+                s.data.extend_right(&tail);
+            } else {
+                let mut data = ins.to_vec();
+                data.extend_from_slice(&tail);
+                self.segments.insert(
+                    i + 1,
+                    SparseSegment {
+                        offset: range.start,
+                        data: data.into(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// The amount of bytes currently loaded.
+    pub fn loaded_len(&self) -> i64 {
+        self.segments.iter().map(|s| s.data.len() as i64).sum()
+    }
+
     /// Find the longest contiguous segment of data starting at `at`.
     pub fn longest_prefix(&self, starting_at: i64) -> &[u8] {
         for s in self.segments.iter().rev() {
@@ -0,0 +1,66 @@
+//! Copies a stream of unknown length, such as a pipe, into a temporary file.
+//!
+//! The rest of the backend relies on being able to read any range of the file at any
+//! time, which a stream cannot do.
+//! Instead, the stream is written to an unnamed file in the background, and the
+//! buffer grows as the data arrives, just like when following a file.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::prelude::*;
+
+use super::Shared;
+
+/// Create a temporary file that is deleted as soon as it is closed.
+pub(super) fn anonymous_file() -> Result<File> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "gaze-spool-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("failed to create spool file \"{}\"", path.display()))?;
+    // On unix the data stays around while the file is open
+    // Elsewhere open files cannot be removed, so the file is left behind
+    let _ = fs::remove_file(&path);
+    Ok(file)
+}
+
+/// Copy the input into the spool file until it runs out.
+/// The manager thread picks up the new data periodically, and is woken up once the
+/// input is over so it can pick up the last of it.
+pub(super) fn spool(
+    shared: &Shared,
+    mut input: Box<dyn Read + Send>,
+    mut out: File,
+    manager: thread::Thread,
+) {
+    let mut buf = vec![0; 64 * 1024];
+    let mut total = 0;
+    let res = loop {
+        if shared.stop.load() {
+            break Ok(());
+        }
+        let n = match input.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err).context("failed to read input"),
+        };
+        if let Err(err) = out.write_all(&buf[..n]) {
+            break Err(err).context("failed to write spool file");
+        }
+        total += n;
+    };
+    shared.spooling.store(false);
+    manager.unpark();
+    match res {
+        Ok(()) => println!("finished reading {} bytes of input", total),
+        Err(err) => shared.notify(true, format!("{:#}", err)),
+    }
+}
@@ -1,8 +1,13 @@
 use crate::{
+    cfg::Cfg,
     filebuf::{
-        linemap::{decode_utf8, LineMapper},
+        compress::{Codec, Decompressed},
+        linemap::LineMapper,
+        persist::{Journal, JournalKey, JournalWriter},
+        piece::{self, PieceTable, Source},
         sparse::SparseData,
-        LoadedData,
+        EditInfo, Encoding, FileBuffer, FilePos, FileRect, LineEnding, LoadedData, PasteRequest,
+        SearchQuery, ViewState,
     },
     prelude::*,
 };
@@ -14,12 +19,13 @@ struct TestInst {
 
 fn init(fsize: i64, max_mem: usize) -> TestInst {
     let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
-    let mut loaded = LoadedData::new(usize::MAX, 64, 0, None);
+    let mut loaded = LoadedData::new(usize::MAX, 64, 0, usize::MAX, None);
     loaded.linemap.file_size = fsize;
     loaded.data.file_size = fsize;
+    loaded.pieces = Some(PieceTable::new(fsize));
     TestInst {
         loaded: Mutex::new(loaded),
-        linemapper: LineMapper::new(CharLayout::new(&font), fsize, max_mem, 3),
+        linemapper: LineMapper::new(CharLayout::new(&font, 4), fsize, max_mem, 3),
     }
 }
 
@@ -46,8 +52,8 @@ fn assert_sanity(t: &TestInst) {
     let loaded = t.loaded.lock();
     for s in loaded.linemap.segments.iter() {
         assert!(s.start < s.end);
-        assert_eq!(s.start, s.anchors.front().unwrap().offset);
-        assert_eq!(s.end, s.anchors.back().unwrap().offset);
+        assert_eq!(s.start, s.anchors.front().unwrap().off(s));
+        assert_eq!(s.end, s.anchors.back().unwrap().off(s));
         assert!(s.first_absolute <= s.anchors.len());
         assert_eq!(s.anchors.front().unwrap().y_offset + s.base_y, 0);
         for i in 1..s.anchors.len() {
@@ -70,15 +76,15 @@ fn assert_linemap_segs_eq(t: &TestInst, segs: Vec<SegSpec>) {
     for (got, ex) in lm.segments.iter().zip(segs.iter()) {
         assert_eq!(got.start, ex.start);
         assert_eq!(got.end, ex.end);
-        assert_eq!(got.anchors.front().unwrap().offset, ex.start);
-        assert_eq!(got.anchors.back().unwrap().offset, ex.end);
+        assert_eq!(got.anchors.front().unwrap().off(got), ex.start);
+        assert_eq!(got.anchors.back().unwrap().off(got), ex.end);
         assert_eq!(got.start == 0, ex.abs_y);
         if ex.abs_x_since > ex.end {
             assert_eq!(got.first_absolute, got.anchors.len());
         } else {
-            assert!(got.anchors[got.first_absolute].offset >= ex.abs_x_since);
+            assert!(got.anchors[got.first_absolute].off(got) >= ex.abs_x_since);
             if got.first_absolute > 0 {
-                assert!(got.anchors[got.first_absolute - 1].offset < ex.abs_x_since);
+                assert!(got.anchors[got.first_absolute - 1].off(got) < ex.abs_x_since);
             }
         }
         assert_eq!(got.widest_line, ex.widest);
@@ -106,7 +112,7 @@ fn assert_full_data_loaded(t: &TestInst, data: &[u8]) {
     let mut w = 0f64;
     let mut idx = 0;
     while idx < data.len() {
-        let (c, adv) = decode_utf8(&data[idx..]);
+        let (c, adv) = t.linemapper.encoding.decode_text(&data[idx..]);
         let c_i = idx;
         idx += adv;
         let x_i = x;
@@ -118,7 +124,7 @@ fn assert_full_data_loaded(t: &TestInst, data: &[u8]) {
                 println!("char {} is newline", c_i);
             }
             c => {
-                x += t.linemapper.layout.advance_for(c);
+                x += t.linemapper.layout.advance_at(c, x);
                 println!("char [{}, {}) uses x [{}, {})", c_i, idx, x_i, x);
             }
         }
@@ -142,6 +148,104 @@ fn assert_full_data_loaded(t: &TestInst, data: &[u8]) {
     assert_sparse_data_eq(&t, vec![(0, data.to_vec())]);
 }
 
+/// Like `assert_full_data_loaded`, but allows line widths to be overestimated and
+/// segments to have more anchors than necessary, as happens after edits.
+fn assert_edited_data_loaded(t: &TestInst, data: &[u8]) {
+    assert_sanity(t);
+    let mut pos = vec![None; data.len() + 1];
+    let mut x = 0.;
+    let mut y = 0;
+    let mut w = 0f64;
+    let mut idx = 0;
+    while idx < data.len() {
+        pos[idx] = Some((y, x));
+        let (c, adv) = t.linemapper.encoding.decode_text(&data[idx..]);
+        idx += adv;
+        match c.unwrap_or(LineMapper::REPLACEMENT_CHAR) {
+            LineMapper::NEWLINE => {
+                w = w.max(x);
+                x = 0.;
+                y += 1;
+            }
+            c => x += t.linemapper.layout.advance_at(c, x),
+        }
+    }
+    pos[idx] = Some((y, x));
+    w = w.max(x);
+    {
+        let lm = &t.loaded.lock().linemap;
+        assert_eq!(lm.file_size, data.len() as i64);
+        assert_eq!(lm.segments.len(), 1);
+        let s = &lm.segments[0];
+        assert_eq!((s.start, s.end), (0, data.len() as i64));
+        assert_eq!(s.first_absolute, 0);
+        assert!(s.widest_line >= w - 1e-6);
+        for a in s.anchors.iter() {
+            let (y, x) = pos[a.off(s) as usize].expect("anchor not on a char boundary");
+            assert_eq!(a.y(s), y, "wrong y for anchor at {}", a.off(s));
+            assert!(
+                (a.x(s) - x).abs() < 1e-6,
+                "wrong x for anchor at {}",
+                a.off(s)
+            );
+        }
+    }
+    assert_sparse_data_eq(t, vec![(0, data.to_vec())]);
+}
+
+/// Pick a random edit that keeps the data valid UTF-8, apply it to `data` and
+/// return it.
+fn rand_edit(rng: &mut TestRng, data: &mut Vec<u8>) -> (ops::Range<i64>, Vec<u8>) {
+    let snap = |data: &[u8], mut i: usize| {
+        while i < data.len() && data[i] & 0xC0 == 0x80 {
+            i += 1;
+        }
+        i
+    };
+    let l = snap(data, rng.gen_range(0..=data.len()));
+    let r = snap(data, rng.gen_range(l..=(l + 64).min(data.len())));
+    let ins = if rng.gen_bool(0.3) {
+        vec![]
+    } else {
+        rand_utf8(rng.gen(), rng.gen_range(1..64))
+    };
+    data.splice(l..r, ins.iter().copied());
+    (l as i64..r as i64, ins)
+}
+
+/// An edit that is not typed in, for tests that do not care about the selection.
+const UNTYPED: EditInfo = EditInfo {
+    sel_before: [0; 2],
+    sel_after: [0; 2],
+    typed: false,
+};
+
+/// Load the data in `[l, r)` as the manager thread would, after an edit.
+fn load_range(t: &TestInst, data: &[u8], l: i64, r: i64) {
+    {
+        let mut loaded = t.loaded.lock();
+        loaded.linemap.edited = false;
+        loaded.data.edited = false;
+    }
+    let subdata = &data[l as usize..r as usize];
+    t.linemapper
+        .process_data(&t.loaded, 0, data, l as usize..r as usize);
+    SparseData::insert_data(&t.loaded, l, subdata.to_vec());
+    t.linemapper.merge_touching(&t.loaded);
+    SparseData::merge_touching(&t.loaded);
+}
+
+/// Load everything that is missing, as the manager thread would.
+fn load_all(t: &TestInst, data: &[u8], max_len: i64) {
+    loop {
+        let ((l, r), _store) = t.loaded.lock().get_range_to_load(max_len, 100000, 0);
+        if l >= r {
+            break;
+        }
+        load_range(t, data, l, r);
+    }
+}
+
 /// The ranges should cover all data.
 fn test_in_order(
     data: &[u8],
@@ -151,7 +255,8 @@ fn test_in_order(
     let t = init(data.len() as i64, max_mem);
     for r in ranges {
         let subdata = &data[r.start as usize..r.end as usize];
-        t.linemapper.process_data(&t.loaded, r.start, subdata);
+        t.linemapper
+            .process_data(&t.loaded, 0, data, r.start as usize..r.end as usize);
         SparseData::insert_data(&t.loaded, r.start, subdata.to_vec());
         assert_sanity(&t);
     }
@@ -189,6 +294,43 @@ fn rand_utf8(seed: u64, len: i64) -> Vec<u8> {
     data.into_bytes()
 }
 
+/// Random ASCII text that breaks lines with a mix of LF, CRLF and lone CR.
+fn rand_lines(seed: u64, len: i64) -> Vec<u8> {
+    let mut rng = TestRng::seed_from_u64(seed);
+    let mut data = Vec::new();
+    while (data.len() as i64) < len {
+        match rng.gen_range(0..40) {
+            0 => data.push(b'\n'),
+            1 => data.extend_from_slice(b"\r\n"),
+            2 => data.push(b'\r'),
+            _ => data.push(rng.gen_range(b'a'..=b'z')),
+        }
+    }
+    data.truncate(len as usize);
+    data
+}
+
+/// Random text with tabs scattered through its lines.
+fn rand_tabbed(seed: u64, len: i64) -> Vec<u8> {
+    let mut rng = TestRng::seed_from_u64(seed);
+    let mut data = String::new();
+    while (data.len() as i64) < len {
+        match rng.gen_range(0..60) {
+            0 => data.push('\n'),
+            1..=5 => data.push('\t'),
+            6..=8 => data.push(rng.gen()),
+            _ => data.push(rng.gen_range('a'..='z')),
+        }
+    }
+    while data.len() as i64 > len {
+        data.pop();
+    }
+    while (data.len() as i64) < len {
+        data.push('a');
+    }
+    data.into_bytes()
+}
+
 fn rand_utf8_blocks(mut seed: u64, block_size: i64, block_count: i64) -> Vec<u8> {
     let mut data = Vec::new();
     for _ in 0..block_count {
@@ -265,6 +407,29 @@ fn meet_in_the_middle() {
     );
 }
 
+#[test]
+fn relative_into_right() {
+    // A short segment inside a long line is merged into a larger segment on its right
+    // while the start of the line is still unknown
+    let mut data = rand_ascii(0x7e1a, 1024);
+    data.retain(|&b| b != b'\n' && b != b'\r');
+    let nl = data.len() as i64;
+    data.extend(rand_lines(0x7e1b, 2048));
+    let t = init(data.len() as i64, 2 * 1024);
+    load_range(&t, &data, 256, 700);
+    load_range(&t, &data, 700, data.len() as i64);
+    {
+        // The anchors up to the end of the line stay relative
+        let lm = &t.loaded.lock().linemap;
+        assert_eq!(lm.segments.len(), 1);
+        let s = &lm.segments[0];
+        let rel = s.anchors.iter().take_while(|a| a.off(s) <= nl).count();
+        assert_eq!(s.first_absolute, rel);
+    }
+    load_range(&t, &data, 0, 256);
+    assert_full_data_loaded(&t, &data);
+}
+
 #[test]
 fn shuffled_blocks() {
     let n = 256;
@@ -283,6 +448,67 @@ fn shuffled_blocks() {
     );
 }
 
+#[test]
+fn utf16_shuffled() {
+    // Odd block sizes split code units and surrogate pairs alike
+    let n = 200;
+    let b = 257;
+    let text = String::from_utf8(rand_utf8(0x1616, b * n)).unwrap();
+    let mut data = Encoding::Utf16Le.encode(&text);
+    data.truncate((b * n) as usize);
+    let n = (data.len() as i64 + b - 1) / b;
+    let mut blocks = (0..n).collect::<Vec<_>>();
+    blocks.shuffle(&mut TestRng::seed_from_u64(0x1617));
+
+    let mut t = init(data.len() as i64, 2 * 1024);
+    t.linemapper.encoding = Encoding::Utf16Le;
+    t.loaded.lock().linemap.encoding = Encoding::Utf16Le;
+    for i in blocks {
+        load_range(&t, &data, b * i, (b * (i + 1)).min(data.len() as i64));
+        assert_sanity(&t);
+    }
+    // The characters cut by the edges of the blocks are mapped once they are loaded
+    // whole
+    load_all(&t, &data, b);
+    assert_full_data_loaded(&t, &data);
+}
+
+#[test]
+fn encodings() {
+    // Byte order marks decide, otherwise the pattern of zeros does
+    let le = Encoding::Utf16Le.encode("plain old text");
+    let be = Encoding::Utf16Be.encode("plain old text");
+    assert_eq!(Encoding::detect(&le), Encoding::Utf16Le);
+    assert_eq!(Encoding::detect(&be), Encoding::Utf16Be);
+    assert_eq!(Encoding::detect(&[0xff, 0xfe, b'a']), Encoding::Utf16Le);
+    assert_eq!(Encoding::detect("caf\u{e9}".as_bytes()), Encoding::Utf8);
+    assert_eq!(
+        Encoding::detect(&"caf\u{e9}".as_bytes()[..4]),
+        Encoding::Utf8
+    );
+    assert_eq!(Encoding::detect(b"caf\xe9 au lait"), Encoding::Windows1252);
+
+    // Surrogate pairs, in both directions
+    let enc = Encoding::Utf16Be;
+    let data = enc.encode("a\u{1f600}");
+    assert_eq!(enc.decode(&data), (Ok('a' as u32), 2));
+    assert_eq!(enc.decode(&data[2..]), (Ok(0x1f600), 4));
+    assert_eq!(enc.decode_rev(&data), (Ok(0x1f600), 4));
+    assert_eq!(enc.decode(&data[4..]).1, 2);
+    assert!(enc.decode(&data[4..]).0.is_err());
+    assert_eq!(enc.decode_all(&data[..3]), "a\u{fffd}");
+    // Decoding from the middle of a character realigns
+    assert_eq!(enc.skip_partial(1, &data[1..]), 1);
+    assert_eq!(enc.skip_partial(4, &data[4..]), 2);
+    assert_eq!(enc.cut_partial(4, &data[..4]), 2);
+    assert_eq!(enc.cut_partial(5, &data[..5]), 3);
+
+    // Windows-1252 covers every byte, and replaces what it cannot encode
+    let enc = Encoding::Windows1252;
+    assert_eq!(enc.decode_all(b"\x80 \xe9\x9f"), "\u{20ac} \u{e9}\u{178}");
+    assert_eq!(enc.encode("\u{20ac}\u{e9}\u{3b1}"), b"\x80\xe9?");
+}
+
 #[test]
 fn unequal_sequential() {
     let n = 256;
@@ -348,7 +574,7 @@ fn binary_babysteps_seq() {
             .map(|s| s.end)
             .unwrap_or(0);
         t.linemapper
-            .process_data(&t.loaded, l, &data[l as usize..r as usize]);
+            .process_data(&t.loaded, 0, &data, l as usize..r as usize);
         SparseData::insert_data(&t.loaded, l, data[l as usize..r as usize].to_vec());
         if old
             == t.loaded
@@ -391,7 +617,7 @@ fn binary_babysteps_rev() {
             .map(|s| s.start)
             .unwrap_or(fsize);
         t.linemapper
-            .process_data(&t.loaded, l, &data[l as usize..r as usize]);
+            .process_data(&t.loaded, 0, &data, l as usize..r as usize);
         SparseData::insert_data(&t.loaded, l, data[l as usize..r as usize].to_vec());
         if old
             == t.loaded
@@ -416,3 +642,1711 @@ fn binary_babysteps_rev() {
     println!("{:?}", t.loaded.lock().linemap);
     assert_full_data_loaded(&t, &data);
 }
+
+#[test]
+fn piece_table() {
+    let file = rand_ascii(0xba5e, 4 * 1024);
+    let mut data = file.clone();
+    let mut table = PieceTable::new(file.len() as i64);
+    let mut rng = TestRng::seed_from_u64(0x91ece);
+    for _ in 0..512 {
+        let (range, ins) = rand_edit(&mut rng, &mut data);
+        table.splice(range, &ins);
+        assert_eq!(table.len(), data.len() as i64);
+        let l = rng.gen_range(0..=data.len());
+        let r = rng.gen_range(l..=data.len());
+        let mut got = vec![];
+        for p in table.pieces_in(l as i64..r as i64) {
+            match p.src {
+                Source::File(off) => {
+                    got.extend_from_slice(&file[off as usize..(off + p.len) as usize])
+                }
+                Source::Ram(..) => got.extend_from_slice(p.ram().unwrap()),
+                Source::Replace(..) => unreachable!(),
+            }
+        }
+        assert_eq!(&got[..], &data[l..r]);
+    }
+}
+
+#[test]
+fn edit_loaded() {
+    let b = 256;
+    let n = 16;
+    let mut data = rand_utf8_blocks(0xed17, b, n);
+    let t = test_in_order(&data, 2 * 1024, (0..n).map(|i| b * i..b * (i + 1)));
+    let mut rng = TestRng::seed_from_u64(0xed17ed);
+    for _ in 0..256 {
+        let (range, ins) = rand_edit(&mut rng, &mut data);
+        t.loaded
+            .lock()
+            .splice(&t.linemapper.layout, range, &ins)
+            .unwrap();
+        load_all(&t, &data, b);
+        assert_edited_data_loaded(&t, &data);
+    }
+}
+
+#[test]
+fn crlf_edits() {
+    // Blocks of odd size split some of the CRLF pairs
+    let b = 97;
+    let n = 40;
+    let mut data = rand_lines(0xc71f, b * n);
+    let mut blocks = (0..n).collect::<Vec<_>>();
+    blocks.shuffle(&mut TestRng::seed_from_u64(0xc71f));
+    let t = init(data.len() as i64, 2 * 1024);
+    for i in blocks {
+        load_range(&t, &data, b * i, b * (i + 1));
+    }
+    load_all(&t, &data, b);
+    assert_full_data_loaded(&t, &data);
+
+    // Edits that join and split line breaks change the layout around them
+    let mut rng = TestRng::seed_from_u64(0xc71fc71f);
+    for _ in 0..256 {
+        let l = rng.gen_range(0..=data.len());
+        let r = rng.gen_range(l..=(l + 8).min(data.len()));
+        let ins = [&b""[..], b"\r", b"\n", b"\r\n", b"ab"][rng.gen_range(0..5)].to_vec();
+        data.splice(l..r, ins.iter().copied());
+        t.loaded
+            .lock()
+            .splice(&t.linemapper.layout, l as i64..r as i64, &ins)
+            .unwrap();
+        load_all(&t, &data, b);
+        assert_edited_data_loaded(&t, &data);
+    }
+}
+
+#[test]
+fn tab_stops() {
+    // Blocks are laid out before the start of their line is known, so the tabs in
+    // them are first placed at the wrong tab stops
+    let b = 97;
+    let n = 40;
+    let mut data = rand_tabbed(0x7ab5, b * n);
+    let mut blocks = (0..n).collect::<Vec<_>>();
+    blocks.shuffle(&mut TestRng::seed_from_u64(0x7ab5));
+    let t = init(data.len() as i64, 2 * 1024);
+    for i in blocks {
+        load_range(&t, &data, b * i, b * (i + 1));
+    }
+    load_all(&t, &data, b);
+    assert_edited_data_loaded(&t, &data);
+
+    // Edits before a tab move the text after it from tab stop to tab stop
+    let mut rng = TestRng::seed_from_u64(0x7ab57ab5);
+    let snap = |data: &[u8], mut i: usize| {
+        while i < data.len() && data[i] & 0xC0 == 0x80 {
+            i += 1;
+        }
+        i
+    };
+    for _ in 0..256 {
+        let l = snap(&data, rng.gen_range(0..=data.len()));
+        let r = snap(&data, rng.gen_range(l..=(l + 8).min(data.len())));
+        let ins = [&b""[..], b"\t", b"a", b"\ta\t", b"\n"][rng.gen_range(0..5)].to_vec();
+        data.splice(l..r, ins.iter().copied());
+        t.loaded
+            .lock()
+            .splice(&t.linemapper.layout, l as i64..r as i64, &ins)
+            .unwrap();
+        load_all(&t, &data, b);
+        assert_edited_data_loaded(&t, &data);
+    }
+}
+
+#[test]
+fn edit_unloaded() {
+    let b = 256;
+    let n = 16;
+    let mut data = rand_utf8_blocks(0xed18, b, n);
+    let t = init(data.len() as i64, 2 * 1024);
+    load_range(&t, &data, b * 4, b * 8);
+    let mut rng = TestRng::seed_from_u64(0xed18ed);
+    for _ in 0..64 {
+        let (range, ins) = rand_edit(&mut rng, &mut data);
+        t.loaded
+            .lock()
+            .splice(&t.linemapper.layout, range, &ins)
+            .unwrap();
+    }
+    load_all(&t, &data, b);
+    assert_edited_data_loaded(&t, &data);
+}
+
+#[test]
+fn paste_large() {
+    let b = 256;
+    let n = 16;
+    let mut data = rand_utf8_blocks(0x9a57e, b, n);
+    let t = test_in_order(&data, 4 * 1024, (0..n).map(|i| b * i..b * (i + 1)));
+    let layout = &t.linemapper.layout;
+    let original = data.clone();
+    // Large pastes are mapped in the background
+    let ins = rand_utf8_blocks(0x9a57e9a57e, b, 8);
+    let (l, r) = (b as usize * 3, b as usize * 5);
+    data.splice(l..r, ins.iter().copied());
+    let info = EditInfo {
+        sel_before: [l as i64, r as i64],
+        sel_after: [(l + ins.len()) as i64; 2],
+        typed: false,
+    };
+    assert!(t
+        .loaded
+        .lock()
+        .edit_owned(layout, l as i64..r as i64, ins, info));
+    load_all(&t, &data, b);
+    assert_edited_data_loaded(&t, &data);
+    // And undone as a single step
+    assert_eq!(
+        t.loaded.lock().undo(layout).map(|r| r.sel),
+        Some(info.sel_before)
+    );
+    load_all(&t, &original, b);
+    assert_edited_data_loaded(&t, &original);
+    // Pastes waiting for the clipboard move along with any edits in the meantime
+    let mut loaded = t.loaded.lock();
+    loaded.pending_pastes.push_back(PasteRequest {
+        range: 10..20,
+        sel_before: [10, 20],
+    });
+    assert!(loaded.edit(layout, 0..4, b"ab", UNTYPED));
+    assert!(loaded.edit(layout, 15..15, b"xyz", UNTYPED));
+    assert!(loaded.edit(layout, 30..40, b"", UNTYPED));
+    let req = &loaded.pending_pastes[0];
+    assert_eq!((req.range.clone(), req.sel_before), (8..21, [8, 21]));
+}
+
+#[test]
+fn undo_redo() {
+    let b = 256;
+    let n = 32;
+    let mut data = rand_utf8_blocks(0x0d0, b, n);
+    let t = test_in_order(&data, 4 * 1024, (0..n).map(|i| b * i..b * (i + 1)));
+    let layout = &t.linemapper.layout;
+    let mut rng = TestRng::seed_from_u64(0x0d00d0);
+    let mut versions = vec![data.clone()];
+    for i in 0..64 {
+        let (range, ins) = if i % 8 == 0 {
+            // Large deletions are undone without having their data at hand
+            let snap = |mut i: usize| {
+                while i < data.len() && data[i] & 0xC0 == 0x80 {
+                    i += 1;
+                }
+                i
+            };
+            let l = snap(rng.gen_range(0..data.len() / 2));
+            let r = snap(l + 512);
+            data.drain(l..r);
+            (l as i64..r as i64, vec![])
+        } else {
+            rand_edit(&mut rng, &mut data)
+        };
+        assert!(t.loaded.lock().edit(layout, range, &ins, UNTYPED));
+        versions.push(data.clone());
+    }
+    // Typed characters are undone in a single step
+    let at = data.len() as i64;
+    for (i, c) in "abc".bytes().enumerate() {
+        let info = EditInfo {
+            sel_before: [at + i as i64; 2],
+            sel_after: [at + i as i64 + 1; 2],
+            typed: true,
+        };
+        let end = at + i as i64;
+        assert!(t.loaded.lock().edit(layout, end..end, &[c], info));
+    }
+    assert_eq!(t.loaded.lock().undo(layout).map(|r| r.sel), Some([at; 2]));
+    // Undo everything
+    for v in versions.iter().rev().skip(1) {
+        assert!(t.loaded.lock().undo(layout).is_some());
+        load_all(&t, v, b);
+        assert_edited_data_loaded(&t, v);
+    }
+    assert_eq!(t.loaded.lock().undo(layout), None);
+    // Redo everything, except for the typed characters
+    for v in versions.iter().skip(1) {
+        assert!(t.loaded.lock().redo(layout).is_some());
+        load_all(&t, v, b);
+        assert_edited_data_loaded(&t, v);
+    }
+    assert_eq!(
+        t.loaded.lock().redo(layout).map(|r| r.sel),
+        Some([at + 3; 2])
+    );
+}
+
+#[test]
+fn typed_overwrite() {
+    let b = 256;
+    let n = 4;
+    let original = rand_binary(0x0e0, b * n);
+    let t = test_in_order(&original, 4 * 1024, (0..n).map(|i| b * i..b * (i + 1)));
+    let layout = &t.linemapper.layout;
+    let mut data = original.clone();
+    // Overwrite three bytes a nibble at a time, like the hex view does
+    let at = 100;
+    for (i, byte) in [0x12u8, 0x34, 0x56].into_iter().enumerate() {
+        let off = at + i as i64;
+        let old = data[off as usize];
+        for (nibble, new) in [(0, byte & 0xf0 | old & 0x0f), (1, byte)] {
+            let info = EditInfo {
+                sel_before: [off; 2],
+                sel_after: [off + nibble; 2],
+                typed: true,
+            };
+            assert!(t.loaded.lock().edit(layout, off..off + 1, &[new], info));
+            data[off as usize] = new;
+        }
+    }
+    load_all(&t, &data, b);
+    assert_edited_data_loaded(&t, &data);
+    let table = t
+        .loaded
+        .lock()
+        .pieces
+        .as_ref()
+        .unwrap()
+        .ram_ranges(0..data.len() as i64);
+    assert_eq!(table, vec![at..at + 3]);
+    // The overwrites are undone in a single step
+    assert_eq!(t.loaded.lock().undo(layout).map(|r| r.sel), Some([at; 2]));
+    load_all(&t, &original, b);
+    assert_edited_data_loaded(&t, &original);
+    assert_eq!(t.loaded.lock().undo(layout), None);
+    let table = t
+        .loaded
+        .lock()
+        .pieces
+        .as_ref()
+        .unwrap()
+        .ram_ranges(0..data.len() as i64);
+    assert!(table.is_empty());
+}
+
+/// Replace the selection by the given text, like typing does.
+/// Returns the cursor after the edit.
+fn type_text(buf: &FileBuffer, sel: [i64; 2], text: &str) -> i64 {
+    let (l, r) = (sel[0].min(sel[1]), sel[0].max(sel[1]));
+    let mut file = buf.lock();
+    let ins = file.encode(text);
+    let cursor = l + ins.len() as i64;
+    let info = EditInfo {
+        sel_before: sel,
+        sel_after: [cursor; 2],
+        typed: true,
+    };
+    assert!(file.splice(l..r, &ins, info));
+    cursor
+}
+
+/// Delete the character before or after the cursor, like Backspace and Delete do.
+/// Returns the cursor after the edit, or `None` if there is no character there.
+fn delete_char(buf: &FileBuffer, at: i64, backward: bool) -> Option<i64> {
+    let mut file = buf.lock();
+    let (l, r) = match backward {
+        true if at > 0 => (file.char_delta(at, -1).unwrap(), at),
+        false if at < file.filebuf.len() => (at, file.char_delta(at, 1).unwrap()),
+        _ => return None,
+    };
+    let info = EditInfo {
+        sel_before: [at; 2],
+        sel_after: [l; 2],
+        typed: false,
+    };
+    assert!(file.splice(l..r, &[], info));
+    Some(l)
+}
+
+/// Wait for the whole buffer to load, and get its contents.
+fn buffer_contents(buf: &FileBuffer) -> Vec<u8> {
+    let start = Instant::now();
+    loop {
+        let file = buf.lock();
+        let data = file.loaded.data.longest_prefix(0);
+        if data.len() as i64 == buf.len() && file.is_backend_idle() {
+            return data.to_vec();
+        }
+        drop(file);
+        assert!(start.elapsed() < Duration::from_secs(10), "load timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn edit_commands() {
+    let text = "aé€😀\n";
+    let (buf, path) = open_temp_buffer("edit-commands", text.as_bytes());
+    assert_eq!(buffer_contents(&buf), text.as_bytes());
+    let end = text.len() as i64;
+    // Typing at the end of the file extends it
+    assert_eq!(type_text(&buf, [end; 2], "ñ"), end + 2);
+    assert_eq!(buffer_contents(&buf), "aé€😀\nñ".as_bytes());
+    // Backspace and Delete remove whole characters, however many bytes they take
+    assert_eq!(delete_char(&buf, end + 2, true), Some(end));
+    assert_eq!(buffer_contents(&buf), text.as_bytes());
+    assert_eq!(delete_char(&buf, end - 1, true), Some(end - 5));
+    assert_eq!(buffer_contents(&buf), "aé€\n".as_bytes());
+    assert_eq!(delete_char(&buf, 3, false), Some(3));
+    assert_eq!(buffer_contents(&buf), "aé\n".as_bytes());
+    // But there is nothing to delete past either end
+    assert_eq!(delete_char(&buf, 0, true), None);
+    assert_eq!(delete_char(&buf, buf.len(), false), None);
+    // Typing over a selection replaces it
+    assert_eq!(type_text(&buf, [3, 1], "xy"), 3);
+    assert_eq!(buffer_contents(&buf), "axy\n".as_bytes());
+    // Undo brings back the data along with the selection from before each edit,
+    // which keeps its direction
+    let restored = buf.lock().undo().unwrap();
+    assert_eq!(
+        (restored.range, restored.len, restored.sel),
+        (1..3, 2, [3, 1])
+    );
+    assert_eq!(buffer_contents(&buf), "aé\n".as_bytes());
+    let restored = buf.lock().undo().unwrap();
+    assert_eq!(
+        (restored.range, restored.len, restored.sel),
+        (3..3, 3, [3; 2])
+    );
+    assert_eq!(buffer_contents(&buf), "aé€\n".as_bytes());
+    // And redo goes forward again, with the cursor from after each edit
+    let restored = buf.lock().redo().unwrap();
+    assert_eq!(
+        (restored.range, restored.len, restored.sel),
+        (3..6, 0, [3; 2])
+    );
+    let restored = buf.lock().redo().unwrap();
+    assert_eq!(
+        (restored.range, restored.len, restored.sel),
+        (1..3, 2, [3; 2])
+    );
+    assert_eq!(buffer_contents(&buf), "axy\n".as_bytes());
+    assert_eq!(buf.lock().redo(), None);
+    // Undoing everything leaves the original file, with the cursor back at its end
+    let mut sel = None;
+    while let Some(restored) = buf.lock().undo() {
+        sel = Some(restored.sel);
+    }
+    assert_eq!(sel, Some([end; 2]));
+    assert_eq!(buffer_contents(&buf), text.as_bytes());
+    close_temp_buffer(buf, path);
+}
+
+/// Wait until the file buffer has finished saving.
+fn wait_for_save(buf: &FileBuffer) {
+    let start = Instant::now();
+    while buf.lock().loaded.pending_save || buf.save_progress().is_some() {
+        assert!(start.elapsed() < Duration::from_secs(10), "save timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn save_line_endings() {
+    let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
+    let path = std::env::temp_dir().join(format!("gaze-crlf-test-{}", std::process::id()));
+    let mut data = b"a\nb\r\nc\rd\r".repeat(400);
+    fs::write(&path, &data).unwrap();
+    let mut k = Cfg::default();
+    k.f.read_size = 999;
+    k.edit.save_line_ending = "crlf".to_string();
+    k.edit.journal_dir = path
+        .with_extension("journal")
+        .to_string_lossy()
+        .into_owned();
+    let buf = FileBuffer::new(path.clone(), CharLayout::new(&font, 4), k).unwrap();
+    while !buf.lock().splice(0..0, b"\n", UNTYPED) {
+        thread::yield_now();
+    }
+    data.insert(0, b'\n');
+    buf.lock().save();
+    wait_for_save(&buf);
+    let crlf = String::from_utf8(data)
+        .unwrap()
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', "\r\n");
+    assert_eq!(fs::read(&path).unwrap(), crlf.as_bytes());
+    assert_eq!(buf.shared.last_file_size.load(), crlf.len() as i64);
+    assert_eq!(buf.lock().line_ending(), Some(LineEnding::CrLf));
+    fs::remove_file(&path).unwrap();
+    let _ = fs::remove_dir_all(path.with_extension("journal"));
+}
+
+#[test]
+fn save_edits() {
+    let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
+    let path = std::env::temp_dir().join(format!("gaze-save-test-{}", std::process::id()));
+    let mut data = rand_utf8_blocks(0x5a7e, 1024, 64);
+    fs::write(&path, &data).unwrap();
+    let mut k = Cfg::default();
+    k.f.read_size = 1000;
+    k.edit.journal_dir = path
+        .with_extension("journal")
+        .to_string_lossy()
+        .into_owned();
+    let buf = FileBuffer::new(path.clone(), CharLayout::new(&font, 4), k).unwrap();
+    let mut rng = TestRng::seed_from_u64(0x5a7e5a7e);
+    let orig = data.clone();
+    for _ in 0..4 {
+        for _ in 0..16 {
+            let (range, ins) = rand_edit(&mut rng, &mut data);
+            while !buf.lock().splice(range.clone(), &ins, UNTYPED) {
+                thread::yield_now();
+            }
+        }
+        buf.lock().save();
+        wait_for_save(&buf);
+        assert_eq!(fs::read(&path).unwrap(), data);
+        assert_eq!(buf.shared.last_file_size.load(), data.len() as i64);
+    }
+    // The undo history is carried over to every saved copy
+    for _ in 0..64 {
+        assert!(buf.lock().undo().is_some());
+    }
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::read(&path).unwrap(), orig);
+    fs::remove_file(&path).unwrap();
+    let _ = fs::remove_dir_all(path.with_extension("journal"));
+}
+
+#[cfg(unix)]
+#[test]
+fn save_in_place() {
+    use std::os::unix::fs::MetadataExt;
+    let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
+    let path = std::env::temp_dir().join(format!("gaze-inplace-test-{}", std::process::id()));
+    let mut data = rand_ascii(0x1b1ace, 64 * 1024);
+    fs::write(&path, &data).unwrap();
+    let ino = fs::metadata(&path).unwrap().ino();
+    let mut k = Cfg::default();
+    k.edit.journal_dir = path
+        .with_extension("journal")
+        .to_string_lossy()
+        .into_owned();
+    let buf = FileBuffer::new(path.clone(), CharLayout::new(&font, 4), k).unwrap();
+    let mut rng = TestRng::seed_from_u64(0x1b1ace);
+    let mut overwrite = |data: &mut Vec<u8>| {
+        for _ in 0..16 {
+            let l = rng.gen_range(0..data.len() - 8);
+            let ins = rand_ascii(rng.gen(), 8);
+            data[l..l + 8].copy_from_slice(&ins);
+            while !buf.lock().splice(l as i64..l as i64 + 8, &ins, UNTYPED) {
+                thread::yield_now();
+            }
+        }
+    };
+    // Same-length edits are written into the original file
+    let orig = data.clone();
+    overwrite(&mut data);
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::read(&path).unwrap(), data);
+    assert_eq!(fs::metadata(&path).unwrap().ino(), ino);
+    // The overwritten data can still be brought back
+    for _ in 0..16 {
+        assert!(buf.lock().undo().is_some());
+    }
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::read(&path).unwrap(), orig);
+    assert_eq!(fs::metadata(&path).unwrap().ino(), ino);
+    data = orig;
+    // If the file is replaced externally, fall back to a full copy
+    let other = path.with_extension("other");
+    fs::write(&other, rand_ascii(0xbad, data.len() as i64)).unwrap();
+    fs::rename(&other, &path).unwrap();
+    let ino = fs::metadata(&path).unwrap().ino();
+    overwrite(&mut data);
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::read(&path).unwrap(), data);
+    assert_ne!(fs::metadata(&path).unwrap().ino(), ino);
+    fs::remove_file(&path).unwrap();
+    let _ = fs::remove_dir_all(path.with_extension("journal"));
+}
+
+#[test]
+fn persist_edits() {
+    let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
+    let path = std::env::temp_dir().join(format!("gaze-persist-test-{}", std::process::id()));
+    let mut data = rand_utf8_blocks(0x9e45, 1024, 16);
+    fs::write(&path, &data).unwrap();
+    let mut k = Cfg::default();
+    k.edit.journal_dir = path
+        .with_extension("journal")
+        .to_string_lossy()
+        .into_owned();
+    let open = || {
+        let buf = FileBuffer::new(path.clone(), CharLayout::new(&font, 4), k.clone()).unwrap();
+        while buf.lock().loaded.pieces.is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        buf
+    };
+    let view = ViewState {
+        sel: [5, 10],
+        scroll: FilePos {
+            base_offset: 7,
+            delta_x: -1.5,
+            delta_y: 2.,
+        },
+    };
+    {
+        let buf = open();
+        let mut rng = TestRng::seed_from_u64(0x9e45);
+        for _ in 0..16 {
+            let (range, ins) = rand_edit(&mut rng, &mut data);
+            while !buf.lock().splice(range.clone(), &ins, UNTYPED) {
+                thread::yield_now();
+            }
+        }
+        buf.lock().set_view(view);
+        buf.close(Duration::from_secs(10));
+    }
+    // Unsaved edits and the view are restored
+    {
+        let buf = open();
+        assert_eq!(buf.len(), data.len() as i64);
+        assert_eq!(buf.lock().take_restored_view(), Some(view));
+        buf.lock().save();
+        wait_for_save(&buf);
+        assert_eq!(fs::read(&path).unwrap(), data);
+        buf.close(Duration::from_secs(10));
+    }
+    // Without unsaved edits, there is nothing to persist
+    let journals = fs::read_dir(path.with_extension("journal")).unwrap();
+    assert_eq!(journals.count(), 0);
+    // Edits are not restored onto a file that changed
+    {
+        let buf = open();
+        while !buf.lock().splice(0..0, b"x", UNTYPED) {
+            thread::yield_now();
+        }
+        buf.close(Duration::from_secs(10));
+    }
+    data.truncate(100);
+    fs::write(&path, &data).unwrap();
+    {
+        let buf = open();
+        assert_eq!(buf.len(), data.len() as i64);
+        assert_eq!(buf.lock().take_restored_view(), None);
+        buf.close(Duration::from_secs(10));
+    }
+    fs::remove_file(&path).unwrap();
+    fs::remove_dir_all(path.with_extension("journal")).unwrap();
+}
+
+#[test]
+fn persist_appends() {
+    let path = std::env::temp_dir().join(format!("gaze-append-test-{}", std::process::id()));
+    let key = JournalKey {
+        path: path.clone(),
+        size: 1000,
+        mtime: None,
+    };
+    let ram = |data: &Arc<[u8]>, off: usize, len: usize| piece::Piece {
+        len: len as i64,
+        src: Source::Ram(data.clone(), off),
+    };
+    let file = |off: i64, len: i64| piece::Piece {
+        len,
+        src: Source::File(off),
+    };
+    let view = |sel: i64| ViewState {
+        sel: [sel, sel],
+        ..default()
+    };
+    let blob: Arc<[u8]> = rand_utf8_blocks(0x7a21, 1 << 20, 1).into();
+    let mut w = JournalWriter::create(
+        &path,
+        &Journal {
+            key: key.clone(),
+            view: view(0),
+            pieces: vec![ram(&blob, 0, blob.len())],
+        },
+    )
+    .unwrap();
+    let initial = fs::metadata(&path).unwrap().len();
+    assert!(initial > blob.len() as u64);
+    // Data that is already in the journal is not written again
+    for i in 1..=16 {
+        let pieces = vec![
+            ram(&blob, 0, 100 * i),
+            file(10, 20),
+            ram(&blob, 100 * i, blob.len() - 100 * i),
+        ];
+        w.append(&Journal {
+            key: key.clone(),
+            view: view(i as i64),
+            pieces,
+        })
+        .unwrap();
+    }
+    let appended = fs::metadata(&path).unwrap().len();
+    assert!(appended - initial < 4096);
+    let check = |sel: i64, at: usize| {
+        let journal = Journal::read(&path).unwrap();
+        assert_eq!(journal.view, view(sel));
+        let mut got = vec![0; blob.len() + 20];
+        piece::read_pieces(&journal.pieces, &mut got, &mut |_, buf| {
+            buf.fill(b'f');
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(got[..at], blob[..at]);
+        assert_eq!(got[at..at + 20], [b'f'; 20]);
+        assert_eq!(got[at + 20..], blob[at..]);
+    };
+    check(16, 1600);
+    // A state that was cut short leaves the one before it
+    let f = File::options().write(true).open(&path).unwrap();
+    f.set_len(appended - 1).unwrap();
+    drop(f);
+    check(15, 1500);
+    // Once most of the data is no longer referred to, the journal should be
+    // written again
+    let mut w = JournalWriter::create(
+        &path,
+        &Journal {
+            key: key.clone(),
+            view: view(0),
+            pieces: vec![ram(&blob, 0, blob.len())],
+        },
+    )
+    .unwrap();
+    assert!(!w.is_bloated());
+    w.append(&Journal {
+        key: key.clone(),
+        view: view(0),
+        pieces: vec![file(0, 1000)],
+    })
+    .unwrap();
+    assert!(w.is_bloated());
+    fs::remove_file(&path).unwrap();
+}
+
+/// Find all non-overlapping occurrences of `needle`, the slow way.
+fn naive_find(data: &[u8], needle: &[u8]) -> Vec<ops::Range<i64>> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i + needle.len() <= data.len() {
+        if &data[i..i + needle.len()] == needle {
+            out.push(i as i64..(i + needle.len()) as i64);
+            i += needle.len();
+        } else {
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Wait until the background search finishes, and return its matches.
+fn wait_for_search(buf: &FileBuffer) -> Vec<ops::Range<i64>> {
+    let start = Instant::now();
+    loop {
+        {
+            let file = buf.lock();
+            let s = file.search_results().unwrap();
+            if s.is_done() {
+                assert_eq!(s.count, s.matches.len() as u64);
+                return s.matches.clone();
+            }
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "search timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Open a buffer over a temporary file with the given data.
+/// Uses a small read size, so that there are plenty of chunk boundaries.
+fn open_temp_buffer(name: &str, data: &[u8]) -> (FileBuffer, PathBuf) {
+    open_temp_buffer_with(name, data, |_| {})
+}
+
+/// Like `open_temp_buffer`, but with some configuration changed.
+fn open_temp_buffer_with(
+    name: &str,
+    data: &[u8],
+    cfg: impl FnOnce(&mut Cfg),
+) -> (FileBuffer, PathBuf) {
+    let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
+    let path = std::env::temp_dir().join(format!("gaze-{}-{}", name, std::process::id()));
+    fs::write(&path, data).unwrap();
+    let mut k = Cfg::default();
+    k.f.read_size = 1000;
+    // Notice external changes quickly
+    k.f.watch_interval = 0.01;
+    k.edit.journal_dir = path
+        .with_extension("journal")
+        .to_string_lossy()
+        .into_owned();
+    cfg(&mut k);
+    let buf = FileBuffer::new(path.clone(), CharLayout::new(&font, 4), k).unwrap();
+    while buf.lock().loaded.pieces.is_none() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    (buf, path)
+}
+
+fn close_temp_buffer(buf: FileBuffer, path: PathBuf) {
+    buf.close(Duration::from_secs(10));
+    fs::remove_file(&path).unwrap();
+    let _ = fs::remove_dir_all(path.with_extension("journal"));
+}
+
+#[test]
+fn search_literal() {
+    // Small alphabet, so that there are plenty of matches across chunk boundaries
+    let mut rng = TestRng::seed_from_u64(0x5ea4c4);
+    let mut data: Vec<u8> = (0..64 * 1024)
+        .map(|_| b"abc\n"[rng.gen_range(0..4)])
+        .collect();
+    let (buf, path) = open_temp_buffer("search-literal", &data);
+    for needle in ["a", "abca", "cc", "b\nb"] {
+        let query = SearchQuery {
+            text: needle.to_string(),
+            ..default()
+        };
+        buf.lock().search(Some(query));
+        let matches = wait_for_search(&buf);
+        assert_eq!(matches, naive_find(&data, needle.as_bytes()));
+    }
+    // Edits move the matches along, even while the search is running
+    for i in 0..64 {
+        let waited = i % 4 == 0;
+        if waited {
+            assert_eq!(wait_for_search(&buf), naive_find(&data, b"b\nb"));
+        }
+        let (range, ins) = rand_edit(&mut rng, &mut data);
+        loop {
+            let mut file = buf.lock();
+            if file.splice(range.clone(), &ins, UNTYPED) {
+                if waited {
+                    // Only the text around the edit is scanned again
+                    assert!(file.search_results().unwrap().scanned >= range.start - 8);
+                }
+                break;
+            }
+            drop(file);
+            thread::yield_now();
+        }
+    }
+    let matches = wait_for_search(&buf);
+    assert_eq!(matches, naive_find(&data, b"b\nb"));
+    // Jump around the matches
+    let file = buf.lock();
+    let s = file.search_results().unwrap();
+    let first = matches[0].clone();
+    let last = matches.last().unwrap().clone();
+    assert_eq!(s.next_match(0..0), Some(first.clone()));
+    assert_eq!(s.next_match(first.clone()), Some(matches[1].clone()));
+    assert_eq!(s.next_match(last.clone()), Some(first.clone()));
+    assert_eq!(s.prev_match(first), Some(last.clone()));
+    assert_eq!(s.prev_match(last), Some(matches[matches.len() - 2].clone()));
+    drop(file);
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn search_dropped_matches() {
+    let mut rng = TestRng::seed_from_u64(0xd409);
+    let mut data: Vec<u8> = (0..4 * 1024 * 1024)
+        .map(|_| b"ab\n"[rng.gen_range(0..3)])
+        .collect();
+    let (buf, path) = open_temp_buffer_with("search-dropped", &data, |k| {
+        k.f.read_size = 64 * 1024;
+        k.search.max_matches = 100;
+    });
+    buf.lock().search(Some(SearchQuery {
+        text: "ab".to_string(),
+        ..default()
+    }));
+    // Only some matches are kept, but all of them are counted, even across edits
+    for i in 0..16 {
+        let start = Instant::now();
+        loop {
+            {
+                let file = buf.lock();
+                let s = file.search_results().unwrap();
+                if s.is_done() {
+                    let expected = naive_find(&data, b"ab");
+                    assert_eq!(s.count, expected.len() as u64);
+                    assert!(s.matches.len() <= 100);
+                    assert!(s.matches.iter().all(|m| expected.contains(m)));
+                    break;
+                }
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "search timed out"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Edits only count the matches around them again, whether they were kept
+        // or not
+        let l = if i % 4 < 2 {
+            rng.gen_range(0..1000)
+        } else {
+            data.len() - rng.gen_range(0..1000)
+        };
+        let r = (l + rng.gen_range(0..8)).min(data.len());
+        let ins = if i % 2 == 0 { &b"xab"[..] } else { b"" };
+        data.splice(l..r, ins.iter().copied());
+        let mut file = buf.lock();
+        while !file.splice(l as i64..r as i64, ins, UNTYPED) {
+            drop(file);
+            thread::yield_now();
+            file = buf.lock();
+        }
+        assert!(file.search_results().unwrap().scanned >= data.len() as i64 - 3 * 1024 * 1024);
+    }
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn search_regex() {
+    let mut rng = TestRng::seed_from_u64(0x7e6e);
+    let alphabet = b"abAB \n\xff";
+    let mut data: Vec<u8> = (0..64 * 1024)
+        .map(|_| alphabet[rng.gen_range(0..7)])
+        .collect();
+    // Compare against a regex run over the whole data at once
+    let find = |data: &[u8], query: &SearchQuery| -> Vec<ops::Range<i64>> {
+        let mut pattern = if query.regex {
+            query.text.clone()
+        } else {
+            regex::escape(&query.text)
+        };
+        if query.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        let re = regex::bytes::RegexBuilder::new(&pattern)
+            .case_insensitive(query.case_insensitive)
+            .multi_line(true)
+            .build()
+            .unwrap();
+        re.find_iter(data)
+            .map(|m| m.start() as i64..m.end() as i64)
+            .collect()
+    };
+    let (buf, path) = open_temp_buffer("search-regex", &data);
+    let queries = [
+        ("ab", false, true, false),
+        ("ab", false, false, true),
+        ("ab", false, true, true),
+        ("a.b", true, false, false),
+        ("(?-u:\\xff)b+", true, true, false),
+        ("^b|a$", true, false, false),
+        ("[ab]{3}", true, false, true),
+        ("b \\w", true, true, false),
+    ];
+    for (text, regex, case_insensitive, whole_word) in queries {
+        let query = SearchQuery {
+            text: text.to_string(),
+            regex,
+            case_insensitive,
+            whole_word,
+        };
+        buf.lock().search(Some(query.clone()));
+        let matches = wait_for_search(&buf);
+        let expected = find(&data, &query);
+        assert!(!expected.is_empty(), "no matches for {:?}", text);
+        assert_eq!(matches, expected, "wrong matches for {:?}", text);
+    }
+    // Word boundaries and line anchors around edits are checked again
+    for text in ["ab", "^b|a$"] {
+        let query = SearchQuery {
+            text: text.to_string(),
+            regex: true,
+            case_insensitive: true,
+            whole_word: true,
+        };
+        buf.lock().search(Some(query.clone()));
+        for i in 0..64 {
+            if i % 8 == 0 {
+                assert_eq!(wait_for_search(&buf), find(&data, &query));
+            }
+            let l = rng.gen_range(0..data.len());
+            let r = (l + rng.gen_range(0..8)).min(data.len());
+            let ins: Vec<u8> = (0..rng.gen_range(0..8))
+                .map(|_| alphabet[rng.gen_range(0..7)])
+                .collect();
+            data.splice(l..r, ins.iter().copied());
+            while !buf.lock().splice(l as i64..r as i64, &ins, UNTYPED) {
+                thread::yield_now();
+            }
+        }
+        assert_eq!(wait_for_search(&buf), find(&data, &query));
+    }
+    // Invalid regexes are reported
+    buf.lock().search(Some(SearchQuery {
+        text: "a(".to_string(),
+        regex: true,
+        ..default()
+    }));
+    wait_for_search(&buf);
+    assert!(buf.lock().search_results().unwrap().error.is_some());
+    close_temp_buffer(buf, path);
+}
+
+/// Replace all matches in the background and wait until the replacement is applied.
+fn replace_all(buf: &FileBuffer, query: SearchQuery, replacement: &str) -> u64 {
+    buf.lock()
+        .replace_all(query, replacement.to_string(), [0; 2]);
+    let start = Instant::now();
+    loop {
+        {
+            let mut file = buf.lock();
+            let r = file.replace_progress().unwrap();
+            assert_eq!(r.error, None);
+            if r.is_done() {
+                let count = r.count;
+                assert_eq!(file.take_replaced().is_some(), count > 0);
+                return count;
+            }
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "replace timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn search_replace_all() {
+    let mut rng = TestRng::seed_from_u64(0x4e91ace);
+    let data: Vec<u8> = (0..64 * 1024)
+        .map(|_| b"abc \n"[rng.gen_range(0..5)])
+        .collect();
+    let (buf, path) = open_temp_buffer("search-replace", &data);
+    // Replace with a longer text, so that offsets shift around
+    let query = SearchQuery {
+        text: "ab".to_string(),
+        ..default()
+    };
+    let count = replace_all(&buf, query, "[$0]");
+    let re = regex::bytes::Regex::new("ab").unwrap();
+    let replaced = re.replace_all(&data, regex::bytes::NoExpand(b"[$0]"));
+    assert_eq!(count as usize, re.find_iter(&data).count());
+    // Regex replacements expand capture groups
+    let query = SearchQuery {
+        text: r"\b(c+) (\w)".to_string(),
+        regex: true,
+        ..default()
+    };
+    let count = replace_all(&buf, query, "$2-${1}");
+    let re = regex::bytes::RegexBuilder::new(r"\b(c+) (\w)")
+        .multi_line(true)
+        .build()
+        .unwrap();
+    let twice = re.replace_all(&replaced, &b"$2-${1}"[..]);
+    assert!(count > 0);
+    assert_eq!(count as usize, re.find_iter(&replaced).count());
+    // Each replace-all is undone as a single step
+    assert_eq!(buf.len(), twice.len() as i64);
+    assert!(buf.lock().undo().is_some());
+    assert_eq!(buf.len(), replaced.len() as i64);
+    assert!(buf.lock().undo().is_some());
+    assert_eq!(buf.len(), data.len() as i64);
+    assert!(buf.lock().redo().is_some());
+    assert!(buf.lock().redo().is_some());
+    // Each replace-all is recorded as a single piece over the replaced range, and
+    // only put together when read
+    // Here, that is the text before the first match, the part of the first
+    // replace-all before the first match of the second one, the second one, and the
+    // text after the last match
+    let pieces = buf.lock().loaded.pieces.as_ref().unwrap().pieces().to_vec();
+    assert_eq!(pieces.len(), 4, "{:?}", pieces);
+    assert!(matches!(pieces[2].src, Source::Replace(..)));
+    for needle in [&b"[ab]"[..], b"-c", b"c\n"] {
+        buf.lock().search(Some(SearchQuery {
+            text: String::from_utf8(needle.to_vec()).unwrap(),
+            ..default()
+        }));
+        assert_eq!(wait_for_search(&buf), naive_find(&twice, needle));
+    }
+    // And journaled as such
+    let journal_path = path.with_extension("replace-journal");
+    let journal = Journal {
+        key: JournalKey {
+            path: path.clone(),
+            size: data.len() as i64,
+            mtime: None,
+        },
+        view: ViewState::default(),
+        pieces,
+    };
+    JournalWriter::create(&journal_path, &journal).unwrap();
+    let journal = Journal::read(&journal_path).unwrap();
+    fs::remove_file(&journal_path).unwrap();
+    let mut got = vec![0; twice.len()];
+    piece::read_pieces(&journal.pieces, &mut got, &mut |off, buf| {
+        buf.copy_from_slice(&data[off as usize..off as usize + buf.len()]);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(got, &*twice);
+    // The pieces are streamed out when saving
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::read(&path).unwrap(), &*twice);
+    // The replace-alls can still be undone after saving
+    assert!(buf.lock().undo().is_some());
+    buf.lock().search(Some(SearchQuery {
+        text: "[ab]".to_string(),
+        ..default()
+    }));
+    assert_eq!(wait_for_search(&buf), naive_find(&replaced, b"[ab]"));
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn replace_all_across_edits() {
+    let mut rng = TestRng::seed_from_u64(0xed175);
+    let mut data: Vec<u8> = (0..4 * 1024 * 1024)
+        .map(|_| b"abc \n"[rng.gen_range(0..5)])
+        .collect();
+    let (buf, path) = open_temp_buffer_with("replace-edits", &data, |k| {
+        k.f.read_size = 200;
+        k.f.follow_interval = 0.01;
+    });
+    buf.lock().replace_all(
+        SearchQuery {
+            text: "ab".to_string(),
+            ..default()
+        },
+        "[$0]".to_string(),
+        [0; 2],
+    );
+    let wait_for_scan = |at: i64| {
+        let start = Instant::now();
+        loop {
+            let scanned = buf.lock().replace_progress().unwrap().scanned;
+            if scanned >= at {
+                return scanned;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "replace timed out"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    };
+    // Edits behind the scan only scan the text after them again, and edits ahead of
+    // it are picked up as the scan gets there, including data appended to the file
+    wait_for_scan(2 * 1024 * 1024);
+    let len = data.len() as i64;
+    for (range, ins) in [
+        (1024 * 1024..1024 * 1024 + 3, &b"xab"[..]),
+        (len - 10..len - 5, b""),
+        (len - 5..len - 5, b"abab"),
+    ] {
+        data.splice(
+            range.start as usize..range.end as usize,
+            ins.iter().copied(),
+        );
+        while !buf.lock().splice(range.clone(), ins, UNTYPED) {
+            thread::yield_now();
+        }
+    }
+    let appended = b"ab\n".repeat(100);
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(&appended)
+        .unwrap();
+    buf.set_follow(true);
+    data.extend_from_slice(&appended);
+    thread::sleep(Duration::from_millis(50));
+    assert!(wait_for_scan(0) >= 1024 * 1024 - 16);
+    let start = Instant::now();
+    while !buf.lock().replace_progress().unwrap().is_done() || buf.lock().take_replaced().is_none()
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "replace timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    let re = regex::bytes::Regex::new("ab").unwrap();
+    let replaced = re.replace_all(&data, regex::bytes::NoExpand(b"[$0]"));
+    assert_eq!(
+        buf.lock().replace_progress().unwrap().count as usize,
+        re.find_iter(&data).count()
+    );
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::read(&path).unwrap(), &*replaced);
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn replace_all_too_many() {
+    let data = b"a b\n".repeat(10000);
+    let (buf, path) = open_temp_buffer_with("replace-many", &data, |k| {
+        k.search.max_replace_mb = 0.01;
+    });
+    let query = SearchQuery {
+        text: "a".to_string(),
+        ..default()
+    };
+    buf.lock().replace_all(query, "c".to_string(), [0; 2]);
+    let start = Instant::now();
+    while !buf.lock().replace_progress().unwrap().is_done() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "replace timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    // The matches do not fit in memory, so nothing is replaced
+    let mut file = buf.lock();
+    assert!(file.replace_progress().unwrap().error.is_some());
+    assert!(file.take_replaced().is_none());
+    assert_eq!(file.undo(), None);
+    drop(file);
+    assert!(!buf.is_dirty());
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn search_visible_matches() {
+    let data = "foo bar baz\n".repeat(1000).into_bytes();
+    let (buf, path) = open_temp_buffer("search-visible", &data);
+    let view = FileRect {
+        corner: FilePos {
+            base_offset: 12 * 500,
+            delta_x: 0.,
+            delta_y: 0.,
+        },
+        size: dvec2(100., 10.),
+    };
+    buf.lock().set_hot_area(view, None);
+    let start = Instant::now();
+    while !buf.lock().is_backend_idle() {
+        assert!(start.elapsed() < Duration::from_secs(10), "load timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+    // The view starts at the line of the anchor before its base offset
+    let first = buf
+        .lock()
+        .lookup_pos(view.corner.base_offset, 0, 0., 1.)
+        .unwrap()
+        .offset
+        / 12;
+    for (text, at) in [("bar", 4), ("z\nfoo", 10), ("a", 5)] {
+        buf.lock().search(Some(SearchQuery {
+            text: text.to_string(),
+            ..default()
+        }));
+        // Highlighting starts as soon as the manager thread compiles the query,
+        // regardless of how far the search got
+        let start = Instant::now();
+        let matches = loop {
+            let matches = buf.lock().visible_matches(view);
+            if !matches.is_empty() {
+                break matches;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "highlight timed out"
+            );
+            thread::sleep(Duration::from_millis(1));
+        };
+        // At least every line in the view has its match highlighted
+        for line in first..first + 10 {
+            let m = 12 * line + at..12 * line + at + text.len() as i64;
+            assert!(matches.contains(&m), "missing {:?} in {:?}", m, matches);
+        }
+        for m in matches {
+            assert_eq!(&data[m.start as usize..m.end as usize], text.as_bytes());
+        }
+    }
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn search_encodings() {
+    let text = "café crème, thé\n".repeat(2000);
+    let enc = Encoding::Utf16Le;
+    let data = enc.encode(&text);
+    let (buf, path) = open_temp_buffer("search-encodings", &data);
+    buf.lock().set_encoding(enc);
+    // Literal queries are encoded like the buffer
+    buf.lock().search(Some(SearchQuery {
+        text: "thé".to_string(),
+        ..default()
+    }));
+    assert_eq!(wait_for_search(&buf), naive_find(&data, &enc.encode("thé")));
+    // The regex engine only understands UTF-8
+    buf.lock().search(Some(SearchQuery {
+        text: "th.".to_string(),
+        regex: true,
+        ..default()
+    }));
+    assert_eq!(wait_for_search(&buf), vec![]);
+    assert!(buf.lock().search_results().unwrap().error.is_some());
+    // Until the buffer is decoded as UTF-8
+    buf.lock().set_encoding(Encoding::Utf8);
+    assert_eq!(wait_for_search(&buf), vec![]);
+    assert_eq!(buf.lock().search_results().unwrap().error, None);
+    buf.lock().set_encoding(enc);
+    // Replacements are encoded too
+    let query = SearchQuery {
+        text: "crème".to_string(),
+        ..default()
+    };
+    assert_eq!(replace_all(&buf, query, "lait"), 2000);
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        enc.encode(&text.replace("crème", "lait"))
+    );
+    close_temp_buffer(buf, path);
+}
+
+/// Follow the estimates for the given line like the file view does, until the exact
+/// position is known.
+fn wait_for_line(buf: &FileBuffer, line: i64) -> i64 {
+    buf.lock().goto_line(Some(line));
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(10), "goto timed out");
+        let (off, exact) = buf.lock().locate_line(line);
+        if exact {
+            buf.lock().goto_line(None);
+            return off;
+        }
+        let view = FileRect {
+            corner: FilePos {
+                base_offset: off,
+                delta_x: 0.,
+                delta_y: 0.,
+            },
+            size: dvec2(100., 10.),
+        };
+        buf.lock().set_hot_area(view, None);
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn goto_line() {
+    let mut rng = TestRng::seed_from_u64(0x90703);
+    let mut data = vec![];
+    let mut starts = vec![];
+    for _ in 0..5000 {
+        starts.push(data.len() as i64);
+        let len = rng.gen_range(0..40);
+        data.extend((0..len).map(|_| b'a' + rng.gen_range(0..26)));
+        data.push(b'\n');
+    }
+    let (buf, path) = open_temp_buffer("goto-line", &data);
+    // Look at the end of the file first, so that the start is not mapped
+    let len = data.len() as i64;
+    buf.lock().set_hot_area(
+        FileRect {
+            corner: FilePos {
+                base_offset: len,
+                delta_x: 0.,
+                delta_y: 0.,
+            },
+            size: dvec2(100., 10.),
+        },
+        None,
+    );
+    for line in [4321, 17, 0, 4999] {
+        assert_eq!(wait_for_line(&buf, line), starts[line as usize]);
+    }
+    // Lines past the end land at the end of the buffer
+    assert_eq!(wait_for_line(&buf, 10000), len);
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn char_boundary() {
+    let text = "añ€😀b\n".repeat(200);
+    let (buf, path) = open_temp_buffer("char-boundary", text.as_bytes());
+    let len = text.len() as i64;
+    buf.lock().set_hot_area(
+        FileRect {
+            corner: FilePos {
+                base_offset: len / 2,
+                delta_x: 0.,
+                delta_y: 0.,
+            },
+            size: dvec2(100., 10.),
+        },
+        None,
+    );
+    let start = Instant::now();
+    while !buf.lock().is_backend_idle() {
+        assert!(start.elapsed() < Duration::from_secs(10), "load timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+    for off in 0..=len {
+        let expected = (0..=off as usize)
+            .rev()
+            .find(|&i| text.is_char_boundary(i))
+            .unwrap();
+        assert_eq!(buf.lock().char_boundary(off), Some(expected as i64));
+    }
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn line_col() {
+    let text = "first\nsécond line\n\nlast";
+    let (buf, path) = open_temp_buffer("line-col", text.as_bytes());
+    let start = Instant::now();
+    while buf.lock().line_count().is_none() || !buf.lock().is_backend_idle() {
+        assert!(start.elapsed() < Duration::from_secs(10), "load timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+    let file = buf.lock();
+    assert_eq!(file.line_count(), Some(4));
+    assert_eq!(file.coverage(), (text.len() as i64, text.len() as i64));
+    for (off, line, col) in [
+        (0, 1, 1),
+        (5, 1, 6),
+        (6, 2, 1),
+        (9, 2, 3),
+        (14, 2, 8),
+        (20, 4, 1),
+    ] {
+        assert_eq!(file.line_col(off), (Some(line), Some(col)), "at {}", off);
+    }
+    drop(file);
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn follow_appended() {
+    let mut text = "line\n".repeat(100);
+    let (buf, path) = open_temp_buffer("follow", text.as_bytes());
+    buf.set_follow(true);
+    for _ in 0..3 {
+        let more = "more\n".repeat(50);
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(more.as_bytes())
+            .unwrap();
+        text += &more;
+        let start = Instant::now();
+        while buf.len() != text.len() as i64
+            || buf.lock().line_count() != Some(text.len() as i64 / 5 + 1)
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "follow timed out"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(!buf.is_dirty());
+        assert_eq!(buf.lock().loaded.data.longest_prefix(0), text.as_bytes());
+    }
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn external_truncation() {
+    let text = "0123456789\n".repeat(100);
+    let (buf, path) = open_temp_buffer("external", text.as_bytes());
+    buf.lock().splice(0..0, b"edit ", UNTYPED);
+    let start = Instant::now();
+    while !buf.lock().is_backend_idle() {
+        assert!(start.elapsed() < Duration::from_secs(10), "load timed out");
+        thread::sleep(Duration::from_millis(1));
+    }
+    // Appending to the file leaves the data that the buffer refers to intact
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"appended\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(buf.external_change(), None);
+    // Another process truncates the file
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(22)
+        .unwrap();
+    let start = Instant::now();
+    while buf.external_change().is_none() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "change not noticed"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(buf.external_change().as_deref(), Some("was truncated"));
+    // The old data stays around until the buffer is reloaded
+    assert_eq!(buf.len(), text.len() as i64 + 5);
+    assert!(buf.is_dirty());
+    // But it cannot be saved, since the file pieces are stale
+    buf.take_notices();
+    buf.lock().save();
+    wait_for_save(&buf);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 22);
+    assert!(buf
+        .take_notices()
+        .iter()
+        .any(|n| n.error && n.text.contains("reload it before saving")));
+    buf.reload();
+    let start = Instant::now();
+    while buf.len() != 22 || buf.lock().loaded.data.longest_prefix(0).len() != 22 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "reload timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(
+        buf.lock().loaded.data.longest_prefix(0),
+        &text.as_bytes()[..22]
+    );
+    assert_eq!(buf.external_change(), None);
+    assert!(!buf.is_dirty());
+    assert_eq!(buf.lock().undo(), None);
+    close_temp_buffer(buf, path);
+}
+
+#[test]
+fn external_rewrite() {
+    let text = "0123456789\n".repeat(100);
+    let (buf, path) = open_temp_buffer("external-rewrite", text.as_bytes());
+    let append = |data: &[u8]| {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(data)
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(buf.external_change(), None);
+    };
+    let wait_for_change = || {
+        let start = Instant::now();
+        while buf.external_change().is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "change not noticed"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(buf.external_change().as_deref(), Some("was modified"));
+    };
+    // Rewriting the file in place with more data is not an append
+    append(b"appended\n");
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .write_all(&b"rewritten\n".repeat(200))
+        .unwrap();
+    wait_for_change();
+    buf.reload();
+    let start = Instant::now();
+    while buf.external_change().is_some() || buf.len() != 2000 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "reload timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    // Rewrites of the same size are noticed after the file grew too
+    append(b"appended\n");
+    let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(io::SeekFrom::Start(5)).unwrap();
+    file.write_all(b"X").unwrap();
+    drop(file);
+    wait_for_change();
+    close_temp_buffer(buf, path);
+}
+
+/// A stream that hands out chunks as they are sent, like a pipe.
+struct ChunkReader(Receiver<Vec<u8>>, Vec<u8>);
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.1.is_empty() {
+            match self.0.recv() {
+                Ok(chunk) => self.1 = chunk,
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.1.len());
+        buf[..n].copy_from_slice(&self.1[..n]);
+        self.1.drain(..n);
+        Ok(n)
+    }
+}
+
+#[test]
+fn spool_stream() {
+    let font = FontArc::try_from_vec(fs::read("font.ttf").unwrap()).unwrap();
+    let (tx, rx) = channel::unbounded();
+    let input = Box::new(ChunkReader(rx, vec![]));
+    let buf = FileBuffer::spool("stdin", input, CharLayout::new(&font, 4), Cfg::default()).unwrap();
+    let mut text = String::new();
+    for i in 0..3 {
+        let more = format!("chunk {}\n", i).repeat(100);
+        tx.send(more.clone().into_bytes()).unwrap();
+        text += &more;
+        let start = Instant::now();
+        while buf.len() != text.len() as i64
+            || buf.lock().loaded.data.longest_prefix(0).len() != text.len()
+        {
+            assert!(start.elapsed() < Duration::from_secs(10), "spool timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(buf.is_spooling());
+        assert!(!buf.is_dirty());
+        assert_eq!(buf.lock().loaded.data.longest_prefix(0), text.as_bytes());
+    }
+    drop(tx);
+    let start = Instant::now();
+    while buf.is_spooling() {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "spool never ended"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(buf.len(), text.len() as i64);
+    assert_eq!(buf.lock().line_count(), Some(301));
+    buf.close(Duration::from_secs(10));
+}
+
+/// Text that compresses well, but not too well.
+fn log_lines(seed: u64, count: usize) -> Vec<u8> {
+    let mut rng = TestRng::seed_from_u64(seed);
+    let mut data = String::new();
+    for i in 0..count {
+        data += &format!(
+            "{} request {} took {}ms\n",
+            i,
+            rng.gen::<u32>(),
+            rng.gen_range(0..500)
+        );
+    }
+    data.into_bytes()
+}
+
+/// Compress each part into a separate gzip member.
+fn gzip(parts: &[&[u8]]) -> Vec<u8> {
+    let mut out = vec![];
+    for part in parts {
+        out.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x08, 0, 0, 0, 0, 0, 0xff]);
+        out.extend_from_slice(b"part.txt\0");
+        out.extend(miniz_oxide::deflate::compress_to_vec(part, 6));
+        // The checksum is not checked
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(part.len() as u32).to_le_bytes());
+    }
+    out
+}
+
+fn check_decompressed(
+    name: &str,
+    compressed: &[u8],
+    codec: Codec,
+    data: &[u8],
+    max_checkpoint_mem: usize,
+) -> Decompressed {
+    let path = std::env::temp_dir().join(format!("gaze-{}-{}", name, std::process::id()));
+    fs::write(&path, compressed).unwrap();
+    let file = File::open(&path).unwrap();
+    assert_eq!(Codec::detect(&file), Some(codec));
+    let mut d = Decompressed::new(file, codec, 64 * 1024, max_checkpoint_mem).unwrap();
+    while !d.done {
+        d.index_step(100_000).unwrap();
+    }
+    assert_eq!(d.len(), data.len() as i64);
+    let mut rng = TestRng::seed_from_u64(0x5eed);
+    for _ in 0..200 {
+        let start = rng.gen_range(0..data.len());
+        let end = (start + rng.gen_range(0..5000)).min(data.len());
+        let mut buf = vec![0; end - start];
+        d.read_exact_at(&mut buf, start as i64).unwrap();
+        assert!(buf == data[start..end], "mismatch at [{}, {})", start, end);
+    }
+    let mut buf = vec![0; 10];
+    assert!(d.read_exact_at(&mut buf, data.len() as i64 - 5).is_err());
+    fs::remove_file(&path).unwrap();
+    d
+}
+
+#[test]
+fn decompress_gzip() {
+    let data = log_lines(1, 40_000);
+    let (a, b) = data.split_at(700_000);
+    check_decompressed("gzip", &gzip(&[a, b]), Codec::Gzip, &data, 64 << 20);
+}
+
+#[test]
+fn decompress_gzip_thinned() {
+    // Only a few checkpoints fit, so they are thinned out as the file is indexed
+    let data = log_lines(5, 40_000);
+    let max_mem = 8 * (32 * 1024 + 16 * 1024);
+    let d = check_decompressed("gzip-thin", &gzip(&[&data]), Codec::Gzip, &data, max_mem);
+    assert!(d.checkpoint_mem <= max_mem);
+    assert!(d.spacing > 64 * 1024);
+}
+
+#[test]
+fn decompress_zstd() {
+    let data = log_lines(2, 40_000);
+    let mut compressed = vec![];
+    for part in data.chunks(100_000) {
+        compressed.extend(zstd::bulk::compress(part, 3).unwrap());
+    }
+    let d = check_decompressed("zstd", &compressed, Codec::Zstd, &data, 64 << 20);
+    assert!(d.notice.is_none());
+}
+
+#[test]
+fn decompress_zstd_single_frame() {
+    // The decoder cannot be snapshotted, so most of the data is spooled instead
+    let data = log_lines(4, 40_000);
+    let compressed = zstd::bulk::compress(&data, 3).unwrap();
+    let d = check_decompressed("zstd-single", &compressed, Codec::Zstd, &data, 64 << 20);
+    assert!(d.notice.is_some_and(|n| !n.error));
+}
+
+#[test]
+fn open_compressed() {
+    let data = log_lines(3, 20_000);
+    let (buf, path) = open_temp_buffer("compressed", &gzip(&[&data]));
+    assert_eq!(buf.codec(), Some(Codec::Gzip));
+    let start = Instant::now();
+    while buf.is_decoding()
+        || buf.len() != data.len() as i64
+        || buf.lock().loaded.data.longest_prefix(0).len() < 100_000
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "decompress timed out"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(
+        buf.lock().loaded.data.longest_prefix(0)[..100_000],
+        data[..100_000]
+    );
+    assert!(!buf.is_dirty());
+    close_temp_buffer(buf, path);
+}
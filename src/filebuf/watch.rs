@@ -0,0 +1,90 @@
+//! Notices when the file is changed by another process.
+//!
+//! The file pieces of a buffer refer to byte ranges of the file as it was when it
+//! was opened, so if the file is truncated or rewritten they point at the wrong
+//! data, or at no data at all.
+//! There is no portable way of being notified of changes, so the manager thread
+//! periodically compares the metadata of the file against the last known state.
+
+use std::time::SystemTime;
+
+use crate::prelude::*;
+
+use super::read_exact_at;
+
+/// How many bytes at the end of the file are kept to tell appends apart from
+/// rewrites.
+const SAMPLE_LEN: i64 = 4096;
+
+/// The identity and state of a file, as far as metadata can tell.
+#[derive(Clone, PartialEq, Debug)]
+pub(super) struct FileStamp {
+    /// The device and inode of the file, which change if the file is replaced.
+    id: Option<(u64, u64)>,
+    len: i64,
+    mtime: Option<SystemTime>,
+    /// The last few bytes of the file, if they were read.
+    /// Appending to the file leaves them alone, but rewriting it usually does not.
+    sample: Vec<u8>,
+}
+impl FileStamp {
+    pub fn of(meta: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let id = {
+            use std::os::unix::fs::MetadataExt;
+            Some((meta.dev(), meta.ino()))
+        };
+        #[cfg(not(unix))]
+        let id = None;
+        Self {
+            id,
+            len: meta.len() as i64,
+            mtime: meta.modified().ok(),
+            sample: vec![],
+        }
+    }
+
+    /// Read the last few bytes of the given file, which this stamp should describe.
+    pub fn sampled(mut self, file: &File) -> Self {
+        let n = self.len.min(SAMPLE_LEN);
+        let mut buf = vec![0; n as usize];
+        if read_exact_at(file, &mut buf, (self.len - n) as u64).is_ok() {
+            self.sample = buf;
+        }
+        self
+    }
+
+    /// Whether the sampled bytes are still where they were.
+    fn sample_intact(&self, file: &File) -> bool {
+        let mut buf = vec![0; self.sample.len()];
+        let off = self.len - self.sample.len() as i64;
+        read_exact_at(file, &mut buf, off as u64).is_ok() && buf == self.sample
+    }
+
+    /// Whether the metadata of `now` is the same as this stamp.
+    pub fn same_as(&self, now: &FileStamp) -> bool {
+        (self.id, self.len, self.mtime) == (now.id, now.len, now.mtime)
+    }
+
+    /// Get the stamp of the file currently at the given path.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        fs::metadata(path).map(|meta| Self::of(&meta))
+    }
+
+    /// Describe how the file changed from this stamp to `now`, if it did.
+    /// Data appended to the same file does not count, since the data that the
+    /// buffer refers to is still there.
+    /// To tell, the sampled bytes are read again from `file`, which should be the
+    /// file at `now`.
+    pub fn change(&self, now: &FileStamp, file: &File) -> Option<&'static str> {
+        if self.id != now.id {
+            Some("was replaced")
+        } else if now.len < self.len {
+            Some("was truncated")
+        } else if now.mtime != self.mtime && (now.len == self.len || !self.sample_intact(file)) {
+            Some("was modified")
+        } else {
+            None
+        }
+    }
+}
@@ -5,13 +5,22 @@ use gl::winit::event::MouseScrollDelta;
 use crate::{
     cfg::Cfg,
     elem2bool,
-    filebuf::{CharLayout, FileLock, FilePos, FileRect},
+    filebuf::{CharLayout, EditInfo, FileLock, FilePos, FileRect, ViewState},
     mouse2id,
     prelude::*,
     ScreenRect, WindowState,
 };
 
+use self::{
+    goto::{GotoBar, GotoTarget},
+    hex::HexView,
+    search::SearchBar,
+};
+
 pub mod drawing;
+mod goto;
+mod hex;
+mod search;
 
 #[derive(Default)]
 struct ScrollManager {
@@ -237,15 +246,66 @@ struct MoveCmd {
     kind: MoveKind,
 }
 
+/// Buffer modification commands.
+/// Queued along with movement commands, so that they are executed in the
+/// same order as they were typed.
+enum EditCmd {
+    /// Replace the selection by the given text.
+    Insert(String),
+    /// Replace the selection by a line break, in the line ending of the buffer.
+    Newline,
+    /// Delete the selection, or the character before the cursor if the
+    /// selection is empty.
+    Backspace,
+    /// Delete the selection, or the character after the cursor if the
+    /// selection is empty.
+    Delete,
+    /// Undo the last edit.
+    Undo,
+    /// Redo the last undone edit.
+    Redo,
+    /// Replace the selection by the clipboard contents.
+    Paste,
+    /// Type a hex digit into the nibble at the cursor of the hex view.
+    Nibble(u8),
+}
+
+enum Cmd {
+    Move(MoveCmd),
+    Edit(EditCmd),
+    /// Select the next or previous search match.
+    Find {
+        forward: bool,
+    },
+    /// Replace the selected search match and select the next one, or replace all
+    /// matches.
+    Replace {
+        all: bool,
+    },
+}
+
 pub struct FileTab {
     pub file: FileBuffer,
     pub view: FileView,
+    /// Close the tab as soon as it finishes saving.
+    pub close_after_save: bool,
+    /// Whether the user was already asked to reload the file after it changed on
+    /// disk.
+    pub reload_asked: bool,
 }
 impl FileTab {
+    /// Open the file at the given path, or read standard input if the path is `-`.
     pub fn new(k: &Cfg, font: &FontArc, path: &Path) -> Result<FileTab> {
+        let layout = CharLayout::new(font, k.g.tab_stop);
+        let file = match path == Path::new("-") {
+            true => FileBuffer::spool("stdin", Box::new(io::stdin()), layout, k.clone())?,
+            false => FileBuffer::new(path.into(), layout, k.clone())?,
+        };
         Ok(Self {
-            file: FileBuffer::new(path.into(), CharLayout::new(font), k.clone())?,
+            file,
             view: FileView::new(),
+            close_after_save: false,
+            reload_asked: false,
         })
     }
 }
@@ -253,11 +313,19 @@ impl FileTab {
 pub struct FileView {
     view: ScreenRect,
     send_sel_copy: Cell<bool>,
+    send_save: Cell<bool>,
+    send_next_encoding: Cell<bool>,
     scroll: ScrollManager,
     selected: Selected,
-    move_queue: Vec<MoveCmd>,
+    cmd_queue: Vec<Cmd>,
     drag: Drag,
     selecting: bool,
+    search: Option<SearchBar>,
+    goto: Option<GotoBar>,
+    /// Show the buffer as a hex dump instead of as text.
+    hex: Option<HexView>,
+    /// The length of the buffer on the last frame, to notice it growing.
+    last_len: i64,
 }
 impl FileView {
     pub fn new() -> FileView {
@@ -275,16 +343,82 @@ impl FileView {
                 last_positions: [Some(default()); 2],
             },
             selecting: false,
-            move_queue: vec![],
+            cmd_queue: vec![],
             send_sel_copy: false.into(),
+            send_save: false.into(),
+            send_next_encoding: false.into(),
+            search: None,
+            goto: None,
+            hex: None,
+            last_len: 0,
         }
     }
 
     fn move_selection(&mut self, cmd: MoveCmd) {
-        self.move_queue.push(cmd);
+        // Moving around by hand cancels any pending jump
+        if let Some(goto) = &mut self.goto {
+            goto.pending = None;
+        }
+        self.cmd_queue.push(Cmd::Move(cmd));
+        self.selected.touch();
+    }
+
+    fn edit(&mut self, cmd: EditCmd) {
+        self.cmd_queue.push(Cmd::Edit(cmd));
         self.selected.touch();
     }
 
+    fn find(&mut self, forward: bool) {
+        self.cmd_queue.push(Cmd::Find { forward });
+        self.selected.touch();
+    }
+
+    fn replace(&mut self, all: bool) {
+        self.cmd_queue.push(Cmd::Replace { all });
+        self.selected.touch();
+    }
+
+    /// Whether keyboard input goes to the find bar.
+    fn search_focused(&self) -> bool {
+        self.goto.is_none() && self.search.as_ref().is_some_and(|bar| bar.focused)
+    }
+
+    /// Switch between the text and hex views, keeping the cursor where it is.
+    fn toggle_hex(&mut self, k: &Cfg, file: &FileBuffer) {
+        match self.hex.is_some() {
+            true => {
+                self.scroll.pos = self.text_scroll(k);
+                self.selected.last_positions = [None; 2];
+                self.hex = None;
+            }
+            false => {
+                let cursor = self.selected.second;
+                self.hex = Some(HexView::new(file.layout(), (cursor, self.scroll.pos)));
+                self.scroll.pos = FilePos {
+                    base_offset: 0,
+                    delta_x: 0.,
+                    delta_y: HexView::row_of(cursor) as f64 - k.ui.cursor_padding,
+                };
+            }
+        }
+    }
+
+    /// The scroll position of the text view.
+    /// While in the hex view, this is where the text view would scroll to when
+    /// switching back: the same place as before if the cursor did not move, or
+    /// right at the cursor otherwise.
+    fn text_scroll(&self, k: &Cfg) -> FilePos {
+        match &self.hex {
+            Some(hex) if hex.text_view.0 == self.selected.second => hex.text_view.1,
+            Some(_) => FilePos {
+                base_offset: self.selected.second,
+                delta_x: -k.ui.cursor_padding,
+                delta_y: -k.ui.cursor_padding,
+            },
+            None => self.scroll.pos,
+        }
+    }
+
     fn text_view(k: &Cfg, view: ScreenRect) -> ScreenRect {
         ScreenRect {
             min: view.min + vec2(k.g.left_bar, 0.),
@@ -467,46 +601,138 @@ impl FileView {
     /// The file manager might take single-digit amount of milliseconds to
     /// release the lock, so we *really* don't want to incur this cost twice.
     fn bookkeep_file(&mut self, state: &mut WindowState, file: &mut FileLock) {
-        // Apply selection movements
+        // Pick up the view from a previous session
+        if let Some(view) = file.take_restored_view() {
+            let len = file.filebuf.len();
+            self.selected.first = view.sel[0].clamp(0, len);
+            self.selected.second = view.sel[1].clamp(0, len);
+            self.selected.last_positions = [None; 2];
+            self.scroll.pos = view.scroll;
+            self.scroll.pos.base_offset = self.scroll.pos.base_offset.clamp(0, len);
+        }
+        // Decode the buffer with the next encoding if requested
+        // Characters may start elsewhere now, so snap the selection to them
+        if self.send_next_encoding.get() {
+            file.set_encoding(file.encoding().next());
+            self.send_next_encoding.set(false);
+            for off in [&mut self.selected.first, &mut self.selected.second] {
+                *off = file.char_boundary(*off).unwrap_or(*off);
+            }
+            let base = self.scroll.pos.base_offset;
+            self.scroll.pos.base_offset = file.char_boundary(base).unwrap_or(base);
+            self.selected.last_positions = [None; 2];
+        }
+        self.tick_goto(&state.k, file);
+        // When following a growing file, keep the cursor at the end if it was there
+        let len = file.filebuf.len();
+        if file.filebuf.is_following()
+            && len > self.last_len
+            && self.selected.first == self.last_len
+            && self.selected.second == self.last_len
+        {
+            self.cmd_queue.push(Cmd::Move(MoveCmd {
+                reset: true,
+                kind: MoveKind::Raw(len),
+            }));
+        }
+        self.last_len = len;
+        // Apply selection movements and edits
         let previous = self.selected.second;
-        for cmd in self.move_queue.drain(..) {
-            // Move offset depending on the command type
-            let current = self.selected.second;
-            match cmd.kind {
-                MoveKind::Absolute(pos) => {
-                    // Select based on a spacial position
-                    let (base, y, x) = pos.floor();
-                    if let Some(at) = file.lookup_pos(base, y, x, 0.5) {
-                        self.selected.second = at.offset;
+        for (range, len) in file.take_pasted() {
+            self.edited(range, len);
+        }
+        if let Some((range, len)) = file.take_replaced() {
+            // Leave the cursor at the first replacement
+            self.edited(range.clone(), len);
+            self.selected.first = range.start;
+            self.selected.second = range.start;
+        }
+        let mut queue = mem::take(&mut self.cmd_queue).into_iter();
+        while let Some(cmd) = queue.next() {
+            let cmd = match cmd {
+                Cmd::Move(cmd) => cmd,
+                Cmd::Edit(cmd) => {
+                    if let Some(cmd) = self.apply_edit(file, cmd) {
+                        // Wait for the data around the cursor, without reordering the
+                        // commands
+                        self.cmd_queue.push(Cmd::Edit(cmd));
+                        self.cmd_queue.extend(queue);
+                        break;
                     }
+                    continue;
                 }
-                MoveKind::Raw(off) => {
-                    // Select based on a raw file offset
-                    self.selected.second = off;
-                }
-                MoveKind::CharDelta(delta) => {
-                    // Move the current selection by this amount of characters
-                    // TODO: This may leave the cursor in the middle of a UTF-8 character
-                    // if we are at the edge of loaded data
-                    // Figure out what to do about it
-                    let off = file.char_delta(current, delta).unwrap_or_else(|e| e);
-                    self.selected.second = off;
+                Cmd::Find { forward } => match self.select_match(file, forward) {
+                    Some(cmd) => cmd,
+                    None => continue,
+                },
+                Cmd::Replace { all } => {
+                    let bar = self.search.as_ref();
+                    let (query, replacement) =
+                        match bar.and_then(|bar| Some((bar.query()?, bar.replace.clone()?))) {
+                            Some(q) => q,
+                            None => continue,
+                        };
+                    let sel_before = [self.selected.first, self.selected.second];
+                    let l = self.selected.first.min(self.selected.second);
+                    let r = self.selected.first.max(self.selected.second);
+                    if all {
+                        file.replace_all(query, replacement, sel_before);
+                        continue;
+                    }
+                    // Only replace the selection if it is a match
+                    if let Some(len) = file.replace_match(l..r, &query, &replacement, sel_before) {
+                        self.edited(l..r, len);
+                    }
+                    match self.select_match(file, true) {
+                        Some(cmd) => cmd,
+                        None => continue,
+                    }
                 }
-                MoveKind::LineDelta(delta) => {
-                    // Move the current selection by this amount of lines
-                    if let Some(at) = file.lookup_offset(current, current) {
-                        if let Some(at_target) = file.lookup_pos(current, at.dy + delta, at.dx, 0.5)
-                        {
-                            self.selected.second = at_target.offset;
+            };
+            // Move offset depending on the command type
+            // The hex view moves around bytes and fixed-length rows instead
+            let current = self.selected.second;
+            if let Some(hex) = &mut self.hex {
+                self.selected.second = hex.apply_move(&cmd, current, file.filebuf.len());
+            } else {
+                match cmd.kind {
+                    MoveKind::Absolute(pos) => {
+                        // Select based on a spacial position
+                        let (base, y, x) = pos.floor();
+                        if let Some(at) = file.lookup_pos(base, y, x, 0.5) {
+                            self.selected.second = at.offset;
                         }
                     }
-                }
-                MoveKind::HorizontalDelta(delta) => {
-                    // Move the current selection by this distance
-                    if let Some(at) = file.lookup_offset(current, current) {
-                        if let Some(at_target) = file.lookup_pos(current, at.dy, at.dx + delta, 0.5)
-                        {
-                            self.selected.second = at_target.offset;
+                    MoveKind::Raw(off) => {
+                        // Select based on a raw file offset
+                        self.selected.second = off;
+                    }
+                    MoveKind::CharDelta(delta) => {
+                        // Move the current selection by this amount of characters
+                        // TODO: This may leave the cursor in the middle of a UTF-8 character
+                        // if we are at the edge of loaded data
+                        // Figure out what to do about it
+                        let off = file.char_delta(current, delta).unwrap_or_else(|e| e);
+                        self.selected.second = off;
+                    }
+                    MoveKind::LineDelta(delta) => {
+                        // Move the current selection by this amount of lines
+                        if let Some(at) = file.lookup_offset(current, current) {
+                            if let Some(at_target) =
+                                file.lookup_pos(current, at.dy + delta, at.dx, 0.5)
+                            {
+                                self.selected.second = at_target.offset;
+                            }
+                        }
+                    }
+                    MoveKind::HorizontalDelta(delta) => {
+                        // Move the current selection by this distance
+                        if let Some(at) = file.lookup_offset(current, current) {
+                            if let Some(at_target) =
+                                file.lookup_pos(current, at.dy, at.dx + delta, 0.5)
+                            {
+                                self.selected.second = at_target.offset;
+                            }
                         }
                     }
                 }
@@ -519,9 +745,72 @@ impl FileView {
                 self.selected.last_positions[0] = self.selected.last_positions[1];
             }
         }
+        match &self.hex {
+            Some(hex) if self.selected.second != previous => {
+                let sz = self.scroll.last_view.size;
+                hex.fit_cursor(&mut self.scroll.pos, sz, self.selected.second, &state.k);
+            }
+            Some(_) => {}
+            None => self.fit_text_cursor(&state.k, file, previous),
+        }
+        // Start or stop searching in the background
+        // Any replace-all refers to the previous query, so forget about it
+        match &mut self.search {
+            Some(bar) if bar.changed => {
+                bar.changed = false;
+                file.search(bar.query());
+                file.stop_replace();
+            }
+            None if file.search_results().is_some() => {
+                file.search(None);
+                file.stop_replace();
+            }
+            _ => {}
+        }
+        // Inform the backend about what area of the file to load (and keep loaded)
+        let mut selection = self.selected.first..self.selected.second;
+        if selection.start > selection.end {
+            mem::swap(&mut selection.start, &mut selection.end);
+        }
+        if !self.cmd_queue.is_empty() {
+            // An edit is waiting for the characters around the cursor
+            selection = (selection.start - 4).max(0)..selection.end + 4;
+        }
+        match &self.hex {
+            Some(_) => {
+                // Rows are at fixed offsets, so there is no need to wait for the linemap
+                let top = self.scroll.pos.delta_y.floor() as i64;
+                let bottom = (self.scroll.pos.delta_y + self.scroll.last_view.size.y).ceil() as i64;
+                let len = file.filebuf.len();
+                let range =
+                    (top * hex::ROW_LEN).clamp(0, len)..(bottom * hex::ROW_LEN).clamp(0, len);
+                file.set_hot_bytes(range, Some(selection));
+            }
+            None => file.set_hot_area(self.scroll.last_view, Some(selection)),
+        }
+        // Keep the backend up to date with the view state, so that it can persist it
+        file.set_view(ViewState {
+            sel: [self.selected.first, self.selected.second],
+            scroll: self.text_scroll(&state.k),
+        });
+        // Send a copy command if requested
+        if self.send_sel_copy.get() {
+            file.copy_selection();
+            self.send_sel_copy.set(false);
+        }
+        // Send a save command if requested
+        if self.send_save.get() {
+            file.save();
+            self.send_save.set(false);
+        }
+    }
+
+    /// Work out where the selection is on the screen, and scroll to keep the cursor
+    /// visible if it moved.
+    fn fit_text_cursor(&mut self, k: &Cfg, file: &FileLock, previous: i64) {
         // Figure out spacial position of selection
-        for k in 0..2 {
-            let pos = &mut self.selected.last_positions[k];
+        for i in 0..2 {
+            let pos = &mut self.selected.last_positions[i];
             if pos.is_none() {
                 let p = file
                     .lookup_offset(self.scroll.pos.base_offset, self.selected.second)
@@ -537,10 +826,10 @@ impl FileView {
         if self.selected.second != previous {
             let sz = self.scroll.last_view.size;
             if let Some(pos) = self.selected.last_positions[1] {
-                let ylo = pos.delta_y + 1. + state.k.ui.cursor_padding - sz.y;
-                let yhi = pos.delta_y - state.k.ui.cursor_padding;
-                let xlo = pos.delta_x + state.k.ui.cursor_padding - sz.x;
-                let xhi = pos.delta_x - state.k.ui.cursor_padding;
+                let ylo = pos.delta_y + 1. + k.ui.cursor_padding - sz.y;
+                let yhi = pos.delta_y - k.ui.cursor_padding;
+                let xlo = pos.delta_x + k.ui.cursor_padding - sz.x;
+                let xhi = pos.delta_x - k.ui.cursor_padding;
                 self.scroll.pos.delta_y = self.scroll.pos.delta_y.clamp(ylo, yhi.max(ylo));
                 self.scroll.pos.delta_x = self.scroll.pos.delta_x.clamp(xlo, xhi.max(xlo));
             } else {
@@ -563,25 +852,264 @@ impl FileView {
                         // loaded point.
                         // Special-case the beggining of the file, to disallow scrolling
                         // before the start of the file.
-                        1. + state.k.ui.cursor_padding - sz.y
+                        1. + k.ui.cursor_padding - sz.y
                     } else {
                         // Scroll from bottom to top
-                        -state.k.ui.cursor_padding
+                        -k.ui.cursor_padding
                     },
                     delta_x: -sz.x / 2.,
                 };
             }
         }
-        // Inform the backend about what area of the file to load (and keep loaded)
-        let mut selection = self.selected.first..self.selected.second;
-        if selection.start > selection.end {
-            mem::swap(&mut selection.start, &mut selection.end);
+    }
+
+    /// Jump to the target of the go-to bar, if any.
+    /// Lines are estimated until the linemap reaches them, and the estimate is
+    /// refined as it grows.
+    /// Offsets are scrolled to right away, and the cursor is placed once the
+    /// surrounding data is loaded and the character boundary is known.
+    fn tick_goto(&mut self, k: &Cfg, file: &mut FileLock) {
+        let goto = match &mut self.goto {
+            Some(goto) => goto,
+            None => {
+                file.goto_line(None);
+                return;
+            }
+        };
+        let (off, exact) = match goto.pending {
+            Some(GotoTarget::Line(line)) => file.locate_line(line),
+            // The hex view can place the cursor on any byte
+            Some(GotoTarget::Offset(off)) if self.hex.is_some() => (off, true),
+            Some(GotoTarget::Offset(off)) => match file.char_boundary(off) {
+                Some(at) => (at, true),
+                None => (off, false),
+            },
+            None => {
+                file.goto_line(None);
+                return;
+            }
+        };
+        file.goto_line(match goto.pending {
+            Some(GotoTarget::Line(line)) if !exact => Some(line),
+            _ => None,
+        });
+        // Only move again if the estimate moved away from the loaded area
+        let radius = k.f.load_radius as i64 / 2;
+        let near = goto.jumped.is_some_and(|j| (off - j).abs() <= radius);
+        if !exact && near {
+            return;
         }
-        file.set_hot_area(self.scroll.last_view, Some(selection));
-        // Send a copy command if requested
-        if self.send_sel_copy.get() {
-            file.copy_selection();
-            self.send_sel_copy.set(false);
+        goto.jumped = Some(off);
+        if let (Some(GotoTarget::Offset(_)), None) = (goto.pending, &self.hex) {
+            // Scroll straight to the offset, so that it starts loading
+            self.scroll.pos = FilePos {
+                base_offset: off,
+                delta_x: -k.ui.cursor_padding,
+                delta_y: -k.ui.cursor_padding,
+            };
+        }
+        if exact || matches!(goto.pending, Some(GotoTarget::Line(_))) {
+            self.cmd_queue.push(Cmd::Move(MoveCmd {
+                reset: true,
+                kind: MoveKind::Raw(off),
+            }));
+        }
+        if exact {
+            self.goto = None;
+        }
+    }
+
+    /// Select the next or previous search match, even if it is nowhere near the
+    /// loaded area.
+    /// While the search is still running and the match might not have been found
+    /// yet, the command is retried on the next frame.
+    fn select_match(&mut self, file: &FileLock, forward: bool) -> Option<MoveCmd> {
+        let (l, r) = (self.selected.first, self.selected.second);
+        let sel = l.min(r)..l.max(r);
+        let s = file.search_results()?;
+        let m = match forward {
+            true => s.next_match(sel.clone()),
+            false => s.prev_match(sel.clone()),
+        };
+        let settled = match &m {
+            Some(m) if forward => m.start > sel.start || (m.start == sel.start && l == r),
+            Some(m) => m.start < sel.start && s.scanned >= sel.start,
+            None => false,
+        };
+        if !settled && !s.is_done() {
+            self.cmd_queue.push(Cmd::Find { forward });
+            return None;
+        }
+        let m = m?;
+        self.selected.first = m.start;
+        self.selected.last_positions[0] = None;
+        Some(MoveCmd {
+            reset: false,
+            kind: MoveKind::Raw(m.end),
+        })
+    }
+
+    /// Apply an edit to the buffer at the current selection, and collapse the
+    /// selection into a cursor after the edit.
+    /// Gives the edit back if it has to wait for more data to be loaded, or for a
+    /// paste to land.
+    fn apply_edit(&mut self, file: &mut FileLock, cmd: EditCmd) -> Option<EditCmd> {
+        if file.is_pasting() {
+            // Keep the edits in order, the cursor moves once the paste lands
+            return Some(cmd);
+        }
+        let sel_before = [self.selected.first, self.selected.second];
+        let mut l = self.selected.first.min(self.selected.second);
+        let mut r = self.selected.first.max(self.selected.second);
+        let encoded;
+        let ins = match &cmd {
+            EditCmd::Nibble(digit) => {
+                self.type_nibble(file, *digit);
+                return None;
+            }
+            EditCmd::Insert(text) => {
+                encoded = file.encode(text);
+                &encoded[..]
+            }
+            EditCmd::Newline => {
+                let le = file.line_ending().unwrap_or_default();
+                encoded = file.encode(le.as_str());
+                &encoded[..]
+            }
+            EditCmd::Backspace | EditCmd::Delete => &[],
+            EditCmd::Paste => {
+                // The clipboard is fetched in the background
+                file.paste(l..r, sel_before);
+                return None;
+            }
+            EditCmd::Undo | EditCmd::Redo => {
+                // Restore the selection from the time of the edit
+                let restored = match cmd {
+                    EditCmd::Undo => file.undo(),
+                    _ => file.redo(),
+                };
+                if let Some(restored) = restored {
+                    self.shift_scroll(restored.range, restored.len);
+                    let [first, second] = restored.sel;
+                    self.selected.first = first;
+                    self.selected.second = second;
+                    self.selected.last_positions = [None; 2];
+                    if let Some(hex) = &mut self.hex {
+                        hex.low_nibble = false;
+                    }
+                }
+                return None;
+            }
+        };
+        if l == r {
+            // Without a selection, deletions remove a single character
+            // If the character is not loaded, we don't know how long it is, so wait
+            // for it to load, unless the file changed and nothing more will load
+            let len = file.filebuf.len();
+            let wait = |cmd| file.external_change().is_none().then_some(cmd);
+            match cmd {
+                // The hex view deals in bytes instead
+                EditCmd::Backspace if self.hex.is_some() => l = (l - 1).max(0),
+                EditCmd::Delete if self.hex.is_some() => r = (r + 1).min(len),
+                EditCmd::Backspace if l > 0 => match file.char_delta(l, -1) {
+                    Ok(off) => l = off,
+                    Err(_) => return wait(cmd),
+                },
+                EditCmd::Delete if r < len => match file.char_delta(r, 1) {
+                    Ok(off) => r = off,
+                    Err(_) => return wait(cmd),
+                },
+                _ => {}
+            }
+        }
+        if l == r && ins.is_empty() {
+            return None;
+        }
+        let cursor = l + ins.len() as i64;
+        let info = EditInfo {
+            sel_before,
+            sel_after: [cursor; 2],
+            typed: matches!(cmd, EditCmd::Insert(_) | EditCmd::Newline),
+        };
+        if !file.splice(l..r, ins, info) {
+            return None;
+        }
+        self.edited(l..r, ins.len() as i64);
+        None
+    }
+
+    /// Type a hex digit at the cursor of the hex view.
+    /// The high nibble starts a byte, either overwriting the byte at the cursor or
+    /// inserting a new one, and the low nibble completes it and moves on to the
+    /// next byte.
+    /// A selection is replaced by the new byte.
+    fn type_nibble(&mut self, file: &mut FileLock, digit: u8) {
+        let hex = match &self.hex {
+            Some(hex) => hex,
+            None => return,
+        };
+        let sel_before = [self.selected.first, self.selected.second];
+        let l = self.selected.first.min(self.selected.second);
+        let r = self.selected.first.max(self.selected.second);
+        let low = l == r && hex.low_nibble;
+        let insert = l != r || !hex.overwrite && !low || l == file.filebuf.len();
+        let (range, byte) = if insert {
+            (l..r, digit << 4)
+        } else {
+            // Keep the other nibble of the byte
+            // If the byte is not loaded, we don't know what it is
+            let old = match file.loaded_bytes(l).first() {
+                Some(&b) => b,
+                None => return,
+            };
+            match low {
+                true => (l..l + 1, old & 0xf0 | digit),
+                false => (l..l + 1, digit << 4 | old & 0x0f),
+            }
+        };
+        let cursor = l + low as i64;
+        let info = EditInfo {
+            sel_before,
+            sel_after: [cursor; 2],
+            typed: true,
+        };
+        if !file.splice(range.clone(), &[byte], info) {
+            return;
+        }
+        self.edited(range, 1);
+        self.selected.first = cursor;
+        self.selected.second = cursor;
+        if let Some(hex) = &mut self.hex {
+            hex.low_nibble = !low;
+        }
+    }
+
+    /// Keep the scroll position still after the given range was replaced by `len`
+    /// bytes, unless its base was removed.
+    fn shift_scroll(&mut self, range: ops::Range<i64>, len: i64) {
+        let (l, r) = (range.start, range.end);
+        let base = match &mut self.hex {
+            Some(hex) => &mut hex.text_view.1.base_offset,
+            None => &mut self.scroll.pos.base_offset,
+        };
+        if *base >= r {
+            *base += len - (r - l);
+        } else if *base > l {
+            *base = l;
+        }
+    }
+
+    /// Adjust the view after the given range was replaced by `len` bytes.
+    fn edited(&mut self, range: ops::Range<i64>, len: i64) {
+        let l = range.start;
+        self.shift_scroll(range, len);
+        // Place the cursor right after the inserted text
+        let cursor = l + len;
+        self.selected.second = cursor;
+        self.selected.first = cursor;
+        self.selected.last_positions = [None; 2];
+        if let Some(hex) = &mut self.hex {
+            hex.low_nibble = false;
         }
     }
 
@@ -598,10 +1126,137 @@ impl FileView {
                     use gl::glutin::event::VirtualKeyCode::*;
                     let down = elem2bool(input.state);
                     match input.virtual_keycode {
+                        Some(G) if down && state.keys.ctrl() => {
+                            // Shift switches from line numbers to byte offsets
+                            let goto = self.goto.get_or_insert_with(default);
+                            goto.offset = state.keys.shift();
+                            state.redraw();
+                        }
+                        Some(Escape) if down && self.goto.is_some() => {
+                            self.goto = None;
+                            state.redraw();
+                        }
+                        Some(Return | NumpadEnter) if down && self.goto.is_some() => {
+                            if let Some(goto) = &mut self.goto {
+                                goto.submit(file.len());
+                            }
+                            state.redraw();
+                        }
+                        Some(Back) if down && self.goto.is_some() => {
+                            if let Some(goto) = &mut self.goto {
+                                goto.text.pop();
+                            }
+                            state.redraw();
+                        }
+                        Some(Tab | Delete) if self.goto.is_some() => {}
+                        Some(F) if down && state.keys.ctrl() => {
+                            self.goto = None;
+                            let bar = self
+                                .search
+                                .get_or_insert_with(|| SearchBar::new(String::new()));
+                            bar.focused = true;
+                            bar.replace_focused = false;
+                            state.redraw();
+                        }
+                        Some(H) if down && state.keys.ctrl() => {
+                            // Open the bar in replace mode, focusing the replacement if
+                            // there is a query already
+                            self.goto = None;
+                            let bar = self
+                                .search
+                                .get_or_insert_with(|| SearchBar::new(String::new()));
+                            bar.replace.get_or_insert_with(String::new);
+                            bar.focused = true;
+                            bar.replace_focused = !bar.text.is_empty();
+                            state.redraw();
+                        }
+                        Some(F3) if down && self.search.is_some() => {
+                            self.find(!state.keys.shift());
+                            state.redraw();
+                        }
+                        Some(Escape) if down && self.search.is_some() => {
+                            self.search = None;
+                            state.redraw();
+                        }
+                        Some(Return | NumpadEnter) if down && self.search_focused() => {
+                            if self.search.as_ref().is_some_and(|bar| bar.replace_focused) {
+                                self.replace(state.keys.ctrl());
+                            } else {
+                                self.find(!state.keys.shift());
+                            }
+                            state.redraw();
+                        }
+                        Some(Back) if down && self.search_focused() => {
+                            if let Some(bar) = &mut self.search {
+                                bar.backspace();
+                            }
+                            state.redraw();
+                        }
+                        Some(Tab) if self.search_focused() => {
+                            if let (true, Some(bar)) = (down, &mut self.search) {
+                                bar.switch_field();
+                                state.redraw();
+                            }
+                        }
+                        Some(Delete) if self.search_focused() => {}
+                        Some(key @ (C | W | R))
+                            if down
+                                && state.keys.alt()
+                                && !state.keys.ctrl()
+                                && self.search_focused() =>
+                        {
+                            // Toggle the search options
+                            if let Some(bar) = &mut self.search {
+                                bar.toggle(|bar| match key {
+                                    C => &mut bar.case_insensitive,
+                                    W => &mut bar.whole_word,
+                                    _ => &mut bar.regex,
+                                });
+                                state.redraw();
+                            }
+                        }
+                        Some(F5) if down && file.external_change().is_some() => {
+                            file.reload();
+                            state.redraw();
+                        }
+                        Some(B) if down && state.keys.ctrl() => {
+                            self.toggle_hex(&state.k, file);
+                            state.redraw();
+                        }
+                        Some(Insert) if down && self.hex.is_some() => {
+                            if let Some(hex) = &mut self.hex {
+                                hex.overwrite = !hex.overwrite;
+                            }
+                            state.redraw();
+                        }
+                        Some(T) if down && state.keys.ctrl() => {
+                            file.set_follow(!file.is_following());
+                            state.redraw();
+                        }
                         Some(C) if down && state.keys.ctrl() => {
                             self.send_sel_copy.set(true);
                             state.redraw();
                         }
+                        Some(S) if down && state.keys.ctrl() => {
+                            self.send_save.set(true);
+                            state.redraw();
+                        }
+                        Some(E) if down && state.keys.ctrl() => {
+                            self.send_next_encoding.set(true);
+                            state.redraw();
+                        }
+                        Some(V) if down && state.keys.ctrl() => {
+                            self.edit(EditCmd::Paste);
+                            state.redraw();
+                        }
+                        Some(Z) if down && state.keys.ctrl() => {
+                            self.edit(if state.keys.shift() {
+                                EditCmd::Redo
+                            } else {
+                                EditCmd::Undo
+                            });
+                            state.redraw();
+                        }
                         Some(A) if down && state.keys.ctrl() => {
                             self.move_selection(MoveCmd {
                                 reset: true,
@@ -609,7 +1264,7 @@ impl FileView {
                             });
                             self.move_selection(MoveCmd {
                                 reset: false,
-                                kind: MoveKind::Raw(file.file_size()),
+                                kind: MoveKind::Raw(file.len()),
                             });
                             state.redraw();
                         }
@@ -627,7 +1282,7 @@ impl FileView {
                         }
                         Some(End) if down => {
                             let kind = if state.keys.ctrl() {
-                                MoveKind::Raw(file.file_size())
+                                MoveKind::Raw(file.len())
                             } else {
                                 MoveKind::HorizontalDelta(f64::INFINITY)
                             };
@@ -683,9 +1338,47 @@ impl FileView {
                             });
                             state.redraw();
                         }
+                        // The hex view only takes hex digits
+                        Some(Return | NumpadEnter | Tab) if self.hex.is_some() => {}
+                        Some(Return | NumpadEnter) if down => {
+                            self.edit(EditCmd::Newline);
+                            state.redraw();
+                        }
+                        Some(Tab) if down && !state.keys.ctrl() => {
+                            self.edit(EditCmd::Insert("\t".to_string()));
+                            state.redraw();
+                        }
+                        Some(Back) if down => {
+                            self.edit(EditCmd::Backspace);
+                            state.redraw();
+                        }
+                        Some(Delete) if down => {
+                            self.edit(EditCmd::Delete);
+                            state.redraw();
+                        }
                         _ => {}
                     }
                 }
+                // Control characters are handled through their virtual keycodes
+                WindowEvent::ReceivedCharacter(c) if !c.is_control() && !state.keys.ctrl() => {
+                    match (&mut self.goto, &mut self.search) {
+                        (Some(goto), _) => goto.text.push(*c),
+                        (None, Some(bar)) if bar.focused => {
+                            // Alt is reserved for toggling search options
+                            if !state.keys.alt() {
+                                bar.type_char(*c);
+                            }
+                        }
+                        // The hex view only takes hex digits
+                        _ if self.hex.is_some() => {
+                            if let Some(digit) = c.to_digit(16) {
+                                self.edit(EditCmd::Nibble(digit as u8));
+                            }
+                        }
+                        _ => self.edit(EditCmd::Insert(c.to_string())),
+                    }
+                    state.redraw();
+                }
                 WindowEvent::MouseWheel { delta, .. } => {
                     if self.view.is_inside(state.last_mouse_pos) {
                         // Scroll directly using mouse/trackpad wheel
@@ -710,6 +1403,31 @@ impl FileView {
                 } => {
                     let button = mouse2id(*button);
                     let down = elem2bool(*st);
+                    if let (true, Some(goto)) = (down, &self.goto) {
+                        // Clicking anywhere else closes the go-to bar
+                        let search = self.search.as_ref();
+                        let bounds = goto.bounds(&state.k, self.view, search);
+                        if bounds.is_inside(state.last_mouse_pos) {
+                            return;
+                        }
+                        self.goto = None;
+                        state.redraw();
+                    }
+                    if let (true, Some(bar)) = (down, &mut self.search) {
+                        // Clicking the find bar gives it focus, clicking elsewhere takes it away
+                        let pos = state.last_mouse_pos;
+                        let inside = bar.bounds(&state.k, self.view).is_inside(pos);
+                        let replace_row = bar.replace.is_some()
+                            && bar.row_bounds(&state.k, self.view, 1).is_inside(pos);
+                        if bar.focused != inside || bar.replace_focused != replace_row {
+                            bar.focused = inside;
+                            bar.replace_focused = replace_row;
+                            state.redraw();
+                        }
+                        if inside {
+                            return;
+                        }
+                    }
                     self.handle_drag(state, button, down);
                 }
                 WindowEvent::CursorMoved { position, .. } => {
@@ -6,13 +6,18 @@ use gl::glium::{
 
 use crate::{
     drawing::{FrameCtx, TRIANGLES_LIST},
-    filebuf::FileRect,
+    filebuf::{CharLayout, FileLock, FileRect},
     fileview::FileView,
     prelude::*,
     ScreenRect, WindowState,
 };
 
-use super::{Drag, FileTab};
+use super::{
+    goto::{GotoBar, GotoTarget},
+    hex::{HexView, ROW_LEN},
+    search::SearchBar,
+    Drag, FileTab,
+};
 
 pub fn draw_withtext(
     state: &mut WindowState,
@@ -26,14 +31,54 @@ pub fn draw_withtext(
 
     state.draw.timing.mark("file-lock");
 
-    let text_view = FileView::text_view(&state.k, fview.view);
-
     // Do any bookkeeping that requires the lock
     // This includes moving the selection, possibly moving the scroll position with it
     fview.bookkeep_file(state, &mut file);
 
     state.draw.timing.mark("book-keep");
 
+    match fview.hex {
+        Some(_) => draw_hex(state, fview, &file, ftab.file.layout(), ctx),
+        None => draw_text(state, fview, &mut file, ftab.file.layout(), ctx),
+    }
+
+    // Draw the find bar
+    if let Some(bar) = &fview.search {
+        draw_search_bar(state, bar, fview.view, &file);
+    }
+    if let Some(goto) = &fview.goto {
+        draw_goto_bar(state, goto, fview.view, fview.search.as_ref(), &file);
+    }
+
+    draw_status_bar(state, fview, &file);
+
+    // Check back periodically, in case the file changes on disk
+    let mut interval = state.k.f.watch_interval;
+    if file.filebuf.is_following() {
+        interval = interval.min(state.k.f.follow_interval);
+    }
+    ctx.schedule_redraw(Instant::now() + Duration::from_secs_f64(interval.max(0.01)));
+
+    // If the backend is not idle, we should render periodically to show any updates
+    // Edits waiting for their data also need to be retried
+    if !file.is_backend_idle() || fview.drag.requires_refresh() || !fview.cmd_queue.is_empty() {
+        state.redraw();
+    }
+
+    Ok(())
+}
+
+/// Draw the text around the scroll position, along with the selection and the
+/// cursor.
+fn draw_text(
+    state: &mut WindowState,
+    fview: &mut FileView,
+    file: &mut FileLock,
+    layout: &CharLayout,
+    ctx: &mut FrameCtx,
+) {
+    let text_view = FileView::text_view(&state.k, fview.view);
+
     // Determine the bounds of the loaded area, and clamp the scroll position to it
     let scroll_bounds = file.bounding_rect(fview.scroll.pos.base_offset);
     fview.scroll.pos = scroll_bounds.clamp_pos(fview.scroll.pos);
@@ -63,6 +108,22 @@ pub fn draw_withtext(
         min: default(),
         max: default(),
     };
+    // Highlight the search matches, with the selected match standing out
+    let matches = match fview.search {
+        Some(_) => file.visible_matches(fview.scroll.last_view),
+        None => vec![],
+    };
+    let mut match_box: Option<(usize, ScreenRect)> = None;
+    let push_match_box = |state: &mut WindowState, b: Option<(usize, ScreenRect)>| {
+        if let Some((i, b)) = b {
+            let color = if matches[i] == sel_range {
+                state.k.g.current_match_bg_color
+            } else {
+                state.k.g.match_bg_color
+            };
+            state.draw.sel_vbo.push_quad(b, color);
+        }
+    };
     let absolute_start = file.lookup_offset(fview.scroll.pos.base_offset, 0);
     file.visit_rect(fview.scroll.last_view, |offset, dx, dy, c| {
         match c {
@@ -75,8 +136,7 @@ pub fn draw_withtext(
                         + ((dy + 1) as f64 - fview.scroll.pos.delta_y) as f32
                             * state.k.g.font_height;
                     let mut draw_char = |c| {
-                        x -=
-                            ftab.file.layout().advance_for(c as u32) as f32 * state.k.g.font_height;
+                        x -= layout.advance_for(c as u32) as f32 * state.k.g.font_height;
                         state.draw.linenums.push(
                             &mut state.draw.glyphs,
                             state.k.g.linenum_color,
@@ -104,7 +164,8 @@ pub fn draw_withtext(
                         draw_char(if linenum < 0 { '-' } else { '+' });
                     }
                 }
-                // Draw previous selection box
+                // Draw previous match and selection boxes
+                push_match_box(state, match_box.take());
                 if sel_box.min.x < sel_box.max.x {
                     state
                         .draw
@@ -129,9 +190,23 @@ pub fn draw_withtext(
                     )
                     .as_vec2()
                         * state.k.g.font_height;
+                let cbox = ScreenRect {
+                    min: vec2(pos.x, sel_box.min.y),
+                    max: vec2(pos.x + hadv as f32 * state.k.g.font_height, sel_box.max.y),
+                };
+                // If the character is part of a match, make sure its match box wraps it
+                let i = matches.partition_point(|m| m.end <= offset);
+                let in_match = matches.get(i).filter(|m| m.start <= offset);
+                if in_match.is_some() {
+                    match &mut match_box {
+                        Some((j, b)) if *j == i => b.max.x = b.max.x.max(cbox.max.x),
+                        _ => push_match_box(state, match_box.replace((i, cbox))),
+                    }
+                }
                 // If the character is selected, make sure the selection box wraps it
+                // The selected match already stands out on its own
                 let is_sel = sel_range.start <= offset && offset < sel_range.end;
-                if is_sel {
+                if is_sel && in_match != Some(&sel_range) {
                     sel_box.min.x = sel_box.min.x.min(pos.x);
                     sel_box.max.x = sel_box
                         .max
@@ -139,6 +214,10 @@ pub fn draw_withtext(
                         .max(pos.x + hadv as f32 * state.k.g.font_height);
                 }
                 // Create and queue the glyph
+                // Tabs are only whitespace
+                if c == CharLayout::TAB {
+                    return;
+                }
                 let g = Glyph {
                     id: state.draw.font.glyph_id(char::from_u32(c).unwrap_or('\0')),
                     scale: state.k.g.font_height.into(),
@@ -157,6 +236,7 @@ pub fn draw_withtext(
         }
     });
     {
+        push_match_box(state, match_box.take());
         if sel_box.min.x < sel_box.max.x {
             state
                 .draw
@@ -193,13 +273,384 @@ pub fn draw_withtext(
     }
 
     state.draw.timing.mark("draw-cursor");
+}
 
-    // If the backend is not idle, we should render periodically to show any updates
-    if !file.is_backend_idle() || fview.drag.requires_refresh() {
+/// Draw the buffer as a hex dump, with the offset of each row in the left bar.
+/// Bytes that are not loaded yet are left blank, and modified bytes stand out.
+fn draw_hex(
+    state: &mut WindowState,
+    fview: &mut FileView,
+    file: &FileLock,
+    layout: &CharLayout,
+    ctx: &mut FrameCtx,
+) {
+    let text_view = FileView::text_view(&state.k, fview.view);
+    let fh = state.k.g.font_height;
+    let len = file.filebuf.len();
+    let hex = match &fview.hex {
+        Some(hex) => hex,
+        None => return,
+    };
+
+    // All rows are known from the start, so scroll within all of them
+    let bounds = FileRect {
+        corner: default(),
+        size: dvec2(hex.width(), HexView::rows(len) as f64),
+    };
+    fview.scroll.pos = bounds.clamp_pos(fview.scroll.pos);
+    fview.scroll.last_view = FileRect {
+        corner: fview.scroll.pos,
+        size: (text_view.size() / fh).as_dvec2(),
+    };
+    if !fview.drag.is_scrollbar() {
+        fview.scroll.last_bounds = bounds;
+    }
+    let scroll = fview.scroll.pos;
+
+    let sel_range = if fview.selected.first <= fview.selected.second {
+        fview.selected.first..fview.selected.second
+    } else {
+        fview.selected.second..fview.selected.first
+    };
+
+    // Center each character in its cell
+    let push_char = |state: &mut WindowState, c: char, x: f64, y: f32, color| {
+        let adv = layout.advance_for(c as u32);
+        let x = text_view.min.x + ((x - scroll.delta_x) + (hex.cell - adv) / 2.) as f32 * fh;
+        let g = Glyph {
+            id: state.draw.font.glyph_id(c),
+            scale: fh.into(),
+            position: (x, y).into(),
+        };
+        state.draw.text.push(&mut state.draw.glyphs, color, g);
+    };
+    // The box behind a range of cells in a row
+    let cell_box = |x0: f64, x1: f64, top: f32| ScreenRect {
+        min: vec2(text_view.min.x + (x0 - scroll.delta_x) as f32 * fh, top),
+        max: vec2(
+            text_view.min.x + (x1 - scroll.delta_x) as f32 * fh,
+            top + fh,
+        ),
+    };
+
+    let top_row = scroll.delta_y.floor() as i64;
+    let bottom_row =
+        ((scroll.delta_y + fview.scroll.last_view.size.y).ceil() as i64).min(HexView::rows(len));
+    let edited = file.edited_ranges(top_row * ROW_LEN..bottom_row * ROW_LEN);
+    for row in top_row.max(0)..bottom_row {
+        let start = row * ROW_LEN;
+        let y = text_view.min.y + ((row + 1) as f64 - scroll.delta_y) as f32 * fh;
+        let top = text_view.min.y
+            + (row as f64 - scroll.delta_y) as f32 * fh
+            + (state.k.g.selection_offset * fh).round();
+        // Write the offset of the row in the left bar, right-aligned like line numbers
+        {
+            let mut x = text_view.min.x - state.k.g.linenum_pad;
+            for c in format!("{:08x}", start).chars().rev() {
+                x -= layout.advance_for(c as u32) as f32 * fh;
+                let g = Glyph {
+                    id: state.draw.font.glyph_id(c),
+                    scale: fh.into(),
+                    position: (x, y).into(),
+                };
+                state
+                    .draw
+                    .linenums
+                    .push(&mut state.draw.glyphs, state.k.g.linenum_color, g);
+            }
+        }
+        let data = file.loaded_bytes(start);
+        for col in 0..(len - start).min(ROW_LEN) {
+            let off = start + col;
+            let is_sel = sel_range.contains(&off);
+            if is_sel {
+                // Join the selection boxes of consecutive bytes
+                let joined = col + 1 < ROW_LEN && sel_range.contains(&(off + 1));
+                let hex_end = match joined {
+                    true => hex.hex_x(col + 1),
+                    false => hex.hex_x(col) + 2. * hex.cell,
+                };
+                let color = state.k.g.selection_bg_color;
+                let ascii_x = hex.ascii_x(col);
+                state
+                    .draw
+                    .sel_vbo
+                    .push_quad(cell_box(hex.hex_x(col), hex_end, top), color);
+                state
+                    .draw
+                    .sel_vbo
+                    .push_quad(cell_box(ascii_x, ascii_x + hex.cell, top), color);
+            }
+            let b = match data.get(col as usize) {
+                Some(&b) => b,
+                None => continue,
+            };
+            let i = edited.partition_point(|r| r.end <= off);
+            let is_edited = edited.get(i).is_some_and(|r| r.start <= off);
+            let color = match (is_sel, is_edited) {
+                (true, _) => state.k.g.selection_color,
+                (false, true) => state.k.g.edited_color,
+                (false, false) => state.k.g.text_color,
+            };
+            let [hi, lo] = HexView::hex_digits(b);
+            push_char(state, hi, hex.hex_x(col), y, color);
+            push_char(state, lo, hex.hex_x(col) + hex.cell, y, color);
+            push_char(state, HexView::ascii_char(b), hex.ascii_x(col), y, color);
+        }
+    }
+
+    state.draw.timing.mark("draw-text");
+
+    // Underline the nibble at the cursor in the hex area, and its byte in the ASCII
+    // gutter
+    let (visible, next) = fview.selected.check_blink(&state.k);
+    ctx.schedule_redraw(next);
+    if visible {
+        let cursor = fview.selected.second;
+        let col = cursor.rem_euclid(ROW_LEN);
+        let top = text_view.min.y
+            + (HexView::row_of(cursor) as f64 - scroll.delta_y) as f32 * fh
+            + (state.k.g.selection_offset * fh).round();
+        let underline = |mut b: ScreenRect| {
+            b.min.y = b.max.y - state.k.g.cursor_width;
+            b
+        };
+        let x = hex.hex_x(col) + if hex.low_nibble { hex.cell } else { 0. };
+        let hex_box = underline(cell_box(x, x + hex.cell, top));
+        let ascii_box = underline(cell_box(hex.ascii_x(col), hex.ascii_x(col) + hex.cell, top));
+        for b in [hex_box, ascii_box] {
+            if text_view.is_inside(b.min) && text_view.is_inside(b.max) {
+                state.draw.aux_vbo.push_quad(b, state.k.g.cursor_color);
+            }
+        }
+    }
+
+    state.draw.timing.mark("draw-cursor");
+}
+
+fn draw_search_bar(state: &mut WindowState, bar: &SearchBar, view: ScreenRect, file: &FileLock) {
+    let bounds = bar.bounds(&state.k, view);
+    state.draw.aux_vbo.push_quad(bounds, state.k.g.bar_bg_color);
+    // Show how the search is going on the right
+    let info = match file.search_results() {
+        Some(s) if s.error.is_some() => s.error.clone().unwrap(),
+        Some(s) if s.is_done() => format!("{} matches", s.count),
+        Some(s) => format!("{} matches ({:.0}%)", s.count, s.progress() * 100.),
+        None => String::new(),
+    };
+    let row = bar.row_bounds(&state.k, view, 0);
+    let y = bar_baseline(state, row);
+    let mut info_x = push_bar_info(state, row, &info);
+    // Show the search options to the left of the info
+    for (label, on) in bar.toggles().into_iter().rev() {
+        let k = &state.k.g;
+        let x = info_x - k.bar_padding - state.draw.text_width(label, k.bar_font_height);
+        state.draw.push_aux_line(
+            label,
+            vec2(x, y).round(),
+            k.bar_font_height,
+            k.bar_toggle_color[on as usize],
+            info_x,
+        );
+        info_x = x;
+    }
+    let caret = bar.focused && !bar.replace_focused;
+    push_bar_field(state, row, "Find: ", &bar.text, caret, info_x);
+    if let Some(text) = &bar.replace {
+        // Show how the replace-all is going, or how to trigger it
+        let info = match file.replace_progress() {
+            Some(r) if r.error.is_some() => r.error.clone().unwrap(),
+            Some(r) if r.is_done() => format!("Replaced {} matches", r.count),
+            Some(r) => format!("Replacing {} ({:.0}%)", r.count, r.progress() * 100.),
+            None => "Enter: replace, Ctrl+Enter: replace all".to_string(),
+        };
+        let row = bar.row_bounds(&state.k, view, 1);
+        let info_x = push_bar_info(state, row, &info);
+        let caret = bar.focused && bar.replace_focused;
+        push_bar_field(state, row, "Replace: ", text, caret, info_x);
+    }
+    if file.search_results().is_some_and(|s| !s.is_done())
+        || file.replace_progress().is_some_and(|r| !r.is_done())
+    {
         state.redraw();
     }
+}
 
-    Ok(())
+fn draw_goto_bar(
+    state: &mut WindowState,
+    goto: &GotoBar,
+    view: ScreenRect,
+    search: Option<&SearchBar>,
+    file: &FileLock,
+) {
+    let row = goto.bounds(&state.k, view, search);
+    state.draw.aux_vbo.push_quad(row, state.k.g.bar_bg_color);
+    // Show how far the linemap got while the exact line is not known yet
+    let info = match goto.pending {
+        Some(GotoTarget::Line(_)) => {
+            let mapped = file.mapped_from_start() as f64 / file.filebuf.len().max(1) as f64;
+            state.redraw();
+            format!("Estimated, {:.0}% mapped", mapped * 100.)
+        }
+        Some(GotoTarget::Offset(_)) => {
+            state.redraw();
+            "Loading".to_string()
+        }
+        None if goto.target(file.filebuf.len()).is_none() && !goto.text.is_empty() => {
+            match goto.offset {
+                true => "Not an offset".to_string(),
+                false => "Not a line number".to_string(),
+            }
+        }
+        None if goto.offset => "Decimal, 0x hex or %".to_string(),
+        None => "Enter: go to line".to_string(),
+    };
+    let info_x = push_bar_info(state, row, &info);
+    push_bar_field(state, row, goto.label(), &goto.text, true, info_x);
+}
+
+/// Fill in the status bar at the bottom of the window with the cursor position and
+/// how much of the buffer is known.
+fn draw_status_bar(state: &mut WindowState, fview: &FileView, file: &FileLock) {
+    let cursor = fview.selected.second;
+    let sel = (fview.selected.second - fview.selected.first).abs();
+    let len = file.filebuf.len();
+    let mut left = match fview.hex {
+        Some(_) => format!("Offset {} (0x{:x})", cursor, cursor),
+        None => format!("Offset {}", cursor),
+    };
+    match file.line_col(cursor) {
+        (Some(line), Some(col)) => left += &format!("    Ln {}, Col {}", line, col),
+        (Some(line), None) => left += &format!("    Ln {}", line),
+        (None, Some(col)) => left += &format!("    Col {}", col),
+        (None, None) => {}
+    }
+    if sel > 0 {
+        left += &format!("    Sel {}", format_size(sel));
+    }
+    let (mapped, loaded) = file.coverage();
+    let pct = |n: i64| n as f64 / len.max(1) as f64 * 100.;
+    let mut right = format!(
+        "{}    {:.0}% mapped, {:.0}% loaded",
+        format_size(len),
+        pct(mapped),
+        pct(loaded),
+    );
+    if let Some(lines) = file.line_count() {
+        right += &format!("    {} lines", lines);
+    }
+    match &fview.hex {
+        Some(hex) if hex.overwrite => right += "    Hex, overwrite",
+        Some(_) => right += "    Hex, insert",
+        None => {}
+    }
+    right += "    ";
+    right += file.encoding().name();
+    if let Some(le) = file.line_ending() {
+        right += "    ";
+        right += le.name();
+    }
+    if let Some(codec) = file.filebuf.codec() {
+        right += "    ";
+        right += codec.name();
+    }
+    if file.filebuf.is_decoding() {
+        right += "    Decompressing";
+    }
+    if file.filebuf.is_spooling() {
+        right += "    Reading input";
+    }
+    if file.filebuf.is_following() {
+        right += "    Following";
+    }
+    if file.external_change().is_some() {
+        right += "    Changed on disk (F5: reload)";
+    }
+
+    let k = &state.k.g;
+    let bounds = WindowState::status_bar_bounds(&state.k, state.screen);
+    let y = (bounds.min.y + bounds.max.y) / 2. + state.draw.center_to_baseline(k.bar_font_height);
+    let max_x = bounds.max.x - k.bar_padding;
+    let right_x = max_x - state.draw.text_width(&right, k.bar_font_height);
+    state.draw.push_aux_line(
+        &right,
+        vec2(right_x, y).round(),
+        k.bar_font_height,
+        k.status_text_color,
+        max_x,
+    );
+    state.draw.push_aux_line(
+        &left,
+        vec2(bounds.min.x + k.bar_padding, y).round(),
+        k.bar_font_height,
+        k.status_text_color,
+        right_x - k.bar_padding,
+    );
+}
+
+/// Format an amount of bytes in a human-readable way.
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut n = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while n >= 1024. && unit + 1 < UNITS.len() {
+        n /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", n, UNITS[unit])
+}
+
+/// The baseline of the text in a bar row.
+fn bar_baseline(state: &WindowState, row: ScreenRect) -> f32 {
+    (row.min.y + row.max.y) / 2. + state.draw.center_to_baseline(state.k.g.bar_font_height)
+}
+
+/// Draw informational text aligned to the right of a bar row.
+/// Returns where the text starts.
+fn push_bar_info(state: &mut WindowState, row: ScreenRect, info: &str) -> f32 {
+    let k = &state.k.g;
+    let max_x = row.max.x - k.bar_padding;
+    let x = max_x - state.draw.text_width(info, k.bar_font_height);
+    let y = bar_baseline(state, row);
+    state.draw.push_aux_line(
+        info,
+        vec2(x, y).round(),
+        k.bar_font_height,
+        k.bar_info_color,
+        max_x,
+    );
+    x
+}
+
+/// Draw a labeled text field on a bar row, up to `max_x`.
+fn push_bar_field(
+    state: &mut WindowState,
+    row: ScreenRect,
+    label: &str,
+    text: &str,
+    caret: bool,
+    max_x: f32,
+) {
+    let k = &state.k.g;
+    let fonth = k.bar_font_height;
+    let y = bar_baseline(state, row);
+    let pos = vec2(row.min.x + k.bar_padding, y).round();
+    let x = state
+        .draw
+        .push_aux_line(label, pos, fonth, k.bar_info_color, max_x);
+    let x = state
+        .draw
+        .push_aux_line(text, vec2(x, y).round(), fonth, k.bar_text_color, max_x);
+    if caret {
+        let caret = ScreenRect {
+            min: vec2(x, row.min.y + (k.bar_height - fonth) / 2.),
+            max: vec2(x + k.cursor_width, row.max.y - (k.bar_height - fonth) / 2.),
+        };
+        state.draw.aux_vbo.push_quad(caret, k.cursor_color);
+    }
 }
 
 pub fn draw_notext(state: &mut WindowState, ftab: &mut FileTab, ctx: &mut FrameCtx) -> Result<()> {
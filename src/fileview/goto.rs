@@ -0,0 +1,84 @@
+//! The go-to bar of a file view, which jumps to a line or to a byte offset.
+
+use crate::{cfg::Cfg, prelude::*, ScreenRect};
+
+use super::search::SearchBar;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GotoTarget {
+    /// A line number, counting from zero.
+    Line(i64),
+    /// A byte offset into the buffer, which might not be at a character boundary.
+    Offset(i64),
+}
+
+#[derive(Default)]
+pub struct GotoBar {
+    /// The line number or offset, as typed in.
+    pub text: String,
+    /// Whether the text is a byte offset instead of a line number.
+    pub offset: bool,
+    /// The target that is being jumped to, while its exact position is not known
+    /// yet.
+    pub pending: Option<GotoTarget>,
+    /// Where the view was last moved to while the target is pending.
+    pub jumped: Option<i64>,
+}
+impl GotoBar {
+    /// Parse the typed text, given the length of the buffer.
+    /// Both modes accept a percentage of the buffer, like `73%`.
+    /// Offsets may be decimal or hexadecimal with a `0x` prefix, and digits may be
+    /// grouped with commas or underscores.
+    pub fn target(&self, len: i64) -> Option<GotoTarget> {
+        let text: String = self
+            .text
+            .chars()
+            .filter(|c| !matches!(c, ',' | '_') && !c.is_whitespace())
+            .collect();
+        if let Some(pct) = text.strip_suffix('%') {
+            let pct: f64 = pct.parse().ok().filter(|p: &f64| p.is_finite())?;
+            let off = (pct.clamp(0., 100.) / 100. * len as f64) as i64;
+            return Some(GotoTarget::Offset(off));
+        }
+        if self.offset {
+            let off = match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+                Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+                None => text.parse().ok()?,
+            };
+            Some(GotoTarget::Offset(off.clamp(0, len)))
+        } else {
+            let line: i64 = text.parse().ok()?;
+            Some(GotoTarget::Line((line - 1).max(0)))
+        }
+    }
+
+    /// Start jumping to the typed target, if it is valid.
+    pub fn submit(&mut self, len: i64) {
+        self.pending = self.target(len);
+        self.jumped = None;
+    }
+
+    /// A description of what can be typed in.
+    pub fn label(&self) -> &'static str {
+        match self.offset {
+            true => "Go to offset: ",
+            false => "Go to line: ",
+        }
+    }
+
+    /// The bar sits at the bottom of the file view, right above the find bar if
+    /// there is one.
+    pub fn bounds(&self, k: &Cfg, view: ScreenRect, search: Option<&SearchBar>) -> ScreenRect {
+        let max = vec2(
+            view.max.x - k.g.scrollbar_width,
+            match search {
+                Some(bar) => bar.bounds(k, view).min.y,
+                None => view.max.y - k.g.scrollbar_width,
+            },
+        );
+        ScreenRect {
+            min: vec2(view.min.x, max.y - k.g.bar_height),
+            max,
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! The hex dump mode of a file view.
+//!
+//! Every row shows a fixed amount of bytes, so the row of any offset is a single
+//! division away and no linemap is needed to jump around the buffer.
+//! Positions are measured like the deltas of a `FilePos` with a zero base offset:
+//! rows vertically and font heights horizontally.
+
+use crate::{
+    cfg::Cfg,
+    filebuf::{CharLayout, FilePos},
+    prelude::*,
+};
+
+use super::{MoveCmd, MoveKind};
+
+#[cfg(test)]
+mod test;
+
+/// How many bytes are shown on each row.
+pub const ROW_LEN: i64 = 16;
+
+/// Space between the hex bytes and the ASCII gutter, in cells.
+const GUTTER_GAP: i64 = 2;
+
+pub struct HexView {
+    /// The width of a character cell, relative to the font height.
+    /// The font might not be monospace, so each character is centered in its cell.
+    pub cell: f64,
+    /// The cursor and scroll position of the text view when switching to hex, to
+    /// return to them if the cursor did not move in the meantime.
+    pub text_view: (i64, FilePos),
+    /// Whether the cursor is on the low nibble of its byte rather than on the high
+    /// one.
+    pub low_nibble: bool,
+    /// Whether typed hex digits overwrite the bytes at the cursor, instead of
+    /// inserting new bytes.
+    pub overwrite: bool,
+}
+impl HexView {
+    pub fn new(layout: &CharLayout, text_view: (i64, FilePos)) -> Self {
+        let cell = (b' '..=b'~')
+            .map(|c| layout.advance_for(c as u32))
+            .fold(0., f64::max);
+        Self {
+            cell,
+            text_view,
+            low_nibble: false,
+            overwrite: true,
+        }
+    }
+
+    /// The row that contains the given offset.
+    pub fn row_of(offset: i64) -> i64 {
+        offset.div_euclid(ROW_LEN)
+    }
+
+    /// The amount of rows needed to show a buffer of the given length, including
+    /// the position right after the last byte.
+    pub fn rows(len: i64) -> i64 {
+        len / ROW_LEN + 1
+    }
+
+    /// The horizontal position of the byte at the given column in the hex area.
+    /// Each byte takes up two cells, followed by a space and an extra space halfway
+    /// through the row.
+    pub fn hex_x(&self, col: i64) -> f64 {
+        (3 * col + (col >= ROW_LEN / 2) as i64) as f64 * self.cell
+    }
+
+    /// The horizontal position of the byte at the given column in the ASCII gutter.
+    pub fn ascii_x(&self, col: i64) -> f64 {
+        (3 * ROW_LEN + GUTTER_GAP + col) as f64 * self.cell
+    }
+
+    /// The width of a whole row.
+    pub fn width(&self) -> f64 {
+        self.ascii_x(ROW_LEN)
+    }
+
+    /// The offset of the byte under the given position, and whether the position is
+    /// on its low nibble.
+    /// Positions past the end of a row map to the start of the next row, so that
+    /// selections can include the last byte of a row.
+    pub fn offset_at(&self, pos: DVec2) -> (i64, bool) {
+        let x = pos.x;
+        let ascii = x >= self.ascii_x(0) - self.cell;
+        let col = if ascii {
+            ((x - self.ascii_x(0)) / self.cell).floor()
+        } else {
+            // Split the space around each byte evenly between its neighbours
+            let gap = if x >= self.hex_x(ROW_LEN / 2) - self.cell / 2. {
+                self.cell
+            } else {
+                0.
+            };
+            ((x + self.cell / 2. - gap) / (3. * self.cell)).floor()
+        };
+        let col = (col as i64).clamp(0, ROW_LEN);
+        let low = !ascii && col < ROW_LEN && x >= self.hex_x(col) + self.cell;
+        let row = (pos.y.floor() as i64).max(0);
+        (row * ROW_LEN + col, low)
+    }
+
+    /// Apply a cursor movement to the given offset, within a buffer of length `len`.
+    /// Characters are nibbles while moving the cursor and bytes while selecting, lines
+    /// are rows, and moving to the start or end of a line stays within the row.
+    pub fn apply_move(&mut self, cmd: &MoveCmd, current: i64, len: i64) -> i64 {
+        let low = mem::replace(&mut self.low_nibble, false);
+        let row_start = Self::row_of(current) * ROW_LEN;
+        let off = match cmd.kind {
+            MoveKind::Absolute(pos) => {
+                let (off, low) = self.offset_at(dvec2(pos.delta_x, pos.delta_y));
+                self.low_nibble = low && cmd.reset && off < len;
+                off
+            }
+            MoveKind::Raw(off) => off,
+            MoveKind::CharDelta(delta) if cmd.reset => {
+                let nibble = (current * 2 + low as i64 + delta as i64).clamp(0, len * 2);
+                self.low_nibble = nibble % 2 == 1;
+                nibble / 2
+            }
+            MoveKind::CharDelta(delta) => current + delta as i64,
+            MoveKind::LineDelta(delta) => current + delta * ROW_LEN,
+            MoveKind::HorizontalDelta(delta) if delta < 0. => row_start,
+            MoveKind::HorizontalDelta(_) => row_start + ROW_LEN - 1,
+        };
+        off.clamp(0, len)
+    }
+
+    /// Scroll just enough to have the byte at the cursor within a view of the
+    /// given size.
+    pub fn fit_cursor(&self, scroll: &mut FilePos, size: DVec2, cursor: i64, k: &Cfg) {
+        let pad = k.ui.cursor_padding;
+        let y = Self::row_of(cursor) as f64;
+        let x = self.hex_x(cursor.rem_euclid(ROW_LEN));
+        let ylo = y + 1. + pad - size.y;
+        let xlo = x + 2. * self.cell + pad - size.x;
+        scroll.delta_y = scroll.delta_y.clamp(ylo, (y - pad).max(ylo));
+        scroll.delta_x = scroll.delta_x.clamp(xlo, (x - pad).max(xlo));
+    }
+
+    /// The character shown for a byte in the ASCII gutter.
+    pub fn ascii_char(b: u8) -> char {
+        match b {
+            b' '..=b'~' => b as char,
+            _ => '.',
+        }
+    }
+
+    /// The two hex digits of a byte.
+    pub fn hex_digits(b: u8) -> [char; 2] {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        [
+            DIGITS[(b >> 4) as usize] as char,
+            DIGITS[(b & 15) as usize] as char,
+        ]
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{
+    filebuf::FilePos,
+    fileview::{hex::HexView, MoveCmd, MoveKind},
+    prelude::*,
+};
+
+fn hex() -> HexView {
+    HexView {
+        cell: 0.5,
+        text_view: (0, FilePos::default()),
+        low_nibble: false,
+        overwrite: true,
+    }
+}
+
+#[test]
+fn hex_layout() {
+    let h = hex();
+    assert_eq!(HexView::row_of(0), 0);
+    assert_eq!(HexView::row_of(15), 0);
+    assert_eq!(HexView::row_of(16), 1);
+    assert_eq!(HexView::rows(0), 1);
+    assert_eq!(HexView::rows(16), 2);
+    // Two cells per byte, a space after each and an extra one halfway through
+    assert_eq!(h.hex_x(1), 1.5);
+    assert_eq!(h.hex_x(8), 12.5);
+    assert_eq!(h.ascii_x(0), 25.);
+    assert_eq!(h.width(), 33.);
+    assert_eq!(HexView::hex_digits(0x3f), ['3', 'f']);
+    assert_eq!(HexView::ascii_char(b'a'), 'a');
+    assert_eq!(HexView::ascii_char(0x7f), '.');
+}
+
+#[test]
+fn hex_hit() {
+    let h = hex();
+    // Clicks land on the nibble under the mouse, on either side
+    assert_eq!(h.offset_at(dvec2(0.2, 0.5)), (0, false));
+    assert_eq!(h.offset_at(dvec2(h.hex_x(3) + 0.9, 2.5)), (35, true));
+    assert_eq!(h.offset_at(dvec2(h.hex_x(8) - 0.1, 0.)), (8, false));
+    assert_eq!(h.offset_at(dvec2(h.ascii_x(5) + 0.2, 1.)), (21, false));
+    // Outside of the row they are clamped
+    assert_eq!(h.offset_at(dvec2(-3., -2.)), (0, false));
+    assert_eq!(h.offset_at(dvec2(h.hex_x(15) + 1.3, 0.)), (16, false));
+    assert_eq!(h.offset_at(dvec2(100., 0.)), (16, false));
+}
+
+#[test]
+fn hex_nibbles() {
+    let mut h = hex();
+    let step = |h: &mut HexView, reset: bool, delta: i16, at: i64| {
+        let cmd = MoveCmd {
+            reset,
+            kind: MoveKind::CharDelta(delta),
+        };
+        h.apply_move(&cmd, at, 20)
+    };
+    // The arrows step through nibbles
+    assert_eq!(step(&mut h, true, 1, 4), 4);
+    assert!(h.low_nibble);
+    assert_eq!(step(&mut h, true, 1, 4), 5);
+    assert!(!h.low_nibble);
+    assert_eq!(step(&mut h, true, -1, 5), 4);
+    assert!(h.low_nibble);
+    // Selecting goes byte by byte
+    assert_eq!(step(&mut h, false, 1, 4), 5);
+    assert!(!h.low_nibble);
+    // There are no nibbles past the end
+    assert_eq!(step(&mut h, true, 3, 19), 20);
+    assert!(!h.low_nibble);
+    let cmd = MoveCmd {
+        reset: true,
+        kind: MoveKind::LineDelta(-1),
+    };
+    assert_eq!(h.apply_move(&cmd, 20, 20), 4);
+}
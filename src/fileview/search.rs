@@ -0,0 +1,119 @@
+//! The find and replace bar of a file view.
+
+use crate::{cfg::Cfg, filebuf::SearchQuery, prelude::*, ScreenRect};
+
+pub struct SearchBar {
+    /// The query, as typed in.
+    pub text: String,
+    pub regex: bool,
+    pub case_insensitive: bool,
+    pub whole_word: bool,
+    /// The replacement text, if the bar is in replace mode.
+    pub replace: Option<String>,
+    /// Whether the bar takes keyboard input, instead of the file view.
+    pub focused: bool,
+    /// Whether keyboard input goes to the replacement text instead of the query.
+    pub replace_focused: bool,
+    /// Whether the query changed since it was last sent to the backend.
+    pub changed: bool,
+}
+impl SearchBar {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            regex: false,
+            case_insensitive: false,
+            whole_word: false,
+            replace: None,
+            focused: true,
+            replace_focused: false,
+            changed: true,
+        }
+    }
+
+    /// The query to search for, if any.
+    pub fn query(&self) -> Option<SearchQuery> {
+        if self.text.is_empty() {
+            None
+        } else {
+            Some(SearchQuery {
+                text: self.text.clone(),
+                regex: self.regex,
+                case_insensitive: self.case_insensitive,
+                whole_word: self.whole_word,
+            })
+        }
+    }
+
+    pub fn type_char(&mut self, c: char) {
+        match &mut self.replace {
+            Some(text) if self.replace_focused => text.push(c),
+            _ => {
+                self.text.push(c);
+                self.changed = true;
+            }
+        }
+    }
+
+    /// Flip one of the search options, as picked by `get`.
+    pub fn toggle(&mut self, get: impl FnOnce(&mut Self) -> &mut bool) {
+        let flag = get(self);
+        *flag = !*flag;
+        self.changed = true;
+    }
+
+    /// The search options, as short regex-like labels along with whether they are
+    /// enabled.
+    pub fn toggles(&self) -> [(&'static str, bool); 3] {
+        [
+            ("i", self.case_insensitive),
+            ("\\b", self.whole_word),
+            (".*", self.regex),
+        ]
+    }
+
+    pub fn backspace(&mut self) {
+        match &mut self.replace {
+            Some(text) if self.replace_focused => {
+                text.pop();
+            }
+            _ => {
+                if self.text.pop().is_some() {
+                    self.changed = true;
+                }
+            }
+        }
+    }
+
+    /// Move the keyboard focus between the query and the replacement text.
+    pub fn switch_field(&mut self) {
+        self.replace_focused = !self.replace_focused && self.replace.is_some();
+    }
+
+    /// The amount of rows in the bar: one for the query and one for the
+    /// replacement, if any.
+    fn rows(&self) -> usize {
+        1 + self.replace.is_some() as usize
+    }
+
+    /// The bar sits at the bottom of the file view, above the horizontal scrollbar.
+    pub fn bounds(&self, k: &Cfg, view: ScreenRect) -> ScreenRect {
+        let max = vec2(
+            view.max.x - k.g.scrollbar_width,
+            view.max.y - k.g.scrollbar_width,
+        );
+        ScreenRect {
+            min: vec2(view.min.x, max.y - k.g.bar_height * self.rows() as f32),
+            max,
+        }
+    }
+
+    /// The bounds of a single row of the bar, with the query on row 0 and the
+    /// replacement on row 1.
+    pub fn row_bounds(&self, k: &Cfg, view: ScreenRect, row: usize) -> ScreenRect {
+        let mut b = self.bounds(k, view);
+        b.min.y += k.g.bar_height * row as f32;
+        b.max.y = b.min.y + k.g.bar_height;
+        b
+    }
+}
@@ -1,5 +1,6 @@
 use crate::prelude::*;
 use cfg::Cfg;
+use dialog::{Choice, Dialog};
 use drawing::DrawState;
 use fileview::FileTab;
 use gl::{
@@ -7,6 +8,7 @@ use gl::{
     winit::event::{ElementState, MouseButton, StartCause, VirtualKeyCode},
     *,
 };
+use toast::Toasts;
 
 mod prelude {
     pub(crate) use crate::filebuf::FileBuffer;
@@ -114,9 +116,11 @@ mod prelude {
 }
 
 mod cfg;
+mod dialog;
 mod drawing;
 mod filebuf;
 mod fileview;
+mod toast;
 
 #[derive(Default)]
 pub struct InputState {
@@ -157,6 +161,10 @@ impl InputState {
     fn shift(&self) -> bool {
         self.key(VirtualKeyCode::LShift) || self.key(VirtualKeyCode::RShift)
     }
+
+    fn alt(&self) -> bool {
+        self.key(VirtualKeyCode::LAlt) || self.key(VirtualKeyCode::RAlt)
+    }
 }
 
 pub struct WindowState {
@@ -164,6 +172,17 @@ pub struct WindowState {
     draw: DrawState,
     cur_tab: usize,
     tabs: Vec<Box<FileTab>>,
+    /// Buffers of closed tabs, whose backends might still be finishing up.
+    closed: Vec<FileBuffer>,
+    /// A modal dialog, which refers to the current tab.
+    dialog: Option<Dialog>,
+    /// Notifications shown over the file view.
+    toasts: Toasts,
+    /// Whether the window should close once every tab is saved or discarded.
+    quitting: bool,
+    /// Once every tab is saved or discarded, how long to wait for the backends to
+    /// finish up before closing the window anyway.
+    quit_deadline: Option<Instant>,
     k: Cfg,
     last_mouse_pos: Vec2,
     screen: ScreenRect,
@@ -196,6 +215,13 @@ impl WindowState {
     fn fileview_bounds(k: &Cfg, screen: ScreenRect) -> ScreenRect {
         ScreenRect {
             min: screen.min + vec2(0., k.g.tab_height),
+            max: screen.max - vec2(0., k.g.status_height),
+        }
+    }
+
+    fn status_bar_bounds(k: &Cfg, screen: ScreenRect) -> ScreenRect {
+        ScreenRect {
+            min: vec2(screen.min.x, screen.max.y - k.g.status_height),
             max: screen.max,
         }
     }
@@ -240,7 +266,48 @@ impl WindowState {
 
     fn try_load_file(&mut self, path: PathBuf) {
         if let Err(err) = self.load_file(path.clone()) {
-            println!("error loading file at \"{}\": {:#}", path.display(), err);
+            self.notify(
+                true,
+                format!("error loading file at \"{}\": {:#}", path.display(), err),
+            );
+        }
+    }
+
+    /// Tell the user about something, with a toast.
+    fn notify(&mut self, error: bool, text: String) {
+        println!("{}", text);
+        self.toasts.push(text, error, Instant::now());
+        self.redraw();
+    }
+
+    /// Show the notices sent by the backends of every buffer, including closed ones.
+    fn poll_notices(&mut self) {
+        let files = self.tabs.iter().map(|t| &t.file).chain(self.closed.iter());
+        for file in files {
+            for notice in file.take_notices() {
+                let text = format!("{}: {}", file.friendly_name(), notice.text);
+                self.toasts.push(text, notice.error, Instant::now());
+            }
+        }
+    }
+
+    /// Dismiss the toast under the mouse, if the event is a click on one.
+    /// Returns whether the event was taken by a toast.
+    fn handle_toast_event(&mut self, ev: &gl::winit::event::Event<()>) -> bool {
+        use gl::winit::event::{Event, WindowEvent};
+        match ev {
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } if elem2bool(*state) && mouse2id(*button) == self.k.ui.select_button => {
+                let view = Self::fileview_bounds(&self.k, self.screen);
+                let hit = self.toasts.handle_click(&self.k, view, self.last_mouse_pos);
+                if hit {
+                    self.redraw();
+                }
+                hit
+            }
+            _ => false,
         }
     }
 
@@ -257,7 +324,10 @@ impl WindowState {
 
     fn kill_tab(&mut self, i: usize) {
         if i < self.tabs.len() {
-            self.tabs.remove(i);
+            let tab = self.tabs.remove(i);
+            tab.file.close(Duration::ZERO);
+            self.closed.retain(|file| !file.is_closed());
+            self.closed.push(tab.file);
             if self.cur_tab > 0 && self.cur_tab == self.tabs.len() {
                 self.cur_tab -= 1;
             }
@@ -265,6 +335,161 @@ impl WindowState {
         }
     }
 
+    /// Close a tab, asking whether to save it first if it has unsaved edits.
+    fn close_tab(&mut self, i: usize) {
+        match self.tabs.get(i) {
+            Some(tab) if tab.file.is_dirty() => {
+                let dialog = Dialog::save_changes(tab.file.friendly_name());
+                self.select_tab(i);
+                self.dialog = Some(dialog);
+                self.redraw();
+            }
+            Some(_) => self.kill_tab(i),
+            None => {}
+        }
+    }
+
+    fn answer_dialog(&mut self, choice: Choice) {
+        self.dialog = None;
+        self.redraw();
+        let i = self.cur_tab;
+        let tab = match self.tabs.get_mut(i) {
+            Some(tab) => tab,
+            None => return,
+        };
+        match choice {
+            Choice::Save => {
+                tab.close_after_save = true;
+                tab.file.lock().save();
+            }
+            Choice::Discard => {
+                tab.file.discard();
+                self.kill_tab(i);
+            }
+            Choice::Cancel => self.quitting = false,
+            Choice::Reload => tab.file.reload(),
+            Choice::Keep => {}
+        }
+    }
+
+    /// Offer to reload the current tab if its file was changed by another process.
+    /// Only asks once per change, the file can still be reloaded by hand afterwards.
+    fn check_external_change(&mut self) {
+        if self.dialog.is_some() {
+            return;
+        }
+        let tab = match self.tabs.get_mut(self.cur_tab) {
+            Some(tab) => tab,
+            None => return,
+        };
+        match tab.file.external_change() {
+            Some(what) if !tab.reload_asked => {
+                tab.reload_asked = true;
+                let name = tab.file.friendly_name();
+                self.dialog = Some(Dialog::reload(name, &what, tab.file.is_dirty()));
+                self.redraw();
+            }
+            Some(_) => {}
+            None => tab.reload_asked = false,
+        }
+    }
+
+    /// Close the tabs that finished saving.
+    /// Returns whether there are any tabs still waiting to be saved.
+    fn check_closing(&mut self) -> bool {
+        if self.dialog.is_some() {
+            // Keep the tab that the dialog refers to in place
+            return true;
+        }
+        let mut waiting = false;
+        let mut i = 0;
+        while i < self.tabs.len() {
+            let tab = &mut self.tabs[i];
+            if tab.close_after_save && !tab.file.lock().is_saving() {
+                if !tab.file.is_dirty() {
+                    self.kill_tab(i);
+                    continue;
+                }
+                let text = format!(
+                    "\"{}\" was not saved, keeping it open",
+                    tab.file.friendly_name()
+                );
+                tab.close_after_save = false;
+                self.quitting = false;
+                self.notify(true, text);
+                i += 1;
+                continue;
+            }
+            waiting |= tab.close_after_save;
+            i += 1;
+        }
+        waiting
+    }
+
+    /// Go through the tabs with unsaved edits, and close the window once they are all
+    /// saved or discarded.
+    fn continue_quit(&mut self, flow: &mut ControlFlow) {
+        if !self.quitting || self.dialog.is_some() {
+            return;
+        }
+        let pending = self
+            .tabs
+            .iter()
+            .position(|t| t.file.is_dirty() && !t.close_after_save);
+        if let Some(i) = pending {
+            self.close_tab(i);
+            return;
+        }
+        if self.tabs.iter().any(|t| t.close_after_save) {
+            return;
+        }
+        // Give the backends a chance to persist the buffer state, all at once and
+        // without blocking the window
+        let mut files = self.tabs.iter().map(|t| &t.file).chain(self.closed.iter());
+        let deadline = *self.quit_deadline.get_or_insert_with(|| {
+            for file in files.clone() {
+                file.close(Duration::ZERO);
+            }
+            Instant::now() + Duration::from_secs(2)
+        });
+        let now = Instant::now();
+        if now >= deadline || files.all(|file| file.is_closed()) {
+            *flow = ControlFlow::Exit;
+        } else {
+            *flow = ControlFlow::WaitUntil((now + Duration::from_millis(10)).min(deadline));
+        }
+    }
+
+    /// Handle input while a dialog is open.
+    fn handle_dialog_event(&mut self, ev: &gl::winit::event::Event<()>) {
+        use gl::winit::event::{Event, WindowEvent};
+        let dialog = match &mut self.dialog {
+            Some(dialog) => dialog,
+            None => return,
+        };
+        let choice = match ev {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::KeyboardInput { input, .. } if elem2bool(input.state) => {
+                    self.display.gl_window().window().request_redraw();
+                    match input.virtual_keycode {
+                        Some(key) => dialog.handle_key(key, self.keys.shift()),
+                        None => None,
+                    }
+                }
+                WindowEvent::MouseInput { state, button, .. }
+                    if elem2bool(*state) && mouse2id(*button) == self.k.ui.select_button =>
+                {
+                    dialog.handle_click(&self.k, self.screen, self.last_mouse_pos)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(choice) = choice {
+            self.answer_dialog(choice);
+        }
+    }
+
     fn handle_tab_click(&mut self, button: u16, down: bool) {
         for i in 0..self.tabs.len() {
             let tab_bounds = Self::tab_bounds(&self.k, i, self.tabs.len(), self.screen);
@@ -273,7 +498,7 @@ impl WindowState {
                 if down && button == self.k.ui.tab_select_button {
                     self.select_tab(i);
                 } else if down && button == self.k.ui.tab_kill_button {
-                    self.kill_tab(i);
+                    self.close_tab(i);
                 }
             }
         }
@@ -281,21 +506,28 @@ impl WindowState {
 
     fn handle_event(&mut self, ev: gl::winit::event::Event<()>, flow: &mut ControlFlow) {
         use gl::winit::event::{Event, WindowEvent};
-        // Dispatch event to active file view
-        if let Some(mut ftab) = self.take_ftab(self.cur_tab) {
+        // Dialogs are modal, so they take over the input
+        let modal = self.dialog.is_some();
+        if modal {
+            self.handle_dialog_event(&ev);
+        } else if self.handle_toast_event(&ev) {
+            // Clicked a toast, which covers the file view
+        } else if let Some(mut ftab) = self.take_ftab(self.cur_tab) {
+            // Dispatch event to active file view
             ftab.view.handle_event(&ftab.file, self, &ev);
             self.put_ftab(self.cur_tab, ftab);
         }
         // Handle event at the window level
         match ev {
             Event::WindowEvent { event, .. } => match event {
-                WindowEvent::CloseRequested => *flow = ControlFlow::Exit,
+                WindowEvent::CloseRequested => self.quitting = true,
                 WindowEvent::KeyboardInput { input, .. } => {
                     use glutin::event::VirtualKeyCode::*;
                     let down = elem2bool(input.state);
                     match input.virtual_keycode {
+                        _ if modal => {}
                         Some(W) if down && self.keys.ctrl() => {
-                            self.kill_tab(self.cur_tab);
+                            self.close_tab(self.cur_tab);
                         }
                         Some(O) if down && self.keys.ctrl() => {
                             let paths = gl::native_dialog::FileDialog::new()
@@ -307,7 +539,9 @@ impl WindowState {
                                         self.try_load_file(path);
                                     }
                                 }
-                                Err(err) => println!("failed to pick file: {:#}", err),
+                                Err(err) => {
+                                    self.notify(true, format!("failed to pick file: {:#}", err))
+                                }
                             }
                         }
                         Some(Tab) if down && self.keys.ctrl() => {
@@ -334,7 +568,7 @@ impl WindowState {
                     let button = mouse2id(button);
                     let down = elem2bool(st);
                     let tabs_bounds = Self::tab_bar_bounds(&self.k, self.screen);
-                    if tabs_bounds.is_inside(self.last_mouse_pos) {
+                    if !modal && tabs_bounds.is_inside(self.last_mouse_pos) {
                         self.handle_tab_click(button, down);
                     }
                     self.keys.set_mouse_down(button, down);
@@ -354,7 +588,13 @@ impl WindowState {
                 _ => {}
             },
             Event::RedrawRequested(_) => match drawing::draw(self) {
-                Ok(next_draw) => {
+                Ok(mut next_draw) => {
+                    self.check_external_change();
+                    if self.check_closing() {
+                        // Keep checking on the tabs that are being saved
+                        let soon = Instant::now() + Duration::from_millis(50);
+                        next_draw = Some(next_draw.map_or(soon, |nxt| nxt.min(soon)));
+                    }
                     *flow = match next_draw {
                         Some(nxt) => ControlFlow::WaitUntil(nxt),
                         None => ControlFlow::Wait,
@@ -366,6 +606,7 @@ impl WindowState {
             },
             _ => {}
         }
+        self.continue_quit(flow);
     }
 }
 
@@ -423,6 +664,11 @@ fn main() -> Result<()> {
 
     let mut state = WindowState {
         tabs: vec![],
+        closed: vec![],
+        dialog: None,
+        toasts: default(),
+        quitting: false,
+        quit_deadline: None,
         cur_tab: 0,
         last_mouse_pos: Vec2::ZERO,
         screen: ScreenRect {
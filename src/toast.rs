@@ -0,0 +1,91 @@
+//! Short notifications shown over the file view, such as errors from the backends.
+//!
+//! Like dialogs, the toast state and layout do not depend on the window, so they can
+//! be driven without a display.
+
+use crate::{cfg::Cfg, prelude::*, ScreenRect};
+
+#[cfg(test)]
+mod test;
+
+/// How many toasts can be shown at once. Older toasts are dropped to make room.
+const MAX_TOASTS: usize = 5;
+
+pub struct Toast {
+    pub text: String,
+    /// Errors stay until they are dismissed, instead of expiring.
+    pub error: bool,
+    /// When the toast was last shown.
+    pub since: Instant,
+}
+
+#[derive(Default)]
+pub struct Toasts {
+    /// The toasts being shown, from oldest to newest.
+    pub list: Vec<Toast>,
+}
+impl Toasts {
+    /// Show a new toast.
+    /// If the same message is already being shown, it is brought back to the front
+    /// instead, so repeated failures do not pile up.
+    pub fn push(&mut self, text: String, error: bool, now: Instant) {
+        if let Some(i) = self.list.iter().position(|t| t.text == text) {
+            self.list.remove(i);
+        }
+        self.list.push(Toast {
+            text,
+            error,
+            since: now,
+        });
+        if self.list.len() > MAX_TOASTS {
+            self.list.remove(0);
+        }
+    }
+
+    /// Drop the informational toasts that were shown for long enough.
+    /// Returns when the next toast expires, if any will.
+    pub fn expire(&mut self, k: &Cfg, now: Instant) -> Option<Instant> {
+        let duration = Duration::from_secs_f64(k.g.toast_duration.max(0.));
+        self.list
+            .retain(|t| t.error || now.saturating_duration_since(t.since) < duration);
+        self.list
+            .iter()
+            .filter(|t| !t.error)
+            .map(|t| t.since + duration)
+            .min()
+    }
+
+    /// The bounds of each toast, in the same order as the list.
+    /// Toasts are stacked at the top right corner of the file view, with the newest
+    /// one on top.
+    pub fn layout(&self, k: &Cfg, view: ScreenRect) -> Vec<ScreenRect> {
+        let size = Vec2::from(k.g.toast_size);
+        let margin = k.g.toast_margin;
+        let right = view.max.x - k.g.scrollbar_width - margin;
+        let n = self.list.len();
+        (0..n)
+            .map(|i| {
+                let min = vec2(
+                    (right - size.x).max(view.min.x),
+                    view.min.y + margin + (n - 1 - i) as f32 * (size.y + margin),
+                );
+                ScreenRect {
+                    min,
+                    max: vec2(right, min.y + size.y),
+                }
+            })
+            .collect()
+    }
+
+    /// Dismiss the toast at the given screen position, if any.
+    /// Returns whether a toast was clicked.
+    pub fn handle_click(&mut self, k: &Cfg, view: ScreenRect, pos: Vec2) -> bool {
+        match self.layout(k, view).iter().position(|b| b.is_inside(pos)) {
+            Some(i) => {
+                self.list.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
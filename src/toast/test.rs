@@ -0,0 +1,50 @@
+use crate::{cfg::Cfg, prelude::*, toast::Toasts, ScreenRect};
+
+fn view() -> ScreenRect {
+    ScreenRect {
+        min: vec2(0., 30.),
+        max: vec2(800., 578.),
+    }
+}
+
+#[test]
+fn toast_expire() {
+    let k = Cfg::default();
+    let now = Instant::now();
+    let mut t = Toasts::default();
+    t.push("saved".to_string(), false, now);
+    t.push("failed".to_string(), true, now);
+    t.push("saved".to_string(), false, now);
+    assert_eq!(t.list.len(), 2);
+    assert_eq!(t.list[1].text, "saved");
+    let duration = Duration::from_secs_f64(k.g.toast_duration);
+    assert_eq!(t.expire(&k, now), Some(now + duration));
+    assert_eq!(t.expire(&k, now + duration), None);
+    assert_eq!(t.list.len(), 1);
+    assert!(t.list[0].error);
+    for i in 0..10 {
+        t.push(format!("error {}", i), true, now);
+    }
+    assert_eq!(t.list.len(), 5);
+    assert_eq!(t.list[4].text, "error 9");
+}
+
+#[test]
+fn toast_clicks() {
+    let k = Cfg::default();
+    let now = Instant::now();
+    let mut t = Toasts::default();
+    t.push("first".to_string(), true, now);
+    t.push("second".to_string(), true, now);
+    let layout = t.layout(&k, view());
+    // The newest toast goes on top
+    assert!(layout[1].max.y <= layout[0].min.y);
+    for b in layout.iter() {
+        assert!(b.min.x >= 0. && b.max.x <= 800. && b.min.y >= 30.);
+    }
+    let center = (layout[0].min + layout[0].max) / 2.;
+    assert!(!t.handle_click(&k, view(), vec2(1., 1.)));
+    assert!(t.handle_click(&k, view(), center));
+    assert_eq!(t.list.len(), 1);
+    assert_eq!(t.list[0].text, "second");
+}